@@ -0,0 +1 @@
+ALTER TABLE audiobooks ADD COLUMN cover_hash VARCHAR(64);
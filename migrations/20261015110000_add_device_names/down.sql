@@ -0,0 +1,2 @@
+ALTER TABLE playstates DROP COLUMN api_token_id;
+ALTER TABLE api_tokens DROP COLUMN device_name;
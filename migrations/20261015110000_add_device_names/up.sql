@@ -0,0 +1,2 @@
+ALTER TABLE api_tokens ADD COLUMN device_name VARCHAR(255);
+ALTER TABLE playstates ADD COLUMN api_token_id VARCHAR(36);
@@ -0,0 +1,8 @@
+CREATE TABLE scans (
+    id VARCHAR(36) PRIMARY KEY,
+    library_id VARCHAR(36) REFERENCES libraries (id) NOT NULL,
+    full BOOLEAN NOT NULL,
+    started_at TIMESTAMP NOT NULL,
+    finished_at TIMESTAMP,
+    error TEXT
+);
@@ -0,0 +1,2 @@
+ALTER TABLE audiobooks DROP COLUMN file_size;
+ALTER TABLE audiobooks DROP COLUMN file_mtime;
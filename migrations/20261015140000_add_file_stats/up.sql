@@ -0,0 +1,2 @@
+ALTER TABLE audiobooks ADD COLUMN file_mtime TIMESTAMP;
+ALTER TABLE audiobooks ADD COLUMN file_size BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE audiobooks ADD COLUMN data_hash BLOB;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN feed_token VARCHAR(64);
+CREATE UNIQUE INDEX users_feed_token ON users (feed_token);
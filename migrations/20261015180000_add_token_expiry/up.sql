@@ -0,0 +1,2 @@
+ALTER TABLE api_tokens ADD COLUMN expires_at TIMESTAMP;
+UPDATE api_tokens SET expires_at = datetime(created_at, '+90 days');
@@ -0,0 +1 @@
+ALTER TABLE audiobooks DROP COLUMN sort_title;
@@ -0,0 +1,2 @@
+ALTER TABLE audiobooks ADD COLUMN sort_title VARCHAR NOT NULL DEFAULT '';
+UPDATE audiobooks SET sort_title = lower(title);
@@ -0,0 +1,3 @@
+ALTER TABLE audiobooks DROP COLUMN author_id;
+DROP TABLE author_aliases;
+DROP TABLE authors;
@@ -0,0 +1,11 @@
+CREATE TABLE authors (
+    id VARCHAR(36) PRIMARY KEY,
+    name VARCHAR NOT NULL
+);
+
+CREATE TABLE author_aliases (
+    normalized_name VARCHAR PRIMARY KEY,
+    author_id VARCHAR(36) REFERENCES authors (id) NOT NULL
+);
+
+ALTER TABLE audiobooks ADD COLUMN author_id VARCHAR(36) REFERENCES authors (id);
@@ -0,0 +1,8 @@
+CREATE TABLE bookmarks (
+    id VARCHAR(36) PRIMARY KEY,
+    user_id VARCHAR(36) REFERENCES users (id) NOT NULL,
+    audiobook_id VARCHAR(36) REFERENCES audiobooks (id) NOT NULL,
+    position_secs DOUBLE NOT NULL,
+    note TEXT,
+    created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,6 @@
+CREATE TABLE audiobook_metadata (
+    audiobook_id VARCHAR(36) REFERENCES audiobooks (id) NOT NULL,
+    key VARCHAR NOT NULL,
+    value VARCHAR NOT NULL,
+    PRIMARY KEY (audiobook_id, key)
+);
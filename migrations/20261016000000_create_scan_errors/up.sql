@@ -0,0 +1,8 @@
+CREATE TABLE scan_errors (
+    id VARCHAR(36) PRIMARY KEY,
+    scan_id VARCHAR(36) REFERENCES scans (id) NOT NULL,
+    path VARCHAR NOT NULL,
+    message TEXT NOT NULL
+);
+
+CREATE INDEX scan_errors_scan_id ON scan_errors (scan_id);
@@ -0,0 +1,3 @@
+ALTER TABLE audiobooks ADD COLUMN deleted_at TIMESTAMP;
+-- The retention window of books deleted before this starts now
+UPDATE audiobooks SET deleted_at = CURRENT_TIMESTAMP WHERE deleted;
@@ -0,0 +1,3 @@
+DROP TABLE audiobook_translations;
+ALTER TABLE users DROP COLUMN preferred_language;
+ALTER TABLE audiobooks DROP COLUMN description;
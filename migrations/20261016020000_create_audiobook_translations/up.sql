@@ -0,0 +1,9 @@
+ALTER TABLE audiobooks ADD COLUMN description TEXT;
+ALTER TABLE users ADD COLUMN preferred_language VARCHAR;
+CREATE TABLE audiobook_translations (
+    audiobook_id VARCHAR(36) REFERENCES audiobooks (id) NOT NULL,
+    language VARCHAR NOT NULL,
+    title VARCHAR,
+    description TEXT,
+    PRIMARY KEY (audiobook_id, language)
+);
@@ -0,0 +1,12 @@
+DROP TRIGGER library_permissions_inserted;
+DROP TRIGGER library_permissions_deleted;
+DROP TRIGGER audiobooks_deleted;
+DROP TRIGGER audiobooks_marked_deleted;
+DROP TABLE book_tombstones;
+DROP TRIGGER audiobook_translations_deleted;
+DROP TRIGGER audiobook_translations_inserted;
+DROP TRIGGER chapters_deleted;
+DROP TRIGGER chapters_inserted;
+DROP TRIGGER audiobooks_updated;
+DROP TRIGGER audiobooks_inserted;
+ALTER TABLE audiobooks DROP COLUMN updated_at;
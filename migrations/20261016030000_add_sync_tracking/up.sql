@@ -0,0 +1,74 @@
+-- When a book last changed in a way clients see, for delta syncs. Kept up to date by triggers so
+-- no write path can forget it.
+ALTER TABLE audiobooks ADD COLUMN updated_at TIMESTAMP;
+UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now');
+
+CREATE TRIGGER audiobooks_inserted AFTER INSERT ON audiobooks
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER audiobooks_updated AFTER UPDATE ON audiobooks
+WHEN OLD.location IS NOT NEW.location OR OLD.title IS NOT NEW.title OR OLD.artist IS NOT NEW.artist
+    OR OLD.length IS NOT NEW.length OR OLD.library_id IS NOT NEW.library_id OR OLD.hash IS NOT NEW.hash
+    OR OLD.file_extension IS NOT NEW.file_extension OR OLD.deleted IS NOT NEW.deleted
+    OR OLD.cover_hash IS NOT NEW.cover_hash OR OLD.author_id IS NOT NEW.author_id
+    OR OLD.description IS NOT NEW.description
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = NEW.id;
+END;
+
+-- Chapters and translations are sent along with their book, so changing them changes the book.
+CREATE TRIGGER chapters_inserted AFTER INSERT ON chapters
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = NEW.audiobook_id;
+END;
+
+CREATE TRIGGER chapters_deleted AFTER DELETE ON chapters
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = OLD.audiobook_id;
+END;
+
+CREATE TRIGGER audiobook_translations_inserted AFTER INSERT ON audiobook_translations
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = NEW.audiobook_id;
+END;
+
+CREATE TRIGGER audiobook_translations_deleted AFTER DELETE ON audiobook_translations
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = OLD.audiobook_id;
+END;
+
+-- Books that went away, for everyone or for the user who lost access to them.
+CREATE TABLE book_tombstones (
+    id INTEGER PRIMARY KEY NOT NULL,
+    audiobook_id VARCHAR(36) NOT NULL,
+    user_id VARCHAR(36),
+    deleted_at TIMESTAMP NOT NULL
+);
+CREATE INDEX book_tombstones_deleted_at ON book_tombstones (deleted_at);
+
+CREATE TRIGGER audiobooks_marked_deleted AFTER UPDATE OF deleted ON audiobooks
+WHEN NEW.deleted AND NOT OLD.deleted
+BEGIN
+    INSERT INTO book_tombstones (audiobook_id, user_id, deleted_at)
+        VALUES (NEW.id, NULL, strftime('%Y-%m-%d %H:%M:%f', 'now'));
+END;
+
+CREATE TRIGGER audiobooks_deleted AFTER DELETE ON audiobooks
+BEGIN
+    INSERT INTO book_tombstones (audiobook_id, user_id, deleted_at)
+        VALUES (OLD.id, NULL, strftime('%Y-%m-%d %H:%M:%f', 'now'));
+END;
+
+CREATE TRIGGER library_permissions_deleted AFTER DELETE ON library_permissions
+BEGIN
+    INSERT INTO book_tombstones (audiobook_id, user_id, deleted_at)
+        SELECT id, OLD.user_id, strftime('%Y-%m-%d %H:%M:%f', 'now') FROM audiobooks WHERE library_id = OLD.library_id;
+END;
+
+-- Books of a library someone just got access to are new to them
+CREATE TRIGGER library_permissions_inserted AFTER INSERT ON library_permissions
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE library_id = NEW.library_id;
+END;
@@ -0,0 +1,15 @@
+DROP TABLE search_index;
+
+CREATE VIRTUAL TABLE search_index USING fts5(
+    book_id UNINDEXED,
+    title,
+    artist,
+    chapters,
+    tokenize = 'unicode61 remove_diacritics 1'
+);
+
+INSERT INTO search_index (book_id, title, artist, chapters)
+    SELECT id, title, coalesce(artist, ''), coalesce(
+        (SELECT group_concat(title, char(10)) FROM chapters WHERE chapters.audiobook_id = audiobooks.id), ''
+    )
+    FROM audiobooks;
@@ -0,0 +1,28 @@
+-- FTS5 tables can't gain columns, so the index is rebuilt with descriptions. Translated titles and
+-- descriptions are indexed along with the tagged ones.
+DROP TABLE search_index;
+
+CREATE VIRTUAL TABLE search_index USING fts5(
+    book_id UNINDEXED,
+    title,
+    artist,
+    chapters,
+    description,
+    tokenize = 'unicode61 remove_diacritics 1'
+);
+
+INSERT INTO search_index (book_id, title, artist, chapters, description)
+    SELECT id,
+        title || coalesce(
+            (SELECT char(10) || group_concat(title, char(10)) FROM audiobook_translations
+             WHERE audiobook_translations.audiobook_id = audiobooks.id AND title IS NOT NULL), ''
+        ),
+        coalesce(artist, ''),
+        coalesce(
+            (SELECT group_concat(title, char(10)) FROM chapters WHERE chapters.audiobook_id = audiobooks.id), ''
+        ),
+        coalesce(description, '') || coalesce(
+            (SELECT char(10) || group_concat(description, char(10)) FROM audiobook_translations
+             WHERE audiobook_translations.audiobook_id = audiobooks.id AND description IS NOT NULL), ''
+        )
+    FROM audiobooks;
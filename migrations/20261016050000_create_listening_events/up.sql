@@ -0,0 +1,9 @@
+-- Time spent listening, derived from playstate updates.
+CREATE TABLE listening_events (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id VARCHAR(36) REFERENCES users (id) NOT NULL,
+    audiobook_id VARCHAR(36) REFERENCES audiobooks (id) NOT NULL,
+    day DATE NOT NULL,
+    seconds DOUBLE NOT NULL
+);
+CREATE INDEX listening_events_user_day ON listening_events (user_id, day);
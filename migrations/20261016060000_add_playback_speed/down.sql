@@ -0,0 +1,2 @@
+ALTER TABLE listening_events DROP COLUMN speed;
+ALTER TABLE playstates DROP COLUMN speed;
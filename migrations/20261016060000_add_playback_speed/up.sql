@@ -0,0 +1,3 @@
+-- The speed clients played at, listening time is content time divided by it.
+ALTER TABLE playstates ADD COLUMN speed DOUBLE NOT NULL DEFAULT 1.0;
+ALTER TABLE listening_events ADD COLUMN speed DOUBLE NOT NULL DEFAULT 1.0;
@@ -0,0 +1,10 @@
+-- What a metadata provider knows about a book, from the match that was applied last.
+CREATE TABLE book_matches (
+    audiobook_id VARCHAR(36) PRIMARY KEY REFERENCES audiobooks (id) NOT NULL,
+    provider VARCHAR NOT NULL,
+    external_id VARCHAR NOT NULL,
+    narrator VARCHAR,
+    series VARCHAR,
+    series_position DOUBLE,
+    matched_at TIMESTAMP NOT NULL
+);
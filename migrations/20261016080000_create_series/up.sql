@@ -0,0 +1,21 @@
+CREATE TABLE series (
+    id VARCHAR(36) PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    normalized_name VARCHAR NOT NULL UNIQUE
+);
+
+ALTER TABLE audiobooks ADD COLUMN series_id VARCHAR(36) REFERENCES series (id);
+ALTER TABLE audiobooks ADD COLUMN series_position DOUBLE;
+
+-- Clients show books grouped by series, so moving a book between series changes it.
+DROP TRIGGER audiobooks_updated;
+CREATE TRIGGER audiobooks_updated AFTER UPDATE ON audiobooks
+WHEN OLD.location IS NOT NEW.location OR OLD.title IS NOT NEW.title OR OLD.artist IS NOT NEW.artist
+    OR OLD.length IS NOT NEW.length OR OLD.library_id IS NOT NEW.library_id OR OLD.hash IS NOT NEW.hash
+    OR OLD.file_extension IS NOT NEW.file_extension OR OLD.deleted IS NOT NEW.deleted
+    OR OLD.cover_hash IS NOT NEW.cover_hash OR OLD.author_id IS NOT NEW.author_id
+    OR OLD.description IS NOT NEW.description OR OLD.series_id IS NOT NEW.series_id
+    OR OLD.series_position IS NOT NEW.series_position
+BEGIN
+    UPDATE audiobooks SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = NEW.id;
+END;
@@ -0,0 +1,8 @@
+-- Only the SHA-256 of a reset token is kept, the token itself is handed to the user once.
+CREATE TABLE password_resets (
+    token_hash BLOB PRIMARY KEY NOT NULL,
+    user_id VARCHAR(36) REFERENCES users (id) NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+CREATE INDEX password_resets_user_id ON password_resets (user_id);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN allow_download BOOLEAN NOT NULL DEFAULT 1;
@@ -0,0 +1 @@
+ALTER TABLE chapters ADD COLUMN has_image BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+-- Users are not referenced so entries outlive the users they are about.
+CREATE TABLE audit_log (
+    id VARCHAR(36) PRIMARY KEY NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    action VARCHAR NOT NULL,
+    user_id VARCHAR(36),
+    ip VARCHAR,
+    target VARCHAR,
+    details VARCHAR
+);
+CREATE INDEX audit_log_created_at ON audit_log (created_at);
+CREATE INDEX audit_log_user_id ON audit_log (user_id);
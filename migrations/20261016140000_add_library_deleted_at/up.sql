@@ -0,0 +1 @@
+ALTER TABLE libraries ADD COLUMN deleted_at TIMESTAMP;
@@ -0,0 +1,185 @@
+use rocket::State;
+use rocket::request::LenientForm;
+use rocket_contrib::json::Json;
+use validator::Validate;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::api::status::ClientIp;
+use crate::config::Config;
+use crate::handlers::Admin;
+use crate::helpers::auth_cache::AuthCache;
+use crate::helpers::db::DB;
+use crate::helpers::pagination::Page;
+use crate::helpers::uuid::Uuid;
+use crate::models::user::User;
+use crate::models::author::Author;
+use crate::models::audiobook::Audiobook;
+use crate::models::audit_log::{self, Action, AuditEntry};
+use crate::models::duplicates;
+use crate::responses::{APIResult, self, ok, created};
+use crate::validation::user::{NewUserSerializer, PasswordSerializer, AllowDownloadSerializer};
+use crate::validation::author::MergeAuthorSerializer;
+use crate::validation::query::{PageQuery, AuditLogQuery};
+use crate::worker::janitor;
+
+fn find_user(user_id: &Uuid, db: &SqliteConnection) -> Result<User, responses::APIError> {
+    use crate::schema::users::dsl;
+    match dsl::users.filter(dsl::id.eq(user_id)).first::<User>(db).optional()? {
+        Some(u) => Ok(u),
+        None => Err(responses::not_found().message("No such user."))
+    }
+}
+
+#[get("/users?<page..>")]
+pub fn list_users(admin: Admin, db: DB, page: LenientForm<PageQuery>) -> APIResult {
+    use crate::schema::users::dsl;
+    page.validate()?;
+    let all_users = dsl::users.order(dsl::email.asc()).load::<User>(&*db)?;
+    Ok(ok().data(json!(Page::slice(all_users, page.limit, page.offset))))
+}
+
+#[post("/users", data = "<user>", format = "application/json")]
+pub fn create_user(admin: Admin, user: Json<NewUserSerializer>, client: ClientIp, db: DB) -> APIResult {
+    user.validate()?;
+    let new_user = db.transaction(|| {
+        let mut new_user = User::create(&user.email, &user.password, &*db)?;
+        if user.is_admin {
+            new_user.set_admin(true, &*db)?;
+        }
+        AuditEntry::new(Action::UserCreated, Some(admin.0.id), client.0)
+            .target(&new_user.id.hyphenated().to_string())
+            .details(&new_user.email)
+            .save(&*db)?;
+        Ok(new_user)
+    })?;
+    Ok(created().message("User created.").data(json!(&new_user)))
+}
+
+#[delete("/users/<user_id>")]
+pub fn delete_user(admin: Admin, user_id: Uuid, client: ClientIp, db: DB, cache: State<AuthCache>) -> APIResult {
+    if admin.0.id == user_id {
+        return Err(responses::conflict().message("You can't delete yourself."));
+    }
+    let user = find_user(&user_id, &*db)?;
+    let user_email = user.email.clone();
+    db.transaction(|| {
+        user.delete(&*db)?;
+        AuditEntry::new(Action::UserDeleted, Some(admin.0.id), client.0)
+            .target(&user_id.hyphenated().to_string())
+            .details(&user_email)
+            .save(&*db)?;
+        Ok(())
+    })?;
+    cache.invalidate_user(&user_id);
+    Ok(ok())
+}
+
+#[post("/users/<user_id>/password", data = "<password>", format = "application/json")]
+pub fn reset_password(admin: Admin, user_id: Uuid, password: Json<PasswordSerializer>, client: ClientIp, db: DB,
+                      cache: State<AuthCache>) -> APIResult {
+    password.validate()?;
+    let mut user = find_user(&user_id, &*db)?;
+    db.transaction(|| {
+        user.set_password(&password.password, &*db)?;
+        AuditEntry::new(Action::PasswordSet, Some(admin.0.id), client.0)
+            .target(&user_id.hyphenated().to_string())
+            .save(&*db)?;
+        Ok(())
+    })?;
+    cache.invalidate_user(&user_id);
+    Ok(ok())
+}
+
+/// Let a user download chapter files and zips of books or not, streaming stays allowed.
+#[put("/users/<user_id>/allow_download", data = "<allow>", format = "application/json")]
+pub fn set_allow_download(admin: Admin, user_id: Uuid, allow: Json<AllowDownloadSerializer>, client: ClientIp,
+                          db: DB, cache: State<AuthCache>) -> APIResult {
+    let mut user = find_user(&user_id, &*db)?;
+    db.transaction(|| {
+        user.set_allow_download(allow.allow_download, &*db)?;
+        AuditEntry::new(Action::DownloadsChanged, Some(admin.0.id), client.0)
+            .target(&user_id.hyphenated().to_string())
+            .details(if allow.allow_download { "allowed" } else { "forbidden" })
+            .save(&*db)?;
+        Ok(())
+    })?;
+    cache.invalidate_user(&user_id);
+    Ok(ok().data(json!(&user)))
+}
+
+/// All authors along with the spellings of their names that were seen.
+#[get("/authors")]
+pub fn list_authors(admin: Admin, db: DB) -> APIResult {
+    use crate::schema::authors::dsl;
+    let all_authors = dsl::authors.order(dsl::name.asc()).load::<Author>(&*db)?;
+    let mut data = Vec::with_capacity(all_authors.len());
+    for author in all_authors {
+        let aliases = author.aliases(&*db)?;
+        let mut entry = json!(author);
+        entry["aliases"] = json!(aliases).into_inner();
+        data.push(entry.into_inner());
+    }
+    Ok(ok().data(json!(data)))
+}
+
+/// Merge an author into another one, e.g. when the same person was tagged in different ways.
+#[post("/authors/<author_id>/merge", data = "<merge>", format = "application/json")]
+pub fn merge_author(admin: Admin, author_id: Uuid, merge: Json<MergeAuthorSerializer>, client: ClientIp, db: DB)
+    -> APIResult {
+    if author_id == merge.into {
+        return Err(responses::conflict().message("Can't merge an author into itself."));
+    }
+    let source = match Author::find(&author_id, &*db)? {
+        Some(a) => a,
+        None => return Err(responses::not_found().message("No such author."))
+    };
+    let target = match Author::find(&merge.into, &*db)? {
+        Some(a) => a,
+        None => return Err(responses::not_found().message("No such author."))
+    };
+    db.transaction(|| {
+        AuditEntry::new(Action::AuthorMerged, Some(admin.0.id), client.0)
+            .target(&target.id.hyphenated().to_string())
+            .details(&source.name)
+            .save(&*db)?;
+        source.merge_into(&target, &*db)?;
+        Ok(())
+    })?;
+    Ok(ok().data(json!(target)))
+}
+
+/// Purge all books marked as deleted right away instead of waiting for the retention window.
+#[post("/purge_deleted")]
+pub fn purge_deleted(admin: Admin, client: ClientIp, db: DB, config: Config) -> APIResult {
+    let purged = db.transaction(|| {
+        let purged = Audiobook::purge_deleted(None, &*db)?;
+        AuditEntry::new(Action::PurgedDeleted, Some(admin.0.id), client.0)
+            .details(&format!("{} books", purged.len()))
+            .save(&*db)?;
+        Ok(purged)
+    })?;
+    janitor::remove_book_files(&config.data_directory, &purged);
+    Ok(ok().data(json!({"purged": purged.len()})))
+}
+
+/// How well the cache of authenticated tokens works, see `auth.cache_ttl`.
+#[get("/auth_cache")]
+pub fn auth_cache_stats(admin: Admin, cache: State<AuthCache>) -> APIResult {
+    Ok(ok().data(json!(cache.stats())))
+}
+
+/// Security relevant events, newest first, see `models::audit_log`. Filtered by `?user_id=`,
+/// `?action=`, `?since=` and `?until=`, `?limit=`/`?offset=` paginate them.
+#[get("/audit_log?<query..>")]
+pub fn get_audit_log(admin: Admin, db: DB, query: LenientForm<AuditLogQuery>) -> APIResult {
+    query.validate()?;
+    let (entries, total) = audit_log::query(&query.filter(), query.limit, query.offset, &*db)?;
+    Ok(ok().data(json!(Page::new(entries, total, query.limit, query.offset))))
+}
+
+/// Books that are probably there twice, see `models::duplicates`.
+#[get("/duplicates")]
+pub fn get_duplicates(admin: Admin, db: DB) -> APIResult {
+    Ok(ok().data(json!(duplicates::report(&*db)?)))
+}
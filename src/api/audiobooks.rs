@@ -9,16 +9,41 @@ use crate::models::playstate::Playstate;
 use crate::models::audiobook::Audiobook;
 use diesel::prelude;
 use std::path::{Path, PathBuf};
-use crate::api::ranged_file::RangedFile;
+use crate::api::ranged_file::{RangedFile, Attachment, FromStart};
 use std::fs;
 use std::io;
 use crate::schema::audiobooks::dsl::{audiobooks, self};
 use crate::responses::{APIResponse, APIError, self, ok, internal_server_error};
+use crate::responses::error_response::public_message;
 use rocket::response::NamedFile;
+use rocket::request::LenientForm;
+use validator::Validate;
+use crate::validation::query::{AudiobookQuery, PageQuery, SearchQuery};
+use crate::validation::audiobook::{MatchSerializer, RescanSerializer, TranslationSerializer, is_language_tag};
+use crate::helpers::db::Pool;
+use crate::worker::scheduler;
+use rocket::State;
+use std::collections::{BTreeMap, HashMap};
 use crate::config::Config;
+use crate::worker::hashing;
+use crate::worker::scanner;
+use crate::worker::splitter;
+use crate::worker::layout;
+use crate::models::chapter::Chapter;
+use crate::models::search;
+use crate::models::metadata;
+use crate::models::translation;
+use crate::models::book_match::BookMatch;
+use crate::models::series;
+use crate::worker::lookup;
+use crate::handlers::Admin;
+use crate::helpers::pagination::Page;
+use crate::helpers::events::{self, Event};
+use log::error as error_log;
 
 #[get("/data/<book_id>")]
-pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<RangedFile, APIError> {
+pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, config: Config, from_start: FromStart)
+    -> Result<RangedFile, APIError> {
     match current_user.get_book_if_accessible(&book_id, &*db)? {
         Some(_) => (),
         None => return Err(responses::not_found())
@@ -28,7 +53,12 @@ pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, config: Config)
     path.push(book.id.hyphenated().to_string());
     path.set_extension(book.file_extension);
     match RangedFile::open(path.clone()) {
-        Ok(f) => Ok(f),
+        Ok(f) => {
+            if from_start.0 {
+                events::publish(Event::StreamStarted { user_id: current_user.id, audiobook_id: book.id });
+            }
+            Ok(f.with_sha256(book.data_hash))
+        },
         Err(_) => {
             println!("Audiobook file not found in data directory: {:?}", path);
             Err(internal_server_error())
@@ -36,6 +66,89 @@ pub fn get_data_file(current_user: User, db: DB, book_id: Uuid, config: Config)
     }
 }
 
+/// Read metadata, chapters, length and cover of the given books again without scanning their
+/// whole libraries. Answers with the outcome for each id. Only for admins, like scans of whole
+/// libraries.
+#[post("/audiobooks/rescan", data = "<rescan>", format = "application/json")]
+pub fn rescan_audiobooks(admin: Admin, rescan: Json<RescanSerializer>, db: DB, pool: State<Pool>, config: Config)
+    -> Result<APIResponse, APIError> {
+    rescan.validate()?;
+    let current_user = admin.0;
+    let mut outcomes = HashMap::new();
+    let mut by_library: HashMap<Uuid, Vec<Audiobook>> = HashMap::new();
+    for book_id in &rescan.ids {
+        match current_user.get_book_if_accessible(book_id, &*db)? {
+            Some(book) => by_library.entry(book.library_id).or_insert_with(Vec::new).push(book),
+            None => { outcomes.insert(*book_id, Some("No book found or not accessible.".to_owned())); },
+        }
+    }
+    for (library_id, books) in by_library {
+        let library = crate::schema::libraries::table
+            .filter(crate::schema::libraries::dsl::id.eq(library_id))
+            .first::<Library>(&*db)?;
+        let results = scheduler::rescan_books(pool.inner(), &config, library, &books)?;
+        for (book, result) in books.iter().zip(results) {
+            outcomes.insert(book.id, result.err().map(|e| {
+                error_log!("Could not rescan {}: {}", book.id.hyphenated(), e);
+                public_message(&e).unwrap_or_else(|| "Could not rescan the book.".to_owned())
+            }));
+        }
+    }
+    let data = rescan.ids.iter()
+        .map(|book_id| json!({"id": book_id, "error": outcomes.get(book_id).cloned().unwrap_or(None)}).into_inner())
+        .collect::<Vec<serde_json::Value>>();
+    Ok(ok().data(json!(data)))
+}
+
+/// Bring back a book that was marked as deleted once its files reappeared, without waiting for
+/// the next scan of its library.
+#[post("/audiobooks/<book_id>/restore")]
+pub fn restore_audiobook(admin: Admin, db: DB, book_id: Uuid) -> Result<APIResponse, APIError> {
+    let book = match dsl::audiobooks.filter(dsl::id.eq(&book_id)).first::<Audiobook>(&*db).optional()? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No such book."))
+    };
+    if !book.deleted {
+        return Err(responses::conflict().message("The book is not deleted."));
+    }
+    let library = crate::schema::libraries::table
+        .filter(crate::schema::libraries::dsl::id.eq(book.library_id))
+        .first::<Library>(&*db)?;
+    if !scanner::recover_book(&library, &book, &*db)? {
+        return Err(responses::conflict().message("The book's files are missing or have changed."));
+    }
+    let book = dsl::audiobooks.filter(dsl::id.eq(&book_id)).first::<Audiobook>(&*db)?;
+    Ok(ok().data(json!(book)))
+}
+
+/// Checksum and size of the file served at `/data/<book_id>` so clients can verify downloads.
+#[get("/audiobooks/<book_id>/checksum")]
+pub fn get_checksum(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<APIResponse, APIError> {
+    let mut book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(a) => a,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    let mut path = PathBuf::from(config.data_directory);
+    path.push(book.id.hyphenated().to_string());
+    path.set_extension(&book.file_extension);
+    let size = match fs::metadata(&path) {
+        Ok(m) => m.len(),
+        Err(_) => return Err(responses::not_found().message("The book has not been processed yet.")),
+    };
+    let sha256 = match book.data_hash.clone() {
+        Some(h) => h,
+        None => {
+            let h = hashing::checksum_file(&path)?;
+            book.set_data_hash(h.clone(), &*db)?;
+            h
+        }
+    };
+    Ok(ok().data(json!({
+        "sha256": hashing::to_hex(&sha256),
+        "size": size,
+    })))
+}
+
 #[get("/coverart/<book_id>")]
 pub fn get_coverart(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<NamedFile, APIError> {
     use crate::schema::libraries::dsl::*;
@@ -43,9 +156,7 @@ pub fn get_coverart(current_user: User, db: DB, book_id: Uuid, config: Config) -
         Some(a) => a,
         None => return Err(responses::not_found().message("No book found or not accessible."))
     };
-    let mut path = PathBuf::from(config.data_directory);
-    path.push("img");
-    path.push(book_id.hyphenated().to_string());
+    let path = layout::cover_path(&config.data_directory, &book_id);
     match NamedFile::open(path) {
         Ok(f) => Ok(f),
         Err(e) => match e.kind() {
@@ -55,19 +166,291 @@ pub fn get_coverart(current_user: User, db: DB, book_id: Uuid, config: Config) -
     }
 }
 
-#[get("/audiobooks")]
-pub fn get_audiobooks(current_user: User, db: DB) -> Result<APIResponse, APIError> {
-    use crate::schema::libraries::dsl::*;
-    let user_books = current_user.accessible_audiobooks(&*db)?;
-    Ok(ok().data(json!(user_books)))
+/// All accessible books, `?q=` searches titles, `?sort=` orders them by `title`, `artist`,
+/// `recent` or `length` and `?limit=`/`?offset=` paginate the results.
+///
+/// `?language=de` only lists books in that language, as tagged or guessed while scanning.
+///
+/// With `?group_by=series` the items are groups of a `series` and its `books` instead, books
+/// outside of any series are a group of their own. Pagination then counts groups.
+#[get("/audiobooks?<query..>")]
+pub fn get_audiobooks(current_user: User, db: DB, query: LenientForm<AudiobookQuery>) -> Result<APIResponse, APIError> {
+    query.validate()?;
+    let search = query.q.as_ref().map(String::as_str);
+    let language = query.language.as_ref().map(String::as_str);
+    if query.group_by.is_some() {
+        let mut user_books = current_user.find_audiobooks(search, language, query.order(), None, None, &*db)?;
+        translation::localize(&mut user_books, current_user.preferred_language.as_ref().map(String::as_str), &*db)?;
+        let groups = series::group(user_books, &*db)?;
+        return Ok(ok().data(json!(Page::slice(groups, query.limit, query.offset))));
+    }
+    let mut user_books = current_user.find_audiobooks(
+        search, language, query.order(), query.limit, query.offset, &*db
+    )?;
+    translation::localize(&mut user_books, current_user.preferred_language.as_ref().map(String::as_str), &*db)?;
+    let total = current_user.count_audiobooks(search, language, &*db)?;
+    Ok(ok().data(json!(Page::new(user_books, total, query.limit, query.offset))))
 }
 
+/// Books whose title, artist, description or chapter titles contain all words of `?q=`, best
+/// matches first. Each comes with a `snippet` highlighting what matched. `?language=` only finds
+/// books in that language.
+#[get("/search?<query..>")]
+pub fn search(current_user: User, db: DB, query: LenientForm<SearchQuery>) -> Result<APIResponse, APIError> {
+    query.validate()?;
+    let found = search::search(
+        &current_user, &query.q, query.language.as_ref().map(String::as_str), query.limit.unwrap_or(50),
+        query.offset.unwrap_or(0), &*db
+    )?;
+    let (mut books, snippets): (Vec<Audiobook>, Vec<String>) = found.into_iter()
+        .map(|f| (f.book, f.snippet))
+        .unzip();
+    translation::localize(&mut books, current_user.preferred_language.as_ref().map(String::as_str), &*db)?;
+    let found = books.into_iter().zip(snippets)
+        .map(|(book, snippet)| search::Found { book, snippet })
+        .collect::<Vec<search::Found>>();
+    Ok(ok().data(json!(found)))
+}
+
+/// A book along with what only its detail view needs. Chapters aren't part of it, `chapter_count`
+/// tells how many pages of `/audiobooks/<id>/chapters` there are to load.
 #[get("/audiobooks/<book_id>")]
-pub fn get_audiobook(current_user: User, db: DB, book_id: Uuid) -> Result<APIResponse, APIError> {
+pub fn get_audiobook(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<APIResponse, APIError> {
     use crate::schema::libraries::dsl::*;
-    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+    let mut book = match current_user.get_book_if_accessible(&book_id, &*db)? {
         Some(a) => a,
         None => return Err(responses::not_found())
     };
-    Ok(ok().data(json!(book)))
+    let last_played = Playstate::last_played(&current_user, &book_id, &*db)?;
+    let fields = metadata::of(&book, &config.metadata.fields, &*db)?;
+    let translations = translation::of(&book, &*db)?;
+    let chapter_count = Chapter::belonging_to(&book).count().get_result::<i64>(&*db)?;
+    translation::localize(
+        std::slice::from_mut(&mut book), current_user.preferred_language.as_ref().map(String::as_str), &*db
+    )?;
+    let mut data = json!(book);
+    data["last_played"] = json!(last_played).into_inner();
+    data["metadata"] = json!(fields).into_inner();
+    data["translations"] = json!(translations).into_inner();
+    data["match"] = json!(BookMatch::of(&book, &*db)?).into_inner();
+    data["chapter_count"] = json!(chapter_count).into_inner();
+    Ok(ok().data(data))
+}
+
+fn find_translatable(book_id: &Uuid, language: &str, db: &DB) -> Result<Audiobook, APIError> {
+    if !is_language_tag(language) {
+        return Err(responses::unprocessable_entity()
+            .message("Invalid input.")
+            .errors(json!({"language": ["Must be a language tag like \"en\" or \"pt-BR\"."]}).into_inner()));
+    }
+    match audiobooks.filter(dsl::id.eq(book_id)).first::<Audiobook>(&**db).optional()? {
+        Some(b) => Ok(b),
+        None => Err(responses::not_found().message("No book found.")),
+    }
+}
+
+/// Set the title and description of a book in another language, leaving out one of them keeps
+/// the tagged one for that language.
+#[put("/audiobooks/<book_id>/translations/<language>", data = "<translation_in>", format = "application/json")]
+pub fn set_translation(admin: Admin, db: DB, book_id: Uuid, language: String,
+                       translation_in: Json<TranslationSerializer>) -> Result<APIResponse, APIError> {
+    translation_in.validate()?;
+    db.transaction(|| {
+        let book = find_translatable(&book_id, &language, &db)?;
+        match translation::set(
+            &book, &language, translation_in.title.as_ref().map(String::as_str),
+            translation_in.description.as_ref().map(String::as_str), &*db
+        )? {
+            Some(t) => Ok(ok().data(json!(t))),
+            None => Ok(ok().message("Translation removed.")),
+        }
+    })
+}
+
+#[delete("/audiobooks/<book_id>/translations/<language>")]
+pub fn delete_translation(admin: Admin, db: DB, book_id: Uuid, language: String) -> Result<APIResponse, APIError> {
+    db.transaction(|| {
+        let book = find_translatable(&book_id, &language, &db)?;
+        translation::set(&book, &language, None, None, &*db)?;
+        Ok(ok().message("Translation removed."))
+    })
+}
+
+/// Search the metadata provider for a book by its title and artist, by `title` and `author` if
+/// given, or by `isbn`. With `candidate` set to the id of one of the results, that result is
+/// applied to the book, replacing its description and cover.
+#[post("/audiobooks/<book_id>/match", data = "<match_in>", format = "application/json")]
+pub fn match_audiobook(admin: Admin, db: DB, book_id: Uuid, match_in: Json<MatchSerializer>,
+                       config: Config) -> Result<APIResponse, APIError> {
+    match_in.validate()?;
+    let lookup_config = match config.metadata.lookup {
+        Some(ref l) => l,
+        None => return Err(responses::not_found().message("Looking up books is not configured.")),
+    };
+    let book = match audiobooks.filter(dsl::id.eq(book_id)).first::<Audiobook>(&*db).optional()? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found."))
+    };
+    let title = match_in.title.as_ref().unwrap_or(&book.title);
+    let author = match_in.author.as_ref().or(book.artist.as_ref()).map(String::as_str);
+    let found = match match_in.isbn {
+        Some(ref isbn) => match lookup::normalize_isbn(isbn) {
+            Some(isbn) => lookup::search_isbn(lookup_config, &isbn),
+            None => return Err(responses::unprocessable_entity()
+                .message("Invalid input.")
+                .errors(json!({"isbn": ["Must be an ISBN-10 or ISBN-13."]}).into_inner())),
+        },
+        None => lookup::search(lookup_config, title, author),
+    };
+    let candidates = found.map_err(|e| {
+        warn!("Looking up {} failed: {}", title, e);
+        responses::service_unavailable().message("The metadata provider could not be reached.")
+    })?;
+    let chosen = match match_in.candidate {
+        Some(ref id) => match candidates.iter().find(|c| &c.id == id) {
+            Some(c) => c,
+            None => return Err(responses::unprocessable_entity()
+                .message("Invalid input.")
+                .errors(json!({"candidate": ["Must be the id of one of the search results."]}).into_inner())),
+        },
+        None => return Ok(ok().data(json!({"candidates": candidates}))),
+    };
+    let updated = lookup::apply(&book, chosen, true, lookup_config, &config, &*db)?;
+    let mut data = json!(updated);
+    data["match"] = json!(BookMatch::of(&updated, &*db)?).into_inner();
+    Ok(ok().data(data))
+}
+
+/// The book's extra fields as configured in the `[metadata]` section, unset ones are `null`.
+#[get("/audiobooks/<book_id>/metadata")]
+pub fn get_metadata(current_user: User, db: DB, book_id: Uuid, config: Config) -> Result<APIResponse, APIError> {
+    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    Ok(ok().data(json!(metadata::of(&book, &config.metadata.fields, &*db)?)))
+}
+
+/// Set extra fields of a book, takes an object of field names to values. `null` clears a field,
+/// fields that are not mentioned are left alone.
+#[patch("/audiobooks/<book_id>/metadata", data = "<changes>", format = "application/json")]
+pub fn update_metadata(admin: Admin, db: DB, book_id: Uuid, changes: Json<BTreeMap<String, Option<String>>>,
+                       config: Config) -> Result<APIResponse, APIError> {
+    let mut errors = serde_json::Map::new();
+    for (key, value) in changes.iter() {
+        if !config.metadata.fields.contains(key) {
+            errors.insert(key.clone(), json!(["Not a configured metadata field."]).into_inner());
+        } else if value.as_ref().map(|v| v.chars().count() > 1000).unwrap_or(false) {
+            errors.insert(key.clone(), json!(["Must be at most 1000 characters."]).into_inner());
+        }
+    }
+    if !errors.is_empty() {
+        return Err(responses::unprocessable_entity()
+            .message("Invalid input.")
+            .errors(serde_json::Value::Object(errors)));
+    }
+    db.transaction(|| {
+        let book = match audiobooks.filter(dsl::id.eq(book_id)).first::<Audiobook>(&*db).optional()? {
+            Some(b) => b,
+            None => return Err(responses::not_found().message("No book found."))
+        };
+        metadata::update(&book, &changes, &*db)?;
+        Ok(ok().data(json!(metadata::of(&book, &config.metadata.fields, &*db)?)))
+    })
+}
+
+fn book_with_chapters(current_user: &User, book_id: &Uuid, db: &DB) -> Result<(Audiobook, Vec<Chapter>), APIError> {
+    use crate::schema::chapters::dsl as chapters_dsl;
+    let book = match current_user.get_book_if_accessible(book_id, &**db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    let chapters = Chapter::belonging_to(&book)
+        .order(chapters_dsl::start_time.asc())
+        .load::<Chapter>(&**db)?;
+    if chapters.is_empty() {
+        return Err(responses::not_found().message("The book has no chapters."));
+    }
+    Ok((book, chapters))
+}
+
+/// The chapters of a book ordered by their start, `?limit=`/`?offset=` paginate them.
+#[get("/audiobooks/<book_id>/chapters?<page..>")]
+pub fn get_chapters(current_user: User, db: DB, book_id: Uuid, page: LenientForm<PageQuery>)
+    -> Result<APIResponse, APIError> {
+    use crate::schema::chapters::dsl as chapters_dsl;
+    page.validate()?;
+    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    let total = Chapter::belonging_to(&book).count().get_result::<i64>(&*db)?;
+    let mut query = Chapter::belonging_to(&book)
+        .order(chapters_dsl::start_time.asc())
+        .into_boxed();
+    // SQLite only accepts an offset after a limit, -1 means no limit
+    if page.limit.is_some() || page.offset.is_some() {
+        query = query.limit(page.limit.unwrap_or(-1)).offset(page.offset.unwrap_or(0));
+    }
+    let chapters = query.load::<Chapter>(&*db)?;
+    Ok(ok().data(json!(Page::new(chapters, total, page.limit, page.offset))))
+}
+
+/// Open a file created by `splitter`, their names contain the hash of the book's data file so
+/// they make good ETags.
+fn open_cached(path: &Path) -> Result<RangedFile, APIError> {
+    let etag = path.file_name().map(|n| n.to_string_lossy().into_owned());
+    match RangedFile::open(path) {
+        Ok(f) => Ok(match etag {
+            Some(etag) => f.with_etag(etag),
+            None => f,
+        }),
+        Err(_) => Err(internal_server_error())
+    }
+}
+
+/// Forbid downloads to users who may only stream, feeds count as downloads.
+pub fn ensure_download_allowed(user: &User) -> Result<(), APIError> {
+    if user.allow_download {
+        Ok(())
+    } else {
+        Err(responses::forbidden().message("You may stream books but not download them."))
+    }
+}
+
+/// What chapter files are encoded as, `?format=mp3` or `opus`, MP3 if none is given.
+fn split_format(format: Option<String>) -> Result<splitter::Format, APIError> {
+    match format {
+        None => Ok(splitter::Format::default()),
+        Some(name) => match splitter::Format::parse(&name) {
+            Some(f) => Ok(f),
+            None => Err(responses::bad_request().message("Format must be mp3 or opus."))
+        }
+    }
+}
+
+/// A single chapter as its own file, `track` counts chapters by their start from 1.
+#[get("/audiobooks/<book_id>/chapters/<track>/file?<format>")]
+pub fn get_chapter_file(current_user: User, db: DB, book_id: Uuid, track: usize, format: Option<String>, config: Config)
+    -> Result<Attachment<RangedFile>, APIError> {
+    ensure_download_allowed(&current_user)?;
+    let format = split_format(format)?;
+    let (book, chapters) = book_with_chapters(&current_user, &book_id, &db)?;
+    if track == 0 || track > chapters.len() {
+        return Err(responses::not_found().message("No such chapter."));
+    }
+    let path = splitter::chapter_file(&config, &book, &chapters, track, format)?;
+    let name = splitter::download_name(&chapters[track - 1], track, chapters.len(), format.extension());
+    Ok(Attachment(open_cached(&path)?, name))
+}
+
+/// All chapters of a book as separate files in a zip archive.
+#[get("/audiobooks/<book_id>/chapters.zip?<format>")]
+pub fn get_chapters_zip(current_user: User, db: DB, book_id: Uuid, format: Option<String>, config: Config)
+    -> Result<Attachment<RangedFile>, APIError> {
+    ensure_download_allowed(&current_user)?;
+    let format = split_format(format)?;
+    let (book, chapters) = book_with_chapters(&current_user, &book_id, &db)?;
+    let path = splitter::chapters_zip(&config, &book, &chapters, format)?;
+    Ok(Attachment(open_cached(&path)?, format!("{}.zip", book.title.replace('/', "_"))))
 }
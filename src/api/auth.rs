@@ -1,36 +1,83 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
 use rocket_contrib::json::Json;
-use crate::validation::user::UserSerializer;
+use validator::Validate;
+use crate::validation::user::{UserSerializer, ResetRequestSerializer, PasswordResetSerializer};
+use crate::validation::audiobook::LanguageSerializer;
 use diesel::prelude::*;
 use diesel;
 use failure::Error;
+use log::error as error_log;
 use serde_json::error::Error as SerdeError;
 
 use crate::config::Config;
 use crate::responses;
 use crate::models::user::{User, NewUser, ApiToken};
+use crate::models::password_reset;
+use crate::models::audit_log::{Action, AuditEntry};
+use crate::handlers::Admin;
+use crate::helpers::clock::SystemClock;
+use crate::helpers::uuid::RandomIds;
+use crate::helpers::mail;
 use crate::schema::users;
 use crate::schema::users::dsl::*;
+use crate::helpers::auth_cache::AuthCache;
+use crate::helpers::login_throttle::{LoginKey, LoginThrottle};
+use crate::helpers::rate_limit::RateLimiter;
+use crate::api::status::ClientIp;
 use crate::helpers::db::DB;
-use crate::responses::{APIError, APIResponse, APIResult, ok, created, conflict, unauthorized, internal_server_error};
+use crate::responses::{APIError, APIResponse, APIResult, ok, created, accepted, conflict, unauthorized,
+                       internal_server_error};
+use rocket::State;
 use rocket::http::Status;
 use crate::validation::token::TokenSerializer;
 use crate::helpers::JsonResult;
 
+/// Answers 429 with `Retry-After` while the email address or client address is locked out after
+/// too many failed attempts, see `LoginThrottle`.
 #[post("/login", data = "<user_in>", format = "application/json")]
-pub fn login(user_in: Json<UserSerializer>, db: DB) -> Result<APIResponse, APIError> {
-    let results = users.filter(email.eq(user_in.email.clone()))
-        .first::<User>(&*db);
-
-    if results.is_err() {
-        return Err(unauthorized().message("Username or password incorrect."));
-    }
-
-    let user = results.unwrap();
-    if !user.verify_password(user_in.password.as_str()) {
-        return Err(unauthorized().message("Username or password incorrect."));
+pub fn login(user_in: Json<UserSerializer>, client: ClientIp, throttle: State<LoginThrottle>, db: DB,
+             config: Config) -> Result<APIResponse, APIError> {
+    let keys = LoginKey::of(&user_in.email, client.0);
+    if let Some(wait) = throttle.wait(&keys) {
+        return Err(responses::too_many_requests()
+            .message("Too many failed logins, try again later.")
+            .retry_after(wait));
     }
 
-    let token = user.generate_api_token(db)?;
+    let mut user = match users.filter(email.eq(user_in.email.clone())).first::<User>(&*db).optional()? {
+        Some(u) if u.verify_password(user_in.password.as_str()) => u,
+        found => {
+            for key in throttle.failed(&keys) {
+                match key {
+                    LoginKey::Email(e) => warn!("Slowing down logins as {} after repeated failures.", e),
+                    LoginKey::Ip(ip) => warn!("Slowing down logins from {} after repeated failures.", ip),
+                }
+            }
+            // The attempt is counted either way, a broken audit log doesn't turn it into a 500
+            let entry = AuditEntry::new(Action::FailedLogin, found.map(|u| u.id), client.0).target(&user_in.email);
+            if let Err(e) = entry.save(&*db) {
+                error_log!("Could not record a failed login in the audit log: {}", e);
+            }
+            return Err(unauthorized().message("Username or password incorrect."));
+        }
+    };
+    throttle.succeeded(&keys);
+    let token = db.transaction(|| {
+        if user.upgrade_password_hash(&user_in.password, &*db)? {
+            info!("Upgraded the password hash of {}.", user.email);
+        }
+        let token = user.generate_api_token_with(
+            user_in.device_name.clone(), config.auth.token_lifetime(), &SystemClock, &RandomIds, &*db
+        )?;
+        let mut entry = AuditEntry::new(Action::Login, Some(user.id), client.0);
+        if let Some(ref device) = user_in.device_name {
+            entry = entry.details(device);
+        }
+        entry.save(&*db)?;
+        Ok(token)
+    })?;
 
     Ok(ok().data(json!(
         TokenSerializer::from(token)
@@ -38,9 +85,14 @@ pub fn login(user_in: Json<UserSerializer>, db: DB) -> Result<APIResponse, APIEr
 }
 
 #[post("/register", data = "<user>", format = "application/json")]
-pub fn register(user: Json<UserSerializer>, db: DB, config: Config) -> APIResult {
+pub fn register(user: Json<UserSerializer>, client: ClientIp, db: DB, config: Config) -> APIResult {
     if config.register_web {
-        let new_user = User::create(&user.email, &user.password, &*db)?;
+        user.validate()?;
+        let new_user = db.transaction(|| {
+            let new_user = User::create(&user.email, &user.password, &*db)?;
+            AuditEntry::new(Action::Registered, Some(new_user.id), client.0).save(&*db)?;
+            Ok(new_user)
+        })?;
         Ok(created().message("User created.").data(json!(&new_user)))
     } else {
         Err(responses::unauthorized().message("Registration is disabled. Create a user via the commandline or enable user \
@@ -49,26 +101,178 @@ pub fn register(user: Json<UserSerializer>, db: DB, config: Config) -> APIResult
 }
 
 
+/// The token that goes into feed URLs, created on first use.
+#[get("/feed_token")]
+pub fn feed_token(mut current_user: User, db: DB, cache: State<AuthCache>) -> APIResult {
+    let token = match current_user.feed_token.clone() {
+        Some(t) => t,
+        None => {
+            let token = current_user.regenerate_feed_token(&*db)?;
+            cache.invalidate_user(&current_user.id);
+            token
+        },
+    };
+    Ok(ok().data(json!({"feed_token": token})))
+}
+
+/// Replace the feed token, feed URLs handed out before stop working.
+#[post("/feed_token")]
+pub fn regenerate_feed_token(mut current_user: User, client: ClientIp, db: DB, cache: State<AuthCache>) -> APIResult {
+    let token = db.transaction(|| {
+        let token = current_user.regenerate_feed_token(&*db)?;
+        AuditEntry::new(Action::FeedTokenRegenerated, Some(current_user.id), client.0).save(&*db)?;
+        Ok(token)
+    })?;
+    cache.invalidate_user(&current_user.id);
+    Ok(ok().data(json!({"feed_token": token})))
+}
+
+/// Show books in this language where they have a translation, `null` shows them as tagged.
+#[put("/language", data = "<language_in>", format = "application/json")]
+pub fn set_language(mut current_user: User, language_in: Json<LanguageSerializer>, db: DB,
+                    cache: State<AuthCache>) -> APIResult {
+    language_in.validate()?;
+    let language = language_in.language.as_ref().map(|l| l.to_lowercase());
+    current_user.set_preferred_language(language, &*db)?;
+    cache.invalidate_user(&current_user.id);
+    Ok(ok().data(json!(&current_user)))
+}
+
 #[get("/whoami")]
 pub fn whoami(current_user: User) -> APIResponse {
     ok().data(json!(&current_user))
 }
 
+/// Exchange a valid token for a new one, the old token stops working.
+#[post("/refresh")]
+pub fn refresh(token: ApiToken, client: ClientIp, db: DB, config: Config, cache: State<AuthCache>) -> APIResult {
+    let old_id = token.id;
+    let new_token = db.transaction(|| {
+        let new_token = token.refresh(config.auth.token_lifetime(), &*db)?;
+        AuditEntry::new(Action::TokenRefreshed, Some(new_token.user_id), client.0).save(&*db)?;
+        Ok(new_token)
+    })?;
+    cache.invalidate_token(&old_id);
+    Ok(ok().data(json!(TokenSerializer::from(new_token))))
+}
+
 #[post("/logout")]
-pub fn logout(current_user: User, token: ApiToken, db: DB) -> Result<APIResponse, APIError> {
+pub fn logout(current_user: User, token: ApiToken, client: ClientIp, db: DB, cache: State<AuthCache>)
+    -> Result<APIResponse, APIError> {
     use crate::schema::api_tokens::table;
     use crate::schema::api_tokens::dsl::id;
 
-    let ret = diesel::delete(table.filter(id.eq(token.id))).execute(&*db)?;
+    let ret = db.transaction(|| {
+        let ret = diesel::delete(table.filter(id.eq(token.id))).execute(&*db)?;
+        AuditEntry::new(Action::Logout, Some(current_user.id), client.0).save(&*db)?;
+        Ok(ret)
+    })?;
+    cache.invalidate_token(&token.id);
     println!("{}", ret);
     Ok(ok())
 }
 
 #[post("/logout_all")]
-pub fn logout_all(current_user: User, token: ApiToken, db: DB) -> Result<APIResponse, APIError> {
+pub fn logout_all(current_user: User, token: ApiToken, client: ClientIp, db: DB, cache: State<AuthCache>)
+    -> Result<APIResponse, APIError> {
     use crate::schema::api_tokens::table;
     use crate::schema::api_tokens::dsl::user_id;
 
-    diesel::delete(table.filter(user_id.eq(current_user.id))).execute(&*db)?;
+    db.transaction(|| {
+        diesel::delete(table.filter(user_id.eq(current_user.id))).execute(&*db)?;
+        AuditEntry::new(Action::LogoutAll, Some(current_user.id), client.0).save(&*db)?;
+        Ok(())
+    })?;
+    cache.invalidate_user(&current_user.id);
     Ok(ok())
 }
+
+/// Limits how often password resets are mailed, so nobody can flood a user's inbox or mail
+/// through the server.
+pub struct ResetLimiter {
+    by_email: RateLimiter<String>,
+    by_client: RateLimiter<Option<IpAddr>>,
+}
+
+impl ResetLimiter {
+    pub fn new(config: &Config) -> Self {
+        let hour = Duration::from_secs(60 * 60);
+        ResetLimiter {
+            by_email: RateLimiter::new(config.auth.reset_requests_per_hour, hour),
+            by_client: RateLimiter::new(config.auth.reset_requests_per_hour, hour),
+        }
+    }
+
+    /// Count a request, returns whether both the address and the client are within the limit.
+    fn check(&self, address: &str, client: Option<IpAddr>) -> bool {
+        let email_allowed = self.by_email.check(address.to_lowercase());
+        self.by_client.check(client) && email_allowed
+    }
+}
+
+/// Create a password reset token for the user with this email address. Admins get the token in
+/// the response to pass it on. Everyone else gets it mailed if `[smtp]` is configured, the
+/// response doesn't tell whether there is a user with the address. Requests beyond
+/// `auth.reset_requests_per_hour` are answered with 429.
+#[post("/request_reset", data = "<request>", format = "application/json")]
+pub fn request_reset(request: Json<ResetRequestSerializer>, admin: Option<Admin>, client: ClientIp,
+                     limiter: State<ResetLimiter>, db: DB, config: Config) -> APIResult {
+    request.validate()?;
+    let user = users.filter(email.eq(&request.email)).first::<User>(&*db).optional()?;
+    if admin.is_some() {
+        return match user {
+            Some(u) => {
+                let token = password_reset::create(&u, config.auth.reset_token_lifetime(), &SystemClock, &*db)?;
+                Ok(ok().data(json!({"token": token})))
+            },
+            None => Err(responses::not_found().message("No user with this email address.")),
+        };
+    }
+    let smtp = match config.smtp {
+        Some(ref s) => s.clone(),
+        None => return Err(responses::not_found().message("Passwords can't be reset by mail, ask an admin.")),
+    };
+    if !limiter.check(&request.email, client.0) {
+        return Err(responses::too_many_requests());
+    }
+    if let Some(u) = user {
+        let token = password_reset::create(&u, config.auth.reset_token_lifetime(), &SystemClock, &*db)?;
+        let minutes = config.auth.reset_token_lifetime().num_minutes();
+        let body = format!(
+            "Someone asked to reset the password of your vorleser account {}.\n\n\
+             Enter this token in your app to choose a new password, it works for {} minutes:\n\n{}\n\n\
+             If that wasn't you, ignore this mail and your password stays as it is.",
+            u.email, minutes, token
+        );
+        // Sending takes a while, answering right away doesn't give away that the user exists
+        std::thread::spawn(move || {
+            if let Err(e) = mail::send(&smtp, &u.email, "Resetting your password", &body) {
+                warn!("Mailing a password reset token to {} failed: {}", u.email, e);
+            }
+        });
+    }
+    Ok(accepted().message("If there is a user with this email address, a reset token was mailed to them."))
+}
+
+/// Set a new password with a token from `request_reset`. All API tokens of the user stop working.
+#[post("/reset", data = "<reset>", format = "application/json")]
+pub fn reset_password(reset: Json<PasswordResetSerializer>, client: ClientIp, db: DB, cache: State<AuthCache>)
+    -> APIResult {
+    reset.validate()?;
+    let user = db.transaction(|| {
+        let user = password_reset::consume(reset.token.trim(), &reset.password, &SystemClock, &*db)?;
+        if let Some(ref u) = user {
+            AuditEntry::new(Action::PasswordReset, Some(u.id), client.0).save(&*db)?;
+        }
+        Ok(user)
+    })?;
+    match user {
+        Some(user) => {
+            cache.invalidate_user(&user.id);
+            Ok(ok().message("Password changed, log in with the new one."))
+        },
+        None => Err(responses::unprocessable_entity()
+            .message("Invalid input.")
+            .errors(json!({"token": ["Unknown, used or expired token."]}).into_inner())),
+    }
+}
@@ -0,0 +1,26 @@
+use std::collections::HashSet;
+
+use diesel::prelude::*;
+
+use crate::helpers::db::DB;
+use crate::helpers::uuid::Uuid;
+use crate::models::author::Author;
+use crate::models::user::User;
+use crate::responses::{APIResult, ok};
+use crate::schema::authors;
+
+/// Authors of the books the user has access to, books reference them by `author_id`.
+#[get("/authors")]
+pub fn get_authors(current_user: User, db: DB) -> APIResult {
+    let author_ids = current_user.accessible_audiobooks(&*db)?
+        .into_iter()
+        .filter_map(|book| book.author_id)
+        .collect::<HashSet<Uuid>>()
+        .into_iter()
+        .collect::<Vec<Uuid>>();
+    let found = authors::table
+        .filter(authors::dsl::id.eq_any(author_ids))
+        .order(authors::dsl::name.asc())
+        .load::<Author>(&*db)?;
+    Ok(ok().data(json!(found)))
+}
@@ -0,0 +1,60 @@
+use rocket_contrib::json::Json;
+use validator::Validate;
+
+use crate::helpers::db::DB;
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::bookmark::Bookmark;
+use crate::models::user::User;
+use crate::responses::{APIError, APIResult, self, ok, created};
+use crate::validation::bookmark::BookmarkSerializer;
+
+fn find_book(current_user: &User, book_id: &Uuid, db: &DB) -> Result<Audiobook, APIError> {
+    match current_user.get_book_if_accessible(book_id, &**db)? {
+        Some(b) => Ok(b),
+        None => Err(responses::not_found().message("No book found or not accessible."))
+    }
+}
+
+fn find_bookmark(current_user: &User, book: &Audiobook, bookmark_id: &Uuid, db: &DB) -> Result<Bookmark, APIError> {
+    match Bookmark::find(current_user, book, bookmark_id, &**db)? {
+        Some(b) => Ok(b),
+        None => Err(responses::not_found().message("No such bookmark."))
+    }
+}
+
+/// The user's bookmarks in a book, ordered by position.
+#[get("/audiobooks/<book_id>/bookmarks")]
+pub fn get_bookmarks(current_user: User, book_id: Uuid, db: DB) -> APIResult {
+    let book = find_book(&current_user, &book_id, &db)?;
+    Ok(ok().data(json!(Bookmark::of(&current_user, &book, &*db)?)))
+}
+
+#[post("/audiobooks/<book_id>/bookmarks", data = "<bookmark>", format = "application/json")]
+pub fn create_bookmark(current_user: User, book_id: Uuid, bookmark: Json<BookmarkSerializer>, db: DB) -> APIResult {
+    bookmark.validate()?;
+    let book = find_book(&current_user, &book_id, &db)?;
+    let bookmark = bookmark.into_inner();
+    let created_bookmark = Bookmark::create(&current_user, &book, bookmark.position_secs, bookmark.note, &*db)?;
+    Ok(created().data(json!(created_bookmark)))
+}
+
+#[put("/audiobooks/<book_id>/bookmarks/<bookmark_id>", data = "<bookmark>", format = "application/json")]
+pub fn update_bookmark(current_user: User, book_id: Uuid, bookmark_id: Uuid, bookmark: Json<BookmarkSerializer>,
+                       db: DB) -> APIResult {
+    bookmark.validate()?;
+    let book = find_book(&current_user, &book_id, &db)?;
+    let mut existing = find_bookmark(&current_user, &book, &bookmark_id, &db)?;
+    let bookmark = bookmark.into_inner();
+    existing.position_secs = bookmark.position_secs;
+    existing.note = bookmark.note;
+    existing.save(&*db)?;
+    Ok(ok().data(json!(existing)))
+}
+
+#[delete("/audiobooks/<book_id>/bookmarks/<bookmark_id>")]
+pub fn delete_bookmark(current_user: User, book_id: Uuid, bookmark_id: Uuid, db: DB) -> APIResult {
+    let book = find_book(&current_user, &book_id, &db)?;
+    find_bookmark(&current_user, &book, &bookmark_id, &db)?.delete(&*db)?;
+    Ok(ok())
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::io::Cursor;
+
+use rocket::Request;
+use rocket::response::{NamedFile, Responder, Response};
+use rocket::http::{Status, ContentType};
+use diesel::prelude::*;
+
+use crate::models::user::User;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::helpers::db::DB;
+use crate::helpers::uuid::Uuid;
+use crate::responses::{APIError, self};
+use crate::config::Config;
+use crate::worker::layout;
+use crate::worker::mediafile::{Image, ImageType};
+use crate::worker::thumbnails;
+
+/// A file that never changes under its URL, clients are told to cache it for a year.
+pub struct ImmutableFile(pub NamedFile);
+
+impl Responder<'static> for ImmutableFile {
+    fn respond_to(self, request: &Request) -> Result<Response<'static>, Status> {
+        let mut response = self.0.respond_to(request)?;
+        response.set_raw_header("Cache-Control", "private, max-age=31536000, immutable");
+        Ok(response)
+    }
+}
+
+/// Serve a cover by the hash of its content.
+/// The URLs for this are part of the audiobook payloads as `cover_url`.
+#[get("/covers/<cover_hash>")]
+pub fn get_cover(current_user: User, db: DB, cover_hash: String, config: Config) -> Result<ImmutableFile, APIError> {
+    let book = match current_user.get_book_by_cover_if_accessible(&cover_hash, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No cover with this hash."))
+    };
+    let path = layout::cover_path(&config.data_directory, &book.id);
+    match NamedFile::open(path) {
+        Ok(f) => Ok(ImmutableFile(f)),
+        Err(_) => Err(responses::not_found().message("No cover art found."))
+    }
+}
+
+/// Cover image with an entity tag, answers with 304 if the client already has this version.
+pub struct Cover {
+    data: Vec<u8>,
+    content_type: ContentType,
+    etag: String,
+}
+
+impl Responder<'static> for Cover {
+    fn respond_to(self, request: &Request) -> Result<Response<'static>, Status> {
+        let mut response = Response::new();
+        response.set_raw_header("ETag", self.etag.clone());
+        let cached = request.headers().get("If-None-Match")
+            .flat_map(|value| value.split(','))
+            .any(|tag| tag.trim() == self.etag || tag.trim() == "*");
+        if cached {
+            response.set_status(Status::NotModified);
+        } else {
+            response.set_header(self.content_type);
+            response.set_sized_body(Cursor::new(self.data));
+        }
+        Ok(response)
+    }
+}
+
+fn original_cover(book: &Audiobook, cover_hash: &str, data: Vec<u8>) -> Cover {
+    let content_type = book.cover_mime.as_ref()
+        .and_then(|mime| ContentType::parse_flexible(mime))
+        .unwrap_or(ContentType::Binary);
+    Cover {
+        data,
+        content_type,
+        etag: format!("\"{}\"", cover_hash),
+    }
+}
+
+/// Serve the cover of a book, optionally scaled down with `?size=small`, `medium` or `large`, see
+/// `worker::thumbnails`.
+#[get("/audiobooks/<book_id>/cover?<size>")]
+pub fn get_audiobook_cover(current_user: User, db: DB, book_id: Uuid, size: Option<String>, config: Config)
+    -> Result<Cover, APIError> {
+    let book = match current_user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    let cover_hash = match book.cover_hash {
+        Some(h) => h,
+        None => return Err(responses::not_found().message("No cover art found."))
+    };
+    let original = match fs::read(layout::cover_path(&config.data_directory, &book.id)) {
+        Ok(data) => data,
+        Err(_) => return Err(responses::not_found().message("No cover art found."))
+    };
+
+    match size {
+        None => Ok(original_cover(&book, &cover_hash, original)),
+        Some(name) => {
+            let pixels = match thumbnails::pixels(&name) {
+                Some(p) => p,
+                None => return Err(responses::bad_request().message("Size must be small, medium or large."))
+            };
+            let data = thumbnails::get(&config.data_directory, &book.id, &cover_hash, &name, pixels, &original)?;
+            let content_type = if thumbnails::is_webp(&data) { ContentType::WEBP } else { ContentType::JPEG };
+            Ok(Cover {
+                data,
+                content_type,
+                etag: format!("\"{}-{}\"", cover_hash, name),
+            })
+        }
+    }
+}
+
+/// Serve the image of a chapter, chapters that have one say so with `has_image`.
+#[get("/audiobooks/<book_id>/chapters/<chapter_id>/image")]
+pub fn get_chapter_image(current_user: User, db: DB, book_id: Uuid, chapter_id: Uuid, config: Config)
+    -> Result<Cover, APIError> {
+    use crate::schema::chapters::dsl;
+    if current_user.get_book_if_accessible(&book_id, &*db)?.is_none() {
+        return Err(responses::not_found().message("No book found or not accessible."));
+    }
+    let chapter = dsl::chapters
+        .filter(dsl::id.eq(&chapter_id))
+        .filter(dsl::audiobook_id.eq(&book_id))
+        .first::<Chapter>(&*db)
+        .optional()?;
+    match chapter.and_then(|c| chapter_image(&c, &config)) {
+        Some(image) => Ok(image),
+        None => Err(responses::not_found().message("The chapter has no image."))
+    }
+}
+
+/// The saved image of a chapter, `None` if it has none.
+fn chapter_image(chapter: &Chapter, config: &Config) -> Option<Cover> {
+    if !chapter.has_image {
+        return None;
+    }
+    let data = fs::read(layout::chapter_image_path(&config.data_directory, &chapter.audiobook_id, &chapter.id)).ok()?;
+    let image_type = ImageType::guess(&data)?;
+    let image = Image { data, image_type };
+    // A rescan may give the chapter another image
+    Some(Cover {
+        etag: format!("\"{}\"", image.checksum()),
+        content_type: ContentType::parse_flexible(image.image_type.mime_type()).unwrap_or(ContentType::Binary),
+        data: image.data,
+    })
+}
+
+/// Serve the image of a chapter, or the cover of its book if it has none. Clients that show an
+/// image for the current chapter don't have to check `has_image` then.
+#[get("/chapters/<chapter_id>/image")]
+pub fn get_chapter_image_or_cover(current_user: User, db: DB, chapter_id: Uuid, config: Config)
+    -> Result<Cover, APIError> {
+    use crate::schema::chapters::dsl;
+    let not_found = || responses::not_found().message("No chapter found or not accessible.");
+    let chapter = match dsl::chapters.filter(dsl::id.eq(&chapter_id)).first::<Chapter>(&*db).optional()? {
+        Some(c) => c,
+        None => return Err(not_found())
+    };
+    let book = match current_user.get_book_if_accessible(&chapter.audiobook_id, &*db)? {
+        Some(b) => b,
+        None => return Err(not_found())
+    };
+    if let Some(image) = chapter_image(&chapter, &config) {
+        return Ok(image);
+    }
+    let cover = book.cover_hash.as_ref()
+        .and_then(|hash| fs::read(layout::cover_path(&config.data_directory, &book.id)).ok().map(|data| (hash, data)));
+    match cover {
+        Some((hash, data)) => Ok(original_cover(&book, hash, data)),
+        None => Err(responses::not_found().message("Neither the chapter nor its book have an image."))
+    }
+}
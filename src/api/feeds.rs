@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use rocket::Outcome;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, Request, FromRequest};
+use rocket::response::content::Content;
+
+use crate::api::audiobooks::ensure_download_allowed;
+use crate::api::ranged_file::{RangedFile, audio_content_type};
+use crate::api::status;
+use crate::config::Config;
+use crate::helpers::db::DB;
+use crate::helpers::feed::{self, Feed, FeedItem};
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::models::user::User;
+use crate::responses::{APIError, self};
+
+/// Scheme and host the server is reached at, feeds need absolute URLs. Taken from
+/// `X-Forwarded-Proto` and `Host` of trusted proxies, see `status::from_trusted_proxy`, and from
+/// the configured address otherwise, anyone else could point enclosures anywhere.
+pub struct BaseUrl(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BaseUrl {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BaseUrl, ()> {
+        let config = match request.guard::<Config>() {
+            Outcome::Success(c) => c,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let own_scheme = if config.tls.is_some() { "https" } else { "http" };
+        let own_host = format!("{}:{}", config.web.address, config.web.port);
+        let headers = request.headers();
+        let (scheme, host) = if status::from_trusted_proxy(request) {
+            (headers.get_one("X-Forwarded-Proto").unwrap_or(own_scheme),
+             headers.get_one("Host").map(str::to_owned).unwrap_or(own_host))
+        } else {
+            (own_scheme, own_host)
+        };
+        Outcome::Success(BaseUrl(format!("{}://{}", scheme, host)))
+    }
+}
+
+fn rss(body: String) -> Content<String> {
+    Content(ContentType::new("application", "rss+xml"), body)
+}
+
+/// The user a feed belongs to. Podcast clients download whole books, so feeds are only for users
+/// who may download.
+fn feed_user(token: &str, db: &DB) -> Result<User, APIError> {
+    match User::find_by_feed_token(token, &*db)? {
+        Some(u) => {
+            ensure_download_allowed(&u)?;
+            Ok(u)
+        },
+        None => Err(responses::not_found().message("Unknown feed."))
+    }
+}
+
+fn data_path(config: &Config, book: &Audiobook) -> PathBuf {
+    let mut path = PathBuf::from(&config.data_directory);
+    path.push(book.id.hyphenated().to_string());
+    path.set_extension(&book.file_extension);
+    path
+}
+
+fn feed_item<'a>(book: &'a Audiobook, chapters: &'a [Chapter], token: &str, base: &BaseUrl, config: &Config)
+    -> FeedItem<'a> {
+    let path = data_path(config, book);
+    FeedItem {
+        book,
+        chapters,
+        enclosure_url: format!(
+            "{}/feeds/{}/data/{}.{}", base.0, token, book.id.hyphenated(), book.file_extension
+        ),
+        enclosure_length: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+        enclosure_type: audio_content_type(&path).to_string(),
+    }
+}
+
+/// All books of a library as a podcast feed, one item per book.
+#[get("/<token>/libraries/<library_id>")]
+pub fn library_feed(token: String, library_id: Uuid, base: BaseUrl, db: DB, config: Config)
+    -> Result<Content<String>, APIError> {
+    use crate::schema::audiobooks::dsl;
+    let user = feed_user(&token, &db)?;
+    let library = match user.get_library_if_accessible(&library_id, &*db)? {
+        Some(l) => l,
+        None => return Err(responses::not_found().message("No library found or not accessible."))
+    };
+    let books = Audiobook::belonging_to(&library)
+        .filter(dsl::deleted.eq(false))
+        .order((dsl::sort_title.asc(), dsl::location.asc()))
+        .load::<Audiobook>(&*db)?;
+    let chapters = Chapter::belonging_to(&books)
+        .order(crate::schema::chapters::dsl::number.asc())
+        .load::<Chapter>(&*db)?
+        .grouped_by(&books);
+    let title = PathBuf::from(&library.location).file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| library.location.clone());
+    let feed = Feed {
+        title,
+        link: format!("{}/feeds/{}/libraries/{}", base.0, token, library.id.hyphenated()),
+        items: books.iter().zip(chapters.iter())
+            .map(|(book, chapters)| feed_item(book, chapters, &token, &base, &config))
+            .collect(),
+    };
+    Ok(rss(feed::render(&feed)))
+}
+
+/// A single book as a podcast feed.
+#[get("/<token>/audiobooks/<book_id>")]
+pub fn audiobook_feed(token: String, book_id: Uuid, base: BaseUrl, db: DB, config: Config)
+    -> Result<Content<String>, APIError> {
+    let user = feed_user(&token, &db)?;
+    let book = match user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    let chapters = Chapter::belonging_to(&book)
+        .order(crate::schema::chapters::dsl::number.asc())
+        .load::<Chapter>(&*db)?;
+    let feed = Feed {
+        title: book.title.clone(),
+        link: format!("{}/feeds/{}/audiobooks/{}", base.0, token, book.id.hyphenated()),
+        items: vec![feed_item(&book, &chapters, &token, &base, &config)],
+    };
+    Ok(rss(feed::render(&feed)))
+}
+
+/// The enclosures of the feeds, `file_name` is the book's id followed by its file extension.
+#[get("/<token>/data/<file_name>")]
+pub fn feed_data(token: String, file_name: String, db: DB, config: Config) -> Result<RangedFile, APIError> {
+    let user = feed_user(&token, &db)?;
+    let book_id = Uuid::parse_str(file_name.split('.').next().unwrap_or(""))
+        .map_err(|_| responses::not_found())?;
+    let book = match user.get_book_if_accessible(&book_id, &*db)? {
+        Some(b) => b,
+        None => return Err(responses::not_found().message("No book found or not accessible."))
+    };
+    match RangedFile::open(data_path(&config, &book)) {
+        Ok(f) => Ok(f.with_sha256(book.data_hash)),
+        Err(_) => Err(responses::internal_server_error())
+    }
+}
@@ -1,48 +1,336 @@
-use crate::models::user::User;
-use crate::responses::{APIResponse, ok};
+use crate::models::user::{User, ApiToken};
+use crate::responses::{APIResponse, APIResult, self, ok, accepted};
+use rocket::State;
+use rocket::request::LenientForm;
 use rocket_contrib::json::Json;
 use diesel::prelude::*;
 use diesel::BelongingToDsl;
 use serde_json;
-use crate::helpers::db::DB;
+use crate::helpers::db::{DB, Pool};
+use crate::helpers::pagination::Page;
+use crate::helpers::uuid::Uuid;
+use crate::helpers::events::{self, Event};
+use crate::config::Config;
 use crate::models::library::Library;
 use crate::models::audiobook::Audiobook;
 use crate::models::chapter::Chapter;
 use crate::models::playstate::{Playstate, ApiPlaystate};
+use crate::models::scan::Scan;
+use crate::models::audit_log::{Action, AuditEntry};
+use crate::api::status::ClientIp;
+use std::net::IpAddr;
+use crate::models::library_permission::LibraryPermission;
+use crate::models::translation;
+use crate::models::sync;
+use crate::models::listening;
+use crate::models::playstate_import;
+use chrono::Utc;
+use crate::worker::scheduler::{self, ScanClaim};
+use crate::handlers::Admin;
+use crate::validation::library::LibraryUpdateSerializer;
+use crate::validation::query::{PageQuery, parse_timestamp};
+use crate::worker::janitor;
+use crate::worker::bundle::{self, Bundle, BundleError};
+use crate::api::ranged_file::Attachment;
+use validator::Validate;
+
+/// A library as clients get it. `initial_scan` is set while the first scan of the library runs,
+/// books show up one by one then and clients can say the library is still filling up.
+fn library_payload(library: &Library) -> serde_json::Value {
+    let mut data = json!(library);
+    data["initial_scan"] = json!(library.last_scan.is_none() && scheduler::is_scanning(library)).into_inner();
+    data.into_inner()
+}
 
 #[get("/libraries")]
 pub fn libraries(current_user: User, db: DB) -> APIResponse {
-    use crate::schema::libraries::dsl::*;
-    let libs = libraries.load::<Library>(&*db).unwrap();
-    ok().data(json!(libs))
+    let libs = current_user.accessible_libraries(&*db).unwrap();
+    ok().data(json!(libs.iter().map(library_payload).collect::<Vec<_>>()))
 }
 
 #[get("/all_the_things")]
 pub fn all_the_things(current_user: User, db: DB) -> APIResponse {
     use crate::schema;
     let libs = current_user.accessible_libraries(&*db).unwrap();
-    let books = current_user.accessible_audiobooks(&*db).unwrap();
-    let chapters: Vec<Chapter> = books.clone().into_iter().flat_map(|b| Chapter::belonging_to(&b).load::<Chapter>(&*db).unwrap()).collect();
-    let playstates: Vec<_> = Playstate::belonging_to(&current_user).load::<Playstate>(&*db)
-                                .unwrap().into_iter().map(|p| p.to_api_playstate()).collect();
+    let mut books = current_user.accessible_audiobooks(&*db).unwrap();
+    translation::localize(&mut books, current_user.preferred_language.as_ref().map(String::as_str), &*db).unwrap();
+    let chapters: Vec<Chapter> = books.clone().into_iter().flat_map(|b| {
+        Chapter::belonging_to(&b).order(schema::chapters::dsl::number.asc()).load::<Chapter>(&*db).unwrap()
+    }).collect();
+    let playstates = Playstate::with_device_names(&current_user, &*db).unwrap();
     ok().data(json!({
-        "libraries": libs,
+        "libraries": libs.iter().map(library_payload).collect::<Vec<_>>(),
         "books": books,
         "chapters": chapters,
         "playstates": playstates,
     }))
 }
 
+/// Books and chapters that changed since `?since=` along with the ids of books that went away,
+/// or everything without it. Pass the `now` of the answer as `since` next time, books that changed
+/// shortly before it may be sent again.
+#[get("/sync?<since>")]
+pub fn sync(current_user: User, db: DB, since: Option<String>) -> APIResult {
+    let since = match since {
+        Some(s) => match parse_timestamp(&s) {
+            Some(t) => Some(t),
+            None => return Err(responses::unprocessable_entity()
+                .message("Invalid input.")
+                .errors(json!({"since": ["Must be a timestamp like 2020-05-01T12:00:00."]}).into_inner())),
+        },
+        None => None,
+    };
+    let now = Utc::now().naive_utc();
+    let mut changes = sync::changes_since(&current_user, since, &*db)?;
+    translation::localize(&mut changes.books, current_user.preferred_language.as_ref().map(String::as_str), &*db)?;
+    let mut data = json!(changes);
+    data["now"] = json!(now).into_inner();
+    Ok(ok().data(data))
+}
+
+/// Save playstates, if one of them is invalid none are. Playstates of books that were removed or that
+/// the user can no longer access are skipped, their ids are listed as `skipped`. Clients that queued
+/// them while offline would otherwise never get their other playstates through.
 #[post("/update_playstates", data = "<playstate>", format = "application/json")]
-pub fn update_playstates(playstate: Json<Vec<ApiPlaystate>>, current_user: User, db: DB) -> APIResponse {
-    use diesel;
-    // TODO: Don't ignore errors here
-    db.exclusive_transaction(|| -> Result<(), diesel::result::Error> {
+pub fn update_playstates(playstate: Json<Vec<ApiPlaystate>>, current_user: User, token: ApiToken, db: DB) -> APIResult {
+    let (updated, skipped) = db.transaction(|| {
+        let mut updated = Vec::new();
+        let mut skipped = Vec::new();
         for state in playstate.into_inner() {
-            state.to_playstate(&current_user)
-                .upsert(&*db)?.to_api_playstate();
+            if current_user.get_book_if_accessible(&state.audiobook_id, &*db)?.is_none() {
+                skipped.push(state.audiobook_id);
+                continue;
+            }
+            if !(state.speed > 0.0 && state.speed <= listening::MAX_SPEED) {
+                return Err(responses::unprocessable_entity()
+                    .message("Invalid input.")
+                    .errors(json!({"speed": [format!("Must be above 0 and at most {}.", listening::MAX_SPEED)]}).into_inner()));
+            }
+            let new_state = state.to_playstate(&current_user, &token);
+            let previous = Playstate::of(&current_user, &state.audiobook_id, &*db)?;
+            listening::record(previous.as_ref(), &new_state, &*db)?;
+            new_state.upsert(&*db)?;
+            updated.push(new_state);
+        }
+        Ok((updated, skipped))
+    })?;
+    for state in updated {
+        events::publish(Event::PlaystateUpdated {
+            user_id: state.user_id,
+            audiobook_id: state.audiobook_id,
+            position: state.position,
+        });
+    }
+    Ok(ok().data(json!({"skipped": skipped})))
+}
+
+/// Create playstates from CSV lines of a book and a position in seconds, as exported by other
+/// players. Books are named by path or title, see `models::playstate_import`. Books that already
+/// have a playstate are skipped, as are lines that match no book or more than one.
+#[post("/import_playstates", data = "<csv>", format = "text/csv")]
+pub fn import_playstates(csv: String, current_user: User, token: ApiToken, db: DB) -> APIResult {
+    let (rows, unreadable) = playstate_import::parse(&csv);
+    let mut imported = db.transaction(|| Ok(playstate_import::import(&current_user, &rows, Some(token.id), &*db)?))?;
+    for state in &imported.imported {
+        events::publish(Event::PlaystateUpdated {
+            user_id: current_user.id,
+            audiobook_id: state.audiobook_id,
+            position: state.position,
+        });
+    }
+    imported.skipped.extend(unreadable);
+    imported.skipped.sort_by_key(|s| s.line);
+    Ok(ok().data(json!(imported)))
+}
+
+/// Listening time of the current user, counted from their playstate updates.
+#[get("/stats")]
+pub fn stats(current_user: User, db: DB) -> APIResult {
+    let stats = listening::stats(&current_user, Utc::now().naive_utc().date(), &*db)?;
+    Ok(ok().data(json!(stats)))
+}
+
+fn find_library(current_user: &User, library_id: &Uuid, db: &DB) -> Result<Library, responses::APIError> {
+    match current_user.get_library_if_accessible(library_id, &*db)? {
+        Some(l) => Ok(l),
+        None => Err(responses::not_found().message("No library found or not accessible."))
+    }
+}
+
+#[post("/libraries/<library_id>/scan?<full>")]
+pub fn scan_library(_admin: Admin, library_id: Uuid, full: Option<bool>, db: DB,
+                    pool: State<Pool>, config: Config) -> APIResult {
+    let library = find_any_library(&library_id, &db)?;
+    scheduler::trigger_scan(pool.inner().clone(), config, library, full.unwrap_or(false))?;
+    Ok(accepted().message("Scan started."))
+}
+
+#[get("/libraries/<library_id>/scans")]
+pub fn get_scans(current_user: User, library_id: Uuid, db: DB) -> APIResult {
+    let library = find_library(&current_user, &library_id, &db)?;
+    let scans = Scan::recent(&library, 20, &*db)?;
+    Ok(ok().data(json!(scans)))
+}
+
+/// Whether the library is being scanned right now, how far the scan got and how scans are
+/// throttled. Clients that show progress poll this.
+#[get("/libraries/<library_id>/scan_status")]
+pub fn get_scan_status(current_user: User, library_id: Uuid, db: DB, config: Config) -> APIResult {
+    let library = find_library(&current_user, &library_id, &db)?;
+    Ok(ok().data(json!({
+        "scanning": scheduler::is_scanning(&library),
+        "progress": scheduler::progress(&library),
+        "throttle": {
+            "max_hash_rate": config.scan.max_hash_rate,
+            "pause_between_books": config.scan.pause_between_books,
+        },
+    })))
+}
+
+/// Outcome of the last finished scan of a library, including the files that were skipped.
+/// `?limit=`/`?offset=` paginate the files.
+#[get("/libraries/<library_id>/scan_report?<page..>")]
+pub fn get_scan_report(current_user: User, library_id: Uuid, db: DB, page: LenientForm<PageQuery>) -> APIResult {
+    page.validate()?;
+    let library = find_library(&current_user, &library_id, &db)?;
+    let scan = match Scan::last_finished(&library, &*db)? {
+        Some(s) => s,
+        None => return Err(responses::not_found().message("The library has not been scanned yet."))
+    };
+    let errors = scan.errors(&*db)?;
+    let mut data = json!(scan);
+    data["errors"] = json!(Page::slice(errors, page.limit, page.offset)).into_inner();
+    Ok(ok().data(data))
+}
+
+fn find_any_library(library_id: &Uuid, db: &DB) -> Result<Library, responses::APIError> {
+    use crate::schema::libraries::dsl;
+    match dsl::libraries.filter(dsl::id.eq(library_id)).first::<Library>(&**db).optional()? {
+        Some(l) => Ok(l),
+        None => Err(responses::not_found().message("No such library."))
+    }
+}
+
+/// Change location or audiobook regex of a library, takes effect with the next scan.
+#[patch("/libraries/<library_id>", data = "<update>", format = "application/json")]
+pub fn update_library(admin: Admin, library_id: Uuid, update: Json<LibraryUpdateSerializer>, db: DB) -> APIResult {
+    update.validate()?;
+    let mut library = find_any_library(&library_id, &db)?;
+    let update = update.into_inner();
+    library.update(update.location, update.is_audiobook_regex, &*db)?;
+    Ok(ok().data(json!(library)))
+}
+
+/// Delete a library and everything in it. With `?purge=false` its books are only marked as
+/// deleted and access to it is revoked.
+#[delete("/libraries/<library_id>?<purge>")]
+pub fn delete_library(admin: Admin, library_id: Uuid, purge: Option<bool>, client: ClientIp, db: DB, config: Config)
+    -> APIResult {
+    let library = find_any_library(&library_id, &db)?;
+    // Holding the claim keeps scans from starting while the library goes away
+    let _claim = ScanClaim::new(&library)
+        .map_err(|_| responses::conflict().message("The library is being scanned."))?;
+    let purge = purge.unwrap_or(true);
+    let removed = db.transaction(|| {
+        AuditEntry::new(Action::LibraryDeleted, Some(admin.0.id), client.0)
+            .target(&library.id.hyphenated().to_string())
+            .details(if purge { "purged" } else { "kept books" })
+            .save(&*db)?;
+        Ok(library.delete(purge, &*db)?)
+    })?;
+    janitor::remove_book_files(&config.data_directory, &removed);
+    Ok(ok())
+}
+
+fn bundle_secret(config: &Config) -> Result<&str, responses::APIError> {
+    match config.bundles {
+        Some(ref b) => Ok(&b.secret),
+        None => Err(responses::not_found().message("Bundles are not configured.")),
+    }
+}
+
+/// The metadata of the books in a library as a signed bundle for `import_library`, without audio.
+#[get("/libraries/<library_id>/export")]
+pub fn export_library(admin: Admin, library_id: Uuid, db: DB, config: Config)
+    -> Result<Attachment<Json<Bundle>>, responses::APIError> {
+    let secret = bundle_secret(&config)?;
+    let library = find_any_library(&library_id, &db)?;
+    let exported = bundle::export(&library, secret, &config, &*db)?;
+    Ok(Attachment(Json(exported), format!("{}.vorleser.json", library.id.hyphenated())))
+}
+
+/// Apply a bundle exported by a server with the same secret to the books of this library whose
+/// files have the same hash.
+#[post("/libraries/<library_id>/import", data = "<bundle_in>", format = "application/json")]
+pub fn import_library(admin: Admin, library_id: Uuid, bundle_in: Json<Bundle>, client: ClientIp, db: DB,
+                      config: Config) -> APIResult {
+    let secret = bundle_secret(&config)?;
+    let library = find_any_library(&library_id, &db)?;
+    let books = bundle::open(&bundle_in, secret).map_err(|e| match e.downcast::<BundleError>() {
+        Ok(e) => responses::unprocessable_entity().message(&e.to_string()),
+        Err(e) => e.into(),
+    })?;
+    if scheduler::is_scanning(&library) {
+        return Err(responses::conflict().message("The library is being scanned."));
+    }
+    let (imported, covers) = db.transaction(|| {
+        let (imported, covers) = bundle::import(&library, &books, &*db)?;
+        AuditEntry::new(Action::LibraryImported, Some(admin.0.id), client.0)
+            .target(&library.id.hyphenated().to_string())
+            .details(&format!("{} books, {} matched", imported.books, imported.matched))
+            .save(&*db)?;
+        Ok((imported, covers))
+    })?;
+    for cover in covers {
+        if let Err(e) = cover.save(&config) {
+            warn!("Could not save the imported cover of {}: {}", cover.audiobook_id, e);
+        }
+    }
+    Ok(ok().data(json!(imported)))
+}
+
+fn find_any_user(user_id: &Uuid, db: &DB) -> Result<User, responses::APIError> {
+    use crate::schema::users::dsl;
+    match dsl::users.filter(dsl::id.eq(user_id)).first::<User>(&**db).optional()? {
+        Some(u) => Ok(u),
+        None => Err(responses::not_found().message("No such user."))
+    }
+}
+
+fn permission_entry(action: Action, admin: &Admin, user: &User, library: &Library, ip: Option<IpAddr>) -> AuditEntry {
+    AuditEntry::new(action, Some(admin.0.id), ip)
+        .target(&user.id.hyphenated().to_string())
+        .details(&format!("library {}", library.id.hyphenated()))
+}
+
+/// Ids of the users who have access to a library.
+#[get("/libraries/<library_id>/permissions")]
+pub fn get_permissions(admin: Admin, library_id: Uuid, db: DB) -> APIResult {
+    let library = find_any_library(&library_id, &db)?;
+    Ok(ok().data(json!(LibraryPermission::users_of(&library, &*db)?)))
+}
+
+#[put("/libraries/<library_id>/permissions/<user_id>")]
+pub fn grant_permission(admin: Admin, library_id: Uuid, user_id: Uuid, client: ClientIp, db: DB) -> APIResult {
+    db.transaction(|| {
+        let library = find_any_library(&library_id, &db)?;
+        let user = find_any_user(&user_id, &db)?;
+        LibraryPermission::ensure(&user, &library, &*db)?;
+        permission_entry(Action::PermissionGranted, &admin, &user, &library, client.0).save(&*db)?;
+        Ok(ok())
+    })
+}
+
+#[delete("/libraries/<library_id>/permissions/<user_id>")]
+pub fn revoke_permission(admin: Admin, library_id: Uuid, user_id: Uuid, client: ClientIp, db: DB) -> APIResult {
+    db.transaction(|| {
+        let library = find_any_library(&library_id, &db)?;
+        let user = find_any_user(&user_id, &db)?;
+        if LibraryPermission::revoke(&user, &library, &*db)? {
+            permission_entry(Action::PermissionRevoked, &admin, &user, &library, client.0).save(&*db)?;
+            Ok(ok())
+        } else {
+            Err(responses::not_found().message("The user has no access to this library."))
         }
-        Ok(())
-    });
-    ok().data(json!({}))
+    })
 }
@@ -2,3 +2,9 @@ pub mod libraries;
 pub mod audiobooks;
 pub mod auth;
 pub mod ranged_file;
+pub mod covers;
+pub mod admin;
+pub mod feeds;
+pub mod authors;
+pub mod bookmarks;
+pub mod status;
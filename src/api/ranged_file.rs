@@ -3,19 +3,23 @@ use std::path::{Path, PathBuf};
 use std::io;
 use std::ops::{Deref, DerefMut};
 
-use rocket::request::Request;
+use rocket::Outcome;
+use rocket::request::{self, FromRequest, Request};
 use rocket::response::{Response, Responder, Body};
 use rocket::http::{Status, ContentType};
 use rocket::http::hyper::header::{Range, ByteRangeSpec, AcceptRanges, RangeUnit, ContentLength, ContentRange, ContentRangeSpec};
 use rocket::http::hyper::header::Range::Bytes;
 use rocket::http::hyper::header::ByteRangeSpec::*;
-use std::fs::metadata;
 use std::io::{Seek, SeekFrom, Read};
+use base64;
+
+use crate::worker::faults;
+use crate::worker::hashing;
 
 /// A file with an associated name; responds with the Content-Type based on the
 /// file extension.
 #[derive(Debug)]
-pub struct RangedFile(PathBuf, File);
+pub struct RangedFile(PathBuf, File, Option<Vec<u8>>, Option<String>);
 
 impl RangedFile {
     /// Attempts to open a file in read-only mode.
@@ -36,8 +40,27 @@ impl RangedFile {
     /// let file = RangedFile::open("foo.txt");
     /// ```
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RangedFile> {
+        faults::read()?;
         let file = File::open(path.as_ref())?;
-        Ok(RangedFile(path.as_ref().to_path_buf(), file))
+        Ok(RangedFile(path.as_ref().to_path_buf(), file, None, None))
+    }
+
+    /// Announce the SHA-256 of the whole file in a `Digest` header, also on partial responses.
+    /// This allows clients to verify downloads they assembled from several ranges.
+    /// Unless set otherwise the hash also becomes the file's ETag.
+    pub fn with_sha256(mut self, sha256: Option<Vec<u8>>) -> RangedFile {
+        if self.3.is_none() {
+            self.3 = sha256.as_ref().map(|hash| hashing::to_hex(hash));
+        }
+        self.2 = sha256;
+        self
+    }
+
+    /// Set the ETag, it has to change whenever the content does. Clients resuming a download
+    /// send it back in `If-Range` so they don't get a range of a different file.
+    pub fn with_etag(mut self, etag: String) -> RangedFile {
+        self.3 = Some(etag);
+        self
     }
 
     /// Retrieve the underlying `File`.
@@ -79,77 +102,173 @@ impl RangedFile {
     }
 }
 
-/// Streams the named file to the client. Sets or overrides the Content-Type in
-/// the response according to the file's extension if the extension is
-/// recognized. See
-/// [ContentType::from_extension](/rocket/http/struct.ContentType.html#method.from_extension)
-/// for more information. If you would like to stream a file with a different
-/// Content-Type than that implied by its extension, use a `File` directly.
-impl Responder<'static> for RangedFile {
-    fn respond_to(self, req: &Request) -> Result<Response<'static>, Status> {
-        let mut response = Response::new();
-        // if let Some(ext) = self.path().extension() {
-        //     // TODO: Use Cow for lowercase.
-        //     let ext_string = ext.to_string_lossy().to_lowercase();
-        //     if let Some(content_type) = ContentType::from_extension(&ext_string) {
-        //         response.set_header(content_type);
-        //     }
-        // }
-        // hard-coded for testing, rocket doesn't have an mp3 content-type
-        response.set_header(ContentType::new("audio", "mpeg"));
-
-        let meta = metadata(self.path()).unwrap();
-        let size = meta.len();
-        response.set_header(AcceptRanges(vec![RangeUnit::Bytes]));
-
-        if let Some(range) = req.headers().get_one("Range") {
-            let r: Range = range.parse().unwrap();
-            println!("{:?}", r);
-            match r {
-                Bytes(vec) => {
-                    let spec = &vec[0];
-                    let mut f = self.take_file();
-                    match *spec {
-                        FromTo(from, to) => {
-                            let first_byte_not_sent = to + 1;
-                            f.seek(SeekFrom::Start(from));
-                            let body = Body::Sized(f.take(first_byte_not_sent - from), first_byte_not_sent - from);
-                            let result_spec = ContentRangeSpec::Bytes{
-                                range: Some((from, to)),
-                                instance_length: Some(size)
-                            };
-                            response.set_header(ContentRange(result_spec));
-                            response.set_raw_body(body);
-                        }
-                        AllFrom(from) => {
-                            f.seek(SeekFrom::Start(from));
-                            let body = Body::Sized(f, meta.len() - from);
-                            let result_spec = ContentRangeSpec::Bytes{
-                                range: Some((from, size - 1)),
-                                instance_length: Some(size)
-                            };
-                            response.set_header(ContentRange(result_spec));
-                            response.set_raw_body(body);
-                        }
-                        Last(n) => {
-                            f.seek(SeekFrom::End(-(n as i64)));
-                            let body = Body::Sized(f, n);
-                            let result_spec = ContentRangeSpec::Bytes{
-                                range: Some((size - n, size - 1)),
-                                instance_length: Some(size)
-                            };
-                            response.set_header(ContentRange(result_spec));
-                            response.set_raw_body(body);
-                        }
-                    };
-                    response.set_status(Status::PartialContent);
-                }
-                _ => unreachable!("can't deal with non-byte ranges")
+/// Resolve a single byte range spec against a body of `size` bytes.
+/// Returns the first and last byte (inclusive) or `None` if the range can't be satisfied.
+pub fn resolve_range(spec: &ByteRangeSpec, size: u64) -> Option<(u64, u64)> {
+    if size == 0 {
+        return None;
+    }
+    match *spec {
+        FromTo(from, to) => {
+            if from > to || from >= size {
+                None
+            } else {
+                Some((from, to.min(size - 1)))
             }
-        } else {
-            response.set_sized_body(self.take_file());
+        },
+        AllFrom(from) => {
+            if from >= size {
+                None
+            } else {
+                Some((from, size - 1))
+            }
+        },
+        Last(0) => None,
+        Last(n) => Some((size - n.min(size), size - 1)),
+    }
+}
+
+/// Whether a request asks for the start of a body, which it does unless its `Range` header starts
+/// later. Clients fetch the rest of a file they are playing in ranges.
+pub struct FromStart(pub bool);
+
+impl<'a, 'r> FromRequest<'a, 'r> for FromStart {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<FromStart, ()> {
+        let first = request.headers().get_one("Range")
+            .and_then(|header| header.parse::<Range>().ok())
+            .and_then(|range| match range {
+                Bytes(specs) => specs.into_iter().next(),
+                _ => None,
+            });
+        Outcome::Success(FromStart(match first {
+            Some(FromTo(from, _)) | Some(AllFrom(from)) => from == 0,
+            Some(Last(_)) => false,
+            None => true,
+        }))
+    }
+}
+
+/// Whether the `If-Range` header allows serving a range of the body with the given ETag.
+/// Only strong ETags are compared, dates never match as we don't send `Last-Modified`.
+pub fn if_range_matches(if_range: Option<&str>, etag: Option<&str>) -> bool {
+    match if_range {
+        None => true,
+        Some(validator) => match etag {
+            Some(etag) => validator.trim() == format!("\"{}\"", etag),
+            None => false,
         }
+    }
+}
+
+/// Value of a `Digest` header (RFC 3230) for the given SHA-256.
+pub fn digest_header(sha256: &[u8]) -> String {
+    format!("sha-256={}", base64::encode(sha256))
+}
 
+/// Value of a `Content-Disposition` header offering a download as `file_name`.
+/// Clients that don't understand the UTF-8 `filename*` fall back to an ASCII version of the name.
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = file_name.bytes()
+        .map(|b| if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            (b as char).to_string()
+        } else {
+            format!("%{:02X}", b)
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// Offers the wrapped response as a download named like the second field.
+pub struct Attachment<R>(pub R, pub String);
+
+impl<'r, R: Responder<'r>> Responder<'r> for Attachment<R> {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        let mut response = self.0.respond_to(req)?;
+        response.set_raw_header("Content-Disposition", content_disposition(&self.1));
+        Ok(response)
+    }
+}
+
+/// Content type for audio files, rocket doesn't know most audio extensions.
+pub fn audio_content_type(path: &Path) -> ContentType {
+    let extension = path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp3" => ContentType::new("audio", "mpeg"),
+        "m4a" | "m4b" | "mp4" => ContentType::new("audio", "mp4"),
+        "ogg" | "oga" | "opus" => ContentType::new("audio", "ogg"),
+        "flac" => ContentType::new("audio", "flac"),
+        ext => ContentType::from_extension(ext).unwrap_or(ContentType::Binary),
+    }
+}
+
+/// Build a response for any seekable body honoring the request's `Range` header.
+///
+/// Headers that can't be parsed or use units other than bytes are ignored and the whole body is
+/// sent. Only the first range of a multi-range request is served. So is the whole body if the
+/// request's `If-Range` doesn't match `etag`.
+pub fn ranged_response<T: Read + Seek + 'static>(mut body: T, size: u64, content_type: ContentType,
+                                                 etag: Option<&str>, req: &Request)
+    -> Result<Response<'static>, Status> {
+    let mut response = Response::new();
+    response.set_header(content_type);
+    response.set_header(AcceptRanges(vec![RangeUnit::Bytes]));
+    if let Some(etag) = etag {
+        response.set_raw_header("ETag", format!("\"{}\"", etag));
+    }
+
+    let requested = req.headers().get_one("Range")
+        .filter(|_| if_range_matches(req.headers().get_one("If-Range"), etag))
+        .and_then(|header| header.parse::<Range>().ok())
+        .and_then(|range| match range {
+            Bytes(specs) => specs.into_iter().next(),
+            _ => None,
+        });
+
+    match requested {
+        Some(spec) => match resolve_range(&spec, size) {
+            Some((from, to)) => {
+                body.seek(SeekFrom::Start(from)).map_err(|_| Status::InternalServerError)?;
+                let length = to - from + 1;
+                response.set_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((from, to)),
+                    instance_length: Some(size)
+                }));
+                response.set_raw_body(Body::Sized(body.take(length), length));
+                response.set_status(Status::PartialContent);
+            },
+            None => {
+                response.set_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(size)
+                }));
+                response.set_status(Status::RangeNotSatisfiable);
+            }
+        },
+        None => response.set_raw_body(Body::Sized(body, size)),
+    }
+
+    Ok(response)
+}
+
+/// Streams the named file to the client, honoring range requests. Sets the Content-Type in the
+/// response according to the file's extension.
+impl Responder<'static> for RangedFile {
+    fn respond_to(self, req: &Request) -> Result<Response<'static>, Status> {
+        let content_type = audio_content_type(self.path());
+        let size = self.file().metadata().map_err(|_| Status::InternalServerError)?.len();
+        let digest = self.2.as_ref().map(|sha256| digest_header(sha256));
+        let etag = self.3.clone();
+        let mut response = ranged_response(self.take_file(), size, content_type, etag.as_ref().map(String::as_str), req)?;
+        if let Some(digest) = digest {
+            response.set_raw_header("Digest", digest);
+        }
         Ok(response)
     }
 }
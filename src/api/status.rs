@@ -0,0 +1,71 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use diesel::dsl::sum;
+use diesel::prelude::*;
+use rocket::{Outcome, State};
+use rocket::request::{self, Request, FromRequest};
+
+use crate::config::Config;
+use crate::helpers::db::DB;
+use crate::helpers::rate_limit::RateLimiter;
+use crate::responses::{APIResult, self, ok};
+use crate::schema::audiobooks::dsl;
+
+/// Limits requests to the public status per client address.
+pub struct StatusLimiter(pub RateLimiter<Option<IpAddr>>);
+
+impl StatusLimiter {
+    pub fn new(config: &Config) -> Self {
+        StatusLimiter(RateLimiter::new(config.status.requests_per_minute, Duration::from_secs(60)))
+    }
+}
+
+/// Whether the request came from one of `web.trusted_proxies`, headers they forward are only
+/// believed then.
+pub fn from_trusted_proxy(request: &Request) -> bool {
+    match (request.remote(), request.guard::<Config>()) {
+        (Some(remote), Outcome::Success(config)) => config.web.trusted_proxies.contains(&remote.ip()),
+        _ => false,
+    }
+}
+
+/// Address of the client. `X-Real-IP` is only used if the request came from a trusted proxy,
+/// anyone else could make up an address to dodge limits and the audit log.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ClientIp, ()> {
+        let remote = request.remote().map(|a| a.ip());
+        match request.real_ip() {
+            Some(ip) if from_trusted_proxy(request) => Outcome::Success(ClientIp(Some(ip))),
+            _ => Outcome::Success(ClientIp(remote)),
+        }
+    }
+}
+
+/// Aggregate numbers about the server that are fine to show to anyone, only available if
+/// `status.public` is enabled.
+#[get("/status")]
+pub fn public_status(client: ClientIp, limiter: State<StatusLimiter>, db: DB, config: Config) -> APIResult {
+    if !config.status.public {
+        return Err(responses::not_found());
+    }
+    if !limiter.0.check(client.0) {
+        return Err(responses::too_many_requests());
+    }
+    let books = dsl::audiobooks.filter(dsl::deleted.eq(false))
+        .count()
+        .get_result::<i64>(&*db)?;
+    let seconds = dsl::audiobooks.filter(dsl::deleted.eq(false))
+        .select(sum(dsl::length))
+        .first::<Option<f64>>(&*db)?
+        .unwrap_or(0.0);
+    Ok(ok().data(json!({
+        "books": books,
+        "hours": (seconds / 3600.0 * 10.0).round() / 10.0,
+        "version": env!("CARGO_PKG_VERSION"),
+    })))
+}
@@ -12,14 +12,18 @@ extern crate regex;
 extern crate vorleser_server;
 extern crate diesel;
 extern crate sentry;
-extern crate scheduled_thread_pool;
+extern crate signal_hook;
+#[macro_use] extern crate serde_json;
 
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::panic;
 use std::panic::AssertUnwindSafe;
 use std::time::Duration;
 use std::fs::OpenOptions;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 use sentry::integrations::panic::register_panic_handler;
 use sentry::integrations::failure::capture_error;
@@ -29,77 +33,129 @@ use clap::{Arg, App, SubCommand, ArgMatches};
 use regex::Regex;
 use log::error as error_log;
 use simplelog::{SimpleLogger, WriteLogger, CombinedLogger, TermLogger, LevelFilter};
-use scheduled_thread_pool::ScheduledThreadPool;
 
-use vorleser_server::worker::scanner::{Scanner, LockingBehavior};
+use vorleser_server::worker::scheduler::{self, ScanScheduler};
+use vorleser_server::worker::layout;
 use vorleser_server::schema::libraries;
 use vorleser_server::schema::libraries::dsl::*;
+use vorleser_server::models::audiobook::Audiobook;
 use vorleser_server::models::library::Library;
 use vorleser_server::models::user::{User, NewUser};
-use vorleser_server::schema::users;
-use vorleser_server::config::{self, Config, WebConfig, LoggingConfig};
+use vorleser_server::models::playstate_import;
+use vorleser_server::schema::{audiobooks, users};
+use vorleser_server::config::{self, Config, SharedConfig, WebConfig, LoggingConfig};
 use vorleser_server::helpers::db::{Pool, init_db_pool, init_db};
 use vorleser_server::helpers;
+use vorleser_server::helpers::shutdown::Shutdown;
+use vorleser_server::helpers::password::{self, HashParams};
 
 static PATH_REGEX: &'static str = "^[^/]+$";
 
+/// How subcommands report what they did: through the log for humans, or with `--json` as a single
+/// JSON object on stdout, `{"ok": true, "data": ...}` or `{"ok": false, "error": "..."}`. Logs go
+/// to stderr with `--json` so they don't get in the way of parsing.
+#[derive(Clone, Copy)]
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn of(matches: &ArgMatches) -> Self {
+        fn wants_json(matches: &ArgMatches) -> bool {
+            matches.is_present("json") || matches.subcommand().1.map_or(false, wants_json)
+        }
+        Output { json: wants_json(matches) }
+    }
+
+    /// Report success, `message` is shown to humans and `data` to scripts. Returns the exit code.
+    fn success(self, message: &str, data: serde_json::Value) -> i32 {
+        if self.json {
+            println!("{}", json!({"ok": true, "data": data}));
+        } else {
+            info!("{}", message);
+        }
+        0
+    }
+
+    fn failure(self, message: &str) -> i32 {
+        if self.json {
+            println!("{}", json!({"ok": false, "error": message}));
+        } else {
+            error_log!("{}", message);
+        }
+        1
+    }
+}
+
 fn main() {
     let command_parser = build_command_parser();
     let matches = command_parser.get_matches();
+    let output = Output::of(&matches);
 
     if let Some(cmd) = matches.subcommand_matches("sample-config") {
         print!(include_str!("../../vorleser-default.toml"));
         std::process::exit(0);
     }
 
-    let mut conf = load_config(&matches);
-    if let Some(level) = matches.value_of("log-level") {
-        conf.logging.level = level.to_owned();
+    if let Some(cmd) = matches.subcommand_matches("config") {
+        if cmd.subcommand_matches("check").is_some() {
+            std::process::exit(check_config(&matches, output));
+        }
     }
 
+    let mut conf = load_config(&matches);
+
     let sentry_guard = match conf.sentry_dsn {
         Some(ref dsn) => Some(init_sentry(dsn)),
         None => None,
     };
 
-    init_logging(&conf.logging);
+    init_logging(&conf.logging, output);
+    password::configure(HashParams::from(&conf.auth));
 
+    match layout::upgrade(&conf.data_directory) {
+        Ok(version) if version < layout::CURRENT_VERSION =>
+            info!("Upgraded the data directory from layout version {}.", version),
+        Ok(_) => (),
+        Err(e) => {
+            error_log!("Could not upgrade the data directory: {}", e);
+            std::process::exit(1);
+        }
+    }
     init_db(conf.database.clone());
     let pool = init_db_pool(conf.database.clone());
 
     if let Some(new_command) = matches.subcommand_matches("create-library") {
         let conn = &*pool.get().unwrap();
-        create_library(new_command, conn);
-        std::process::exit(0);
+        std::process::exit(create_library(new_command, conn, output));
     };
 
     if let Some(scan_match) = matches.subcommand_matches("scan") {
-        run_scan_command(scan_match, &pool, &conf);
-        std::process::exit(0);
+        std::process::exit(run_scan_command(scan_match, &pool, &conf, output));
+    }
+
+    if let Some(cmd) = matches.subcommand_matches("create-user") {
+        let conn = &*pool.get().unwrap();
+        std::process::exit(create_user(cmd, conn, output));
+    }
+
+    if let Some(cmd) = matches.subcommand_matches("passwd") {
+        let conn = &*pool.get().unwrap();
+        std::process::exit(set_password(cmd, conn, output));
     }
 
-    if let Some(create_user) = matches.subcommand_matches("create-user") {
-        let db = &*pool.get().unwrap();
+    if let Some(cmd) = matches.subcommand_matches("list-books") {
+        let conn = &*pool.get().unwrap();
+        std::process::exit(list_books(cmd, conn, output));
+    }
 
-        let email = create_user.value_of("email").expect("a man has no name");
-        let pass = create_user.value_of("password").expect("a man has no password");
-        let user = User::create(&email, &pass, db).expect("Error saving user");
+    if let Some(cmd) = matches.subcommand_matches("import-playstates") {
+        let conn = &*pool.get().unwrap();
+        std::process::exit(import_playstates(cmd, conn, output));
     }
 
 
     if let Some(serve) = matches.subcommand_matches("serve") {
-        let scan_thread_pool = ScheduledThreadPool::new(1);
-        if conf.scan.enabled {
-            let scan_db_pool = pool.clone();
-            let scan_config = conf.clone();
-            scan_thread_pool.execute_with_fixed_delay(
-                Duration::new(10, 0),
-                Duration::new(conf.scan.interval, 0),
-                move || {
-                    scan_job(scan_db_pool.clone(), scan_config.clone());
-                }
-            );
-        }
         if let Some(port_string) = serve.value_of("port") {
             let port = port_string.parse::<u16>().expect("Invalid value for port.");
             conf = Config {
@@ -110,8 +166,30 @@ fn main() {
                 .. conf
             };
         }
-        match helpers::rocket::factory(pool, conf) {
-            Ok(r) => error_log!("{}", r.launch()),
+        let shared = SharedConfig::new(conf.clone());
+        if conf.scan.enabled {
+            ScanScheduler::start(pool.clone(), shared.clone());
+        }
+        reload_on_sighup(&matches, shared.clone());
+        if let Some(ref tls) = conf.tls {
+            if !cfg!(feature = "tls") {
+                error_log!("TLS is configured but this build can not serve HTTPS, build it with `--features tls`.");
+                std::process::exit(1);
+            }
+            if tls.redirect_port.is_some() {
+                match helpers::rocket::redirect_factory(shared.clone()) {
+                    Ok(r) => { thread::spawn(move || error_log!("{}", r.launch())); },
+                    Err(e) => error_log!("Invalid redirect configuration: {}", e)
+                };
+            }
+        }
+        match helpers::rocket::factory(pool, shared) {
+            Ok(r) => {
+                if let Some(shutdown) = r.state::<Shutdown>() {
+                    shut_down_on_signal(shutdown.clone(), Duration::from_secs(conf.web.shutdown_timeout));
+                }
+                error_log!("{}", r.launch())
+            },
             Err(e) => error_log!("Invalid web-server configuration: {}", e)
         };
     }
@@ -137,12 +215,30 @@ fn build_command_parser<'a, 'b>() -> App<'a, 'b> {
             )
         )
         .subcommand(SubCommand::with_name("scan")
+            .about("Scan all libraries once")
             .arg(Arg::with_name("full")
                  .long("full")
                  .help("Perform a full scan, not an incremental one")
             )
         )
         .subcommand(SubCommand::with_name("create-user")
+            .about("Create a new user")
+            .visible_alias("useradd")
+            .arg(Arg::with_name("email")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("password")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("admin")
+                .long("admin")
+                .help("Allow the user to manage other users via the API.")
+            )
+        )
+        .subcommand(SubCommand::with_name("passwd")
+            .about("Set the password of a user")
             .arg(Arg::with_name("email")
                 .takes_value(true)
                 .required(true)
@@ -154,6 +250,7 @@ fn build_command_parser<'a, 'b>() -> App<'a, 'b> {
         )
         .subcommand(SubCommand::with_name("create-library")
             .about("Create a new Library")
+            .visible_alias("libraryadd")
             .arg(Arg::with_name("path")
                 .takes_value(true)
                 .required(true)
@@ -172,10 +269,40 @@ fn build_command_parser<'a, 'b>() -> App<'a, 'b> {
                 .long("log-level")
                 .value_name("LOG_LEVEL")
                 .takes_value(true)
+        ).arg(Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("Print the result as JSON, for scripts.")
+        )
+        .subcommand(SubCommand::with_name("list-books")
+            .about("List the books of all libraries")
+            .arg(Arg::with_name("library")
+                 .long("library")
+                 .value_name("ID")
+                 .help("Only list the books of this library.")
+                 .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("import-playstates")
+            .about("Import positions from a CSV file of book paths or titles and seconds, e.g. exported by another player")
+            .arg(Arg::with_name("email")
+                .takes_value(true)
+                .required(true)
+            )
+            .arg(Arg::with_name("file")
+                .takes_value(true)
+                .required(true)
+            )
         )
         .subcommand(SubCommand::with_name("sample-config")
             .about("Print the default configuration file to stdout.")
         )
+        .subcommand(SubCommand::with_name("config")
+            .about("Work with the configuration file.")
+            .subcommand(SubCommand::with_name("check")
+                .about("Check the configuration file and the libraries for problems.")
+            )
+        )
         .subcommand(SubCommand::with_name("mlltify")
             .arg(Arg::with_name("file").index(1))
         )
@@ -187,14 +314,23 @@ fn init_sentry(dsn: &str) -> sentry::internals::ClientInitGuard {
     return sentry_guard;
 }
 
-fn load_config(matches: &ArgMatches) -> Config {
-    let config_result = if let Some(config_path) = matches.value_of("config") {
+/// The config chosen on the command line, with the log level overridden if one was given.
+fn read_config(config_path: Option<&str>, log_level: Option<&str>) -> Result<Config, String> {
+    let mut conf = if let Some(config_path) = config_path {
         config::load_config_from_path(&config_path)
     } else {
         config::load_config()
-    };
+    }.map_err(|e| e.to_string())?;
+    if let Some(level) = log_level {
+        conf.logging.level = level.to_owned();
+    }
+    Ok(conf)
+}
+
+fn load_config(matches: &ArgMatches) -> Config {
+    let config_result = read_config(matches.value_of("config"), matches.value_of("log-level"));
 
-    if let Err(e) = config_result {
+    if let Err(ref e) = config_result {
         error_log!("Error loading config: {}", e);
         panic!("Error loading config. Try using --config to supply a valid configuration file.\nYou can get a default config file with the sample-config subcommand.");
     } else {
@@ -203,7 +339,122 @@ fn load_config(matches: &ArgMatches) -> Config {
     config_result.unwrap()
 }
 
-fn create_library(command: &ArgMatches, conn: &SqliteConnection) {
+/// Print the problems `config::check` and the libraries in the database have, returns the exit code.
+fn check_config(matches: &ArgMatches, output: Output) -> i32 {
+    let conf = match read_config(matches.value_of("config"), matches.value_of("log-level")) {
+        Ok(c) => c,
+        Err(e) => {
+            let message = format!("The config could not be loaded: {}", e);
+            if output.json {
+                return output.failure(&message);
+            }
+            eprintln!("{}", message);
+            return 1;
+        }
+    };
+    let mut problems = config::check(&conf);
+    if Path::new(&conf.database).exists() {
+        match SqliteConnection::establish(&conf.database) {
+            Ok(conn) => problems.extend(check_libraries(&conn)),
+            Err(e) => problems.push(format!("database: {:?} can not be opened ({}).", conf.database, e)),
+        }
+    }
+    if output.json {
+        println!("{}", json!({"ok": problems.is_empty(), "data": {"problems": problems}}));
+        return if problems.is_empty() { 0 } else { 1 };
+    }
+    if problems.is_empty() {
+        println!("The config is valid.");
+        0
+    } else {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        1
+    }
+}
+
+fn check_libraries(conn: &SqliteConnection) -> Vec<String> {
+    let all_libraries = match libraries.load::<Library>(conn) {
+        Ok(l) => l,
+        Err(e) => return vec![format!(
+            "database: the libraries can not be loaded ({}), starting the server migrates the database.", e
+        )],
+    };
+    let mut problems = Vec::new();
+    for library in all_libraries {
+        if let Err(e) = Regex::new(&library.is_audiobook_regex) {
+            problems.push(format!(
+                "library {}: the regex {:?} is invalid: {}", library.location, library.is_audiobook_regex, e
+            ));
+        }
+        if !Path::new(&library.location).is_dir() {
+            problems.push(format!("library {}: the location is not a directory.", library.location));
+        }
+    }
+    problems
+}
+
+/// Reload the config whenever the process receives SIGHUP, see `SharedConfig` for what changes.
+fn reload_on_sighup(matches: &ArgMatches, shared: SharedConfig) {
+    let requested = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::SIGHUP, requested.clone()) {
+        warn!("Can not reload the config on SIGHUP: {}", e);
+        return;
+    }
+    let config_path = matches.value_of("config").map(str::to_owned);
+    let log_level = matches.value_of("log-level").map(str::to_owned);
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        if !requested.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        match read_config(config_path.as_ref().map(String::as_str), log_level.as_ref().map(String::as_str)) {
+            Ok(new) => {
+                shared.reload(&new);
+                log::set_max_level(log_level_filter(&new.logging));
+                info!("Reloaded the config.");
+            },
+            Err(e) => error_log!("Not reloading the config, it could not be loaded: {}", e),
+        }
+    });
+}
+
+/// On SIGTERM or SIGINT stop taking requests and exit once the running ones and the scans are
+/// done, see `helpers::shutdown`. Signalling again exits right away.
+fn shut_down_on_signal(shutdown: Shutdown, timeout: Duration) {
+    let requested = Arc::new(AtomicBool::new(false));
+    for signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+        if let Err(e) = signal_hook::flag::register(*signal, requested.clone()) {
+            warn!("Can not shut down gracefully: {}", e);
+            return;
+        }
+    }
+    thread::spawn(move || {
+        while !requested.swap(false, Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(200));
+        }
+        info!("Shutting down, waiting up to {}s for requests and scans to finish.", timeout.as_secs());
+        shutdown.begin();
+        scheduler::stop_scans();
+        let forced = requested.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(200));
+            if forced.load(Ordering::SeqCst) {
+                warn!("Asked to stop again, exiting right away.");
+                std::process::exit(1);
+            }
+        });
+        if shutdown.drain(timeout) {
+            info!("Shut down.");
+            std::process::exit(0);
+        }
+        warn!("Requests or scans did not finish in time, {} requests were still active.", shutdown.active_requests());
+        std::process::exit(1);
+    });
+}
+
+fn create_library(command: &ArgMatches, conn: &SqliteConnection, output: Output) -> i32 {
     let input_path = PathBuf::from(
         command.value_of("path").expect("Please provide a valid utf-8 path.")
     );
@@ -217,68 +468,183 @@ fn create_library(command: &ArgMatches, conn: &SqliteConnection) {
         Ok(_) => {
             match Library::create(path.to_string_lossy().into_owned(), regex.to_owned(), &*conn)
             {
-                Ok(lib) => info!("Successfully created library."),
-                Err(error) => error_log!("Library creation failed: {}", error)
+                Ok(lib) => output.success("Successfully created library.", json!({
+                    "id": lib.id,
+                    "location": lib.location,
+                    "regex": lib.is_audiobook_regex,
+                })),
+                Err(error) => output.failure(&format!("Library creation failed: {}", error))
             }
         },
-        Err(e) => error_log!("Invalid regex: {:?}", e)
+        Err(e) => output.failure(&format!("Invalid regex: {:?}", e))
     }
 }
 
-fn run_scan_command(command: &ArgMatches, pool: &Pool, config: &Config) {
-    run_scan(pool, config, command.is_present("full"));
+fn create_user(command: &ArgMatches, conn: &SqliteConnection, output: Output) -> i32 {
+    let email = command.value_of("email").expect("a man has no name");
+    let password = command.value_of("password").expect("a man has no password");
+    let mut user = match conn.exclusive_transaction(|| User::create(&email, &password, conn)) {
+        Ok(u) => u,
+        Err(e) => return output.failure(&format!("Creating the user failed: {}", e)),
+    };
+    if command.is_present("admin") {
+        if let Err(e) = user.set_admin(true, conn) {
+            return output.failure(&format!("Making {} an admin failed: {}", email, e));
+        }
+    }
+    output.success(&format!("Created the user {}.", email), json!({
+        "id": user.id,
+        "email": user.email,
+        "is_admin": user.is_admin,
+    }))
 }
 
-fn run_scan(pool: &Pool, config: &Config, full_scan: bool) {
-    let conn = &*pool.get().unwrap();
-    let all_libraries = libraries.load::<Library>(conn).unwrap();
-    for l in all_libraries {
-        let mut scanner = Scanner {
-            regex: Regex::new(&l.is_audiobook_regex).expect("Invalid Regex!"),
-            library: l,
-            pool: pool.clone(),
-            config: config.clone()
-        };
-
-        let scan_result = if full_scan {
-            scanner.full_scan(LockingBehavior::Block)
-        } else {
-            scanner.incremental_scan(LockingBehavior::Block)
-        };
+fn set_password(command: &ArgMatches, conn: &SqliteConnection, output: Output) -> i32 {
+    let email = command.value_of("email").expect("a man has no name");
+    let password = command.value_of("password").expect("a man has no password");
+    let user = users::table.filter(users::dsl::email.eq(email)).first::<User>(conn).optional();
+    match user {
+        Ok(Some(mut user)) => match user.set_password(&password, conn) {
+            Ok(()) => output.success(
+                &format!("Changed the password of {}.", email),
+                json!({"id": user.id, "email": user.email})
+            ),
+            Err(e) => output.failure(&format!("Changing the password failed: {}", e)),
+        },
+        Ok(None) => output.failure(&format!("There is no user {}.", email)),
+        Err(e) => output.failure(&format!("Loading the user failed: {}", e)),
+    }
+}
 
-        if let Err(error) = scan_result {
-            capture_error(&error);
-            error_log!("Scan failed with error: {}", error);
-            error_log!("Backtrace: {}", error.backtrace());
-        } else {
-            info!("Scan succeeded!");
+/// Print one line per book: id, library id, length, artist and title, separated by tabs. With
+/// `--json` the books are a list of objects with those keys and the length in seconds.
+fn list_books(command: &ArgMatches, conn: &SqliteConnection, output: Output) -> i32 {
+    let mut query = audiobooks::table
+        .filter(audiobooks::dsl::deleted.eq(false))
+        .order((audiobooks::dsl::library_id.asc(), audiobooks::dsl::sort_title.asc()))
+        .into_boxed();
+    if let Some(library_id) = command.value_of("library") {
+        match helpers::uuid::Uuid::parse_str(library_id) {
+            Ok(library_id) => query = query.filter(audiobooks::dsl::library_id.eq(library_id)),
+            Err(_) => return output.failure(&format!("{} is not a library id.", library_id)),
         }
     }
+    let books = match query.load::<Audiobook>(conn) {
+        Ok(b) => b,
+        Err(e) => return output.failure(&format!("Loading the books failed: {}", e)),
+    };
+    if output.json {
+        let books = books.iter().map(|book| json!({
+            "id": book.id,
+            "library_id": book.library_id,
+            "length": book.length,
+            "artist": book.artist,
+            "title": book.title,
+        })).collect::<Vec<_>>();
+        return output.success("", json!(books));
+    }
+    for book in books {
+        let seconds = book.length as u64;
+        println!(
+            "{}\t{}\t{}:{:02}:{:02}\t{}\t{}",
+            book.id.hyphenated(),
+            book.library_id.hyphenated(),
+            seconds / 3600, seconds / 60 % 60, seconds % 60,
+            book.artist.as_ref().map(String::as_str).unwrap_or(""),
+            book.title
+        );
+    }
+    0
 }
 
-fn scan_job(pool: Pool, config: Config) {
-    let result = panic::catch_unwind(AssertUnwindSafe(|| {
-        run_scan(&pool, &config, false);
-    }));
-    info!("Completed scan, result is: {:?}", result);
+fn import_playstates(command: &ArgMatches, conn: &SqliteConnection, output: Output) -> i32 {
+    let email = command.value_of("email").expect("a man has no name");
+    let file = command.value_of("file").expect("Please provide a valid utf-8 path.");
+    let user = match users::table.filter(users::dsl::email.eq(email)).first::<User>(conn).optional() {
+        Ok(Some(u)) => u,
+        Ok(None) => return output.failure(&format!("There is no user {}.", email)),
+        Err(e) => return output.failure(&format!("Loading the user failed: {}", e)),
+    };
+    let csv = match std::fs::read_to_string(file) {
+        Ok(c) => c,
+        Err(e) => return output.failure(&format!("Reading {} failed: {}", file, e)),
+    };
+    let (rows, unreadable) = playstate_import::parse(&csv);
+    let mut imported = match conn.exclusive_transaction(|| playstate_import::import(&user, &rows, None, conn)) {
+        Ok(i) => i,
+        Err(e) => return output.failure(&format!("Importing failed: {}", e)),
+    };
+    imported.skipped.extend(unreadable);
+    imported.skipped.sort_by_key(|s| s.line);
+    if !output.json {
+        for s in &imported.skipped {
+            println!("line {}: skipped {}: {}", s.line, s.book, s.reason);
+        }
+    }
+    output.success(
+        &format!("Imported {} playstates for {}, skipped {} lines.", imported.imported.len(), email, imported.skipped.len()),
+        json!(imported)
+    )
 }
 
-fn init_logging(config: &LoggingConfig) {
-    let level = match config.level.to_lowercase().as_str() {
-            "error" => LevelFilter::Error,
-            "warn" => LevelFilter::Warn,
-            "debug" => LevelFilter::Debug,
-            "trace" => LevelFilter::Trace,
-            "off" => LevelFilter::Off,
-            _ => LevelFilter::Info,
+fn run_scan_command(command: &ArgMatches, pool: &Pool, config: &Config, output: Output) -> i32 {
+    let all_libraries = match libraries.filter(libraries::deleted_at.is_null()).load::<Library>(&*pool.get().unwrap()) {
+        Ok(l) => l,
+        Err(e) => return output.failure(&format!("Loading the libraries failed: {}", e)),
     };
+    let results = run_scan(pool, config, all_libraries, command.is_present("full"));
+    let failed = results.iter().filter(|r| !r["error"].is_null()).count();
+    if failed == 0 {
+        output.success("All scans succeeded.", json!(results))
+    } else if output.json {
+        // The details of each library matter more to scripts than a summary
+        println!("{}", json!({"ok": false, "error": format!("{} scans failed.", failed), "data": results}));
+        1
+    } else {
+        output.failure(&format!("{} scans failed.", failed))
+    }
+}
+
+/// Scan each of `all_libraries`, returns a `{"library_id", "location", "scan", "error"}` object per
+/// library.
+fn run_scan(pool: &Pool, config: &Config, all_libraries: Vec<Library>, full_scan: bool) -> Vec<serde_json::Value> {
+    let mut results = Vec::new();
+    for l in all_libraries {
+        let (library_id, location) = (l.id, l.location.clone());
+        match scheduler::run_scan(pool, config, l, full_scan) {
+            Ok(scan) => {
+                info!("Scan succeeded!");
+                results.push(json!({"library_id": library_id, "location": location, "scan": scan, "error": null}));
+            },
+            Err(error) => {
+                capture_error(&error);
+                error_log!("Scan failed with error: {}", error);
+                error_log!("Backtrace: {}", error.backtrace());
+                results.push(json!({
+                    "library_id": library_id, "location": location, "scan": null, "error": error.to_string()
+                }));
+            },
+        }
+    }
+    results
+}
+
+fn log_level_filter(config: &LoggingConfig) -> LevelFilter {
+    config::parse_log_level(&config.level).unwrap_or(LevelFilter::Info)
+}
+
+fn init_logging(config: &LoggingConfig, output: Output) {
+    let level = log_level_filter(config);
+    // The loggers let everything through, the global max level filters so it can be reloaded
     let mut loggers: Vec<Box<dyn simplelog::SharedLogger>> = Vec::new();
-    let term_logger = TermLogger::new(level, simplelog::Config::default());
-    if let Some(logger) = term_logger {
+    let term_logger = TermLogger::new(LevelFilter::Trace, simplelog::Config::default());
+    if output.json {
+        loggers.push(WriteLogger::new(LevelFilter::Trace, simplelog::Config::default(), std::io::stderr()));
+    } else if let Some(logger) = term_logger {
         loggers.push(logger)
     } else {
         loggers.push(
-            SimpleLogger::new(level, simplelog::Config::default())
+            SimpleLogger::new(LevelFilter::Trace, simplelog::Config::default())
         );
     }
     if let Some(ref file_path) = config.file {
@@ -289,7 +655,7 @@ fn init_logging(config: &LoggingConfig) {
             .open(file_path)
             .expect("Unable to open log file for writing.");
         loggers.push(
-            WriteLogger::new(level, simplelog::Config::default(), file)
+            WriteLogger::new(LevelFilter::Trace, simplelog::Config::default(), file)
         );
     }
     let combined = CombinedLogger::new(loggers);
@@ -303,4 +669,5 @@ fn init_logging(config: &LoggingConfig) {
             emit_warning_events: false,
         }
     );
+    log::set_max_level(level);
 }
@@ -2,13 +2,19 @@ use std::sync::Arc;
 use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::path::{Path, PathBuf};
 use std::fs::File;
+use std::net::{IpAddr, TcpListener, ToSocketAddrs};
 use std::io;
 use std::io::{Write, Read};
 use toml;
 use rocket::request::{self, FromRequest};
 use simplelog::LevelFilter;
 use rocket::{Request, State, Outcome};
+use serde::{Deserialize, Deserializer};
+use serde::de;
 use failure::Error;
+
+use crate::helpers::password::HashParams;
+
 /// This module holds functions for loading config files.
 
 #[cfg(not(debug_assertions))]
@@ -55,6 +61,165 @@ pub struct Config {
     pub scan: ScanConfig,
     pub sentry_dsn: Option<String>,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub status: StatusConfig,
+    /// Serve HTTPS instead of HTTP, needs the `tls` feature.
+    pub tls: Option<TlsConfig>,
+    /// Share library metadata with other servers, see `worker::bundle`. Off without this section.
+    pub bundles: Option<BundleConfig>,
+    /// Mail password reset tokens to users, see `helpers::mail`. Only admins can reset passwords
+    /// without this section.
+    pub smtp: Option<SmtpConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SmtpConfig {
+    /// Mail server that relays mail from this host without authentication.
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    /// Sender address of the mails.
+    pub from: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BundleConfig {
+    /// Key bundles are signed with, servers exchanging bundles need the same one.
+    pub secret: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain.
+    pub cert_path: String,
+    /// PEM file with the private key, RSA or PKCS#8.
+    pub key_path: String,
+    /// Also listen for plain HTTP on this port and redirect it to HTTPS.
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StatusConfig {
+    /// Offer aggregate numbers about the server without authentication, for status widgets.
+    #[serde(default)] // default to false
+    pub public: bool,
+    /// How often a single client may ask for them.
+    #[serde(default = "default_status_requests_per_minute")]
+    pub requests_per_minute: u32,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        StatusConfig {
+            public: false,
+            requests_per_minute: default_status_requests_per_minute(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RetentionConfig {
+    /// Seconds books that went away are kept around in case they come back, e.g. `"30d"`.
+    /// After that their playstates, bookmarks and chapters are purged. Kept forever if not set.
+    #[serde(default, alias = "keep_deleted_for", deserialize_with = "deserialize_optional_duration")]
+    pub deleted_books: Option<u64>,
+    /// Seconds audit log entries are kept, see `worker::janitor::prune_audit_log`.
+    #[serde(default = "default_audit_log_retention", deserialize_with = "deserialize_duration")]
+    pub audit_log: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            deleted_books: None,
+            audit_log: default_audit_log_retention(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct MetadataConfig {
+    /// Names of extra fields books may have, like `"translator"`, see `models::metadata`.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Look up books at a metadata provider, see `worker::lookup`. Off without this section.
+    pub lookup: Option<LookupConfig>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LookupConfig {
+    /// Base URL of the Audible catalog API, the region decides which books are found.
+    #[serde(default = "default_lookup_url")]
+    pub url: String,
+    /// Match new books while scanning, only if the title and author agree.
+    #[serde(default)] // default to false
+    pub on_scan: bool,
+    /// Seconds to wait for the provider.
+    #[serde(default = "default_lookup_timeout", deserialize_with = "deserialize_duration")]
+    pub timeout: u64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthConfig {
+    /// Seconds an API token stays valid, refreshing a token starts this over.
+    #[serde(default = "default_token_lifetime", deserialize_with = "deserialize_duration")]
+    pub token_lifetime: u64,
+    /// Seconds authenticated tokens are remembered without asking the database, 0 disables this.
+    #[serde(default = "default_auth_cache_ttl", deserialize_with = "deserialize_duration")]
+    pub cache_ttl: u64,
+    /// Requests a single user may make per hour with all of their tokens, unlimited if not set.
+    #[serde(default)]
+    pub requests_per_hour: Option<u32>,
+    /// Failed logins in a row after which further attempts are slowed down.
+    #[serde(default = "default_login_attempts")]
+    pub login_attempts: u32,
+    /// The longest a client has to wait between failed logins.
+    #[serde(default = "default_login_lockout", deserialize_with = "deserialize_duration")]
+    pub login_lockout: u64,
+    /// Seconds a password reset token can be used, see `models::password_reset`.
+    #[serde(default = "default_reset_token_lifetime", deserialize_with = "deserialize_duration")]
+    pub reset_token_lifetime: u64,
+    /// Password resets that may be asked for per hour, counted for each email address and each
+    /// client address.
+    #[serde(default = "default_reset_requests_per_hour")]
+    pub reset_requests_per_hour: u32,
+    /// KiB of memory hashing a password takes, see `helpers::password`.
+    #[serde(default = "default_password_memory")]
+    pub password_memory: u32,
+    #[serde(default = "default_password_iterations")]
+    pub password_iterations: u32,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            token_lifetime: default_token_lifetime(),
+            cache_ttl: default_auth_cache_ttl(),
+            requests_per_hour: None,
+            login_attempts: default_login_attempts(),
+            login_lockout: default_login_lockout(),
+            reset_token_lifetime: default_reset_token_lifetime(),
+            reset_requests_per_hour: default_reset_requests_per_hour(),
+            password_memory: default_password_memory(),
+            password_iterations: default_password_iterations(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.token_lifetime as i64)
+    }
+
+    pub fn reset_token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.reset_token_lifetime as i64)
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -68,8 +233,27 @@ pub struct LoggingConfig {
 pub struct ScanConfig {
     #[serde(default)] // default to false
     pub enabled: bool,
-    #[serde(default= "default_scan_interval")]
+    /// Seconds between two scans of a library, may be given as e.g. `"30m"` in the config file.
+    #[serde(default = "default_scan_interval", deserialize_with = "deserialize_duration")]
     pub interval: u64,
+    /// Also watch libraries for changes and update books right away.
+    #[serde(default)] // default to false
+    pub watch: bool,
+    /// Upper bound for how many MB per second scans read for hashing, unlimited if not set.
+    #[serde(default)]
+    pub max_hash_rate: Option<u64>,
+    /// Seconds to wait after each book a scan had to read, may be given as e.g. `"2s"`.
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub pause_between_books: u64,
+    /// Save the slide shown at the start of each chapter of books with a slide track.
+    #[serde(default)] // default to false
+    pub chapter_images: bool,
+}
+
+impl ScanConfig {
+    pub fn max_hash_bytes_per_sec(&self) -> Option<u64> {
+        self.max_hash_rate.map(|mb| mb * 1024 * 1024)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -79,6 +263,13 @@ pub struct WebConfig {
     pub port: u16,
     #[serde(default)] // default to false
     pub debug: bool,
+    /// Seconds to wait for requests and scans to finish when asked to stop.
+    #[serde(default = "default_shutdown_timeout", deserialize_with = "deserialize_duration")]
+    pub shutdown_timeout: u64,
+    /// Addresses of reverse proxies whose `X-Real-IP`, `X-Forwarded-Proto` and `Host` headers are
+    /// believed, requests from anywhere else are attributed to the address they came from.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 fn default_log_level() -> String {
@@ -89,6 +280,54 @@ fn default_scan_interval() -> u64 {
     600
 }
 
+fn default_status_requests_per_minute() -> u32 {
+    30
+}
+
+fn default_token_lifetime() -> u64 {
+    90 * 24 * 60 * 60
+}
+
+fn default_auth_cache_ttl() -> u64 {
+    30
+}
+
+fn default_login_attempts() -> u32 {
+    5
+}
+
+fn default_login_lockout() -> u64 {
+    15 * 60
+}
+
+fn default_reset_token_lifetime() -> u64 {
+    60 * 60
+}
+
+fn default_reset_requests_per_hour() -> u32 {
+    5
+}
+
+fn default_audit_log_retention() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+fn default_password_memory() -> u32 {
+    HashParams::default().memory
+}
+
+fn default_password_iterations() -> u32 {
+    HashParams::default().iterations
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
 fn default_data_address() -> String {
     "localhost".to_owned()
 }
@@ -97,17 +336,188 @@ fn default_data_directory() -> String {
     "data".to_owned()
 }
 
+fn default_lookup_url() -> String {
+    "https://api.audible.com".to_owned()
+}
+
+fn default_lookup_timeout() -> u64 {
+    10
+}
+
 fn default_log_location() -> Option<String> {
     let mut path = default_data_directory();
     path.push_str("/vorleser.log");
     Some(path)
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(u64),
+    Text(String),
+}
+
+/// Parse a duration like `"90"`, `"45s"`, `"30m"`, `"12h"` or `"7d"` into seconds.
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<u64>()
+        .map_err(|_| format!("Invalid duration: {:?}", value))?;
+    let factor = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        other => return Err(format!("Unknown unit {:?} in duration {:?}", other, value)),
+    };
+    Ok(number * factor)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match DurationValue::deserialize(deserializer)? {
+        DurationValue::Seconds(seconds) => Ok(seconds),
+        DurationValue::Text(text) => parse_duration(&text).map_err(de::Error::custom),
+    }
+}
+
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+pub fn parse_log_level(level: &str) -> Option<LevelFilter> {
+    match level.to_lowercase().as_str() {
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        "off" => Some(LevelFilter::Off),
+        _ => None,
+    }
+}
+
+fn check_directory(problems: &mut Vec<String>, setting: &str, directory: &Path) {
+    if !directory.is_dir() {
+        problems.push(format!(
+            "{}: the directory {:?} does not exist, create it or change the setting.", setting, directory
+        ));
+    }
+}
+
+/// Problems that would keep a server with this config from starting or working, as messages
+/// telling what to change. The configured port is bound briefly, so this fails while the server
+/// is running.
+pub fn check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    check_directory(&mut problems, "data_directory", Path::new(&config.data_directory));
+    if let Some(parent) = Path::new(&config.database).parent() {
+        if parent != Path::new("") {
+            check_directory(&mut problems, "database", parent);
+        }
+    }
+    if parse_log_level(&config.logging.level).is_none() {
+        problems.push(format!(
+            "logging.level: {:?} is not a log level, use one of error, warn, info, debug, trace or off.",
+            config.logging.level
+        ));
+    }
+    if let Some(ref file) = config.logging.file {
+        if let Some(parent) = Path::new(file).parent() {
+            if parent != Path::new("") {
+                check_directory(&mut problems, "logging.file", parent);
+            }
+        }
+    }
+    if let Some(ref tls) = config.tls {
+        if !cfg!(feature = "tls") {
+            problems.push(
+                "tls: this build of vorleser can not serve HTTPS, build it with `--features tls` or remove the section.".to_owned()
+            );
+        }
+        for (setting, file) in &[("tls.cert_path", &tls.cert_path), ("tls.key_path", &tls.key_path)] {
+            if !Path::new(file).is_file() {
+                problems.push(format!("{}: the file {:?} does not exist.", setting, file));
+            }
+        }
+        if tls.redirect_port == Some(config.web.port) {
+            problems.push("tls.redirect_port: must be another port than web.port.".to_owned());
+        }
+    }
+    if config.auth.password_memory < 8 {
+        problems.push("auth.password_memory: must be at least 8 KiB.".to_owned());
+    }
+    if config.auth.password_iterations < 1 {
+        problems.push("auth.password_iterations: must be at least 1.".to_owned());
+    }
+    if let Some(ref smtp) = config.smtp {
+        if !smtp.from.contains('@') {
+            problems.push(format!("smtp.from: {:?} is not an email address.", smtp.from));
+        }
+    }
+    if let Some(ref bundles) = config.bundles {
+        if bundles.secret.len() < 16 {
+            problems.push("bundles.secret: must be at least 16 characters long.".to_owned());
+        }
+    }
+    match (config.web.address.as_str(), config.web.port).to_socket_addrs() {
+        Ok(addresses) => {
+            let addresses: Vec<_> = addresses.collect();
+            if let Err(e) = TcpListener::bind(&addresses[..]) {
+                problems.push(format!(
+                    "web: can not listen on {}:{} ({}), pick another port or stop what is using it.",
+                    config.web.address, config.web.port, e
+                ));
+            }
+        },
+        Err(e) => problems.push(format!(
+            "web.address: {:?} can not be resolved ({}).", config.web.address, e
+        )),
+    }
+    problems
+}
+
+/// The config of a running server. Reloading it only takes over settings that are safe to change
+/// while running: the log level, the scan interval and whether users may register.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Config>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig(Arc::new(RwLock::new(config)))
+    }
+
+    pub fn get(&self) -> Config {
+        self.read().clone()
+    }
+
+    fn read(&self) -> RwLockReadGuard<Config> {
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<Config> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn reload(&self, new: &Config) {
+        let mut config = self.write();
+        config.logging.level = new.logging.level.clone();
+        config.scan.interval = new.scan.interval;
+        config.register_web = new.register_web;
+    }
+}
+
+impl From<Config> for SharedConfig {
+    fn from(config: Config) -> Self {
+        SharedConfig::new(config)
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for Config {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Config, ()> {
-        request.guard::<State<Config>>()
-            .map(|config| config.clone())
+        request.guard::<State<SharedConfig>>()
+            .map(|config| config.get())
     }
 }
@@ -1,15 +1,19 @@
 use rocket::Outcome;
+use chrono::Utc;
 use rocket::http::Status;
 use rocket::request::{self, Request, FromRequest};
+use rocket::State;
 
 use crate::models::user::{self, User, ApiToken};
 use crate::models::library::Library;
 use diesel;
 use diesel::prelude::*;
 use crate::helpers::uuid::Uuid;
+use crate::helpers::auth_cache::AuthCache;
+use crate::helpers::quota::{UserQuota, QuotaUsage};
 use crate::helpers::db::DB;
 use crate::responses::{APIResponse, APIError, bad_request, unauthorized, forbidden, not_found,
-                internal_server_error, service_unavailable};
+                internal_server_error, service_unavailable, too_many_requests};
 
 
 
@@ -17,16 +21,24 @@ impl<'a, 'r> FromRequest<'a, 'r> for User {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<User, ()> {
-        use crate::schema::users::dsl;
-
-        let token_result = <ApiToken as FromRequest>::from_request(request);
-        let db = <DB as FromRequest>::from_request(request).unwrap();
-        println!("{:?}", token_result);
-        match token_result {
-            Outcome::Success(token) => Outcome::Success(
-                dsl::users.filter(dsl::id.eq(token.user_id))
-                    .first::<User>(&*db)
-                    .unwrap()),
+        authenticate(request).map(|(_, user)| user)
+    }
+}
+
+/// Request guard for routes only admins may use.
+/// Fails with 403 if the authenticated user is not an admin.
+pub struct Admin(pub User);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        match <User as FromRequest>::from_request(request) {
+            Outcome::Success(user) => if user.is_admin {
+                Outcome::Success(Admin(user))
+            } else {
+                Outcome::Failure((Status::Forbidden, ()))
+            },
             Outcome::Failure(err) => Outcome::Failure(err),
             Outcome::Forward(()) => Outcome::Forward(())
         }
@@ -37,43 +49,70 @@ impl<'a, 'r> FromRequest<'a, 'r> for ApiToken {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<ApiToken, ()> {
-        let db = <DB as FromRequest>::from_request(request).unwrap();
-        let mut tokens = request.headers().get("Authorization");
-        let token = match tokens.next() {
-            Some(t) => t,
-            None => {
-                match request.uri().query().and_then(|q| {
-                    q.split('&')
-                     .filter(|s| s.starts_with("auth="))
-                     .map(|s| s.split_at(5).1)
-                     .next()
-                }) {
-                    Some(t) => t,
-                    None => return Outcome::Failure((Status::Unauthorized, ()))
-                }
-            }
-        };
-        use crate::schema;
-        use crate::schema::api_tokens::dsl::api_tokens;
-
-        use crate::schema::api_tokens::dsl::id as token_id;
-        use diesel::query_dsl::filter_dsl::FilterDsl;
-        use diesel::RunQueryDsl;
-        use diesel::prelude::*;
-
-        if let Ok(submitted_id) = Uuid::parse_str(token) {
-            let token_option = diesel::query_dsl::filter_dsl::
-                FilterDsl::filter(api_tokens, token_id.eq(&submitted_id))
-                .first::<ApiToken>(&*db)
-                .optional()
-                .expect("Database error!");
-            match token_option {
-                Some(token) => Outcome::Success(token),
-                None => Outcome::Failure((Status::Unauthorized, ()))
-            }
-        } else {
-            Outcome::Failure((Status::BadRequest, ()))
+        authenticate(request).map(|(token, _)| token)
+    }
+}
+
+/// The token from the Authorization header or the `auth` query parameter.
+fn submitted_token<'r>(request: &'r Request) -> Option<&'r str> {
+    match request.headers().get("Authorization").next() {
+        Some(t) => Some(t),
+        None => request.uri().query().and_then(|q| {
+            q.split('&')
+             .filter(|s| s.starts_with("auth="))
+             .map(|s| s.split_at(5).1)
+             .next()
+        })
+    }
+}
+
+fn lookup_token(token: &str, request: &Request) -> Result<(ApiToken, User), Status> {
+    use crate::schema::{api_tokens, users};
+
+    let cache = request.guard::<State<AuthCache>>().succeeded();
+    if let Some((api_token, user)) = cache.as_ref().and_then(|c| c.get(token)) {
+        return Ok((api_token, user));
+    }
+    let submitted_id = Uuid::parse_str(token).map_err(|_| Status::BadRequest)?;
+    let db = request.guard::<DB>().succeeded().ok_or(Status::ServiceUnavailable)?;
+    let api_token = api_tokens::table.filter(api_tokens::dsl::id.eq(&submitted_id))
+        .first::<ApiToken>(&*db)
+        .optional()
+        .expect("Database error!")
+        .ok_or(Status::Unauthorized)?;
+    let user = users::table.filter(users::dsl::id.eq(&api_token.user_id))
+        .first::<User>(&*db)
+        .optional()
+        .expect("Database error!")
+        .ok_or(Status::Unauthorized)?;
+    if let Some(c) = cache {
+        c.insert(token, api_token.clone(), user.clone());
+    }
+    Ok((api_token, user))
+}
+
+/// The token of the request along with its user, looked up once per request.
+fn authenticate(request: &Request) -> request::Outcome<(ApiToken, User), ()> {
+    let result = request.local_cache(|| {
+        let token = submitted_token(request).ok_or(Status::Unauthorized)?;
+        let (api_token, user) = lookup_token(token, request)?;
+        if api_token.is_expired(Utc::now().naive_utc()) {
+            return Err(Status::Unauthorized);
         }
+        Ok((api_token, user))
+    });
+    let authenticated = match result {
+        Ok(authenticated) => authenticated,
+        Err(status) => return Outcome::Failure((*status, ())),
+    };
+    // Counted once per request however many guards authenticate
+    let usage = request.local_cache(|| {
+        let quota = request.guard::<State<UserQuota>>().succeeded();
+        QuotaUsage(quota.and_then(|q| q.0.as_ref().map(|limiter| limiter.check_usage(authenticated.1.id))))
+    });
+    match usage.0 {
+        Some(ref u) if !u.allowed => Outcome::Failure((Status::TooManyRequests, ())),
+        _ => Outcome::Success(authenticated.clone()),
     }
 }
 
@@ -97,6 +136,11 @@ pub (crate) fn not_found_handler() -> APIError {
     not_found()
 }
 
+#[catch(429)]
+pub (crate) fn too_many_requests_handler() -> APIError {
+    too_many_requests().message("Quota exceeded, see the Retry-After header for when to try again.")
+}
+
 #[catch(500)]
 pub (crate) fn internal_server_error_handler() -> APIError {
     internal_server_error()
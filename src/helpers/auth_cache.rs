@@ -0,0 +1,122 @@
+//! Tokens and their users as looked up by recent requests, so authenticating doesn't have to ask
+//! the database every time.
+//!
+//! Entries are keyed by a hash of the submitted token and expire after `auth.cache_ttl` seconds.
+//! Routes that delete tokens or change users have to invalidate them, changes made by other
+//! processes show up once the entries expire.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use ring::digest;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::user::{ApiToken, User};
+
+const MAX_ENTRIES: usize = 1024;
+
+struct Entry {
+    token: ApiToken,
+    user: User,
+    cached_at: Instant,
+}
+
+pub struct AuthCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<Vec<u8>, Entry>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub hit_rate: f64,
+    pub entries: usize,
+}
+
+fn key(token: &str) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token.as_bytes()).as_ref().to_vec()
+}
+
+impl AuthCache {
+    /// A `ttl` of zero disables the cache.
+    pub fn new(ttl: Duration) -> Self {
+        AuthCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self, token: &str) -> Option<(ApiToken, User)> {
+        self.get_at(token, Instant::now())
+    }
+
+    pub fn get_at(&self, token: &str, now: Instant) -> Option<(ApiToken, User)> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&key(token)) {
+            Some(entry) if now.duration_since(entry.cached_at) < self.ttl => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some((entry.token.clone(), entry.user.clone()))
+            },
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, token: &str, api_token: ApiToken, user: User) {
+        self.insert_at(token, api_token, user, Instant::now())
+    }
+
+    pub fn insert_at(&self, token: &str, api_token: ApiToken, user: User, now: Instant) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+        let ttl = self.ttl;
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, e| now.duration_since(e.cached_at) < ttl);
+        }
+        if entries.len() >= MAX_ENTRIES {
+            let oldest = entries.iter()
+                .min_by_key(|(_, e)| e.cached_at)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key(token), Entry {
+            token: api_token,
+            user,
+            cached_at: now,
+        });
+    }
+
+    /// Forget a token, e.g. because it was deleted.
+    pub fn invalidate_token(&self, token_id: &Uuid) {
+        self.entries.lock().unwrap().retain(|_, e| e.token.id != *token_id);
+    }
+
+    /// Forget all tokens of a user, e.g. because the user changed.
+    pub fn invalidate_user(&self, user_id: &Uuid) {
+        self.entries.lock().unwrap().retain(|_, e| e.user.id != *user_id);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 },
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+use chrono::prelude::*;
+use chrono::{Duration, NaiveDateTime};
+
+/// Source of the current time.
+/// Models and the scanner take a `Clock` so tests can pin timestamps to exact values.
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The real UTC wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct FixedClock(Mutex<NaiveDateTime>);
+
+impl FixedClock {
+    pub fn new(time: NaiveDateTime) -> Self {
+        FixedClock(Mutex::new(time))
+    }
+
+    pub fn set(&self, time: NaiveDateTime) {
+        *self.0.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.0.lock().unwrap();
+        *time = *time + duration;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.0.lock().unwrap()
+    }
+}
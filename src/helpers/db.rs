@@ -7,6 +7,8 @@ use diesel::r2d2::{self, ConnectionManager, CustomizeConnection};
 use std::env;
 use diesel::dsl::sql;
 use diesel;
+use crate::responses::APIError;
+use crate::helpers::shutdown::Shutdown;
 
 pub type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
 pub type PooledConnection = r2d2::PooledConnection<ConnectionManager<SqliteConnection>>;
@@ -68,10 +70,27 @@ impl Deref for DB {
     }
 }
 
+impl DB {
+    /// Run the writes of a route in one transaction, which is rolled back if `f` returns an error
+    /// response so no request leaves half of its changes behind.
+    ///
+    /// SQLite can't nest exclusive transactions, so model functions called from `f` must run plain
+    /// statements and leave the transaction to their callers.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, APIError>
+        where F: FnOnce() -> Result<T, APIError> {
+        self.0.exclusive_transaction(f)
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for DB {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<DB, ()> {
+        if let Some(shutdown) = request.guard::<State<Shutdown>>().succeeded() {
+            if shutdown.has_begun() {
+                return Outcome::Failure((Status::ServiceUnavailable, ()));
+            }
+        }
         let pool = match <State<Pool> as FromRequest>::from_request(request) {
             Outcome::Success(pool) => pool,
             Outcome::Failure(e) => return Outcome::Failure(e),
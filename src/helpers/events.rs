@@ -0,0 +1,84 @@
+//! Telling other parts of the server what happened without them calling each other.
+//!
+//! Whoever wants to know about events calls `subscribe` once and reads its receiver, usually on a
+//! thread of its own. `publish` hands every event to all subscribers and never blocks, a
+//! subscriber that falls `QUEUE_SIZE` events behind misses the ones after until it catches up.
+//! Receivers that were dropped are forgotten on the next event. Events are published after what
+//! they describe is committed.
+
+use std::sync::Mutex;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+use crate::helpers::uuid::Uuid;
+
+/// How many events a subscriber may have waiting.
+pub const QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A scan found a book the library did not have yet.
+    BookAdded {
+        library_id: Uuid,
+        audiobook_id: Uuid,
+    },
+    ScanFinished {
+        library_id: Uuid,
+        scan_id: Uuid,
+        /// The error the scan stopped with, if any.
+        error: Option<String>,
+    },
+    PlaystateUpdated {
+        user_id: Uuid,
+        audiobook_id: Uuid,
+        position: f64,
+    },
+    /// A client requested the start of a book's audio.
+    StreamStarted {
+        user_id: Uuid,
+        audiobook_id: Uuid,
+    },
+}
+
+/// Subscribers to events, `subscribe` and `publish` use one for the whole process.
+pub struct Bus {
+    subscribers: Mutex<Vec<SyncSender<Event>>>,
+    queue_size: usize,
+}
+
+impl Bus {
+    pub fn new(queue_size: usize) -> Self {
+        Bus { subscribers: Mutex::new(Vec::new()), queue_size }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = sync_channel(self.queue_size);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        debug!("Publishing {:?}", event);
+        self.subscribers.lock().unwrap().retain(|s| match s.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("Dropping {:?} for a subscriber that fell behind", event);
+                true
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+lazy_static! {
+    static ref BUS: Bus = Bus::new(QUEUE_SIZE);
+}
+
+/// A receiver for all events published from now on.
+pub fn subscribe() -> Receiver<Event> {
+    BUS.subscribe()
+}
+
+pub fn publish(event: Event) {
+    BUS.publish(event)
+}
@@ -0,0 +1,95 @@
+//! Rendering of RSS 2.0 feeds so audiobooks can be listened to in podcast clients.
+//!
+//! Chapters are included as Podlove Simple Chapters, which most podcast clients understand.
+
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+
+/// A book as it appears in a feed.
+pub struct FeedItem<'a> {
+    pub book: &'a Audiobook,
+    pub chapters: &'a [Chapter],
+    pub enclosure_url: String,
+    pub enclosure_length: u64,
+    pub enclosure_type: String,
+}
+
+pub struct Feed<'a> {
+    pub title: String,
+    pub link: String,
+    pub items: Vec<FeedItem<'a>>,
+}
+
+/// Escape text for use in XML content and attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format seconds as normal play time (`HH:MM:SS.mmm`), as used by Podlove Simple Chapters.
+pub fn normal_play_time(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn render_item(item: &FeedItem, out: &mut String) {
+    let book = item.book;
+    out.push_str("<item>\n");
+    out.push_str(&format!("<title>{}</title>\n", escape(&book.title)));
+    if let Some(ref artist) = book.artist {
+        out.push_str(&format!("<itunes:author>{}</itunes:author>\n", escape(artist)));
+    }
+    out.push_str(&format!("<guid isPermaLink=\"false\">{}</guid>\n", book.id.hyphenated()));
+    out.push_str(&format!(
+        "<enclosure url=\"{}\" length=\"{}\" type=\"{}\"/>\n",
+        escape(&item.enclosure_url), item.enclosure_length, escape(&item.enclosure_type)
+    ));
+    out.push_str(&format!("<itunes:duration>{}</itunes:duration>\n", book.length.round() as u64));
+    if !item.chapters.is_empty() {
+        out.push_str("<psc:chapters version=\"1.2\">\n");
+        for chapter in item.chapters {
+            let title = match chapter.title {
+                Some(ref t) => t.clone(),
+                None => format!("Chapter {}", chapter.number + 1),
+            };
+            out.push_str(&format!(
+                "<psc:chapter start=\"{}\" title=\"{}\"/>\n",
+                normal_play_time(chapter.start_time), escape(&title)
+            ));
+        }
+        out.push_str("</psc:chapters>\n");
+    }
+    out.push_str("</item>\n");
+}
+
+pub fn render(feed: &Feed) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<rss version=\"2.0\" xmlns:itunes=\"http://www.itunes.com/dtds/podcast-1.0.dtd\" \
+                  xmlns:psc=\"http://podlove.org/simple-chapters\">\n");
+    out.push_str("<channel>\n");
+    out.push_str(&format!("<title>{}</title>\n", escape(&feed.title)));
+    out.push_str(&format!("<link>{}</link>\n", escape(&feed.link)));
+    out.push_str(&format!("<description>{}</description>\n", escape(&feed.title)));
+    for item in &feed.items {
+        render_item(item, &mut out);
+    }
+    out.push_str("</channel>\n</rss>\n");
+    out
+}
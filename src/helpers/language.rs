@@ -0,0 +1,72 @@
+//! Guessing the language a book is read in.
+//!
+//! A language tag wins if there is one, names and ISO 639-2 codes like `"ger"` are turned into
+//! the two letter tags clients know. Otherwise the common words of the title and description
+//! decide between German and English, most libraries mix just these two. Books that give too
+//! little to go by get no language rather than a wrong one.
+
+use crate::validation::audiobook::is_language_tag;
+
+const TAG_NAMES: &[&str] = &["language", "lang", "tlan"];
+
+/// Three letter codes and names of languages by their two letter tag.
+const NAMES: &[(&str, &[&str])] = &[
+    ("de", &["ger", "deu", "german", "deutsch"]),
+    ("en", &["eng", "english", "englisch"]),
+    ("fr", &["fra", "fre", "french", "français", "francais"]),
+    ("es", &["spa", "spanish", "español", "espanol"]),
+    ("it", &["ita", "italian", "italiano"]),
+    ("nl", &["nld", "dut", "dutch", "nederlands"]),
+    ("sv", &["swe", "swedish", "svenska"]),
+    ("pl", &["pol", "polish", "polski"]),
+    ("ru", &["rus", "russian"]),
+    ("ja", &["jpn", "japanese"]),
+];
+
+const GERMAN_WORDS: &[&str] = &[
+    "der", "die", "das", "und", "ein", "eine", "einer", "des", "dem", "den", "ist", "nicht", "mit",
+    "von", "zu", "auf", "für", "im", "sich", "auch", "wie", "aus", "über", "wird", "sie", "er",
+];
+const ENGLISH_WORDS: &[&str] = &[
+    "the", "and", "of", "a", "an", "to", "in", "is", "with", "for", "from", "on", "his", "her",
+    "that", "this", "was", "who", "by", "at", "it", "he", "she", "be", "are", "their",
+];
+
+/// The language tag a language tag value stands for, `None` for unknown or undetermined ones.
+pub fn normalize_tag(value: &str) -> Option<String> {
+    let value = value.trim().to_lowercase();
+    if let Some((tag, _)) = NAMES.iter().find(|(tag, names)| *tag == value || names.contains(&value.as_str())) {
+        return Some((*tag).to_owned());
+    }
+    // Other three letter codes include `und` for undetermined and `mul` for multiple languages
+    if value.len() == 3 {
+        return None;
+    }
+    Some(value.replace('_', "-")).filter(|v| is_language_tag(v))
+}
+
+/// German or English, whichever has clearly more of its common words in `text`.
+pub fn guess(text: &str) -> Option<String> {
+    let words = text.split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>();
+    let count = |common: &[&str]| words.iter().filter(|w| common.contains(&w.as_str())).count();
+    let (german, english) = (count(GERMAN_WORDS), count(ENGLISH_WORDS));
+    if german >= 2 && german >= english * 2 {
+        Some("de".to_owned())
+    } else if english >= 2 && english >= german * 2 {
+        Some("en".to_owned())
+    } else {
+        None
+    }
+}
+
+/// The language of a book with these tags, title and description.
+pub fn detect<'a, I: IntoIterator<Item = (&'a String, &'a String)>>(tags: I, title: &str, description: Option<&str>)
+    -> Option<String> {
+    let tagged = tags.into_iter()
+        .find(|(k, _)| TAG_NAMES.contains(&k.to_lowercase().as_str()))
+        .and_then(|(_, v)| normalize_tag(v));
+    tagged.or_else(|| guess(&format!("{} {}", title, description.unwrap_or(""))))
+}
@@ -0,0 +1,136 @@
+//! Slows down password guessing on login.
+//!
+//! Failed logins are counted per email address and per client address. After `auth.login_attempts`
+//! failures in a row, each further attempt has to wait twice as long as the one before, starting at
+//! a second and up to `auth.login_lockout`. A successful login clears the count of its email
+//! address, failures are forgotten after a day without any.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+/// Keys whose failures are remembered at most, the ones that failed longest ago are forgotten
+/// first. Keeps guessing at lots of different addresses from eating up memory.
+pub const MAX_TRACKED: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LoginKey {
+    Email(String),
+    Ip(IpAddr),
+}
+
+impl LoginKey {
+    /// The keys a login attempt counts against, email addresses ignore case.
+    pub fn of(email: &str, ip: Option<IpAddr>) -> Vec<LoginKey> {
+        let mut keys = vec![LoginKey::Email(email.to_lowercase())];
+        keys.extend(ip.map(LoginKey::Ip));
+        keys
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+pub struct LoginThrottle {
+    free_attempts: u32,
+    max_lockout: Duration,
+    max_tracked: usize,
+    failures: Mutex<HashMap<LoginKey, Failures>>,
+}
+
+fn forget_expired(failures: &mut HashMap<LoginKey, Failures>, now: Instant) {
+    failures.retain(|_, f| now.duration_since(f.last) < FORGET_AFTER);
+}
+
+impl LoginThrottle {
+    pub fn new(config: &Config) -> Self {
+        Self::with_limits(config.auth.login_attempts, Duration::from_secs(config.auth.login_lockout))
+    }
+
+    pub fn with_limits(free_attempts: u32, max_lockout: Duration) -> Self {
+        LoginThrottle {
+            free_attempts,
+            max_lockout,
+            max_tracked: MAX_TRACKED,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember the failures of at most `max_tracked` keys instead of `MAX_TRACKED`.
+    pub fn max_tracked(mut self, max_tracked: usize) -> Self {
+        self.max_tracked = max_tracked;
+        self
+    }
+
+    /// Number of keys with failures that are remembered.
+    pub fn tracked(&self) -> usize {
+        self.failures.lock().unwrap().len()
+    }
+
+    fn lockout(&self, count: u32) -> Duration {
+        if count < self.free_attempts {
+            return Duration::from_secs(0);
+        }
+        let doublings = (count - self.free_attempts).min(31);
+        Duration::from_secs(1u64 << doublings).min(self.max_lockout)
+    }
+
+    /// How long the slowest of `keys` still has to wait before trying again, if at all.
+    pub fn wait_at(&self, keys: &[LoginKey], now: Instant) -> Option<Duration> {
+        let mut failures = self.failures.lock().unwrap();
+        forget_expired(&mut failures, now);
+        keys.iter()
+            .filter_map(|k| failures.get(k))
+            .map(|f| self.lockout(f.count).checked_sub(now.duration_since(f.last)).unwrap_or_default())
+            .filter(|wait| *wait > Duration::from_secs(0))
+            .max()
+    }
+
+    pub fn wait(&self, keys: &[LoginKey]) -> Option<Duration> {
+        self.wait_at(keys, Instant::now())
+    }
+
+    /// Count a failed attempt against `keys`, returns the keys that are locked out because of it.
+    pub fn failed_at(&self, keys: &[LoginKey], now: Instant) -> Vec<LoginKey> {
+        let mut failures = self.failures.lock().unwrap();
+        forget_expired(&mut failures, now);
+        let locked: Vec<LoginKey> = keys.iter()
+            .filter(|k| {
+                let entry = failures.entry((*k).clone()).or_insert(Failures { count: 0, last: now });
+                entry.count += 1;
+                entry.last = now;
+                entry.count >= self.free_attempts
+            })
+            .cloned()
+            .collect();
+        if failures.len() > self.max_tracked {
+            let mut by_age = failures.iter().map(|(k, f)| (f.last, k.clone())).collect::<Vec<_>>();
+            by_age.sort_by_key(|(last, _)| *last);
+            // Some room to spare so this doesn't happen on every further failure
+            let excess = failures.len() - (self.max_tracked - self.max_tracked / 10);
+            for (_, key) in by_age.into_iter().filter(|(_, k)| !keys.contains(k)).take(excess) {
+                failures.remove(&key);
+            }
+        }
+        locked
+    }
+
+    pub fn failed(&self, keys: &[LoginKey]) -> Vec<LoginKey> {
+        self.failed_at(keys, Instant::now())
+    }
+
+    /// Forget the failures of the email addresses in `keys`. Client addresses are left alone so
+    /// that logging into one account doesn't allow guessing at others.
+    pub fn succeeded(&self, keys: &[LoginKey]) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys.iter().filter(|k| match k { LoginKey::Email(_) => true, _ => false }) {
+            failures.remove(key);
+        }
+    }
+}
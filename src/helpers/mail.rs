@@ -0,0 +1,84 @@
+//! Sending plain text mails through the mail server in `[smtp]`.
+//!
+//! This speaks just enough SMTP to hand a mail to a relay on the local network, like the mail
+//! server of the host. There is no TLS or authentication, so the relay has to accept mail from
+//! this host as it is.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::config::SmtpConfig;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    /// Read a reply, which may span several lines, and fail unless its code is `expected`.
+    fn expect(&mut self, expected: u16) -> io::Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The mail server closed the connection."));
+            }
+            let code = line.get(..3).and_then(|c| c.parse::<u16>().ok());
+            if code != Some(expected) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other, format!("The mail server answered: {}", line.trim_end())
+                ));
+            }
+            // Every line but the last has a dash after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, expected: u16) -> io::Result<()> {
+        write!(self.writer, "{}\r\n", command)?;
+        self.expect(expected)
+    }
+}
+
+/// The mail as it goes after `DATA`: headers, then the body with lines starting with a dot
+/// escaped and line breaks as CRLF, then the terminating dot.
+pub fn format_message(from: &str, to: &str, subject: &str, body: &str) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from, to, subject, Utc::now().to_rfc2822()
+    );
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    message
+}
+
+pub fn send(config: &SmtpConfig, to: &str, subject: &str, body: &str) -> io::Result<()> {
+    if [to, subject].iter().any(|v| v.contains('\r') || v.contains('\n')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Mail headers can't contain line breaks."));
+    }
+    let stream = TcpStream::connect((config.host.as_str(), config.port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut connection = Connection { reader: BufReader::new(stream.try_clone()?), writer: stream };
+    connection.expect(220)?;
+    connection.command("EHLO vorleser", 250)?;
+    connection.command(&format!("MAIL FROM:<{}>", config.from), 250)?;
+    connection.command(&format!("RCPT TO:<{}>", to), 250)?;
+    connection.command("DATA", 354)?;
+    connection.writer.write_all(format_message(&config.from, to, subject, body).as_bytes())?;
+    connection.expect(250)?;
+    connection.command("QUIT", 221)
+}
@@ -1,7 +1,24 @@
 pub mod db;
 pub mod rocket;
 pub mod uuid;
+pub mod clock;
 pub mod mllt;
 pub mod json_result;
+pub mod ogg;
+pub mod feed;
+pub mod sorting;
+pub mod zip;
+pub mod rate_limit;
+pub mod auth_cache;
+pub mod quota;
+pub mod login_throttle;
+pub mod pagination;
+pub mod language;
+pub mod shutdown;
+pub mod events;
+pub mod mail;
+pub mod password;
+#[cfg(test)]
+pub mod tests;
 
 pub use self::json_result::JsonResult;
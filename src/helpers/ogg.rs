@@ -0,0 +1,258 @@
+//! Validation of ogg/opus streams.
+//!
+//! Checks the things an encoder regression would most likely break: the page CRCs, the page
+//! sequence, continuity of granule positions and the structure of the opus header packets.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use failure::Error;
+
+const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+const HEADER_SIZE: usize = 27;
+const OPUS_SAMPLE_RATE: f64 = 48000.0;
+
+const FLAG_CONTINUED: u8 = 0x01;
+const FLAG_BEGINNING_OF_STREAM: u8 = 0x02;
+const FLAG_END_OF_STREAM: u8 = 0x04;
+
+lazy_static! {
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for i in 0..256 {
+            let mut r = (i as u32) << 24;
+            for _ in 0..8 {
+                r = if r & 0x8000_0000 != 0 {
+                    (r << 1) ^ 0x04c1_1db7
+                } else {
+                    r << 1
+                };
+            }
+            table[i] = r;
+        }
+        table
+    };
+}
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum OggError {
+    #[fail(display = "Missing capture pattern in page {}", page)]
+    MissingCapturePattern { page: u32 },
+    #[fail(display = "Unsupported ogg version {} in page {}", version, page)]
+    UnsupportedVersion { page: u32, version: u8 },
+    #[fail(display = "Stream ends in the middle of page {}", page)]
+    Truncated { page: u32 },
+    #[fail(display = "Bad checksum in page {}: expected {:08x}, found {:08x}", page, expected, found)]
+    CrcMismatch { page: u32, expected: u32, found: u32 },
+    #[fail(display = "Expected page sequence number {}, found {}", expected, found)]
+    SequenceGap { expected: u32, found: u32 },
+    #[fail(display = "Granule position decreased in page {}", page)]
+    GranuleDecreased { page: u32 },
+    #[fail(display = "Page {} belongs to a different logical stream", page)]
+    SerialChanged { page: u32 },
+    #[fail(display = "The first page is not marked as the beginning of the stream")]
+    MissingBeginningOfStream,
+    #[fail(display = "The last page is not marked as the end of the stream")]
+    MissingEndOfStream,
+    #[fail(display = "The first packet is not a valid OpusHead")]
+    InvalidOpusHead,
+    #[fail(display = "The second packet is not a valid OpusTags")]
+    InvalidOpusTags,
+    #[fail(display = "The stream contains no pages")]
+    Empty,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageHeader {
+    pub header_type: u8,
+    pub granule_position: i64,
+    pub serial: u32,
+    pub sequence: u32,
+    pub checksum: u32,
+    pub segments: Vec<u8>,
+}
+
+impl PageHeader {
+    fn body_len(&self) -> usize {
+        self.segments.iter().map(|&s| s as usize).sum()
+    }
+}
+
+/// Summary of a stream that passed validation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OggSummary {
+    pub serial: u32,
+    pub pages: u32,
+    pub channels: u8,
+    pub pre_skip: u16,
+    pub last_granule_position: i64,
+}
+
+impl OggSummary {
+    /// Duration of the stream in seconds, as described by the final granule position.
+    pub fn duration(&self) -> f64 {
+        (self.last_granule_position - i64::from(self.pre_skip)).max(0) as f64 / OPUS_SAMPLE_RATE
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    update_crc(0, data)
+}
+
+fn update_crc(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[(((crc >> 24) as u8) ^ byte) as usize]
+    })
+}
+
+/// Read a single page, returns `None` at the end of the stream.
+fn read_page(reader: &mut dyn Read, page: u32) -> Result<Option<(PageHeader, Vec<u8>)>, Error> {
+    let mut header = [0u8; HEADER_SIZE];
+    match read_exact_or_eof(reader, &mut header)? {
+        0 => return Ok(None),
+        HEADER_SIZE => (),
+        _ => return Err(OggError::Truncated { page }.into()),
+    }
+    if &header[0..4] != CAPTURE_PATTERN {
+        return Err(OggError::MissingCapturePattern { page }.into());
+    }
+    if header[4] != 0 {
+        return Err(OggError::UnsupportedVersion { page, version: header[4] }.into());
+    }
+
+    let mut segments = vec![0u8; header[26] as usize];
+    if read_exact_or_eof(reader, &mut segments)? != segments.len() {
+        return Err(OggError::Truncated { page }.into());
+    }
+
+    let page_header = PageHeader {
+        header_type: header[5],
+        granule_position: le_i64(&header[6..14]),
+        serial: le_u32(&header[14..18]),
+        sequence: le_u32(&header[18..22]),
+        checksum: le_u32(&header[22..26]),
+        segments,
+    };
+
+    let mut body = vec![0u8; page_header.body_len()];
+    if read_exact_or_eof(reader, &mut body)? != body.len() {
+        return Err(OggError::Truncated { page }.into());
+    }
+
+    // The checksum is calculated over the whole page with the checksum field set to zero.
+    let mut crc = update_crc(0, &header[0..22]);
+    crc = update_crc(crc, &[0, 0, 0, 0]);
+    crc = update_crc(crc, &header[26..27]);
+    crc = update_crc(crc, &page_header.segments);
+    crc = update_crc(crc, &body);
+    if crc != page_header.checksum {
+        return Err(OggError::CrcMismatch { page, expected: crc, found: page_header.checksum }.into());
+    }
+
+    Ok(Some((page_header, body)))
+}
+
+/// Like `read_exact` but returns the number of bytes read instead of failing at the end of the
+/// stream.
+fn read_exact_or_eof(reader: &mut dyn Read, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | u32::from(b))
+}
+
+fn le_i64(bytes: &[u8]) -> i64 {
+    bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | u64::from(b)) as i64
+}
+
+/// Validate an ogg/opus stream, returning a summary if it is intact.
+pub fn validate(reader: &mut dyn Read) -> Result<OggSummary, Error> {
+    let mut page_number = 0;
+    let mut serial = None;
+    let mut last_granule_position = 0;
+    let mut last_header_type = 0;
+    // The first two packets, OpusHead and OpusTags, are needed to check the header structure.
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut current_packet: Vec<u8> = Vec::new();
+
+    while let Some((header, body)) = read_page(reader, page_number)? {
+        if page_number == 0 && header.header_type & FLAG_BEGINNING_OF_STREAM == 0 {
+            return Err(OggError::MissingBeginningOfStream.into());
+        }
+        match serial {
+            None => serial = Some(header.serial),
+            Some(s) if s != header.serial => return Err(OggError::SerialChanged { page: page_number }.into()),
+            _ => (),
+        }
+        if header.sequence != page_number {
+            return Err(OggError::SequenceGap { expected: page_number, found: header.sequence }.into());
+        }
+        // A granule position of -1 means no packet finishes on this page.
+        if header.granule_position != -1 {
+            if header.granule_position < last_granule_position {
+                return Err(OggError::GranuleDecreased { page: page_number }.into());
+            }
+            last_granule_position = header.granule_position;
+        }
+
+        if packets.len() < 2 {
+            if header.header_type & FLAG_CONTINUED == 0 {
+                current_packet.clear();
+            }
+            let mut offset = 0;
+            for &segment in &header.segments {
+                current_packet.extend_from_slice(&body[offset..offset + segment as usize]);
+                offset += segment as usize;
+                if segment < 255 {
+                    packets.push(current_packet.split_off(0));
+                }
+            }
+        }
+
+        last_header_type = header.header_type;
+        page_number += 1;
+    }
+
+    if page_number == 0 {
+        return Err(OggError::Empty.into());
+    }
+    if last_header_type & FLAG_END_OF_STREAM == 0 {
+        return Err(OggError::MissingEndOfStream.into());
+    }
+
+    let (channels, pre_skip) = match packets.get(0) {
+        Some(head) if is_opus_head(head) => (head[9], head[10] as u16 | (head[11] as u16) << 8),
+        _ => return Err(OggError::InvalidOpusHead.into()),
+    };
+    match packets.get(1) {
+        Some(tags) if tags.starts_with(b"OpusTags") => (),
+        _ => return Err(OggError::InvalidOpusTags.into()),
+    }
+
+    Ok(OggSummary {
+        serial: serial.unwrap_or(0),
+        pages: page_number,
+        channels,
+        pre_skip,
+        last_granule_position,
+    })
+}
+
+fn is_opus_head(packet: &[u8]) -> bool {
+    // magic, version 1, at least one channel
+    packet.len() >= 19 && packet.starts_with(b"OpusHead") && packet[8] & 0xf0 == 0 && packet[9] > 0
+}
+
+pub fn validate_file(path: &dyn AsRef<Path>) -> Result<OggSummary, Error> {
+    let mut reader = BufReader::new(File::open(path.as_ref())?);
+    validate(&mut reader)
+}
@@ -0,0 +1,56 @@
+use serde::Serialize;
+
+/// The envelope lists are returned in: a slice of the list and what is needed to get the rest.
+///
+/// Clients get the following slice by passing `next_offset` as `?offset=`, along with the same
+/// `?limit=`. Without a limit the whole list is returned.
+#[derive(Debug, Serialize)]
+pub struct Page<T: Serialize> {
+    pub items: Vec<T>,
+    pub page: PageInfo,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PageInfo {
+    /// Length of the whole list.
+    pub total: i64,
+    pub limit: Option<i64>,
+    pub offset: i64,
+    /// Where the next slice starts, `null` on the last one.
+    pub next_offset: Option<i64>,
+    /// Where the previous slice starts, `null` on the first one.
+    pub prev_offset: Option<i64>,
+}
+
+impl<T: Serialize> Page<T> {
+    /// `items` are the slice starting at `offset` of a list of `total` items.
+    pub fn new(items: Vec<T>, total: i64, limit: Option<i64>, offset: Option<i64>) -> Self {
+        let offset = offset.unwrap_or(0);
+        let end = offset + items.len() as i64;
+        Page {
+            items,
+            page: PageInfo {
+                total,
+                limit,
+                offset,
+                next_offset: if end < total { Some(end) } else { None },
+                prev_offset: if offset > 0 {
+                    Some((offset - limit.unwrap_or(offset)).max(0))
+                } else {
+                    None
+                },
+            },
+        }
+    }
+
+    /// The requested slice of a list that was loaded as a whole.
+    pub fn slice(all: Vec<T>, limit: Option<i64>, offset: Option<i64>) -> Self {
+        let total = all.len() as i64;
+        let start = offset.unwrap_or(0).min(total).max(0) as usize;
+        let items = all.into_iter()
+            .skip(start)
+            .take(limit.map(|l| l.max(0) as usize).unwrap_or(usize::max_value()))
+            .collect();
+        Page::new(items, total, limit, offset)
+    }
+}
@@ -0,0 +1,97 @@
+//! Hashing passwords.
+//!
+//! Passwords are hashed with Argon2id, stored as PHC strings like `$argon2id$v=19$m=19456,t=2,p=1$…`
+//! that carry the algorithm and its parameters. Hashes made before that are base64 encoded
+//! Argon2i hashes of `argon2rs`, they never start with `$`. Both can be verified, and a login
+//! with a hash of the old kind or with other parameters than `auth.password_memory` and
+//! `auth.password_iterations` replaces it with a new hash.
+
+use std::sync::RwLock;
+
+use argon2::{self, ThreadMode, Variant, Version};
+use argon2rs::verifier;
+use base64;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::AuthConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashParams {
+    /// Memory in KiB.
+    pub memory: u32,
+    pub iterations: u32,
+}
+
+impl Default for HashParams {
+    /// What OWASP recommends for Argon2id.
+    fn default() -> Self {
+        HashParams { memory: 19 * 1024, iterations: 2 }
+    }
+}
+
+impl<'a> From<&'a AuthConfig> for HashParams {
+    fn from(config: &AuthConfig) -> Self {
+        HashParams { memory: config.password_memory, iterations: config.password_iterations }
+    }
+}
+
+lazy_static! {
+    static ref PARAMS: RwLock<HashParams> = RwLock::new(HashParams::default());
+}
+
+/// Use `params` for new hashes, called once the config is loaded.
+pub fn configure(params: HashParams) {
+    *PARAMS.write().unwrap() = params;
+}
+
+const SALT_LENGTH: usize = 16;
+
+pub fn hash(password: &str) -> String {
+    let params = *PARAMS.read().unwrap();
+    let mut salt = [0u8; SALT_LENGTH];
+    SystemRandom::new().fill(&mut salt[..]).expect("Could not generate a salt.");
+    let config = argon2::Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: params.memory,
+        time_cost: params.iterations,
+        lanes: 1,
+        thread_mode: ThreadMode::Sequential,
+        secret: &[],
+        ad: &[],
+        hash_length: 32,
+    };
+    argon2::hash_encoded(password.as_bytes(), &salt, &config).expect("Invalid password hashing parameters.")
+}
+
+pub fn verify(hash: &str, password: &str) -> bool {
+    if hash.starts_with('$') {
+        return argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false);
+    }
+    base64::decode(hash).ok()
+        .and_then(|data| verifier::Encoded::from_u8(&data).ok())
+        .map(|encoded| encoded.verify(password.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// The parameters of an Argon2id hash, `None` for other hashes.
+pub fn params_of(hash: &str) -> Option<HashParams> {
+    let parts = hash.split('$').collect::<Vec<&str>>();
+    if parts.len() != 6 || parts[1] != "argon2id" || parts[2] != "v=19" {
+        return None;
+    }
+    let (mut memory, mut iterations) = (None, None);
+    for param in parts[3].split(',') {
+        match param.split_at(param.find('=')? + 1) {
+            ("m=", value) => memory = value.parse().ok(),
+            ("t=", value) => iterations = value.parse().ok(),
+            _ => (),
+        }
+    }
+    Some(HashParams { memory: memory?, iterations: iterations? })
+}
+
+/// Whether `hash` should be replaced by a hash with the configured parameters.
+pub fn needs_rehash(hash: &str) -> bool {
+    params_of(hash) != Some(*PARAMS.read().unwrap())
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+
+use crate::config::Config;
+use crate::helpers::rate_limit::{RateLimiter, Usage};
+use crate::helpers::uuid::Uuid;
+
+/// Requests per user and hour, enforced when authenticating if `auth.requests_per_hour` is set.
+/// All tokens of a user count against the same quota, so logging in again doesn't start over.
+pub struct UserQuota(pub Option<RateLimiter<Uuid>>);
+
+impl UserQuota {
+    pub fn new(config: &Config) -> Self {
+        UserQuota(config.auth.requests_per_hour.map(|max| {
+            RateLimiter::new(max, Duration::from_secs(60 * 60))
+        }))
+    }
+}
+
+/// The quota usage of the user that authenticated the current request, if any.
+pub struct QuotaUsage(pub Option<Usage>);
+
+/// Tells clients about their quota in `RateLimit-*` headers, and when to come back once it is
+/// used up in `Retry-After`.
+pub struct QuotaHeaders();
+
+impl Fairing for QuotaHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add quota headers to responses",
+            kind: Kind::Response
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some(usage) = request.local_cache(|| QuotaUsage(None)).0 {
+            let reset = usage.reset.as_secs().to_string();
+            response.set_header(Header::new("RateLimit-Limit", usage.limit.to_string()));
+            response.set_header(Header::new("RateLimit-Remaining", usage.remaining.to_string()));
+            response.set_header(Header::new("RateLimit-Reset", reset.clone()));
+            if response.status() == Status::TooManyRequests {
+                response.set_header(Header::new("Retry-After", reset));
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counts requests per key in fixed windows and refuses those above the limit.
+///
+/// Windows of keys that were not seen for a while are dropped on the next check, so the map only
+/// holds recent clients.
+pub struct RateLimiter<K: Hash + Eq> {
+    max_requests: u32,
+    window: Duration,
+    windows: Mutex<HashMap<K, (Instant, u32)>>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(max_requests: u32, window: Duration) -> Self {
+        RateLimiter {
+            max_requests,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request for `key`, returns whether it is within the limit.
+    pub fn check(&self, key: K) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn check_at(&self, key: K, now: Instant) -> bool {
+        self.count_at(key, now).allowed
+    }
+
+    pub fn check_usage(&self, key: K) -> Usage {
+        self.count_at(key, Instant::now())
+    }
+
+    /// Like `check_at` but also tells how much of the window is left.
+    pub fn count_at(&self, key: K, now: Instant) -> Usage {
+        let window = self.window;
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, (started, _)| now.duration_since(*started) < window);
+        let entry = windows.entry(key).or_insert((now, 0));
+        entry.1 += 1;
+        Usage {
+            allowed: entry.1 <= self.max_requests,
+            limit: self.max_requests,
+            remaining: self.max_requests.saturating_sub(entry.1),
+            reset: window - now.duration_since(entry.0),
+        }
+    }
+}
+
+/// Where a key stands after counting a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the window starts over.
+    pub reset: Duration,
+}
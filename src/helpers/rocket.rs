@@ -2,15 +2,23 @@ use rocket::{self, Rocket};
 use crate::api;
 use crate::handlers;
 
-use rocket::{Request, Response};
-use rocket::config::{Config, Environment};
+use rocket::{Outcome, Request, Response};
+use rocket::config::{Config, ConfigBuilder, Environment};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::Redirect;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::{Header, ContentType, Method};
 use std::io::Cursor;
 use std::path::PathBuf;
+use std::time::Duration;
 use rocket::config::Result;
 
 use crate::config;
+use crate::helpers::auth_cache::AuthCache;
+use crate::helpers::quota::{UserQuota, QuotaHeaders};
+use crate::helpers::login_throttle::LoginThrottle;
+use crate::helpers::shutdown::Shutdown;
 pub struct CORS();
 
 impl Fairing for CORS {
@@ -45,6 +53,66 @@ fn options_handler<'a>(path: PathBuf) -> Response<'a> {
         .finalize()
 }
 
+#[cfg(feature = "tls")]
+fn with_tls(builder: ConfigBuilder, tls: &config::TlsConfig) -> ConfigBuilder {
+    builder.tls(tls.cert_path.clone(), tls.key_path.clone())
+}
+
+/// `config::check` refuses TLS settings in builds without the feature, so this isn't reached.
+#[cfg(not(feature = "tls"))]
+fn with_tls(builder: ConfigBuilder, _tls: &config::TlsConfig) -> ConfigBuilder {
+    warn!("TLS is configured but this build can not serve HTTPS, serving plain HTTP.");
+    builder
+}
+
+/// Where to send a plain HTTP request to on the HTTPS port.
+pub struct HttpsLocation(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for HttpsLocation {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<HttpsLocation, ()> {
+        let port = match request.guard::<config::Config>() {
+            Outcome::Success(c) => c.web.port,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let host = match request.headers().get_one("Host") {
+            // Drop the port of the plain HTTP listener, but keep IPv6 addresses in one piece
+            Some(h) => match h.rfind(':') {
+                Some(i) if !h[i..].contains(']') => h[..i].to_owned(),
+                _ => h.to_owned(),
+            },
+            None => return Outcome::Failure((Status::BadRequest, ())),
+        };
+        let authority = if port == 443 { host } else { format!("{}:{}", host, port) };
+        Outcome::Success(HttpsLocation(format!("https://{}{}", authority, request.uri())))
+    }
+}
+
+#[get("/")]
+fn redirect_root(location: HttpsLocation) -> Redirect {
+    Redirect::permanent(location.0)
+}
+
+#[get("/<_path..>")]
+fn redirect_to_https(_path: PathBuf, location: HttpsLocation) -> Redirect {
+    Redirect::permanent(location.0)
+}
+
+/// Listens for plain HTTP on `tls.redirect_port` and redirects everything to HTTPS.
+pub fn redirect_factory(config: impl Into<config::SharedConfig>) -> Result<Rocket> {
+    let shared = config.into();
+    let config = shared.get();
+    let port = config.tls.as_ref().and_then(|t| t.redirect_port).unwrap_or(80);
+    let rocket_config = Config::build(Environment::Production)
+        .address(config.web.address.clone())
+        .port(port)
+        .finalize()?;
+    Ok(rocket::custom(rocket_config)
+        .manage(shared)
+        .mount("/", routes![redirect_root, redirect_to_https]))
+}
+
 fn add_catchers(rocket_result: Result<Rocket>) -> Result<Rocket> {
     rocket_result.map(|rocket|
         rocket.register(catchers![
@@ -52,6 +120,7 @@ fn add_catchers(rocket_result: Result<Rocket>) -> Result<Rocket> {
             handlers::unauthorized_handler,
             handlers::forbidden_handler,
             handlers::not_found_handler,
+            handlers::too_many_requests_handler,
             handlers::internal_server_error_handler,
             handlers::service_unavailable_handler,
         ])
@@ -60,7 +129,7 @@ fn add_catchers(rocket_result: Result<Rocket>) -> Result<Rocket> {
 
 
 #[cfg(feature = "webfrontend")]
-pub fn factory(pool: super::db::Pool, config: config::Config) -> Result<Rocket> {
+pub fn factory(pool: super::db::Pool, config: impl Into<config::SharedConfig>) -> Result<Rocket> {
     use crate::static_files;
     add_catchers(
         base_factory(pool, config).map(|r|
@@ -78,35 +147,112 @@ pub fn factory(pool: super::db::Pool, config: config::Config) -> Result<Rocket>
 }
 
 #[cfg(not(feature = "webfrontend"))]
-pub fn factory(pool: super::db::Pool, config: config::Config) -> Result<Rocket> {
+pub fn factory(pool: super::db::Pool, config: impl Into<config::SharedConfig>) -> Result<Rocket> {
     add_catchers(base_factory(pool, config))
 }
 
-pub fn base_factory(pool: super::db::Pool, config: config::Config) -> Result<Rocket> {
-    let rocket_config = Config::build(Environment::Production)
+/// `config` may be shared with whatever reloads it, requests see the reloaded settings.
+pub fn base_factory(pool: super::db::Pool, config: impl Into<config::SharedConfig>) -> Result<Rocket> {
+    let shared = config.into();
+    let config = shared.get();
+    let mut builder = Config::build(Environment::Production)
         .address(config.web.address.clone())
-        .port(config.web.port)
-        .finalize()?;
+        .port(config.web.port);
+    if let Some(ref tls) = config.tls {
+        builder = with_tls(builder, tls);
+    }
+    let rocket_config = builder.finalize()?;
+    let shutdown = Shutdown::new();
     Ok(rocket::custom(rocket_config)
         .attach(CORS())
+        .attach(QuotaHeaders())
+        .attach(shutdown.clone())
+        .manage(shutdown)
         .manage(pool)
-        .manage(config.clone())
+        .manage(api::status::StatusLimiter::new(&config))
+        .manage(AuthCache::new(Duration::from_secs(config.auth.cache_ttl)))
+        .manage(UserQuota::new(&config))
+        .manage(LoginThrottle::new(&config))
+        .manage(api::auth::ResetLimiter::new(&config))
+        .manage(shared)
         .mount("/", routes![options_handler])
-        .mount("/", routes![api::audiobooks::get_data_file])
+        .mount("/", routes![
+            api::audiobooks::get_data_file,
+            api::covers::get_cover,
+        ])
         .mount("/api", routes![
             api::libraries::libraries,
             api::libraries::all_the_things,
+            api::libraries::sync,
             api::libraries::update_playstates,
+            api::libraries::import_playstates,
+            api::libraries::stats,
+            api::libraries::scan_library,
+            api::libraries::get_scans,
+            api::libraries::get_scan_report,
+            api::libraries::get_scan_status,
+            api::libraries::update_library,
+            api::libraries::export_library,
+            api::libraries::import_library,
+            api::libraries::delete_library,
+            api::libraries::get_permissions,
+            api::libraries::grant_permission,
+            api::libraries::revoke_permission,
             api::audiobooks::get_coverart,
             api::audiobooks::get_audiobook,
+            api::audiobooks::get_checksum,
+            api::audiobooks::get_metadata,
+            api::audiobooks::update_metadata,
+            api::audiobooks::match_audiobook,
+            api::audiobooks::rescan_audiobooks,
+            api::audiobooks::restore_audiobook,
+            api::audiobooks::set_translation,
+            api::audiobooks::delete_translation,
+            api::audiobooks::get_chapters,
+            api::audiobooks::get_chapter_file,
+            api::audiobooks::get_chapters_zip,
+            api::covers::get_audiobook_cover,
+            api::covers::get_chapter_image,
+            api::covers::get_chapter_image_or_cover,
             api::audiobooks::get_audiobooks,
+            api::audiobooks::search,
+            api::authors::get_authors,
+            api::bookmarks::get_bookmarks,
+            api::bookmarks::create_bookmark,
+            api::bookmarks::update_bookmark,
+            api::bookmarks::delete_bookmark,
+            api::status::public_status,
         ])
         .mount("/api/auth", routes![
             api::auth::login,
             api::auth::logout,
+            api::auth::refresh,
             api::auth::logout_all,
             api::auth::register,
             api::auth::whoami,
+            api::auth::set_language,
+            api::auth::feed_token,
+            api::auth::regenerate_feed_token,
+            api::auth::request_reset,
+            api::auth::reset_password,
+        ])
+        .mount("/feeds", routes![
+            api::feeds::library_feed,
+            api::feeds::audiobook_feed,
+            api::feeds::feed_data,
+        ])
+        .mount("/api/admin", routes![
+            api::admin::list_users,
+            api::admin::create_user,
+            api::admin::delete_user,
+            api::admin::reset_password,
+            api::admin::set_allow_download,
+            api::admin::list_authors,
+            api::admin::merge_author,
+            api::admin::purge_deleted,
+            api::admin::auth_cache_stats,
+            api::admin::get_audit_log,
+            api::admin::get_duplicates,
         ])
     )
 }
@@ -0,0 +1,100 @@
+//! Stopping the server without cutting off requests or leaving scans half done.
+//!
+//! Once a shutdown began, new requests are answered with 503 Service Unavailable by the `DB`
+//! guard. Scans stop before their next book, each book is imported in a transaction of its own so
+//! none is left half imported. The process exits when the requests being handled and the scans
+//! are done, or after `web.shutdown_timeout` at the latest. Playstates are written as they come
+//! in, so there is nothing else to flush.
+
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+
+use crate::worker::scheduler;
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    begun: Arc<AtomicBool>,
+    active_requests: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn away new requests, scans are stopped by `scheduler::stop_scans`.
+    pub fn begin(&self) {
+        self.begun.store(true, Ordering::SeqCst);
+    }
+
+    pub fn has_begun(&self) -> bool {
+        self.begun.load(Ordering::SeqCst)
+    }
+
+    pub fn active_requests(&self) -> usize {
+        self.active_requests.load(Ordering::SeqCst)
+    }
+
+    /// Wait until no requests are being handled and no scans are running, returns false if that
+    /// took longer than `timeout`.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.active_requests() > 0 || scheduler::any_scanning() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+}
+
+/// Counts as an active request until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A response body that keeps its request active until it was sent, or the client went away.
+struct CountedBody<R> {
+    inner: R,
+    _in_flight: InFlight,
+}
+
+impl<R: Read> Read for CountedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Fairing for Shutdown {
+    fn info(&self) -> Info {
+        Info {
+            name: "Count active requests for shutting down",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    // Every request gets a response, so this counts the requests whose response isn't sent yet.
+    // Bodies like downloads are streamed after the response left the fairings, they hold on to
+    // the count until they are dropped.
+    fn on_request(&self, _: &mut Request, _: &Data) {
+        self.active_requests.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        let in_flight = InFlight(self.active_requests.clone());
+        if let Some(body) = response.take_body() {
+            response.set_raw_body(body.map(|inner| CountedBody { inner, _in_flight: in_flight }));
+        }
+    }
+}
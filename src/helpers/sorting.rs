@@ -0,0 +1,60 @@
+//! Sort keys that order titles the way people expect in a bookshelf.
+//!
+//! SQLite only compares bytes, so the collation is baked into the key: leading articles are
+//! dropped, case and accents are folded following DIN 5007 (ä sorts as a, ß as ss) and numbers are
+//! padded so "Part 2" comes before "Part 10".
+
+/// Articles that are ignored at the start of a title.
+const ARTICLES: &[&str] = &["the", "a", "an", "der", "die", "das"];
+
+/// Numbers are padded to this many digits.
+const NUMBER_WIDTH: usize = 10;
+
+fn strip_article(title: &str) -> &str {
+    let trimmed = title.trim_start();
+    if let Some(end) = trimmed.find(char::is_whitespace) {
+        let rest = trimmed[end..].trim_start();
+        if !rest.is_empty() && ARTICLES.contains(&trimmed[..end].to_lowercase().as_str()) {
+            return rest;
+        }
+    }
+    trimmed
+}
+
+fn fold(c: char, out: &mut String) {
+    match c {
+        'ä' | 'à' | 'á' | 'â' | 'ã' | 'å' => out.push('a'),
+        'ö' | 'ò' | 'ó' | 'ô' | 'õ' | 'ø' => out.push('o'),
+        'ü' | 'ù' | 'ú' | 'û' => out.push('u'),
+        'é' | 'è' | 'ê' | 'ë' => out.push('e'),
+        'í' | 'ì' | 'î' | 'ï' => out.push('i'),
+        'ç' => out.push('c'),
+        'ñ' => out.push('n'),
+        'ß' => out.push_str("ss"),
+        'æ' => out.push_str("ae"),
+        'œ' => out.push_str("oe"),
+        c => out.push(c),
+    }
+}
+
+/// The key a title is sorted by, stored in `audiobooks.sort_title`.
+pub fn sort_title(title: &str) -> String {
+    let mut key = String::with_capacity(title.len());
+    let mut digits = String::new();
+    let stripped = strip_article(title).trim_start_matches(|c: char| !c.is_alphanumeric());
+    for c in stripped.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        if !digits.is_empty() {
+            key.push_str(&format!("{:0>width$}", digits, width = NUMBER_WIDTH));
+            digits.clear();
+        }
+        fold(c, &mut key);
+    }
+    if !digits.is_empty() {
+        key.push_str(&format!("{:0>width$}", digits, width = NUMBER_WIDTH));
+    }
+    key
+}
@@ -0,0 +1,296 @@
+use std::io::Cursor;
+use crate::helpers::ogg::{self, OggError};
+use crate::helpers::feed::{self, Feed, FeedItem};
+use crate::helpers::sorting::sort_title;
+use crate::helpers::zip;
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::{Audiobook, test_book};
+use crate::models::chapter::Chapter;
+
+fn page(header_type: u8, granule_position: i64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+    let mut segments = vec![255u8; packet.len() / 255];
+    segments.push((packet.len() % 255) as u8);
+
+    let mut page = Vec::new();
+    page.extend_from_slice(b"OggS");
+    page.push(0);
+    page.push(header_type);
+    page.extend_from_slice(&granule_position.to_le_bytes());
+    page.extend_from_slice(&1234u32.to_le_bytes());
+    page.extend_from_slice(&sequence.to_le_bytes());
+    page.extend_from_slice(&[0, 0, 0, 0]);
+    page.push(segments.len() as u8);
+    page.extend_from_slice(&segments);
+    page.extend_from_slice(packet);
+
+    let crc = ogg::crc32(&page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+fn opus_stream() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0, 0, 0]);
+    let mut stream = page(0x02, 0, 0, &head);
+    stream.extend(page(0x00, 0, 1, b"OpusTags\x07\x00\x00\x00vorleser\x00\x00\x00\x00"));
+    stream.extend(page(0x00, 48312, 2, &[0xfc; 300]));
+    stream.extend(page(0x04, 96312, 3, &[0xfc; 20]));
+    stream
+}
+
+#[test]
+fn ogg_accepts_valid_stream() {
+    let summary = ogg::validate(&mut Cursor::new(opus_stream())).unwrap();
+    assert_eq!(summary.pages, 4);
+    assert_eq!(summary.channels, 2);
+    assert_eq!(summary.pre_skip, 312);
+    assert_eq!(summary.duration(), 2.0);
+}
+
+#[test]
+fn ogg_detects_broken_crc() {
+    let mut stream = opus_stream();
+    let last = stream.len() - 1;
+    stream[last] ^= 0xff;
+    let error = ogg::validate(&mut Cursor::new(stream)).unwrap_err();
+    match error.downcast_ref::<OggError>() {
+        Some(OggError::CrcMismatch { page: 3, .. }) => (),
+        other => panic!("Expected a crc mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn ogg_detects_missing_end_of_stream() {
+    let mut stream = opus_stream();
+    let eos_page = page(0x04, 96312, 3, &[0xfc; 20]);
+    let length = stream.len() - eos_page.len();
+    stream.truncate(length);
+    let error = ogg::validate(&mut Cursor::new(stream)).unwrap_err();
+    assert_eq!(error.downcast_ref::<OggError>(), Some(&OggError::MissingEndOfStream));
+}
+
+#[test]
+fn ogg_detects_granule_going_backwards() {
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, 1, 0, 0, 0x80, 0xbb, 0, 0, 0, 0, 0]);
+    let mut stream = page(0x02, 0, 0, &head);
+    stream.extend(page(0x00, 0, 1, b"OpusTags"));
+    stream.extend(page(0x00, 960, 2, &[0xfc; 10]));
+    stream.extend(page(0x04, 480, 3, &[0xfc; 10]));
+    let error = ogg::validate(&mut Cursor::new(stream)).unwrap_err();
+    assert_eq!(error.downcast_ref::<OggError>(), Some(&OggError::GranuleDecreased { page: 3 }));
+}
+
+#[test]
+fn feed_contains_escaped_items_and_chapters() {
+    let book = Audiobook {
+        artist: Some("<Anonymous>".to_owned()),
+        length: 3725.4,
+        ..test_book(Uuid::new_v4(), "book.mp3", "Tom & Jerry")
+    };
+    let chapters = vec![
+        Chapter { id: Uuid::new_v4(), title: Some("Intro".to_owned()), audiobook_id: book.id, start_time: 0.0, number: 0, has_image: false },
+        Chapter { id: Uuid::new_v4(), title: None, audiobook_id: book.id, start_time: 3661.5, number: 1, has_image: false },
+    ];
+    let rendered = feed::render(&Feed {
+        title: "Library".to_owned(),
+        link: "http://localhost/feeds/secret/libraries/1".to_owned(),
+        items: vec![FeedItem {
+            book: &book,
+            chapters: &chapters,
+            enclosure_url: "http://localhost/feeds/secret/data/1.mp3".to_owned(),
+            enclosure_length: 1234,
+            enclosure_type: "audio/mpeg".to_owned(),
+        }],
+    });
+    assert!(rendered.contains("<title>Tom &amp; Jerry</title>"));
+    assert!(rendered.contains("<itunes:author>&lt;Anonymous&gt;</itunes:author>"));
+    assert!(rendered.contains("<enclosure url=\"http://localhost/feeds/secret/data/1.mp3\" length=\"1234\" type=\"audio/mpeg\"/>"));
+    assert!(rendered.contains("<psc:chapter start=\"00:00:00.000\" title=\"Intro\"/>"));
+    assert!(rendered.contains("<psc:chapter start=\"01:01:01.500\" title=\"Chapter 2\"/>"));
+}
+
+#[test]
+fn sort_titles_ignore_articles() {
+    assert_eq!(sort_title("The Hobbit"), sort_title("hobbit"));
+    assert_eq!(sort_title("Die Unendliche Geschichte"), "unendliche geschichte");
+    assert_eq!(sort_title("A"), "a");
+    assert!(sort_title("Ärger im Paradies") < sort_title("Berge"));
+    assert!(sort_title("Part 2") < sort_title("Part 10"));
+    assert!(sort_title("\"Quoted\" Title") > sort_title("Paper"));
+}
+
+#[test]
+fn detects_book_languages() {
+    use std::collections::HashMap;
+    use crate::helpers::language;
+    let mut tags = HashMap::new();
+    assert_eq!(language::detect(&tags, "Die Vermessung der Welt", None), Some("de".to_owned()));
+    assert_eq!(language::detect(&tags, "The Lord of the Rings", None), Some("en".to_owned()));
+    assert_eq!(language::detect(&tags, "Dune", None), None);
+    tags.insert("LANGUAGE".to_owned(), "ger".to_owned());
+    assert_eq!(language::detect(&tags, "The Lord of the Rings", None), Some("de".to_owned()));
+    tags.insert("LANGUAGE".to_owned(), "und".to_owned());
+    assert_eq!(language::detect(&tags, "Dune", None), None);
+    assert_eq!(language::normalize_tag("pt_BR"), Some("pt-br".to_owned()));
+    assert_eq!(language::normalize_tag("Deutsch"), Some("de".to_owned()));
+}
+
+#[test]
+fn zip_checksums() {
+    assert_eq!(zip::crc32(b"123456789"), 0xcbf4_3926);
+    let mut archive = Vec::new();
+    zip::write_stored(&mut archive, &[]).unwrap();
+    assert_eq!(archive, b"PK\x05\x06\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0".to_vec());
+}
+
+#[test]
+fn rate_limiter_counts_per_window() {
+    use std::time::{Duration, Instant};
+    use crate::helpers::rate_limit::RateLimiter;
+    let limiter = RateLimiter::new(2, Duration::from_secs(60));
+    let start = Instant::now();
+    assert!(limiter.check_at("a", start));
+    assert!(limiter.check_at("a", start));
+    assert!(!limiter.check_at("a", start + Duration::from_secs(1)));
+    assert!(limiter.check_at("b", start + Duration::from_secs(1)));
+    assert!(limiter.check_at("a", start + Duration::from_secs(61)));
+}
+
+#[test]
+fn login_throttle_backs_off() {
+    use std::time::{Duration, Instant};
+    use crate::helpers::login_throttle::{LoginKey, LoginThrottle};
+    let throttle = LoginThrottle::with_limits(2, Duration::from_secs(5));
+    let keys = LoginKey::of("A@test.com", None);
+    let start = Instant::now();
+    assert_eq!(throttle.failed_at(&keys, start), vec![]);
+    assert_eq!(throttle.wait_at(&keys, start), None);
+    assert_eq!(throttle.failed_at(&keys, start), vec![LoginKey::Email("a@test.com".to_owned())]);
+    assert_eq!(throttle.wait_at(&keys, start), Some(Duration::from_secs(1)));
+    assert_eq!(throttle.wait_at(&keys, start + Duration::from_secs(1)), None);
+    throttle.failed_at(&keys, start);
+    assert_eq!(throttle.wait_at(&keys, start), Some(Duration::from_secs(2)));
+    for _ in 0..5 {
+        throttle.failed_at(&keys, start);
+    }
+    assert_eq!(throttle.wait_at(&keys, start), Some(Duration::from_secs(5)));
+    assert_eq!(throttle.wait_at(&LoginKey::of("b@test.com", None), start), None);
+    throttle.succeeded(&keys);
+    assert_eq!(throttle.wait_at(&keys, start), None);
+}
+
+#[test]
+fn login_throttle_forgets_old_failures() {
+    use std::time::{Duration, Instant};
+    use crate::helpers::login_throttle::{LoginKey, LoginThrottle};
+    let throttle = LoginThrottle::with_limits(2, Duration::from_secs(5)).max_tracked(4);
+    let start = Instant::now();
+    for (i, email) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+        throttle.failed_at(&LoginKey::of(email, None), start + Duration::from_secs(i as u64));
+    }
+    assert_eq!(throttle.tracked(), 4);
+    // "a" failed longest ago and was forgotten, "e" is remembered
+    assert_eq!(throttle.failed_at(&LoginKey::of("a", None), start + Duration::from_secs(5)), vec![]);
+    assert_eq!(throttle.failed_at(&LoginKey::of("e", None), start + Duration::from_secs(5)),
+               vec![LoginKey::Email("e".to_owned())]);
+
+    assert_eq!(throttle.wait_at(&LoginKey::of("e", None), start + Duration::from_secs(25 * 60 * 60)), None);
+    assert_eq!(throttle.tracked(), 0);
+}
+
+#[test]
+fn sends_mails_over_smtp() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use crate::config::SmtpConfig;
+    use crate::helpers::mail;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut received = Vec::new();
+        stream.write_all(b"220 test ESMTP\r\n").unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            received.push(line.clone());
+            let reply: &[u8] = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-test\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                stream.write_all(b"221 bye\r\n").unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.write_all(reply).unwrap();
+        }
+        received
+    });
+
+    let config = SmtpConfig { host: "127.0.0.1".to_owned(), port, from: "vorleser@example.com".to_owned() };
+    mail::send(&config, "user@example.com", "Hello", "First line\n.dotted line").unwrap();
+    let received = server.join().unwrap();
+    assert_eq!(received[1], "MAIL FROM:<vorleser@example.com>\r\n");
+    assert_eq!(received[2], "RCPT TO:<user@example.com>\r\n");
+    assert!(received.contains(&"Subject: Hello\r\n".to_owned()));
+    assert!(received.contains(&"..dotted line\r\n".to_owned()));
+    assert_eq!(received.last().unwrap(), "QUIT\r\n");
+
+    assert!(mail::send(&config, "user@example.com\r\nBcc: other@example.com", "Hello", "").is_err());
+}
+
+#[test]
+fn hashes_passwords_with_argon2id() {
+    use argon2rs::verifier;
+    use crate::helpers::password::{self, HashParams};
+
+    let hash = password::hash("secret");
+    assert!(hash.starts_with("$argon2id$v=19$"));
+    assert!(password::verify(&hash, "secret"));
+    assert!(!password::verify(&hash, "Secret"));
+    assert_eq!(password::params_of(&hash), Some(HashParams::default()));
+    assert!(!password::needs_rehash(&hash));
+    let weaker = hash.replace("m=19456,t=2", "m=4096,t=2");
+    assert_eq!(password::params_of(&weaker), Some(HashParams { memory: 4096, iterations: 2 }));
+    assert!(password::needs_rehash(&weaker));
+
+    // Hashes from before Argon2id
+    let legacy = base64::encode(&verifier::Encoded::default2i(b"secret", b"saltsalt10", &[], &[]).to_u8());
+    assert!(password::verify(&legacy, "secret"));
+    assert!(!password::verify(&legacy, "Secret"));
+    assert!(password::needs_rehash(&legacy));
+    assert!(!password::verify("not a hash", "secret"));
+}
+
+#[test]
+fn events_are_dropped_for_subscribers_that_fall_behind() {
+    use crate::helpers::events::{Bus, Event};
+    let bus = Bus::new(2);
+    let slow = bus.subscribe();
+    let gone = bus.subscribe();
+    drop(gone);
+    let id = Uuid::new_v4();
+    let played = |position: f64| Event::PlaystateUpdated { user_id: id, audiobook_id: id, position };
+    for position in 0..4 {
+        bus.publish(played(f64::from(position)));
+    }
+    assert_eq!(slow.try_iter().collect::<Vec<Event>>(), vec![played(0.0), played(1.0)]);
+
+    bus.publish(played(4.0));
+    assert_eq!(slow.try_iter().collect::<Vec<Event>>(), vec![played(4.0)]);
+}
@@ -13,6 +13,7 @@ use rocket::http::RawStr;
 
 use uuid;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug, Hash, Eq, PartialEq, Serialize, Deserialize, Clone, Copy, AsExpression, FromSqlRow)]
 #[sql_type = "Text"]
@@ -32,6 +33,41 @@ impl Uuid {
     }
 }
 
+/// Source of new ids.
+/// Models and the scanner take an `IdGen` so tests can predict the ids that get assigned.
+pub trait IdGen {
+    fn new_id(&self) -> Uuid;
+}
+
+/// Random version 4 UUIDs, used everywhere outside of tests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomIds;
+
+impl IdGen for RandomIds {
+    fn new_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Hands out `00000000-0000-0000-0000-000000000001`, `...0002` and so on.
+#[derive(Debug, Default)]
+pub struct SequentialIds(AtomicUsize);
+
+impl SequentialIds {
+    pub fn new() -> Self {
+        SequentialIds(AtomicUsize::new(0))
+    }
+}
+
+impl IdGen for SequentialIds {
+    fn new_id(&self) -> Uuid {
+        let count = self.0.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+        let mut bytes = [0u8; 16];
+        bytes[8..].copy_from_slice(&count.to_be_bytes());
+        Uuid(uuid::Uuid::from_bytes(bytes))
+    }
+}
+
 impl ToSql<Text, Sqlite> for Uuid {
     fn to_sql<W: Write>(
         &self,
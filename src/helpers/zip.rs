@@ -0,0 +1,125 @@
+//! Writing of zip archives that store their files uncompressed, audio doesn't compress anyway.
+//!
+//! Only what is needed to bundle a handful of files is supported: no compression, no zip64, so
+//! neither single files nor the whole archive may exceed 4 GiB.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const VERSION: u16 = 20;
+/// File names are UTF-8.
+const FLAGS: u16 = 1 << 11;
+/// 1980-01-01 00:00, the earliest date zip can store.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// CRC-32 as used by zip (IEEE 802.3, reflected).
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Zip archives without zip64 are limited to 4 GiB")
+}
+
+fn checksum_file(path: &PathBuf) -> io::Result<(u32, u32)> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut crc = 0;
+    let mut size: u64 = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        crc = crc32_update(crc, &buffer[..read]);
+        size += read as u64;
+    }
+    if size > u64::from(u32::max_value()) {
+        return Err(too_large());
+    }
+    Ok((crc, size as u32))
+}
+
+fn write_u16(out: &mut dyn Write, value: u16) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u32(out: &mut dyn Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+/// Fields shared by the local and the central header, from the version needed to the extra length.
+fn write_common(out: &mut dyn Write, crc: u32, size: u32, name: &str) -> io::Result<()> {
+    write_u16(out, VERSION)?;
+    write_u16(out, FLAGS)?;
+    write_u16(out, 0)?;
+    write_u16(out, DOS_TIME)?;
+    write_u16(out, DOS_DATE)?;
+    write_u32(out, crc)?;
+    write_u32(out, size)?;
+    write_u32(out, size)?;
+    write_u16(out, name.len() as u16)?;
+    write_u16(out, 0)
+}
+
+/// Write an archive containing the files at the given paths under the given names.
+pub fn write_stored(out: &mut dyn Write, entries: &[(String, PathBuf)]) -> io::Result<()> {
+    let mut offset: u64 = 0;
+    let mut central = Vec::new();
+    for (name, path) in entries {
+        let (crc, size) = checksum_file(path)?;
+        if offset > u64::from(u32::max_value()) {
+            return Err(too_large());
+        }
+
+        write_u32(out, LOCAL_HEADER)?;
+        write_common(out, crc, size, name)?;
+        out.write_all(name.as_bytes())?;
+        let copied = io::copy(&mut File::open(path)?, out)?;
+        if copied != u64::from(size) {
+            return Err(io::Error::new(io::ErrorKind::Other, "File changed while archiving it"));
+        }
+
+        write_u32(&mut central, CENTRAL_HEADER)?;
+        write_u16(&mut central, VERSION)?;
+        write_common(&mut central, crc, size, name)?;
+        // comment length, disk number, internal and external attributes
+        write_u16(&mut central, 0)?;
+        write_u16(&mut central, 0)?;
+        write_u16(&mut central, 0)?;
+        write_u32(&mut central, 0)?;
+        write_u32(&mut central, offset as u32)?;
+        central.extend_from_slice(name.as_bytes());
+
+        offset += 30 + name.len() as u64 + u64::from(size);
+    }
+    if offset > u64::from(u32::max_value()) {
+        return Err(too_large());
+    }
+    out.write_all(&central)?;
+
+    write_u32(out, END_OF_CENTRAL_DIRECTORY)?;
+    write_u16(out, 0)?;
+    write_u16(out, 0)?;
+    write_u16(out, entries.len() as u16)?;
+    write_u16(out, entries.len() as u16)?;
+    write_u32(out, central.len() as u32)?;
+    write_u32(out, offset as u32)?;
+    write_u16(out, 0)
+}
@@ -26,6 +26,7 @@ extern crate validator;
 #[macro_use] extern crate diesel_migrations;
 extern crate chrono;
 extern crate argon2rs;
+extern crate argon2;
 extern crate ffmpeg_sys as ffmpeg;
 extern crate regex;
 extern crate walkdir;
@@ -35,6 +36,8 @@ extern crate humanesort;
 extern crate toml;
 extern crate id3;
 extern crate mp3_metadata;
+extern crate notify;
+extern crate reqwest;
 
 #[cfg(test)] #[macro_use] extern crate speculate;
 
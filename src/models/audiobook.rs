@@ -6,9 +6,14 @@ use chrono::NaiveDateTime;
 use diesel::sqlite::SqliteConnection;
 use crate::models::user::User;
 use crate::helpers::uuid::Uuid;
+use crate::helpers::sorting;
+use serde::Serializer;
 
 use crate::models::library::Library;
 use crate::models::chapter::Chapter;
+use crate::models::author::Author;
+use crate::models::series::Series;
+use crate::models::search;
 use crate::schema::{audiobooks, playstates, library_permissions};
 
 #[table_name="audiobooks"]
@@ -24,40 +29,279 @@ pub struct Audiobook {
     pub library_id: Uuid,
     pub hash: Vec<u8>,
     pub file_extension: String,
-    pub deleted: bool
+    pub deleted: bool,
+    /// Hex encoded SHA-256 of the cover image, serialized as a content addressed URL.
+    #[serde(rename = "cover_url", serialize_with = "serialize_cover_url")]
+    pub cover_hash: Option<String>,
+    #[serde(skip_serializing)]
+    pub cover_mime: Option<String>,
+    /// Modification time and size of the source files when they were last hashed, used by
+    /// incremental scans to skip unchanged books.
+    #[serde(skip_serializing)]
+    pub file_mtime: Option<NaiveDateTime>,
+    #[serde(skip_serializing)]
+    pub file_size: Option<i64>,
+    /// SHA-256 of the file in the data directory, which is what clients download.
+    #[serde(skip_serializing)]
+    pub data_hash: Option<Vec<u8>>,
+    /// Key browse lists are ordered by, see `helpers::sorting`.
+    #[serde(skip_serializing)]
+    pub sort_title: String,
+    /// Author the artist tag resolved to, see `models::author`.
+    pub author_id: Option<Uuid>,
+    /// When the book was marked as deleted, it is purged once the retention window passed.
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<NaiveDateTime>,
+    /// Taken from the description or comment tag, see `models::translation` for other languages.
+    pub description: Option<String>,
+    /// Last change clients can see, including its chapters and translations, maintained by the
+    /// database for `GET /api/sync`.
+    pub updated_at: Option<NaiveDateTime>,
+    /// Series the book is part of, see `models::series`.
+    pub series_id: Option<Uuid>,
+    /// Where in its series the book is, fractions are used for novellas between two books.
+    pub series_position: Option<f64>,
+    /// Language tag like `"de"`, from the tags or guessed, see `helpers::language`.
+    pub language: Option<String>,
+}
+
+/// A book in `library_id` at `location` with nothing else worth mentioning, for tests. Its hash is
+/// made from the location, so books at different locations are different files.
+#[cfg(test)]
+pub fn test_book(library_id: Uuid, location: &str, title: &str) -> Audiobook {
+    Audiobook {
+        id: Uuid::new_v4(),
+        location: location.to_owned(),
+        title: title.to_owned(),
+        artist: None,
+        length: 0.0,
+        library_id,
+        hash: location.as_bytes().to_vec(),
+        file_extension: "mp3".to_owned(),
+        deleted: false,
+        cover_hash: None,
+        cover_mime: None,
+        file_mtime: None,
+        file_size: None,
+        data_hash: None,
+        sort_title: sorting::sort_title(title),
+        author_id: None,
+        deleted_at: None,
+        description: None,
+        updated_at: None,
+        series_id: None,
+        series_position: None,
+        language: None,
+    }
+}
+
+fn serialize_cover_url<S: Serializer>(cover_hash: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *cover_hash {
+        Some(ref hash) => serializer.serialize_some(&cover_url(hash)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// URL a cover with the given hash is served at.
+/// As the URL changes whenever the image does clients may cache these indefinitely.
+pub fn cover_url(cover_hash: &str) -> String {
+    format!("/covers/{}", cover_hash)
+}
+
+/// Orders books can be listed in, ties are broken by title and then location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookOrder {
+    Title,
+    Artist,
+    /// Most recently changed files first.
+    Recent,
+    /// Shortest first.
+    Length,
+}
+
+impl BookOrder {
+    pub fn parse(name: &str) -> Option<BookOrder> {
+        match name {
+            "title" => Some(BookOrder::Title),
+            "artist" => Some(BookOrder::Artist),
+            "recent" => Some(BookOrder::Recent),
+            "length" => Some(BookOrder::Length),
+            _ => None,
+        }
+    }
+}
+
+impl Default for BookOrder {
+    fn default() -> Self {
+        BookOrder::Title
+    }
 }
 
 pub enum Update {
     Nothing,
-    Path,
+    /// The book was moved within its library, this is it at its new location.
+    Path(Audiobook),
+    /// The book was moved over from another library.
+    Library(Audiobook),
     NotFound
 }
 
 impl Audiobook {
-    fn find_by_hash(hash: &[u8], conn: &diesel::sqlite::SqliteConnection) -> Result<Audiobook, diesel::result::Error> {
-        audiobooks::dsl::audiobooks.filter(audiobooks::dsl::hash.eq(hash)).get_result(conn)
-    }
-
-    /// Updates the path of any book with the given hash to the new_path provided.
-    /// Returns true if a path is now correct, returns false if no book with this hash exists.
-    pub fn update_path(book_hash: &[u8], new_path: &dyn AsRef<str>, conn: &diesel::sqlite::SqliteConnection)
+    /// Point the book with the given hash in `library` to `new_path`.
+    ///
+    /// Without such a book, a book with this hash whose files are gone from another library is
+    /// moved to `new_path` in this one, keeping its playstates and bookmarks. Books copied to
+    /// several libraries stay separate books.
+    pub fn update_path(book_hash: &[u8], new_path: &dyn AsRef<str>, library: &Library, conn: &SqliteConnection)
         -> Result<Update, diesel::result::Error> {
-        if let Ok(book) = Self::find_by_hash(book_hash, conn) {
+        use crate::schema::audiobooks::dsl;
+        use crate::schema::libraries;
+
+        let existing = Self::belonging_to(library)
+            .filter(dsl::hash.eq(book_hash))
+            .first::<Audiobook>(conn)
+            .optional()?;
+        if let Some(mut book) = existing {
             if book.location != new_path.as_ref() {
-                diesel::update(audiobooks::dsl::audiobooks.filter(audiobooks::dsl::hash.eq(book_hash)))
-                    .set(audiobooks::dsl::location.eq(new_path.as_ref())).execute(conn)?;
-                return Ok(Update::Path)
+                diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                    .set(dsl::location.eq(new_path.as_ref())).execute(conn)?;
+                book.location = new_path.as_ref().to_owned();
+                return Ok(Update::Path(book))
             };
             return Ok(Update::Nothing)
-        } else {
-            return Ok(Update::NotFound);
         }
+
+        let elsewhere = dsl::audiobooks.inner_join(libraries::table)
+            .filter(dsl::hash.eq(book_hash))
+            .filter(dsl::library_id.ne(&library.id))
+            .load::<(Audiobook, Library)>(conn)?;
+        for (mut book, old_library) in elsewhere {
+            if book.deleted || !Path::new(&old_library.location).join(&book.location).exists() {
+                info!("{} moved from library {} to {}", book.title, old_library.location, library.location);
+                diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                    .set((
+                        dsl::library_id.eq(&library.id),
+                        dsl::location.eq(new_path.as_ref()),
+                        dsl::deleted.eq(false),
+                        dsl::deleted_at.eq(None::<NaiveDateTime>),
+                    ))
+                    .execute(conn)?;
+                book.library_id = library.id;
+                book.location = new_path.as_ref().to_owned();
+                book.deleted = false;
+                book.deleted_at = None;
+                return Ok(Update::Library(book))
+            }
+        }
+        Ok(Update::NotFound)
+    }
+
+    /// Remember the file stats of any book with the given hash.
+    pub fn update_file_stats(book_hash: &[u8], mtime: NaiveDateTime, size: i64, conn: &SqliteConnection)
+        -> Result<usize, diesel::result::Error> {
+        use crate::schema::audiobooks::dsl;
+        diesel::update(dsl::audiobooks.filter(dsl::hash.eq(book_hash)))
+            .set((dsl::file_mtime.eq(mtime), dsl::file_size.eq(size)))
+            .execute(conn)
+    }
+
+    pub fn set_data_hash(&mut self, data_hash: Vec<u8>, conn: &SqliteConnection) -> Result<(), diesel::result::Error> {
+        use crate::schema::audiobooks::dsl;
+        diesel::update(dsl::audiobooks.filter(dsl::id.eq(&self.id)))
+            .set(dsl::data_hash.eq(&data_hash))
+            .execute(conn)?;
+        self.data_hash = Some(data_hash);
+        Ok(())
+    }
+
+    /// Recompute the sort key from the title, needed for books scanned before the key existed.
+    pub fn update_sort_title(&mut self, conn: &SqliteConnection) -> Result<(), diesel::result::Error> {
+        use crate::schema::audiobooks::dsl;
+        let key = sorting::sort_title(&self.title);
+        diesel::update(dsl::audiobooks.filter(dsl::id.eq(&self.id)))
+            .set(dsl::sort_title.eq(&key))
+            .execute(conn)?;
+        self.sort_title = key;
+        Ok(())
+    }
+
+    pub fn set_author(&mut self, author: &Author, conn: &SqliteConnection) -> Result<(), diesel::result::Error> {
+        use crate::schema::audiobooks::dsl;
+        diesel::update(dsl::audiobooks.filter(dsl::id.eq(&self.id)))
+            .set(dsl::author_id.eq(&author.id))
+            .execute(conn)?;
+        self.author_id = Some(author.id);
+        Ok(())
+    }
+
+    pub fn set_series(&mut self, series: &Series, position: Option<f64>, conn: &SqliteConnection)
+        -> Result<(), diesel::result::Error> {
+        use crate::schema::audiobooks::dsl;
+        diesel::update(dsl::audiobooks.filter(dsl::id.eq(&self.id)))
+            .set((dsl::series_id.eq(&series.id), dsl::series_position.eq(position)))
+            .execute(conn)?;
+        self.series_id = Some(series.id);
+        self.series_position = position;
+        Ok(())
+    }
+
+    pub fn set_language(&mut self, language: Option<String>, conn: &SqliteConnection)
+        -> Result<(), diesel::result::Error> {
+        use crate::schema::audiobooks::dsl;
+        diesel::update(dsl::audiobooks.filter(dsl::id.eq(&self.id)))
+            .set(dsl::language.eq(&language))
+            .execute(conn)?;
+        self.language = language;
+        Ok(())
     }
 
     pub fn delete_all_chapters(&self, conn: &diesel::sqlite::SqliteConnection) -> diesel::result::QueryResult<usize> {
         diesel::delete(Chapter::belonging_to(self)).execute(&*conn)
     }
 
+    /// Remove books from the database along with everything that refers to them.
+    /// Their files are left alone, see `janitor::remove_book_files`.
+    pub fn purge(book_ids: &[Uuid], conn: &SqliteConnection) -> QueryResult<()> {
+        use crate::schema::{audiobook_metadata, audiobook_translations, book_matches, bookmarks, chapters,
+                            listening_events};
+        conn.transaction(|| {
+            diesel::delete(playstates::table.filter(playstates::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            diesel::delete(bookmarks::table.filter(bookmarks::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            diesel::delete(listening_events::table.filter(listening_events::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            diesel::delete(chapters::table.filter(chapters::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            diesel::delete(audiobook_metadata::table.filter(audiobook_metadata::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            diesel::delete(audiobook_translations::table.filter(audiobook_translations::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            diesel::delete(book_matches::table.filter(book_matches::dsl::audiobook_id.eq_any(book_ids)))
+                .execute(conn)?;
+            search::remove_books(book_ids, conn)?;
+            diesel::delete(audiobooks::table.filter(audiobooks::dsl::id.eq_any(book_ids)))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Purge books that were marked as deleted before `deleted_before`, or all of them.
+    /// Returns the purged books so their files can be removed.
+    pub fn purge_deleted(deleted_before: Option<NaiveDateTime>, conn: &SqliteConnection)
+        -> QueryResult<Vec<Audiobook>> {
+        use crate::schema::audiobooks::dsl;
+        conn.transaction(|| {
+            let mut query = dsl::audiobooks.filter(dsl::deleted.eq(true)).into_boxed();
+            if let Some(before) = deleted_before {
+                query = query.filter(dsl::deleted_at.lt(before));
+            }
+            let books = query.load::<Audiobook>(conn)?;
+            Audiobook::purge(&books.iter().map(|b| b.id).collect::<Vec<Uuid>>(), conn)?;
+            Ok(books)
+        })
+    }
+
     pub fn ensure_exists_in(relative_path: &dyn AsRef<str>, library: &Library,
                             new_book: &Audiobook, conn: &SqliteConnection)
         -> Result<Audiobook, diesel::result::Error> {
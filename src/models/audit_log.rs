@@ -0,0 +1,162 @@
+//! A record of who did what to whose account, for admins of servers that several people share.
+//!
+//! Entries are written along with what they describe and are never changed, after
+//! `retention.audit_log` they are removed. `user_id` is who acted, `target` what it was done to:
+//! the id of a user, library or author, or the email address a failed login was for. Entries stay
+//! when their users are deleted.
+
+use std::net::IpAddr;
+
+use chrono::NaiveDateTime;
+use chrono::prelude::*;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::schema::audit_log;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Login,
+    FailedLogin,
+    TokenRefreshed,
+    Logout,
+    LogoutAll,
+    PasswordReset,
+    UserCreated,
+    UserDeleted,
+    PasswordSet,
+    DownloadsChanged,
+    PermissionGranted,
+    PermissionRevoked,
+    PurgedDeleted,
+    Registered,
+    FeedTokenRegenerated,
+    LibraryDeleted,
+    LibraryImported,
+    AuthorMerged,
+}
+
+const ACTIONS: &[(Action, &str)] = &[
+    (Action::Login, "login"),
+    (Action::FailedLogin, "failed_login"),
+    (Action::TokenRefreshed, "token_refreshed"),
+    (Action::Logout, "logout"),
+    (Action::LogoutAll, "logout_all"),
+    (Action::PasswordReset, "password_reset"),
+    (Action::UserCreated, "user_created"),
+    (Action::UserDeleted, "user_deleted"),
+    (Action::PasswordSet, "password_set"),
+    (Action::DownloadsChanged, "downloads_changed"),
+    (Action::PermissionGranted, "permission_granted"),
+    (Action::PermissionRevoked, "permission_revoked"),
+    (Action::PurgedDeleted, "purged_deleted"),
+    (Action::Registered, "registered"),
+    (Action::FeedTokenRegenerated, "feed_token_regenerated"),
+    (Action::LibraryDeleted, "library_deleted"),
+    (Action::LibraryImported, "library_imported"),
+    (Action::AuthorMerged, "author_merged"),
+];
+
+impl Action {
+    pub fn parse(name: &str) -> Option<Action> {
+        ACTIONS.iter().find(|(_, n)| *n == name).map(|(action, _)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        ACTIONS.iter().find(|(a, _)| *a == self).map(|(_, name)| *name).unwrap()
+    }
+}
+
+/// Characters of `target` that are kept, no email address is longer.
+pub const MAX_TARGET_LENGTH: usize = 254;
+
+#[table_name="audit_log"]
+#[derive(Debug, Clone, PartialEq, Queryable, Insertable, Serialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub action: String,
+    pub user_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub target: Option<String>,
+    pub details: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(action: Action, user_id: Option<Uuid>, ip: Option<IpAddr>) -> Self {
+        AuditEntry {
+            id: Uuid::new_v4(),
+            created_at: Utc::now().naive_utc(),
+            action: action.name().to_owned(),
+            user_id,
+            ip: ip.map(|ip| ip.to_string()),
+            target: None,
+            details: None,
+        }
+    }
+
+    /// Targets longer than `MAX_TARGET_LENGTH` characters are cut off, failed logins can be for
+    /// anything.
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.chars().take(MAX_TARGET_LENGTH).collect());
+        self
+    }
+
+    pub fn details(mut self, details: &str) -> Self {
+        self.details = Some(details.to_owned());
+        self
+    }
+
+    pub fn save(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::insert_into(audit_log::table).values(self).execute(conn)?;
+        Ok(())
+    }
+}
+
+/// Which entries to list, filters that are `None` don't apply.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    /// Entries where this user acted or was acted on.
+    pub user_id: Option<Uuid>,
+    pub action: Option<Action>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+/// The entries matching `filter`, newest first, along with how many there are in total.
+pub fn query(filter: &AuditFilter, limit: Option<i64>, offset: Option<i64>, conn: &SqliteConnection)
+    -> QueryResult<(Vec<AuditEntry>, i64)> {
+    use crate::schema::audit_log::dsl;
+    let filtered = || {
+        let mut query = dsl::audit_log.into_boxed();
+        if let Some(user_id) = filter.user_id {
+            let target = user_id.hyphenated().to_string();
+            query = query.filter(dsl::user_id.eq(user_id).or(dsl::target.eq(target)));
+        }
+        if let Some(action) = filter.action {
+            query = query.filter(dsl::action.eq(action.name()));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(dsl::created_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(dsl::created_at.lt(until));
+        }
+        query
+    };
+    let total = filtered().count().get_result::<i64>(conn)?;
+    let mut query = filtered().order(dsl::created_at.desc());
+    // SQLite only accepts an offset after a limit, -1 means no limit
+    if limit.is_some() || offset.is_some() {
+        query = query.limit(limit.unwrap_or(-1)).offset(offset.unwrap_or(0));
+    }
+    Ok((query.load::<AuditEntry>(conn)?, total))
+}
+
+/// Remove entries written before `before`, returns how many there were.
+pub fn prune(before: NaiveDateTime, conn: &SqliteConnection) -> QueryResult<usize> {
+    use crate::schema::audit_log::dsl;
+    diesel::delete(dsl::audit_log.filter(dsl::created_at.lt(before))).execute(conn)
+}
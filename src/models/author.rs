@@ -0,0 +1,92 @@
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::schema::{authors, author_aliases, audiobooks};
+
+/// A person books are attributed to. Books reference authors through the artist tag of their
+/// files, all spellings of a name that were seen are kept as aliases.
+#[table_name="authors"]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Insertable, Serialize)]
+pub struct Author {
+    pub id: Uuid,
+    pub name: String,
+}
+
+#[table_name="author_aliases"]
+#[belongs_to(Author)]
+#[primary_key(normalized_name)]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, Insertable)]
+pub struct AuthorAlias {
+    pub normalized_name: String,
+    pub author_id: Uuid,
+}
+
+/// Reduce a name to a form that is the same for common variants of it.
+/// "Tolkien, J. R. R." and "J.R.R. Tolkien" both become "j r r tolkien".
+pub fn normalize_name(name: &str) -> String {
+    let reordered = match name.find(',') {
+        Some(i) if !name[i + 1..].trim().is_empty() => format!("{} {}", &name[i + 1..], &name[..i]),
+        _ => name.to_owned(),
+    };
+    reordered.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+impl Author {
+    pub fn find(author_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Author>> {
+        authors::table.filter(authors::dsl::id.eq(author_id)).first(conn).optional()
+    }
+
+    /// The author known under `name`, created along with an alias if there is none yet.
+    pub fn for_name(name: &str, conn: &SqliteConnection) -> QueryResult<Author> {
+        let normalized = normalize_name(name);
+        conn.exclusive_transaction(|| {
+            let existing = author_aliases::table
+                .inner_join(authors::table)
+                .filter(author_aliases::dsl::normalized_name.eq(&normalized))
+                .select(authors::all_columns)
+                .first::<Author>(conn)
+                .optional()?;
+            if let Some(author) = existing {
+                return Ok(author);
+            }
+            let author = Author {
+                id: Uuid::new_v4(),
+                name: name.trim().to_owned(),
+            };
+            diesel::insert_into(authors::table).values(&author).execute(conn)?;
+            diesel::insert_into(author_aliases::table).values(&AuthorAlias {
+                normalized_name: normalized,
+                author_id: author.id,
+            }).execute(conn)?;
+            Ok(author)
+        })
+    }
+
+    pub fn aliases(&self, conn: &SqliteConnection) -> QueryResult<Vec<String>> {
+        AuthorAlias::belonging_to(self)
+            .select(author_aliases::dsl::normalized_name)
+            .order(author_aliases::dsl::normalized_name.asc())
+            .load(conn)
+    }
+
+    /// Fold this author into `target`, its aliases and books move over and it is deleted. Callers
+    /// run this in a transaction.
+    pub fn merge_into(self, target: &Author, conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::update(author_aliases::table.filter(author_aliases::dsl::author_id.eq(&self.id)))
+            .set(author_aliases::dsl::author_id.eq(&target.id))
+            .execute(conn)?;
+        diesel::update(audiobooks::table.filter(audiobooks::dsl::author_id.eq(&self.id)))
+            .set(audiobooks::dsl::author_id.eq(&target.id))
+            .execute(conn)?;
+        diesel::delete(authors::table.filter(authors::dsl::id.eq(&self.id))).execute(conn)?;
+        Ok(())
+    }
+}
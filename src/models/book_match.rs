@@ -0,0 +1,40 @@
+use chrono::NaiveDateTime;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::schema::book_matches;
+
+/// What a metadata provider knows about a book beyond its tags, see `worker::lookup`. Title and
+/// description of a match go into the book itself, this keeps the rest.
+#[table_name="book_matches"]
+#[primary_key(audiobook_id)]
+#[belongs_to(Audiobook)]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, Insertable, Serialize)]
+pub struct BookMatch {
+    #[serde(skip_serializing)]
+    pub audiobook_id: Uuid,
+    /// Name of the provider, like `"audible"`.
+    pub provider: String,
+    /// The provider's id of the book.
+    pub external_id: String,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    /// Position in the series, fractions are used for novellas between two books.
+    pub series_position: Option<f64>,
+    pub matched_at: NaiveDateTime,
+}
+
+impl BookMatch {
+    pub fn of(book: &Audiobook, conn: &SqliteConnection) -> QueryResult<Option<BookMatch>> {
+        BookMatch::belonging_to(book).first::<BookMatch>(conn).optional()
+    }
+
+    /// Replace the match of the book.
+    pub fn save(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::replace_into(book_matches::table).values(self).execute(conn)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::NaiveDateTime;
+use chrono::prelude::*;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::user::User;
+use crate::schema::bookmarks;
+
+/// A position in a book a user marked, optionally with a note.
+#[table_name="bookmarks"]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, AsChangeset, Insertable, Serialize)]
+#[changeset_options(treat_none_as_null = "true")]
+#[belongs_to(User)]
+#[belongs_to(Audiobook)]
+pub struct Bookmark {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub audiobook_id: Uuid,
+    pub position_secs: f64,
+    pub note: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Bookmark {
+    pub fn create(user: &User, book: &Audiobook, position_secs: f64, note: Option<String>,
+                  conn: &SqliteConnection) -> QueryResult<Bookmark> {
+        let bookmark = Bookmark {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            audiobook_id: book.id,
+            position_secs,
+            note,
+            created_at: Utc::now().naive_utc(),
+        };
+        diesel::insert_into(bookmarks::table).values(&bookmark).execute(conn)?;
+        Ok(bookmark)
+    }
+
+    /// Bookmarks of a user in a book, ordered by position.
+    pub fn of(user: &User, book: &Audiobook, conn: &SqliteConnection) -> QueryResult<Vec<Bookmark>> {
+        Bookmark::belonging_to(book)
+            .filter(bookmarks::dsl::user_id.eq(&user.id))
+            .order(bookmarks::dsl::position_secs.asc())
+            .load(conn)
+    }
+
+    /// A bookmark of `user` in `book`, `None` if it doesn't exist or belongs to someone else.
+    pub fn find(user: &User, book: &Audiobook, bookmark_id: &Uuid, conn: &SqliteConnection)
+        -> QueryResult<Option<Bookmark>> {
+        Bookmark::belonging_to(book)
+            .filter(bookmarks::dsl::user_id.eq(&user.id))
+            .filter(bookmarks::dsl::id.eq(bookmark_id))
+            .first(conn)
+            .optional()
+    }
+
+    pub fn save(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::update(bookmarks::table.filter(bookmarks::dsl::id.eq(&self.id)))
+            .set(self)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete(self, conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::delete(bookmarks::table.filter(bookmarks::dsl::id.eq(&self.id))).execute(conn)?;
+        Ok(())
+    }
+}
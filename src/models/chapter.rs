@@ -3,6 +3,14 @@ use diesel::prelude::*;
 use crate::models::audiobook::Audiobook;
 use crate::schema::chapters;
 
+/// Chapters are numbered from 0 in the order they are played, which is also the order of their
+/// start times. Lists of chapters are ordered by number.
+///
+/// Clients keep chapter ids, so rescanning a book keeps the id of each chapter that is still
+/// there, see `keep_ids`. Chapters that are new get new ids.
+///
+/// With `scan.chapter_images` the slide shown at the start of a chapter is kept as its image, see
+/// `MediaFile::for_each_slide_at`.
 #[table_name="chapters"]
 #[derive(Debug, Queryable, Associations, Identifiable, Serialize, Insertable)]
 #[belongs_to(Audiobook)]
@@ -11,5 +19,32 @@ pub struct Chapter {
     pub title: Option<String>,
     pub audiobook_id: Uuid,
     pub start_time: f64,
-    pub number: i64
+    pub number: i64,
+    pub has_image: bool,
+}
+
+/// How far apart in seconds the starts of a chapter may be in two scans, remuxing can move them
+/// a little.
+const START_TOLERANCE: f64 = 0.5;
+
+/// Give the chapters of a rescan the ids of the chapters they replace. A chapter that starts
+/// where an old one did is that chapter, even if chapters before it were added or removed.
+/// Chapters that moved take the id of the old chapter with their number, if no other took it.
+pub fn keep_ids(old: &[Chapter], new: &mut [Chapter]) {
+    let mut unused = old.iter().collect::<Vec<&Chapter>>();
+    let mut matched = vec![false; new.len()];
+    for (chapter, matched) in new.iter_mut().zip(matched.iter_mut()) {
+        let same_start = unused.iter()
+            .position(|o| o.number == chapter.number && (o.start_time - chapter.start_time).abs() <= START_TOLERANCE)
+            .or_else(|| unused.iter().position(|o| (o.start_time - chapter.start_time).abs() <= START_TOLERANCE));
+        if let Some(i) = same_start {
+            chapter.id = unused.remove(i).id;
+            *matched = true;
+        }
+    }
+    for (chapter, _) in new.iter_mut().zip(matched.iter()).filter(|(_, matched)| !**matched) {
+        if let Some(i) = unused.iter().position(|o| o.number == chapter.number) {
+            chapter.id = unused.remove(i).id;
+        }
+    }
 }
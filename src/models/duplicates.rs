@@ -0,0 +1,85 @@
+//! Finding books that are probably there twice, e.g. when a collection was put together from
+//! several sources.
+//!
+//! Books are the same if they have the same title and author, compared like in sorting and
+//! author aliases, or if they are the same length to within `LENGTH_TOLERANCE`. Books whose length
+//! is unknown, 0, aren't compared by length. Books with the same hash are the same file and never
+//! reported. Books of a group are ordered by location.
+
+use std::collections::BTreeMap;
+
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::models::audiobook::Audiobook;
+use crate::models::author::normalize_name;
+
+/// Seconds two books may differ in length and still count as the same.
+pub const LENGTH_TOLERANCE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    SameTitle,
+    SameLength,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DuplicateGroup {
+    pub reason: Reason,
+    pub books: Vec<Audiobook>,
+}
+
+/// What title and author come down to, books with the same key have the same title and author.
+fn title_key(book: &Audiobook) -> (String, Option<String>) {
+    let author = match book.author_id {
+        Some(id) => Some(id.hyphenated().to_string()),
+        None => book.artist.as_ref().map(|a| normalize_name(a)),
+    };
+    (book.sort_title.clone(), author)
+}
+
+fn group(reason: Reason, mut books: Vec<&Audiobook>) -> Option<DuplicateGroup> {
+    books.sort_by(|a, b| a.hash.cmp(&b.hash));
+    books.dedup_by(|a, b| a.hash == b.hash);
+    books.sort_by(|a, b| a.location.cmp(&b.location));
+    if books.len() < 2 {
+        return None;
+    }
+    Some(DuplicateGroup { reason, books: books.into_iter().cloned().collect() })
+}
+
+/// Groups of probable duplicates among `books`. Books that have the same title and the same length
+/// are only reported for their title.
+pub fn find(books: &[Audiobook]) -> Vec<DuplicateGroup> {
+    let mut by_title: BTreeMap<(String, Option<String>), Vec<&Audiobook>> = BTreeMap::new();
+    for book in books {
+        by_title.entry(title_key(book)).or_insert_with(Vec::new).push(book);
+    }
+    let mut groups = by_title.into_iter()
+        .filter_map(|(_, books)| group(Reason::SameTitle, books))
+        .collect::<Vec<DuplicateGroup>>();
+
+    let mut by_length = books.iter().filter(|b| b.length > 0.0).collect::<Vec<&Audiobook>>();
+    by_length.sort_by(|a, b| a.length.partial_cmp(&b.length).unwrap_or(std::cmp::Ordering::Equal));
+    let mut start = 0;
+    while start < by_length.len() {
+        let end = by_length[start..].iter()
+            .position(|b| b.length - by_length[start].length > LENGTH_TOLERANCE)
+            .map_or(by_length.len(), |i| start + i);
+        let same_length = by_length[start..end].to_vec();
+        let same_title = same_length.iter().all(|b| title_key(b) == title_key(same_length[0]));
+        if !same_title {
+            groups.extend(group(Reason::SameLength, same_length));
+        }
+        start = end;
+    }
+    groups
+}
+
+/// Probable duplicates among the books that aren't deleted, see `find`.
+pub fn report(conn: &SqliteConnection) -> QueryResult<Vec<DuplicateGroup>> {
+    use crate::schema::audiobooks::dsl;
+    let books = dsl::audiobooks.filter(dsl::deleted.eq(false)).load::<Audiobook>(conn)?;
+    Ok(find(&books))
+}
@@ -1,5 +1,6 @@
 use crate::helpers::uuid::Uuid;
 use chrono::NaiveDateTime;
+use chrono::prelude::*;
 use std::time::SystemTime;
 use diesel;
 use diesel::prelude::*;
@@ -19,7 +20,10 @@ pub struct Library {
     #[serde(skip_serializing)]
     pub is_audiobook_regex: String,
     #[serde(skip_serializing)]
-    pub last_scan: Option<NaiveDateTime>
+    pub last_scan: Option<NaiveDateTime>,
+    /// Set when the library was deleted without purging its books, it is no longer scanned then.
+    #[serde(skip_serializing)]
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
 impl Library {
@@ -30,7 +34,8 @@ impl Library {
                 id: Uuid::new_v4(),
                 location,
                 is_audiobook_regex: audiobook_regex,
-                last_scan: None
+                last_scan: None,
+                deleted_at: None,
             };
             diesel::insert_into(libraries::table)
                 .values(&lib).execute(&*db)?;
@@ -42,4 +47,55 @@ impl Library {
             Ok(lib)
         })
     }
+
+    /// Change where the library lives and which paths in it are books.
+    pub fn update(&mut self, location: Option<String>, audiobook_regex: Option<String>,
+                  db: &db::Connection) -> Result<(), diesel::result::Error> {
+        if let Some(location) = location {
+            self.location = location;
+        }
+        if let Some(regex) = audiobook_regex {
+            self.is_audiobook_regex = regex;
+        }
+        diesel::update(libraries::table.filter(libraries::dsl::id.eq(&self.id)))
+            .set(&*self)
+            .execute(&*db)?;
+        Ok(())
+    }
+
+    /// Remove the library along with its books, their chapters and playstates, its permissions
+    /// and scans. Returns the removed books so their files can be cleaned up.
+    ///
+    /// Without `purge` the books are only marked as deleted and nobody has access anymore, so
+    /// playstates survive and the library can be given back to users later. The library is not
+    /// scanned anymore, scans would bring its books back.
+    ///
+    /// Callers make sure the library isn't being scanned, see `scheduler::ScanClaim`, and run this
+    /// in a transaction.
+    pub fn delete(self, purge: bool, db: &db::Connection) -> Result<Vec<Audiobook>, diesel::result::Error> {
+        use crate::schema::{scan_errors, scans};
+        let books = Audiobook::belonging_to(&self).load::<Audiobook>(&*db)?;
+        let book_ids = books.iter().map(|b| b.id).collect::<Vec<Uuid>>();
+        diesel::delete(library_permissions::table.filter(library_permissions::dsl::library_id.eq(&self.id)))
+            .execute(&*db)?;
+        if !purge {
+            let now = Utc::now().naive_utc();
+            diesel::update(audiobooks::table.filter(audiobooks::dsl::library_id.eq(&self.id)))
+                .set((audiobooks::dsl::deleted.eq(true), audiobooks::dsl::deleted_at.eq(now)))
+                .execute(&*db)?;
+            diesel::update(libraries::table.filter(libraries::dsl::id.eq(&self.id)))
+                .set(libraries::dsl::deleted_at.eq(now))
+                .execute(&*db)?;
+            return Ok(Vec::new());
+        }
+        Audiobook::purge(&book_ids, &*db)?;
+        let scan_ids = scans::table.filter(scans::dsl::library_id.eq(&self.id)).select(scans::dsl::id);
+        diesel::delete(scan_errors::table.filter(scan_errors::dsl::scan_id.eq_any(scan_ids)))
+            .execute(&*db)?;
+        diesel::delete(scans::table.filter(scans::dsl::library_id.eq(&self.id)))
+            .execute(&*db)?;
+        diesel::delete(libraries::table.filter(libraries::dsl::id.eq(&self.id)))
+            .execute(&*db)?;
+        Ok(books)
+    }
 }
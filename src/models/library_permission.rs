@@ -28,5 +28,33 @@ impl LibraryPermission {
             .values(&permission).execute(&*db)?;
         Ok(permission)
     }
-}
 
+    /// Like `permit` but does nothing if the user already has access.
+    pub fn ensure(user: &User, library: &Library, db: &db::Connection) -> Result<Self, diesel::result::Error> {
+        let existing = library_permissions::table
+            .filter(library_permissions::dsl::library_id.eq(&library.id))
+            .filter(library_permissions::dsl::user_id.eq(&user.id))
+            .first::<LibraryPermission>(&*db)
+            .optional()?;
+        match existing {
+            Some(p) => Ok(p),
+            None => Self::permit(user, library, db),
+        }
+    }
+
+    /// Take away a user's access to a library. Returns false if the user had no access.
+    pub fn revoke(user: &User, library: &Library, db: &db::Connection) -> Result<bool, diesel::result::Error> {
+        let deleted = diesel::delete(library_permissions::table
+            .filter(library_permissions::dsl::library_id.eq(&library.id))
+            .filter(library_permissions::dsl::user_id.eq(&user.id)))
+            .execute(&*db)?;
+        Ok(deleted > 0)
+    }
+
+    /// Ids of all users with access to a library.
+    pub fn users_of(library: &Library, db: &db::Connection) -> Result<Vec<Uuid>, diesel::result::Error> {
+        Self::belonging_to(library)
+            .select(library_permissions::dsl::user_id)
+            .load(&*db)
+    }
+}
@@ -0,0 +1,163 @@
+//! Listening time, derived from playstate updates.
+//!
+//! When a playstate moves forward no faster than a client could have played it, the distance is
+//! counted as listened on the day of the update. Jumps further than that are seeks and don't count.
+//! Each event keeps the speed it was played at, so the time spent listening (`real_seconds`) can
+//! be told apart from the content it covered (`seconds`).
+
+use std::collections::BTreeSet;
+
+use chrono::{Duration, NaiveDate};
+use diesel;
+use diesel::dsl::sum;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::playstate::Playstate;
+use crate::models::user::User;
+use crate::schema::listening_events;
+
+/// The fastest playback speed clients offer, with some slack for clock differences.
+pub const MAX_SPEED: f64 = 3.5;
+
+/// Books count as finished once their playstate is this close to the end, in seconds.
+const FINISHED_WITHIN: f64 = 30.0;
+
+#[table_name="listening_events"]
+#[derive(Debug, Insertable)]
+struct NewListeningEvent {
+    user_id: Uuid,
+    audiobook_id: Uuid,
+    day: NaiveDate,
+    seconds: f64,
+    speed: f64,
+}
+
+/// Seconds listened between two playstates of the same book, 0 for seeks and rewinds.
+pub fn listened(previous: &Playstate, current: &Playstate) -> f64 {
+    let progress = current.position - previous.position;
+    let elapsed = (current.timestamp - previous.timestamp).num_milliseconds() as f64 / 1000.0;
+    if progress > 0.0 && elapsed > 0.0 && progress <= elapsed * MAX_SPEED {
+        progress
+    } else {
+        0.0
+    }
+}
+
+/// Count what was listened on the way from `previous` to `current`, call before saving `current`.
+pub fn record(previous: Option<&Playstate>, current: &Playstate, conn: &SqliteConnection) -> QueryResult<()> {
+    let seconds = match previous {
+        Some(p) => listened(p, current),
+        None => 0.0,
+    };
+    if seconds > 0.0 {
+        diesel::insert_into(listening_events::table).values(&NewListeningEvent {
+            user_id: current.user_id,
+            audiobook_id: current.audiobook_id,
+            day: current.timestamp.date(),
+            seconds,
+            speed: current.speed,
+        }).execute(conn)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DayStats {
+    pub day: NaiveDate,
+    pub seconds: f64,
+    pub real_seconds: f64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct BookStats {
+    pub audiobook_id: Uuid,
+    pub seconds: f64,
+    pub real_seconds: f64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Stats {
+    /// Content listened to, regardless of the speed.
+    pub total_seconds: f64,
+    /// Time spent listening, shorter than `total_seconds` when playing faster than normal.
+    pub total_real_seconds: f64,
+    /// Listened during the seven days up to and including today.
+    pub week_seconds: f64,
+    pub week_real_seconds: f64,
+    /// Each of the last seven days, oldest first.
+    pub week: Vec<DayStats>,
+    pub books: Vec<BookStats>,
+    pub books_finished: i64,
+    /// Days in a row with some listening, ending today or yesterday.
+    pub streak_days: i64,
+}
+
+fn streak(days: &BTreeSet<NaiveDate>, today: NaiveDate) -> i64 {
+    let mut day = if days.contains(&today) { today } else { today - Duration::days(1) };
+    let mut streak = 0;
+    while days.contains(&day) {
+        streak += 1;
+        day = day - Duration::days(1);
+    }
+    streak
+}
+
+/// Aggregates of what `user` listened to, `today` is in UTC like the recorded days.
+pub fn stats(user: &User, today: NaiveDate, conn: &SqliteConnection) -> QueryResult<Stats> {
+    use crate::schema::listening_events::dsl;
+    use crate::schema::{audiobooks, playstates};
+
+    let per_day = dsl::listening_events
+        .filter(dsl::user_id.eq(&user.id))
+        .group_by(dsl::day)
+        .select((dsl::day, sum(dsl::seconds), sum(dsl::seconds / dsl::speed)))
+        .order(dsl::day.asc())
+        .load::<(NaiveDate, Option<f64>, Option<f64>)>(conn)?;
+    let books = dsl::listening_events
+        .filter(dsl::user_id.eq(&user.id))
+        .group_by(dsl::audiobook_id)
+        .select((dsl::audiobook_id, sum(dsl::seconds), sum(dsl::seconds / dsl::speed)))
+        .load::<(Uuid, Option<f64>, Option<f64>)>(conn)?;
+    let books_finished = playstates::table.inner_join(audiobooks::table)
+        .filter(playstates::dsl::user_id.eq(&user.id))
+        .filter(audiobooks::dsl::deleted.eq(false))
+        .filter(playstates::dsl::position.ge(audiobooks::dsl::length - FINISHED_WITHIN))
+        .count()
+        .get_result::<i64>(conn)?;
+
+    let week = (0..7).rev()
+        .map(|ago| today - Duration::days(ago))
+        .map(|day| match per_day.iter().find(|(d, _, _)| *d == day) {
+            Some((_, seconds, real_seconds)) => DayStats {
+                day,
+                seconds: seconds.unwrap_or(0.0),
+                real_seconds: real_seconds.unwrap_or(0.0),
+            },
+            None => DayStats { day, seconds: 0.0, real_seconds: 0.0 },
+        })
+        .collect::<Vec<DayStats>>();
+    let days = per_day.iter()
+        .filter(|(_, s, _)| s.unwrap_or(0.0) > 0.0)
+        .map(|(d, _, _)| *d)
+        .collect::<BTreeSet<NaiveDate>>();
+    let mut books = books.into_iter()
+        .map(|(audiobook_id, seconds, real_seconds)| BookStats {
+            audiobook_id,
+            seconds: seconds.unwrap_or(0.0),
+            real_seconds: real_seconds.unwrap_or(0.0),
+        })
+        .collect::<Vec<BookStats>>();
+    books.sort_by(|a, b| b.seconds.partial_cmp(&a.seconds).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(Stats {
+        total_seconds: per_day.iter().filter_map(|(_, s, _)| *s).sum(),
+        total_real_seconds: per_day.iter().filter_map(|(_, _, r)| *r).sum(),
+        week_seconds: week.iter().map(|d| d.seconds).sum(),
+        week_real_seconds: week.iter().map(|d| d.real_seconds).sum(),
+        week,
+        books,
+        books_finished,
+        streak_days: streak(&days, today),
+    })
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::schema::audiobook_metadata;
+
+/// Extra fields of a book as key-value pairs, which keys exist is up to the config file so nobody
+/// needs a migration for their niche field. Values of keys removed from the config stay in the
+/// database but are no longer shown.
+#[table_name="audiobook_metadata"]
+#[primary_key(audiobook_id, key)]
+#[belongs_to(Audiobook)]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, Insertable)]
+pub struct MetadataEntry {
+    pub audiobook_id: Uuid,
+    pub key: String,
+    pub value: String,
+}
+
+/// The values of all `fields` for `book`, `None` for fields that were never set.
+pub fn of(book: &Audiobook, fields: &[String], conn: &SqliteConnection)
+    -> QueryResult<BTreeMap<String, Option<String>>> {
+    let mut values = fields.iter()
+        .map(|f| (f.clone(), None))
+        .collect::<BTreeMap<String, Option<String>>>();
+    for entry in MetadataEntry::belonging_to(book).load::<MetadataEntry>(conn)? {
+        if let Some(value) = values.get_mut(&entry.key) {
+            *value = Some(entry.value);
+        }
+    }
+    Ok(values)
+}
+
+/// Set the given fields of `book`, `None` or an empty value removes a field. Callers run this in a
+/// transaction.
+pub fn update(book: &Audiobook, changes: &BTreeMap<String, Option<String>>, conn: &SqliteConnection)
+    -> QueryResult<()> {
+    use crate::schema::audiobook_metadata::dsl;
+    for (key, value) in changes {
+        diesel::delete(MetadataEntry::belonging_to(book).filter(dsl::key.eq(key))).execute(conn)?;
+        match value.as_ref().map(|v| v.trim()) {
+            Some(v) if !v.is_empty() => {
+                diesel::insert_into(audiobook_metadata::table).values(&MetadataEntry {
+                    audiobook_id: book.id,
+                    key: key.clone(),
+                    value: v.to_owned(),
+                }).execute(conn)?;
+            },
+            _ => (),
+        }
+    }
+    Ok(())
+}
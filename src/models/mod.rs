@@ -4,5 +4,19 @@ pub mod chapter;
 pub mod library;
 pub mod library_permission;
 pub mod playstate;
+pub mod scan;
+pub mod author;
+pub mod bookmark;
+pub mod search;
+pub mod metadata;
+pub mod translation;
+pub mod sync;
+pub mod listening;
+pub mod book_match;
+pub mod series;
+pub mod playstate_import;
+pub mod password_reset;
+pub mod audit_log;
+pub mod duplicates;
 #[cfg(test)]
 pub mod tests;
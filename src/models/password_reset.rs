@@ -0,0 +1,73 @@
+//! Letting users who forgot their password set a new one.
+//!
+//! A reset token is handed out once, by mail or to an admin, and only its hash is stored. Using
+//! it sets the new password and logs the user out everywhere. A token works once and until it
+//! expires, using one also discards the other tokens of the user.
+
+use chrono::{Duration, NaiveDateTime};
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::helpers::clock::Clock;
+use crate::helpers::uuid::Uuid;
+use crate::models::user::User;
+use crate::schema::{api_tokens, password_resets};
+
+#[table_name="password_resets"]
+#[primary_key(token_hash)]
+#[derive(Debug, Clone, Queryable, Identifiable, Insertable)]
+pub struct PasswordReset {
+    pub token_hash: Vec<u8>,
+    pub user_id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+fn hash(token: &str) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token.as_bytes()).as_ref().to_vec()
+}
+
+/// Create a reset token for `user` that is valid for `lifetime`. Expired tokens of all users are
+/// forgotten on the way.
+pub fn create(user: &User, lifetime: Duration, clock: &dyn Clock, conn: &SqliteConnection) -> QueryResult<String> {
+    use crate::schema::password_resets::dsl;
+    diesel::delete(dsl::password_resets.filter(dsl::expires_at.le(clock.now()))).execute(conn)?;
+    let rand = SystemRandom::new();
+    let mut secret: [u8; 24] = [0; 24];
+    rand.fill(&mut secret[..]).expect("Could not generate a reset token.");
+    let token: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+    let now = clock.now();
+    diesel::insert_into(password_resets::table)
+        .values(&PasswordReset {
+            token_hash: hash(&token),
+            user_id: user.id,
+            created_at: now,
+            expires_at: now + lifetime,
+        })
+        .execute(conn)?;
+    Ok(token)
+}
+
+/// Set the password of the user `token` was created for and delete their API tokens. Returns the
+/// user, or `None` if the token is unknown, used or expired. Callers run this in a transaction.
+pub fn consume(token: &str, new_password: &str, clock: &dyn Clock, conn: &SqliteConnection)
+    -> QueryResult<Option<User>> {
+    use crate::schema::password_resets::dsl;
+    let reset = dsl::password_resets
+        .filter(dsl::token_hash.eq(hash(token)))
+        .filter(dsl::expires_at.gt(clock.now()))
+        .first::<PasswordReset>(conn)
+        .optional()?;
+    let reset = match reset {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let mut user = crate::schema::users::table.find(&reset.user_id).first::<User>(conn)?;
+    user.set_password(&new_password, conn)?;
+    diesel::delete(api_tokens::table.filter(api_tokens::dsl::user_id.eq(&user.id))).execute(conn)?;
+    diesel::delete(dsl::password_resets.filter(dsl::user_id.eq(&user.id))).execute(conn)?;
+    Ok(Some(user))
+}
@@ -16,6 +16,10 @@ pub struct Playstate {
     pub user_id: Uuid,
     pub position: f64,
     pub timestamp: NaiveDateTime,
+    /// The token that last updated this playstate, used to tell which device it came from.
+    pub api_token_id: Option<Uuid>,
+    /// Playback speed since the last update, 1.0 is normal speed.
+    pub speed: f64,
 }
 
 impl Playstate {
@@ -27,13 +31,57 @@ impl Playstate {
         Ok(self.clone())
     }
 
+    pub fn of(user: &User, book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Playstate>> {
+        playstates::table
+            .filter(playstates::dsl::user_id.eq(&user.id))
+            .filter(playstates::dsl::audiobook_id.eq(book_id))
+            .first::<Playstate>(conn)
+            .optional()
+    }
+
     pub fn to_api_playstate(&self) -> ApiPlaystate {
         ApiPlaystate {
             audiobook_id: self.audiobook_id,
             position: self.position,
             timestamp: DateTime::<Utc>::from_utc(self.timestamp, Utc),
+            speed: self.speed,
+            device_name: None,
         }
     }
+
+    /// All playstates of a user along with the name of the device that last updated them.
+    pub fn with_device_names(user: &User, conn: &SqliteConnection) -> QueryResult<Vec<ApiPlaystate>> {
+        use crate::schema::api_tokens;
+
+        let states = playstates::table.left_join(api_tokens::table)
+            .filter(playstates::dsl::user_id.eq(&user.id))
+            .select((playstates::all_columns, api_tokens::dsl::device_name.nullable()))
+            .load::<(Playstate, Option<String>)>(conn)?;
+        Ok(states.into_iter().map(|(state, device_name)| {
+            ApiPlaystate {
+                device_name,
+                .. state.to_api_playstate()
+            }
+        }).collect())
+    }
+
+    /// Where and when a user last listened to a book.
+    pub fn last_played(user: &User, book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<ApiPlaystate>> {
+        use crate::schema::api_tokens;
+
+        let state = playstates::table.left_join(api_tokens::table)
+            .filter(playstates::dsl::user_id.eq(&user.id))
+            .filter(playstates::dsl::audiobook_id.eq(book_id))
+            .select((playstates::all_columns, api_tokens::dsl::device_name.nullable()))
+            .first::<(Playstate, Option<String>)>(conn)
+            .optional()?;
+        Ok(state.map(|(state, device_name)| {
+            ApiPlaystate {
+                device_name,
+                .. state.to_api_playstate()
+            }
+        }))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -41,17 +89,29 @@ pub struct ApiPlaystate {
     pub audiobook_id: Uuid,
     pub position: f64,
     pub timestamp: DateTime<Utc>,
+    /// Clients that don't know about speeds play at normal speed.
+    #[serde(default = "normal_speed")]
+    pub speed: f64,
+    /// Device that last updated the playstate, only ever sent to clients.
+    #[serde(default, skip_deserializing)]
+    pub device_name: Option<String>,
+}
+
+fn normal_speed() -> f64 {
+    1.0
 }
 
-use crate::models::user::User;
+use crate::models::user::{User, ApiToken};
 
 impl ApiPlaystate {
-    pub fn to_playstate(&self, user: &User) -> Playstate {
+    pub fn to_playstate(&self, user: &User, token: &ApiToken) -> Playstate {
         Playstate {
             audiobook_id: self.audiobook_id,
             user_id: user.id,
             position: self.position,
             timestamp: self.timestamp.naive_utc(),
+            api_token_id: Some(token.id),
+            speed: self.speed,
         }
     }
 }
@@ -0,0 +1,212 @@
+//! Importing positions kept by other players, for users moving their progress over.
+//!
+//! The input is CSV with a book and a position per line, like apps such as Smart AudioBook Player
+//! can export. Books are given by a path or by title, paths are matched against the location of
+//! the books, titles like browse lists compare them (case, accents and leading articles don't
+//! matter). A book that matches more than one accessible book is left out rather than guessed.
+
+use chrono::Utc;
+use diesel::sqlite::SqliteConnection;
+use diesel::QueryResult;
+
+use crate::helpers::sorting::sort_title;
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::playstate::Playstate;
+use crate::models::user::User;
+
+/// A line of the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    /// Line number, starting at 1.
+    pub line: usize,
+    /// Path or title of the book.
+    pub book: String,
+    /// Seconds into the book.
+    pub position: f64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Skipped {
+    pub line: usize,
+    pub book: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ImportedPlaystate {
+    pub line: usize,
+    pub audiobook_id: Uuid,
+    pub title: String,
+    pub position: f64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Imported {
+    pub imported: Vec<ImportedPlaystate>,
+    pub skipped: Vec<Skipped>,
+}
+
+/// The fields of a CSV line, quotes may contain the separator and `""` stands for a quote.
+fn split_line(line: &str, separator: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            },
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.into_iter().map(|f| f.trim().to_owned()).collect()
+}
+
+/// Seconds like `"1234.5"` or `"1:02:03"`.
+pub fn parse_position(text: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    for part in text.trim().split(':') {
+        let value: f64 = part.trim().parse().ok()?;
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Some(seconds)
+}
+
+/// The rows of `csv` along with the lines that could not be read. The separator is a comma or a
+/// semicolon, whichever the first line has, and a first line without a position is a header.
+pub fn parse(csv: &str) -> (Vec<Row>, Vec<Skipped>) {
+    let separator = match csv.lines().next() {
+        Some(first) if first.contains(';') && !first.contains(',') => ';',
+        _ => ',',
+    };
+    let mut rows = Vec::new();
+    let mut skipped = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_line(line, separator);
+        let book = fields[0].clone();
+        match fields.get(1).and_then(|p| parse_position(p)) {
+            Some(position) if !book.is_empty() => rows.push(Row { line: i + 1, book, position }),
+            None if i == 0 => (),
+            _ => skipped.push(Skipped {
+                line: i + 1,
+                book,
+                reason: "Needs a book and a position in seconds.".to_owned(),
+            }),
+        }
+    }
+    (rows, skipped)
+}
+
+fn components(path: &str) -> Vec<String> {
+    path.split(|c| c == '/' || c == '\\')
+        .filter(|c| !c.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// How many components of `location` appear in `reference` where a book or a file of it would.
+fn path_score(location: &[String], reference: &[String]) -> usize {
+    if location.is_empty() || location.len() > reference.len() {
+        return 0;
+    }
+    // The book itself, or a file inside a multi file book
+    let ends = [reference.len(), reference.len() - 1];
+    for end in ends.iter().filter(|e| **e >= location.len()) {
+        if reference[end - location.len()..*end] == location[..] {
+            return location.len();
+        }
+    }
+    0
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Match<'a> {
+    Book(&'a Audiobook),
+    Nothing,
+    Ambiguous,
+}
+
+fn unique<'a, I: Iterator<Item = &'a Audiobook>>(mut found: I) -> Match<'a> {
+    match (found.next(), found.next()) {
+        (Some(book), None) => Match::Book(book),
+        (None, _) => Match::Nothing,
+        _ => Match::Ambiguous,
+    }
+}
+
+/// The book of `books` that `reference` names.
+pub fn find_book<'a>(books: &'a [Audiobook], reference: &str) -> Match<'a> {
+    let reference_path = components(reference);
+    let best = books.iter().map(|b| path_score(&components(&b.location), &reference_path)).max().unwrap_or(0);
+    if best > 0 {
+        return unique(books.iter().filter(|b| path_score(&components(&b.location), &reference_path) == best));
+    }
+    // A title, or the name of a file or folder named after the book
+    let name = reference_path.last().map(String::as_str).unwrap_or(reference);
+    let stem = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 5 && !name[dot..].contains(' ') => &name[..dot],
+        _ => name,
+    };
+    let key = sort_title(stem);
+    if key.is_empty() {
+        return Match::Nothing;
+    }
+    match unique(books.iter().filter(|b| b.sort_title == key)) {
+        // Titles with a subtitle or series added on one side
+        Match::Nothing if key.chars().count() >= 4 => unique(books.iter().filter(|b| {
+            !b.sort_title.is_empty() && (b.sort_title.contains(&key) || key.contains(&b.sort_title))
+        })),
+        found => found,
+    }
+}
+
+/// Create playstates of `user` for the rows, books they already have a playstate for are left
+/// alone. Positions past the end of a book are moved to its end.
+pub fn import(user: &User, rows: &[Row], api_token_id: Option<Uuid>, conn: &SqliteConnection) -> QueryResult<Imported> {
+    let books = user.accessible_audiobooks(conn)?;
+    let mut result = Imported { imported: Vec::new(), skipped: Vec::new() };
+    let skip = |row: &Row, reason: &str| Skipped { line: row.line, book: row.book.clone(), reason: reason.to_owned() };
+    for row in rows {
+        let book = match find_book(&books, &row.book) {
+            Match::Book(book) => book,
+            Match::Nothing => {
+                result.skipped.push(skip(row, "No book matches."));
+                continue;
+            },
+            Match::Ambiguous => {
+                result.skipped.push(skip(row, "More than one book matches."));
+                continue;
+            },
+        };
+        if Playstate::of(user, &book.id, conn)?.is_some() {
+            result.skipped.push(skip(row, "There already is a playstate for this book."));
+            continue;
+        }
+        let position = row.position.min(book.length);
+        Playstate {
+            audiobook_id: book.id,
+            user_id: user.id,
+            position,
+            timestamp: Utc::now().naive_utc(),
+            api_token_id,
+            speed: 1.0,
+        }.upsert(conn)?;
+        result.imported.push(ImportedPlaystate {
+            line: row.line,
+            audiobook_id: book.id,
+            title: book.title.clone(),
+            position,
+        });
+    }
+    Ok(result)
+}
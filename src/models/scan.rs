@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+use chrono::prelude::*;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use failure::Error;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::library::Library;
+use crate::schema::{scan_errors, scans};
+
+/// Record of a single scan of a library.
+#[table_name="scans"]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, AsChangeset, Insertable, Serialize)]
+#[changeset_options(treat_none_as_null = "true")]
+#[belongs_to(Library)]
+pub struct Scan {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    pub full: bool,
+    pub started_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+    pub error: Option<String>,
+}
+
+impl Scan {
+    pub fn start(library: &Library, full: bool, conn: &SqliteConnection) -> QueryResult<Scan> {
+        let scan = Scan {
+            id: Uuid::new_v4(),
+            library_id: library.id,
+            full,
+            started_at: Utc::now().naive_utc(),
+            finished_at: None,
+            error: None,
+        };
+        diesel::insert_into(scans::table).values(&scan).execute(conn)?;
+        Ok(scan)
+    }
+
+    pub fn finish(&mut self, result: &Result<(), Error>, conn: &SqliteConnection) -> QueryResult<()> {
+        self.finished_at = Some(Utc::now().naive_utc());
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        diesel::update(scans::table.filter(scans::dsl::id.eq(&self.id)))
+            .set(&*self)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Remember the paths that could not be scanned and why.
+    pub fn record_errors(&self, failures: &[(PathBuf, String)], conn: &SqliteConnection) -> QueryResult<()> {
+        let errors = failures.iter().map(|(path, message)| ScanError {
+            id: Uuid::new_v4(),
+            scan_id: self.id,
+            path: path.to_string_lossy().into_owned(),
+            message: message.clone(),
+        }).collect::<Vec<ScanError>>();
+        if !errors.is_empty() {
+            diesel::insert_into(scan_errors::table).values(&errors).execute(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn errors(&self, conn: &SqliteConnection) -> QueryResult<Vec<ScanError>> {
+        ScanError::belonging_to(self)
+            .order(scan_errors::dsl::path.asc())
+            .load(conn)
+    }
+
+    /// The last scan of a library that finished, if any.
+    pub fn last_finished(library: &Library, conn: &SqliteConnection) -> QueryResult<Option<Scan>> {
+        Scan::belonging_to(library)
+            .filter(scans::dsl::finished_at.is_not_null())
+            .order(scans::dsl::started_at.desc())
+            .first(conn)
+            .optional()
+    }
+
+    /// The most recent scans of a library, newest first.
+    pub fn recent(library: &Library, limit: i64, conn: &SqliteConnection) -> QueryResult<Vec<Scan>> {
+        Scan::belonging_to(library)
+            .order(scans::dsl::started_at.desc())
+            .limit(limit)
+            .load(conn)
+    }
+}
+
+/// A path that was skipped during a scan.
+#[table_name="scan_errors"]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, Insertable, Serialize)]
+#[belongs_to(Scan)]
+pub struct ScanError {
+    #[serde(skip_serializing)]
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub scan_id: Uuid,
+    pub path: String,
+    pub message: String,
+}
@@ -0,0 +1,155 @@
+//! Full text search over books, backed by the FTS5 table `search_index`.
+//!
+//! diesel can't describe virtual tables, so the index is only ever accessed with raw SQL from
+//! here. Rows are keyed by the book's id and hold its title, artist, chapter titles and
+//! description. Titles and descriptions of translations are indexed along with the book's own.
+
+use diesel;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::models::translation;
+use crate::models::user::User;
+use crate::schema::{audiobooks, chapters};
+
+/// Marks around matches in snippets, they can't occur in text so they are safe to replace after
+/// escaping.
+const MATCH_START: char = '\u{1}';
+const MATCH_END: char = '\u{2}';
+
+#[derive(QueryableByName)]
+struct Hit {
+    #[sql_type = "Text"]
+    book_id: Uuid,
+    #[sql_type = "Text"]
+    snippet: String,
+}
+
+/// A book found by `search` with an excerpt of where it matched.
+#[derive(Debug, Serialize)]
+pub struct Found {
+    #[serde(flatten)]
+    pub book: Audiobook,
+    /// HTML escaped text around the matches, which are wrapped in `<mark>` elements.
+    pub snippet: String,
+}
+
+fn lines(texts: Vec<Option<String>>) -> String {
+    texts.into_iter()
+        .filter_map(|t| t)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Put the current title, artist, chapters, description and translations of `book` into the index.
+pub fn index_book(book: &Audiobook, conn: &SqliteConnection) -> QueryResult<()> {
+    let chapter_titles = lines(Chapter::belonging_to(book)
+        .select(chapters::dsl::title)
+        .order(chapters::dsl::number.asc())
+        .load::<Option<String>>(conn)?);
+    let translations = translation::of(book, conn)?;
+    let titles = lines(Some(book.title.clone()).into_iter()
+        .chain(translations.iter().map(|t| t.title.clone()))
+        .collect());
+    let descriptions = lines(Some(book.description.clone()).into_iter()
+        .chain(translations.into_iter().map(|t| t.description))
+        .collect());
+    conn.transaction(|| {
+        remove_books(&[book.id], conn)?;
+        diesel::sql_query(
+            "INSERT INTO search_index (book_id, title, artist, chapters, description) VALUES (?, ?, ?, ?, ?)"
+        )
+            .bind::<Text, _>(&book.id)
+            .bind::<Text, _>(&titles)
+            .bind::<Text, _>(book.artist.as_ref().map(String::as_str).unwrap_or(""))
+            .bind::<Text, _>(&chapter_titles)
+            .bind::<Text, _>(&descriptions)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn remove_books(book_ids: &[Uuid], conn: &SqliteConnection) -> QueryResult<()> {
+    for book_id in book_ids {
+        diesel::sql_query("DELETE FROM search_index WHERE book_id = ?")
+            .bind::<Text, _>(book_id)
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Turn user input into an FTS5 query that finds books containing every word, the last one may
+/// be incomplete. Operators in the input are taken literally.
+pub fn fts_query(input: &str) -> Option<String> {
+    let words = input.split_whitespace()
+        .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+        .collect::<Vec<String>>();
+    if words.is_empty() {
+        return None;
+    }
+    Some(format!("{}*", words.join(" ")))
+}
+
+/// Escape `snippet` for HTML and turn the match marks into `<mark>` elements.
+pub fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Books accessible to `user` matching `input`, best matches first. Title matches weigh more than
+/// artist matches, then descriptions and then chapters. Accents don't matter on either side.
+pub fn search(user: &User, input: &str, language: Option<&str>, limit: i64, offset: i64, conn: &SqliteConnection)
+    -> QueryResult<Vec<Found>> {
+    let language = language.map(str::to_lowercase);
+    let query = match fts_query(input) {
+        Some(q) => q,
+        None => return Ok(Vec::new()),
+    };
+    let hits = diesel::sql_query(
+        "SELECT search_index.book_id AS book_id, \
+         snippet(search_index, -1, char(1), char(2), '…', 12) AS snippet \
+         FROM search_index \
+         INNER JOIN audiobooks ON audiobooks.id = search_index.book_id \
+         INNER JOIN library_permissions ON library_permissions.library_id = audiobooks.library_id \
+         WHERE search_index MATCH ? AND audiobooks.deleted = 0 AND library_permissions.user_id = ? \
+         AND (? IS NULL OR audiobooks.language = ? OR audiobooks.language LIKE ? || '-%') \
+         ORDER BY bm25(search_index, 0.0, 10.0, 5.0, 1.0, 2.0), audiobooks.sort_title \
+         LIMIT ? OFFSET ?")
+        .bind::<Text, _>(&query)
+        .bind::<Text, _>(&user.id)
+        .bind::<Nullable<Text>, _>(&language)
+        .bind::<Nullable<Text>, _>(&language)
+        .bind::<Nullable<Text>, _>(&language)
+        .bind::<BigInt, _>(limit)
+        .bind::<BigInt, _>(offset)
+        .load::<Hit>(conn)?;
+
+    let ids = hits.iter().map(|h| h.book_id).collect::<Vec<Uuid>>();
+    let books = audiobooks::table
+        .filter(audiobooks::dsl::id.eq_any(&ids))
+        .load::<Audiobook>(conn)?;
+    let mut found = books.into_iter().filter_map(|book| {
+        let hit = hits.iter().find(|h| h.book_id == book.id)?;
+        Some(Found {
+            snippet: highlight(&hit.snippet),
+            book,
+        })
+    }).collect::<Vec<Found>>();
+    found.sort_by_key(|f| ids.iter().position(|id| *id == f.book.id));
+    Ok(found)
+}
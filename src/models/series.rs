@@ -0,0 +1,161 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::author::normalize_name;
+use crate::schema::series;
+
+/// Books that belong together, like the volumes of "The Expanse". Books reference their series
+/// by `series_id` and are ordered within it by `series_position`.
+///
+/// Series come from the series tag of single file books, the series a metadata provider knows a
+/// book to be part of, or else from folders that contain numbered books.
+#[table_name="series"]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Insertable, Serialize)]
+pub struct Series {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub normalized_name: String,
+}
+
+impl Series {
+    pub fn find(series_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Series>> {
+        series::table.filter(series::dsl::id.eq(series_id)).first(conn).optional()
+    }
+
+    /// The series called `name`, created if there is none yet. Names are compared like author
+    /// names, so "Expanse, The" is the same series as "The Expanse". Callers that may race other
+    /// writers run this in their transaction.
+    pub fn for_name(name: &str, conn: &SqliteConnection) -> QueryResult<Series> {
+        let normalized = normalize_name(name);
+        let existing = series::table
+            .filter(series::dsl::normalized_name.eq(&normalized))
+            .first::<Series>(conn)
+            .optional()?;
+        if let Some(s) = existing {
+            return Ok(s);
+        }
+        let created = Series {
+            id: Uuid::new_v4(),
+            name: name.trim().to_owned(),
+            normalized_name: normalized,
+        };
+        diesel::insert_into(series::table).values(&created).execute(conn)?;
+        Ok(created)
+    }
+
+    /// The series of `books`, in no particular order.
+    pub fn of_books(books: &[Audiobook], conn: &SqliteConnection) -> QueryResult<Vec<Series>> {
+        let ids = books.iter()
+            .filter_map(|b| b.series_id)
+            .collect::<HashSet<Uuid>>()
+            .into_iter()
+            .collect::<Vec<Uuid>>();
+        let mut found = Vec::new();
+        // SQLite limits the number of parameters of a query
+        for some in ids.chunks(500) {
+            found.extend(series::table.filter(series::dsl::id.eq_any(some)).load::<Series>(conn)?);
+        }
+        Ok(found)
+    }
+}
+
+/// A series with its books, or a single book outside of any series.
+#[derive(Debug, Serialize)]
+pub struct Group {
+    pub series: Option<Series>,
+    pub books: Vec<Audiobook>,
+}
+
+/// Group `books` by their series. Each group takes the place of its first book, the books of a
+/// series are ordered by their position, books without one come last.
+pub fn group(books: Vec<Audiobook>, conn: &SqliteConnection) -> QueryResult<Vec<Group>> {
+    let mut found = Series::of_books(&books, conn)?;
+    let mut groups: Vec<Group> = Vec::new();
+    let mut positions: HashMap<Uuid, usize> = HashMap::new();
+    for book in books {
+        let series_id = match book.series_id {
+            Some(id) => id,
+            None => {
+                groups.push(Group { series: None, books: vec![book] });
+                continue;
+            },
+        };
+        if let Some(&i) = positions.get(&series_id) {
+            groups[i].books.push(book);
+            continue;
+        }
+        let series = found.iter().position(|s| s.id == series_id).map(|i| found.swap_remove(i));
+        positions.insert(series_id, groups.len());
+        groups.push(Group { series, books: vec![book] });
+    }
+    for g in groups.iter_mut() {
+        // stable, so books at the same position keep the requested order
+        g.books.sort_by(|a, b| match (a.series_position, b.series_position) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+    }
+    Ok(groups)
+}
+
+const TAG_NAMES: &[&str] = &["series", "mvnm", "movementname"];
+const POSITION_TAG_NAMES: &[&str] = &["series-part", "series_part", "mvin", "movement"];
+
+/// Series name and position from the tags of a file.
+pub fn from_tags<'a, I: IntoIterator<Item = (&'a String, &'a String)>>(tags: I) -> Option<(String, Option<f64>)> {
+    let tags = tags.into_iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim()))
+        .filter(|(_, v)| !v.is_empty())
+        .collect::<Vec<(String, &str)>>();
+    let find = |names: &[&str]| tags.iter().find(|(k, _)| names.contains(&k.as_str())).map(|(_, v)| *v);
+    let name = find(TAG_NAMES)?;
+    Some((name.to_owned(), find(POSITION_TAG_NAMES).and_then(parse_position)))
+}
+
+/// Series name and position from where a book is in its library: a book whose name starts with
+/// a number, like `Expanse/03 Abaddon's Gate.m4b` or `Expanse/Book 3 - Abaddon's Gate`, is that
+/// volume of the series its folder is named after. Books directly in the library are in none.
+pub fn from_location(location: &str) -> Option<(String, f64)> {
+    let path = Path::new(location);
+    let folder = path.parent()?.file_name()?.to_str()?;
+    // Folders of multi file books can have dots in their names, like `Book 2.5 - The Churn`
+    let has_extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.len() <= 4 && e.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or(false);
+    let name = if has_extension { path.file_stem()? } else { path.file_name()? }.to_str()?;
+    let lower = name.to_lowercase();
+    let numbered = ["book", "volume", "vol.", "vol", "band", "part", "teil", "#"].iter()
+        .find(|p| lower.starts_with(*p))
+        .and_then(|p| name.get(p.len()..))
+        .map(str::trim_start)
+        .unwrap_or(name);
+    let digits = numbered.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(numbered.len());
+    let separated = numbered[digits..].chars().next().map(|c| " -_.)".contains(c)).unwrap_or(true);
+    if digits == 0 || !separated {
+        return None;
+    }
+    let position: f64 = numbered[..digits].trim_end_matches('.').parse().ok()?;
+    // Larger numbers are years, like `Orwell/1984.m4b`
+    if position >= 1000.0 {
+        return None;
+    }
+    Some((folder.to_owned(), position))
+}
+
+/// Positions like `"3"`, `"2.5"`, `"3/9"` or `"Book 4"`.
+pub fn parse_position(text: &str) -> Option<f64> {
+    text.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .find(|part| !part.is_empty())
+        .and_then(|part| part.parse().ok())
+}
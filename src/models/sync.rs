@@ -0,0 +1,89 @@
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::models::user::User;
+
+/// What changed for a user since their last sync.
+///
+/// Changed books come with all of their chapters, clients replace the chapters they have for
+/// them. Books in `deleted` were deleted or the user lost access to them.
+#[derive(Debug, Serialize)]
+pub struct Changes {
+    pub books: Vec<Audiobook>,
+    pub chapters: Vec<Chapter>,
+    pub deleted: Vec<Uuid>,
+}
+
+/// Seconds before `since` changes are looked for again. Changes are stamped when they are written
+/// but only seen once their transaction commits, which can be after a sync that started later.
+/// Clients get such books twice, which does no harm as they replace what they have.
+pub const OVERLAP_SECS: i64 = 5 * 60;
+
+/// Ids of the books `user` can currently see.
+fn accessible_ids(user: &User, conn: &SqliteConnection) -> QueryResult<Vec<Uuid>> {
+    use crate::schema::library_permissions::dsl::{library_permissions, user_id};
+    use crate::schema::libraries::dsl::libraries;
+    use crate::schema::audiobooks::dsl::{audiobooks, deleted, id};
+
+    audiobooks.inner_join(libraries.inner_join(library_permissions))
+        .filter(deleted.eq(false))
+        .filter(user_id.eq(&user.id))
+        .select(id)
+        .load(conn)
+}
+
+/// Changes after `since`, less `OVERLAP_SECS`, everything if it is not given.
+pub fn changes_since(user: &User, since: Option<NaiveDateTime>, conn: &SqliteConnection) -> QueryResult<Changes> {
+    use crate::schema::audiobooks::dsl as books;
+    use crate::schema::book_tombstones::dsl as tombstones;
+    use crate::schema::chapters::dsl as chapters_dsl;
+    use crate::schema::library_permissions::dsl::{library_permissions, user_id};
+    use crate::schema::libraries::dsl::libraries;
+    use crate::schema::audiobooks::all_columns;
+
+    let since = since.map(|s| s - Duration::seconds(OVERLAP_SECS));
+    let visible = accessible_ids(user, conn)?;
+    let mut query = books::audiobooks.inner_join(libraries.inner_join(library_permissions))
+        .filter(books::deleted.eq(false))
+        .filter(user_id.eq(&user.id))
+        .select(all_columns)
+        .order((books::sort_title.asc(), books::location.asc()))
+        .into_boxed();
+    if let Some(since) = since {
+        query = query.filter(books::updated_at.gt(since));
+    }
+    let changed = query.load::<Audiobook>(conn)?;
+    // SQLite limits the number of parameters of a query
+    let mut chapters = Vec::new();
+    for some in changed.chunks(500) {
+        chapters.extend(Chapter::belonging_to(some)
+            .order((chapters_dsl::audiobook_id.asc(), chapters_dsl::start_time.asc()))
+            .load::<Chapter>(conn)?);
+    }
+
+    let deleted = match since {
+        Some(since) => {
+            tombstones::book_tombstones
+                .filter(tombstones::deleted_at.gt(since))
+                .filter(tombstones::user_id.is_null().or(tombstones::user_id.eq(&user.id)))
+                .select(tombstones::audiobook_id)
+                .distinct()
+                .order(tombstones::audiobook_id.asc())
+                .load::<Uuid>(conn)?
+                .into_iter()
+                // Books that came back since are in `books` again
+                .filter(|i| !visible.contains(i))
+                .collect()
+        },
+        None => Vec::new(),
+    };
+    Ok(Changes {
+        books: changed,
+        chapters,
+        deleted,
+    })
+}
@@ -6,8 +6,11 @@ use crate::*;
 use crate::models::user::{NewUser, User};
 use crate::models::library::Library;
 use crate::models::library_permission::LibraryPermission;
-use crate::models::audiobook::Audiobook;
-use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::{Audiobook, test_book};
+use crate::models::author::{self, Author};
+use crate::helpers::uuid::{Uuid, SequentialIds};
+use crate::helpers::clock::FixedClock;
+use chrono::{NaiveDate, Duration};
 
 speculate! {
     before {
@@ -25,6 +28,7 @@ speculate! {
                 location: "/foo/bar".to_string(),
                 is_audiobook_regex: ".*".to_string(),
                 last_scan: None,
+                deleted_at: None,
             };
             diesel::insert_into(schema::libraries::table)
                 .values(&accessible_lib).execute(&*db).unwrap();
@@ -34,6 +38,7 @@ speculate! {
                 location: "/foo/baz".to_string(),
                 is_audiobook_regex: ".*".to_string(),
                 last_scan: None,
+                deleted_at: None,
             };
             diesel::insert_into(schema::libraries::table)
                 .values(&inaccessible_lib).execute(&*db).unwrap();
@@ -48,23 +53,49 @@ speculate! {
                     id: Uuid::new_v4(),
                     location: "loc1".to_string(),
                     title: "book 1".to_string(),
+                    sort_title: "book 0000000001".to_string(),
+                    author_id: None,
+                    deleted_at: None,
+                    description: None,
+                    updated_at: None,
+                    series_id: None,
+                    series_position: None,
+                    language: None,
                     artist: Some("artist 1".to_string()),
                     length: 1234.5,
                     library_id: accessible_lib.id.clone(),
                     hash: vec![1, 2, 3],
                     file_extension: ".mp3".to_owned(),
                     deleted: false,
+                    cover_hash: None,
+                    cover_mime: None,
+                    file_mtime: None,
+                    file_size: None,
+                    data_hash: None,
                 },
                 Audiobook {
                     id: Uuid::new_v4(),
                     location: "loc2".to_string(),
                     title: "book 2".to_string(),
+                    sort_title: "book 0000000002".to_string(),
+                    author_id: None,
+                    deleted_at: None,
+                    description: None,
+                    updated_at: None,
+                    series_id: None,
+                    series_position: None,
+                    language: None,
                     artist: None,
                     length: 1232.1,
                     library_id: inaccessible_lib.id,
                     hash: vec![3, 4, 5],
                     file_extension: ".mp3".to_owned(),
                     deleted: false,
+                    cover_hash: None,
+                    cover_mime: None,
+                    file_mtime: None,
+                    file_size: None,
+                    data_hash: None,
                 },
             ];
 
@@ -74,5 +105,186 @@ speculate! {
 
             assert_eq!(user.accessible_libraries(&*db).unwrap(), vec![accessible_lib]);
         }
+
+        it "uses the injected clock and ids" {
+            let time = NaiveDate::from_ymd(2017, 2, 11).and_hms(13, 18, 57);
+            let clock = FixedClock::new(time);
+            let ids = SequentialIds::new();
+            let user = User::create_with(&"some@example.com", &"password", &clock, &ids, &*db).unwrap();
+            assert_eq!(user.created_at, time);
+            assert_eq!(user.updated_at, time);
+            assert_eq!(user.id, Uuid::parse_str("00000000-0000-0000-0000-000000000001").unwrap());
+
+            let token = user.generate_api_token_with(
+                Some("Pixel 7".to_owned()), Duration::days(30), &clock, &ids, &*db
+            ).unwrap();
+            assert_eq!(token.created_at, time);
+            assert_eq!(token.expires_at, Some(time + Duration::days(30)));
+            assert!(!token.is_expired(time + Duration::days(29)));
+            assert!(token.is_expired(time + Duration::days(30)));
+            assert_eq!(token.device_name, Some("Pixel 7".to_owned()));
+            assert_eq!(token.id, Uuid::parse_str("00000000-0000-0000-0000-000000000002").unwrap());
+        }
+    }
+
+    describe "author tests" {
+        it "resolves variants of a name to the same author" {
+            assert_eq!(author::normalize_name("Tolkien, J. R. R."), "j r r tolkien");
+            let first = Author::for_name("J.R.R. Tolkien", &*db).unwrap();
+            let second = Author::for_name("Tolkien, J. R. R.", &*db).unwrap();
+            assert_eq!(first, second);
+            assert_eq!(first.name, "J.R.R. Tolkien");
+        }
+
+        it "merges authors" {
+            let kept = Author::for_name("Ursula K. Le Guin", &*db).unwrap();
+            let merged = Author::for_name("Ursula LeGuin", &*db).unwrap();
+            assert_ne!(kept, merged);
+            let library = Library::create("/foo/bar".to_owned(), ".*".to_owned(), &*db).unwrap();
+            let book = Audiobook {
+                author_id: Some(merged.id),
+                ..test_book(library.id, "earthsea", "A Wizard of Earthsea")
+            };
+            diesel::insert_into(schema::audiobooks::table).values(&book).execute(&*db).unwrap();
+            merged.merge_into(&kept, &*db).unwrap();
+            assert_eq!(Author::for_name("Ursula LeGuin", &*db).unwrap(), kept);
+            assert_eq!(kept.aliases(&*db).unwrap(), vec!["ursula k le guin", "ursula leguin"]);
+            let author_id = schema::audiobooks::table.select(schema::audiobooks::dsl::author_id)
+                .get_result::<Option<Uuid>>(&*db).unwrap();
+            assert_eq!(author_id, Some(kept.id));
+        }
+    }
+
+    describe "purging" {
+        it "purges books deleted before the cutoff" {
+            let library = Library::create("/foo/bar".to_owned(), ".*".to_owned(), &*db).unwrap();
+            let now = NaiveDate::from_ymd(2020, 5, 1).and_hms(12, 0, 0);
+            let book = |location: &str, deleted_at: Option<chrono::NaiveDateTime>| Audiobook {
+                deleted_at,
+                deleted: deleted_at.is_some(),
+                length: 10.0,
+                ..test_book(library.id, location, location)
+            };
+            let books = vec![
+                book("expired", Some(now - Duration::days(40))),
+                book("recent", Some(now - Duration::days(2))),
+                book("present", None),
+            ];
+            diesel::insert_into(schema::audiobooks::table).values(&books).execute(&*db).unwrap();
+
+            let purged = Audiobook::purge_deleted(Some(now - Duration::days(30)), &*db).unwrap();
+            assert_eq!(purged, vec![books[0].clone()]);
+            let left = schema::audiobooks::table.count().get_result::<i64>(&*db).unwrap();
+            assert_eq!(left, 2);
+
+            let purged = Audiobook::purge_deleted(None, &*db).unwrap();
+            assert_eq!(purged, vec![books[1].clone()]);
+        }
+    }
+
+    describe "playstate import" {
+        it "matches books by path and title" {
+            use crate::models::playstate::Playstate;
+            use crate::models::playstate_import::{self, Match};
+            let user = User::create(&"some@example.com", &"password", &*db).unwrap();
+            let library = Library::create("/books".to_owned(), ".*".to_owned(), &*db).unwrap();
+            LibraryPermission::ensure(&user, &library, &*db).unwrap();
+            let book = |location: &str, title: &str| Audiobook {
+                length: 3600.0,
+                ..test_book(library.id, location, title)
+            };
+            let books = vec![
+                book("Herbert/Dune", "Dune"),
+                book("Der Hobbit.m4b", "Der Hobbit"),
+                book("Expanse/01 Leviathan Wakes.m4b", "Leviathan Wakes"),
+                book("Expanse/09 Leviathan Falls.m4b", "Leviathan Falls"),
+            ];
+            diesel::insert_into(schema::audiobooks::table).values(&books).execute(&*db).unwrap();
+
+            assert_eq!(playstate_import::find_book(&books, "/sdcard/Audiobooks/Herbert/Dune/03.mp3"), Match::Book(&books[0]));
+            assert_eq!(playstate_import::find_book(&books, "C:\\Books\\der hobbit.m4b"), Match::Book(&books[1]));
+            assert_eq!(playstate_import::find_book(&books, "hobbit"), Match::Book(&books[1]));
+            assert_eq!(playstate_import::find_book(&books, "Leviathan Wakes (Expanse 1)"), Match::Book(&books[2]));
+            assert_eq!(playstate_import::find_book(&books, "Leviathan"), Match::Ambiguous);
+            assert_eq!(playstate_import::find_book(&books, "Neuromancer"), Match::Nothing);
+
+            let csv = "book;position\n\"Herbert/Dune\";1:00:30\nhobbit;90000\nNeuromancer;10\nbroken\n";
+            let (rows, unreadable) = playstate_import::parse(csv);
+            assert_eq!(rows.len(), 3);
+            assert_eq!(rows[0].position, 3630.0);
+            assert_eq!(unreadable.len(), 1);
+            assert_eq!(unreadable[0].line, 5);
+
+            let imported = playstate_import::import(&user, &rows, None, &*db).unwrap();
+            assert_eq!(imported.imported.len(), 2);
+            assert_eq!(imported.imported[1].position, 3600.0);
+            assert_eq!(imported.skipped[0].book, "Neuromancer");
+            assert_eq!(Playstate::of(&user, &books[0].id, &*db).unwrap().unwrap().position, 3630.0);
+
+            let again = playstate_import::import(&user, &rows[..1], None, &*db).unwrap();
+            assert!(again.imported.is_empty());
+        }
+    }
+
+    describe "duplicates" {
+        it "finds books that are there twice" {
+            use crate::models::duplicates::{self, Reason};
+            let library_id = Uuid::new_v4();
+            let book = |location: &str, title: &str, artist: Option<&str>, length: f64, hash: &str| Audiobook {
+                artist: artist.map(str::to_owned),
+                length,
+                hash: hash.as_bytes().to_vec(),
+                ..test_book(library_id, location, title)
+            };
+            let books = vec![
+                book("mp3/The Hobbit", "The Hobbit", Some("J.R.R. Tolkien"), 39000.0, "a"),
+                book("m4b/Hobbit.m4b", "Hobbit", Some("Tolkien, J. R. R."), 38000.0, "b"),
+                // Another reading
+                book("m4b/Hobbit (Serkis).m4b", "Hobbit", Some("Andy Serkis"), 41000.0, "c"),
+                book("Dune", "Dune", None, 75600.0, "d"),
+                book("Dune (copy)", "Dune Book 1", None, 75600.5, "e"),
+                // The same file in two libraries
+                book("Neuromancer", "Neuromancer", None, 1000.0, "f"),
+                book("other/Neuromancer", "Neuromancer", None, 1000.0, "f"),
+                // Lengths that weren't found out aren't the same length
+                book("unknown/1", "Snow Crash", None, 0.0, "g"),
+                book("unknown/2", "The Diamond Age", None, 0.0, "h"),
+            ];
+
+            let groups = duplicates::find(&books);
+            assert_eq!(groups.len(), 2);
+            assert_eq!(groups[0].reason, Reason::SameTitle);
+            assert_eq!(groups[0].books, vec![books[1].clone(), books[0].clone()]);
+            assert_eq!(groups[1].reason, Reason::SameLength);
+            assert_eq!(groups[1].books, vec![books[3].clone(), books[4].clone()]);
+        }
+    }
+
+    describe "chapters" {
+        it "keep their ids across rescans" {
+            use crate::models::chapter::{self, Chapter};
+            let book_id = Uuid::new_v4();
+            let chapter = |number: i64, start_time: f64| Chapter {
+                id: Uuid::new_v4(),
+                title: None,
+                audiobook_id: book_id,
+                start_time,
+                number,
+                has_image: false,
+            };
+            let old = vec![chapter(0, 0.0), chapter(1, 600.0), chapter(2, 1200.0)];
+
+            // A chapter was added in between, the last one moved a bit
+            let mut new = vec![chapter(0, 0.0), chapter(1, 300.0), chapter(2, 600.0), chapter(3, 1200.2)];
+            let added = new[1].id;
+            chapter::keep_ids(&old, &mut new);
+            assert_eq!(new.iter().map(|c| c.id).collect::<Vec<Uuid>>(), vec![old[0].id, added, old[1].id, old[2].id]);
+
+            // A chapter moved a lot and one was added at the end
+            let mut new = vec![chapter(0, 0.0), chapter(1, 650.0), chapter(2, 1200.0), chapter(3, 1800.0)];
+            let added = new[3].id;
+            chapter::keep_ids(&old, &mut new);
+            assert_eq!(new.iter().map(|c| c.id).collect::<Vec<Uuid>>(), vec![old[0].id, old[1].id, old[2].id, added]);
+        }
     }
 }
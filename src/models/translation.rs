@@ -0,0 +1,87 @@
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::search;
+use crate::schema::audiobook_translations;
+
+/// Title and description of a book in another language than the one it was tagged in.
+///
+/// Books are shown translated to users that set a preferred language, falling back to the tagged
+/// title and description for anything that has no translation.
+#[table_name="audiobook_translations"]
+#[primary_key(audiobook_id, language)]
+#[belongs_to(Audiobook)]
+#[derive(PartialEq, Debug, Clone, Queryable, Identifiable, Associations, Insertable, Serialize)]
+pub struct Translation {
+    #[serde(skip_serializing)]
+    pub audiobook_id: Uuid,
+    /// A language tag like `"de"` or `"pt-BR"`, stored in lower case.
+    pub language: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+/// All translations of `book`, ordered by language.
+pub fn of(book: &Audiobook, conn: &SqliteConnection) -> QueryResult<Vec<Translation>> {
+    use crate::schema::audiobook_translations::dsl;
+    Translation::belonging_to(book)
+        .order(dsl::language.asc())
+        .load::<Translation>(conn)
+}
+
+/// Replace the translation of `book` into `language`, leaving both empty removes it. The book is
+/// indexed again so it can be found by its translations. Callers run this in a transaction.
+pub fn set(book: &Audiobook, language: &str, title: Option<&str>, description: Option<&str>,
+           conn: &SqliteConnection) -> QueryResult<Option<Translation>> {
+    use crate::schema::audiobook_translations::dsl;
+    let language = language.to_lowercase();
+    let non_empty = |v: Option<&str>| v.map(|v| v.trim().to_owned()).filter(|v| !v.is_empty());
+    let translation = Translation {
+        audiobook_id: book.id,
+        language: language.clone(),
+        title: non_empty(title),
+        description: non_empty(description),
+    };
+    diesel::delete(Translation::belonging_to(book).filter(dsl::language.eq(&language))).execute(conn)?;
+    let saved = if translation.title.is_none() && translation.description.is_none() {
+        None
+    } else {
+        diesel::insert_into(audiobook_translations::table).values(&translation).execute(conn)?;
+        Some(translation)
+    };
+    search::index_book(book, conn)?;
+    Ok(saved)
+}
+
+/// Show `books` in `language` where they have a translation. A translation into exactly that
+/// language wins over one that only shares the primary language, e.g. `"pt"` for `"pt-BR"`.
+pub fn localize(books: &mut [Audiobook], language: Option<&str>, conn: &SqliteConnection) -> QueryResult<()> {
+    use crate::schema::audiobook_translations::dsl;
+    let language = match language {
+        Some(l) => l.to_lowercase(),
+        None => return Ok(()),
+    };
+    let primary = language.split('-').next().unwrap_or("").to_owned();
+    let book_ids = books.iter().map(|b| b.id).collect::<Vec<Uuid>>();
+    let translations = dsl::audiobook_translations
+        .filter(dsl::audiobook_id.eq_any(book_ids))
+        .filter(dsl::language.eq(&language).or(dsl::language.eq(&primary)))
+        .load::<Translation>(conn)?;
+    for book in books.iter_mut() {
+        let best = translations.iter()
+            .filter(|t| t.audiobook_id == book.id)
+            .max_by_key(|t| t.language == language);
+        if let Some(t) = best {
+            if let Some(ref title) = t.title {
+                book.title = title.clone();
+            }
+            if let Some(ref description) = t.description {
+                book.description = Some(description.clone());
+            }
+        }
+    }
+    Ok(())
+}
@@ -2,25 +2,27 @@ use uuid;
 use crate::helpers::uuid::Uuid;
 use chrono::NaiveDateTime;
 use chrono::prelude::*;
-use argon2rs::{verifier, Argon2};
+use chrono::Duration;
 use diesel::sqlite::SqliteConnection;
 use diesel::prelude::*;
 use diesel::expression::exists;
-use crate::models::audiobook::Audiobook;
+use crate::models::audiobook::{Audiobook, BookOrder};
 use crate::models::library::Library;
 use crate::models::library_permission::LibraryPermission;
 use std::result::Result as StdResult;
 use diesel;
 use diesel::result::QueryResult;
-use base64;
 use ring::rand::{SystemRandom, SecureRandom};
 use failure::Error;
 
 use crate::schema::{users, api_tokens};
 use crate::schema;
 use crate::helpers::db::DB;
+use crate::helpers::clock::{Clock, SystemClock};
+use crate::helpers::uuid::{IdGen, RandomIds};
+use crate::helpers::password;
 
-#[derive(Identifiable, Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[derive(Identifiable, Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
 #[table_name="users"]
 pub struct User {
     pub id: Uuid,
@@ -29,10 +31,24 @@ pub struct User {
     pub email: String,
     #[serde(skip_serializing)]
     pub password_hash: String,
+    pub is_admin: bool,
+    /// Secret part of the user's feed URLs, podcast clients can't send an Authorization header.
+    #[serde(skip_serializing)]
+    pub feed_token: Option<String>,
+    /// Language tag like `"de"` books are shown in where they have a translation.
+    pub preferred_language: Option<String>,
+    /// Whether the user may download chapter files and zips, streaming is always allowed.
+    pub allow_download: bool,
 }
 
 type Result<T> = StdResult<T, Error>;
 
+/// A `LIKE` pattern for titles containing `search`, wildcards in it are escaped with `\`.
+fn contains_pattern(search: &str) -> String {
+    let escaped = search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct UserLoginToken {
     user_id: Uuid,
@@ -47,17 +63,9 @@ pub enum UserError {
 }
 
 impl User {
+    /// See `helpers::password`.
     pub fn make_password_hash(new_password: &dyn AsRef<str>) -> String {
-        let rand = SystemRandom::new();
-        let mut salt: [u8; 10] = [0; 10];
-        rand.fill(&mut salt[..]);
-        let session = verifier::Encoded::default2i(
-            &new_password.as_ref().as_bytes(),
-            &salt,
-            &[],
-            &[]
-        );
-        base64::encode(&session.to_u8())
+        password::hash(new_password.as_ref())
     }
 
     pub fn accessible_libraries(&self, conn: &SqliteConnection) -> Result<Vec<Library>> {
@@ -74,24 +82,78 @@ impl User {
 
     pub fn accessible_audiobooks(&self, conn: &SqliteConnection)
                 -> QueryResult<Vec<Audiobook>> {
-        use diesel::expression::sql_literal::*;
-        use diesel::sql_types::*;
+        self.find_audiobooks(None, None, BookOrder::Title, None, None, conn)
+    }
+
+    /// Accessible audiobooks with `search` in their title in the given order, skipping `offset`
+    /// and returning at most `limit`. With a `language` only books in it are found, `"de"` also
+    /// finds books in `"de-at"`.
+    pub fn find_audiobooks(&self, search: Option<&str>, language: Option<&str>, order: BookOrder, limit: Option<i64>,
+                           offset: Option<i64>, conn: &SqliteConnection) -> QueryResult<Vec<Audiobook>> {
         use crate::schema::library_permissions::dsl::{library_permissions, user_id as library_permissions_user_id};
-        use crate::schema::libraries::dsl::{libraries, id};
-        use crate::schema::audiobooks::dsl::{audiobooks, library_id, location, deleted};
+        use crate::schema::libraries::dsl::libraries;
+        use crate::schema::audiobooks::dsl::{audiobooks, location, deleted, title, sort_title, artist, file_mtime, length};
+        use crate::schema::audiobooks::dsl::language as book_language;
         use crate::schema::audiobooks::all_columns;
-        use crate::schema::users::dsl::users;
 
-        audiobooks.inner_join(
+        let query = audiobooks.inner_join(
             libraries.inner_join(library_permissions))
             .filter(deleted.eq(false))
             .filter(library_permissions_user_id.eq(&self.id))
             .select(all_columns)
-            .order(location.asc())
-            .get_results::<Audiobook>(&*conn)
+            .into_boxed();
+        let mut query = match order {
+            BookOrder::Title => query.order((sort_title.asc(), location.asc())),
+            // Books without an artist go last
+            BookOrder::Artist => query.order((artist.is_null().asc(), artist.asc(), sort_title.asc(), location.asc())),
+            BookOrder::Recent => query.order((file_mtime.desc(), sort_title.asc(), location.asc())),
+            BookOrder::Length => query.order((length.asc(), sort_title.asc(), location.asc())),
+        };
+        if let Some(search) = search {
+            query = query.filter(title.like(contains_pattern(search)).escape('\\'));
+        }
+        if let Some(l) = language {
+            let l = l.to_lowercase();
+            query = query.filter(book_language.eq(l.clone()).or(book_language.like(format!("{}-%", l))));
+        }
+        // SQLite only accepts an offset after a limit, -1 means no limit
+        if limit.is_some() || offset.is_some() {
+            query = query.limit(limit.unwrap_or(-1)).offset(offset.unwrap_or(0));
+        }
+        query.load::<Audiobook>(conn)
     }
 
+    /// How many books `find_audiobooks` finds for `search` and `language` without a limit.
+    pub fn count_audiobooks(&self, search: Option<&str>, language: Option<&str>, conn: &SqliteConnection)
+        -> QueryResult<i64> {
+        use crate::schema::library_permissions::dsl::{library_permissions, user_id as library_permissions_user_id};
+        use crate::schema::libraries::dsl::libraries;
+        use crate::schema::audiobooks::dsl::{audiobooks, deleted, title};
+        use crate::schema::audiobooks::dsl::language as book_language;
+
+        let mut query = audiobooks.inner_join(
+            libraries.inner_join(library_permissions))
+            .filter(deleted.eq(false))
+            .filter(library_permissions_user_id.eq(&self.id))
+            .into_boxed();
+        if let Some(search) = search {
+            query = query.filter(title.like(contains_pattern(search)).escape('\\'));
+        }
+        if let Some(l) = language {
+            let l = l.to_lowercase();
+            query = query.filter(book_language.eq(l.clone()).or(book_language.like(format!("{}-%", l))));
+        }
+        query.count().get_result(conn)
+    }
+
+    /// A new user with access to all libraries. Callers run this in a transaction.
     pub fn create(email: &dyn AsRef<str>, password: &dyn AsRef<str>, conn: &SqliteConnection) -> Result<User> {
+        Self::create_with(email, password, &SystemClock, &RandomIds, conn)
+    }
+
+    /// Like `create` but takes the clock and id source to use, handy for tests.
+    pub fn create_with(email: &dyn AsRef<str>, password: &dyn AsRef<str>, clock: &dyn Clock, ids: &dyn IdGen,
+                       conn: &SqliteConnection) -> Result<User> {
         use crate::schema::users;
         use crate::schema::users::dsl;
         let new_password_hash = User::make_password_hash(password);
@@ -102,42 +164,140 @@ impl User {
                 user_name: email.as_ref().to_owned()
             }.into());
         }
-        conn.exclusive_transaction(|| -> _ {
-            debug!("Start transaction creating user.");
-            let user = User {
-                id: Uuid::new_v4(),
-                created_at: Utc::now().naive_utc(),
-                updated_at: Utc::now().naive_utc(),
-                email: email.as_ref().to_owned(),
-                password_hash: new_password_hash,
-            };
-            diesel::insert_into(users::table).values(&user).execute(&*conn)?;
-            let libraries: Vec<Library> = schema::libraries::table.load(&*conn)?;
-            for l in &libraries {
-                LibraryPermission::permit(&user, &l, &*conn)?;
-            }
-            debug!("End transaction creating user.");
-            Ok(user)
-        })
+        let now = clock.now();
+        let user = User {
+            id: ids.new_id(),
+            created_at: now,
+            updated_at: now,
+            email: email.as_ref().to_owned(),
+            password_hash: new_password_hash,
+            is_admin: false,
+            feed_token: None,
+            preferred_language: None,
+            allow_download: true,
+        };
+        diesel::insert_into(users::table).values(&user).execute(&*conn)?;
+        let libraries: Vec<Library> = schema::libraries::table.load(&*conn)?;
+        for l in &libraries {
+            LibraryPermission::permit(&user, &l, &*conn)?;
+        }
+        Ok(user)
+    }
+
+    /// Replace the feed token, invalidating all feed URLs handed out before.
+    pub fn regenerate_feed_token(&mut self, conn: &SqliteConnection) -> QueryResult<String> {
+        use crate::schema::users::dsl;
+        let rand = SystemRandom::new();
+        let mut secret: [u8; 24] = [0; 24];
+        rand.fill(&mut secret[..]).expect("Could not generate a feed token.");
+        let token: String = secret.iter().map(|b| format!("{:02x}", b)).collect();
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set(dsl::feed_token.eq(&token))
+            .execute(conn)?;
+        self.feed_token = Some(token.clone());
+        Ok(token)
+    }
+
+    pub fn find_by_feed_token(token: &str, conn: &SqliteConnection) -> QueryResult<Option<User>> {
+        use crate::schema::users::dsl;
+        dsl::users.filter(dsl::feed_token.eq(token))
+            .first::<User>(conn)
+            .optional()
+    }
+
+    pub fn set_admin(&mut self, is_admin: bool, conn: &SqliteConnection) -> QueryResult<()> {
+        use crate::schema::users::dsl;
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set(dsl::is_admin.eq(is_admin))
+            .execute(conn)?;
+        self.is_admin = is_admin;
+        Ok(())
+    }
+
+    pub fn set_allow_download(&mut self, allow_download: bool, conn: &SqliteConnection) -> QueryResult<()> {
+        use crate::schema::users::dsl;
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set(dsl::allow_download.eq(allow_download))
+            .execute(conn)?;
+        self.allow_download = allow_download;
+        Ok(())
+    }
+
+    /// `None` shows books as they were tagged.
+    pub fn set_preferred_language(&mut self, language: Option<String>, conn: &SqliteConnection) -> QueryResult<()> {
+        use crate::schema::users::dsl;
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set(dsl::preferred_language.eq(&language))
+            .execute(conn)?;
+        self.preferred_language = language;
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, new_password: &dyn AsRef<str>, conn: &SqliteConnection) -> QueryResult<()> {
+        use crate::schema::users::dsl;
+        let new_password_hash = User::make_password_hash(new_password);
+        let now = Utc::now().naive_utc();
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set((dsl::password_hash.eq(&new_password_hash), dsl::updated_at.eq(now)))
+            .execute(conn)?;
+        self.password_hash = new_password_hash;
+        self.updated_at = now;
+        Ok(())
+    }
+
+    /// Delete the user along with everything that belongs to them. Callers run this in a transaction.
+    pub fn delete(self, conn: &SqliteConnection) -> QueryResult<()> {
+        use crate::schema::{api_tokens, bookmarks, library_permissions, listening_events, password_resets, playstates,
+                            users};
+        diesel::delete(bookmarks::table.filter(bookmarks::dsl::user_id.eq(&self.id))).execute(conn)?;
+        diesel::delete(api_tokens::table.filter(api_tokens::dsl::user_id.eq(&self.id))).execute(conn)?;
+        diesel::delete(password_resets::table.filter(password_resets::dsl::user_id.eq(&self.id))).execute(conn)?;
+        diesel::delete(library_permissions::table.filter(library_permissions::dsl::user_id.eq(&self.id)))
+            .execute(conn)?;
+        diesel::delete(playstates::table.filter(playstates::dsl::user_id.eq(&self.id))).execute(conn)?;
+        diesel::delete(listening_events::table.filter(listening_events::dsl::user_id.eq(&self.id)))
+            .execute(conn)?;
+        diesel::delete(users::table.filter(users::dsl::id.eq(&self.id))).execute(conn)?;
+        Ok(())
     }
 
     pub fn verify_password(&self, candidate_password: &str) -> bool {
-        let data = base64::decode(&self.password_hash).expect("Malformed hash");
-        let session = verifier::Encoded::from_u8(
-            &data
-        ).expect("Cant load hashing setting.");
-        session.verify(candidate_password.as_bytes())
+        password::verify(&self.password_hash, candidate_password)
     }
 
-    pub fn generate_api_token(&self, db: DB) -> Result<ApiToken> {
+    /// Hash the password again if its hash is of an old kind or was made with other parameters
+    /// than the configured ones. Only call this with the password that was just verified.
+    pub fn upgrade_password_hash(&mut self, password: &str, conn: &SqliteConnection) -> QueryResult<bool> {
+        use crate::schema::users::dsl;
+        if !password::needs_rehash(&self.password_hash) {
+            return Ok(false);
+        }
+        let new_password_hash = password::hash(password);
+        diesel::update(dsl::users.filter(dsl::id.eq(&self.id)))
+            .set(dsl::password_hash.eq(&new_password_hash))
+            .execute(conn)?;
+        self.password_hash = new_password_hash;
+        Ok(true)
+    }
+
+    /// Create a new API token, `device_name` names the client it was handed to.
+    pub fn generate_api_token(&self, device_name: Option<String>, lifetime: Duration, db: DB) -> Result<ApiToken> {
+        self.generate_api_token_with(device_name, lifetime, &SystemClock, &RandomIds, &*db)
+    }
+
+    pub fn generate_api_token_with(&self, device_name: Option<String>, lifetime: Duration, clock: &dyn Clock,
+                                   ids: &dyn IdGen, conn: &SqliteConnection) -> Result<ApiToken> {
+        let now = clock.now();
         let token = ApiToken {
-            id: Uuid::new_v4(),
+            id: ids.new_id(),
             user_id: self.id,
-            created_at: Utc::now().naive_utc(),
+            created_at: now,
+            device_name,
+            expires_at: Some(now + lifetime),
         };
         diesel::insert_into(api_tokens::table)
             .values(&token)
-            .execute(&*db)?;
+            .execute(&*conn)?;
         Ok(token)
     }
 
@@ -155,7 +315,7 @@ impl User {
         }
     }
 
-    pub fn get_book_if_accessible(self, book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Audiobook>> {
+    pub fn get_book_if_accessible(&self, book_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Audiobook>> {
         use diesel::expression::sql_literal::*;
         use diesel::sql_types::*;
         use crate::schema::library_permissions::dsl::{library_permissions, user_id as library_permissions_user_id};
@@ -166,11 +326,40 @@ impl User {
         Ok(audiobooks.inner_join(
                 libraries.inner_join(library_permissions)
             )
-            .filter(library_permissions_user_id.eq(self.id))
+            .filter(library_permissions_user_id.eq(&self.id))
             .filter(audiobook_id.eq(book_id))
             .select(all_columns)
             .get_result::<Audiobook>(&*conn).optional()?)
     }
+
+    /// A book with this cover the user has access to, books in several libraries may share a cover.
+    pub fn get_book_by_cover_if_accessible(&self, cover_hash: &str, conn: &SqliteConnection)
+        -> QueryResult<Option<Audiobook>> {
+        use crate::schema::library_permissions::dsl::{library_permissions, user_id as library_permissions_user_id};
+        use crate::schema::audiobooks::dsl::{audiobooks, cover_hash as audiobook_cover_hash};
+        use crate::schema::audiobooks::all_columns;
+        use crate::schema::libraries::dsl::libraries;
+
+        audiobooks.inner_join(
+                libraries.inner_join(library_permissions)
+            )
+            .filter(library_permissions_user_id.eq(&self.id))
+            .filter(audiobook_cover_hash.eq(cover_hash))
+            .select(all_columns)
+            .first::<Audiobook>(conn).optional()
+    }
+
+    pub fn get_library_if_accessible(&self, library_id: &Uuid, conn: &SqliteConnection) -> QueryResult<Option<Library>> {
+        use crate::schema::library_permissions::dsl::{library_permissions, user_id};
+        use crate::schema::libraries::dsl::{libraries, id};
+        use crate::schema::libraries::all_columns;
+
+        library_permissions.inner_join(libraries)
+            .filter(user_id.eq(&self.id))
+            .filter(id.eq(library_id))
+            .select(all_columns)
+            .get_result::<Library>(conn).optional()
+    }
 }
 
 #[derive(Insertable)]
@@ -186,10 +375,33 @@ pub struct NewApiToken {
     pub user_id: Uuid,
 }
 
-#[derive(Debug, Queryable, Serialize, Insertable)]
+#[derive(Debug, Clone, Queryable, Serialize, Insertable)]
 #[table_name="api_tokens"]
 pub struct ApiToken {
     pub id: Uuid,
     pub user_id: Uuid,
     pub created_at: NaiveDateTime,
+    pub device_name: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl ApiToken {
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        self.expires_at.map(|expiry| expiry <= now).unwrap_or(false)
+    }
+
+    /// Replace this token with a new one for the same device, valid for another `lifetime`.
+    /// Playstates remember which token last updated them, they are moved over to the new token.
+    /// Callers run this in a transaction.
+    pub fn refresh(self, lifetime: Duration, conn: &SqliteConnection) -> Result<ApiToken> {
+        use crate::schema::playstates::dsl as playstates;
+        use crate::schema::api_tokens::dsl;
+        let user = schema::users::table.find(&self.user_id).first::<User>(conn)?;
+        let token = user.generate_api_token_with(self.device_name.clone(), lifetime, &SystemClock, &RandomIds, conn)?;
+        diesel::update(playstates::playstates.filter(playstates::api_token_id.eq(&self.id)))
+            .set(playstates::api_token_id.eq(&token.id))
+            .execute(conn)?;
+        diesel::delete(dsl::api_tokens.filter(dsl::id.eq(&self.id))).execute(conn)?;
+        Ok(token)
+    }
 }
@@ -1,14 +1,20 @@
 use std::io::Cursor;
+use std::time::Duration;
 use failure::Error;
+use log::error as error_log;
 use rocket::Request;
 use rocket::Outcome;
 use rocket::response::{Response, Responder};
 use rocket::request::FromRequest;
 use rocket::http::{Status, ContentType};
 use crate::models::user::UserError;
+use crate::worker::error::WorkerError;
 use uuid;
-use crate::responses::responses::{bad_request, not_found, internal_server_error, conflict};
+use crate::responses::responses::{bad_request, not_found, internal_server_error, conflict, unprocessable_entity,
+                                  service_unavailable};
 use serde_json::error::Error as SerdeError;
+use serde_json::Value;
+use validator::ValidationErrors;
 use diesel;
 
 use crate::config::Config;
@@ -17,6 +23,10 @@ use crate::config::Config;
 pub struct APIError {
     pub(super) message: Option<String>,
     pub(super) error: Option<Error>,
+    /// Problems with individual fields of the input, keyed by field name.
+    pub(super) errors: Option<Value>,
+    /// Sent as `Retry-After` in seconds.
+    pub(super) retry_after: Option<Duration>,
     pub(super) status: Status,
 }
 
@@ -25,6 +35,8 @@ impl APIError {
         Self {
             message: None,
             error: None,
+            errors: None,
+            retry_after: None,
             status,
         }
     }
@@ -38,6 +50,16 @@ impl APIError {
         self.error = Some(err);
         self
     }
+
+    pub fn errors(mut self, errors: Value) -> Self {
+        self.errors = Some(errors);
+        self
+    }
+
+    pub fn retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after = Some(wait);
+        self
+    }
 }
 
 impl From<uuid::parser::ParseError> for APIError {
@@ -52,11 +74,30 @@ impl From<SerdeError> for APIError {
         APIError {
             message: Some(format!("Error parsing input: {}", error)),
             error: Some(Error::from(error)),
+            errors: None,
+            retry_after: None,
             status: Status::BadRequest
         }
     }
 }
 
+/// Lists every invalid field at once so clients don't have to fix their input one field at a time.
+impl From<ValidationErrors> for APIError {
+    fn from(errors: ValidationErrors) -> Self {
+        let fields = errors.field_errors().into_iter()
+            .map(|(field, errors)| {
+                let messages = errors.iter()
+                    .map(|e| e.message.as_ref().map(|m| m.to_string()).unwrap_or_else(|| e.code.to_string()))
+                    .collect::<Vec<String>>();
+                (field.to_string(), json!(messages))
+            })
+            .collect::<serde_json::Map<String, Value>>();
+        unprocessable_entity()
+            .message("Invalid input.")
+            .errors(Value::Object(fields))
+    }
+}
+
 fn backtrace_list(err: &Error) -> Vec<String> {
     format!("{}", err.backtrace())
         .lines()
@@ -72,7 +113,7 @@ impl<'r> Responder<'r> for APIError {
             _ => false,
         };
 
-        let body = match (debug, self.message, &self.error.as_ref()) {
+        let mut body = match (debug, self.message, &self.error.as_ref()) {
             (false, Some(msg), _) => json!({"message": msg}),
             (false, None, _) => json!({}),
             (true, None, err) => json!({
@@ -85,12 +126,20 @@ impl<'r> Responder<'r> for APIError {
                 "backtrace": err.map(backtrace_list),
             })
         };
+        if let Some(errors) = self.errors {
+            body["errors"] = errors;
+        }
 
-        Response::build()
-            .status(self.status)
+        let mut response = Response::build();
+        response.status(self.status)
             .sized_body(Cursor::new(body.to_string()))
-            .header(ContentType::JSON)
-            .ok()
+            .header(ContentType::JSON);
+        if let Some(wait) = self.retry_after {
+            // Round up so clients don't come back a moment too early
+            let seconds = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+            response.raw_header("Retry-After", seconds.to_string());
+        }
+        response.ok()
     }
 }
 
@@ -110,6 +159,31 @@ impl<'a> From<&'a diesel::result::Error> for APIError {
     }
 }
 
+/// Say what failed, "Internal Server Error" alone doesn't help anyone. Which file it was is only
+/// logged, paths on the server are nobody else's business.
+impl<'a> From<&'a WorkerError> for APIError {
+    fn from(error: &WorkerError) -> Self {
+        match *error {
+            WorkerError::AlreadyRunning => conflict().message("A scan of this library is already running."),
+            WorkerError::LibraryDeleted => not_found().message("The library was deleted."),
+            WorkerError::Stopped => service_unavailable().message("The server is shutting down."),
+            WorkerError::File(ref err) => {
+                error_log!("{}", err);
+                internal_server_error().message(&format!("Could not {} a file.", err.step))
+            }
+            _ => internal_server_error()
+        }
+    }
+}
+
+/// What clients may be told about a failed step, `None` if there's nothing to tell.
+pub fn public_message(error: &Error) -> Option<String> {
+    match error.downcast_ref::<WorkerError>() {
+        Some(WorkerError::File(err)) => Some(format!("Could not {} a file.", err.step)),
+        _ => None,
+    }
+}
+
 impl From<Error> for APIError {
     fn from(error: Error) -> Self {
         if let Some(err) = error.downcast_ref::<UserError>() {
@@ -124,10 +198,10 @@ impl From<Error> for APIError {
         if let Some(err) = error.downcast_ref::<diesel::result::Error>() {
             return err.into()
         }
-        APIError {
-            message: None,
-            error: Some(error),
-            status: Status::InternalServerError
+        let response = error.downcast_ref::<WorkerError>().map(APIError::from);
+        match response {
+            Some(response) => response.error(error),
+            None => APIError::new(Status::InternalServerError).error(error),
         }
     }
 }
@@ -60,6 +60,10 @@ pub fn unprocessable_entity() -> APIError {
     APIError::new(Status::UnprocessableEntity).message("Unprocessable Entity")
 }
 
+pub fn too_many_requests() -> APIError {
+    APIError::new(Status::TooManyRequests).message("Too Many Requests")
+}
+
 pub fn internal_server_error() -> APIError {
     APIError::new(Status::InternalServerError).message("Internal Server Error")
 }
@@ -3,6 +3,25 @@ table! {
         id -> Text,
         user_id -> Text,
         created_at -> Timestamp,
+        device_name -> Nullable<Varchar>,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    audiobook_metadata (audiobook_id, key) {
+        audiobook_id -> Text,
+        key -> Varchar,
+        value -> Varchar,
+    }
+}
+
+table! {
+    audiobook_translations (audiobook_id, language) {
+        audiobook_id -> Text,
+        language -> Varchar,
+        title -> Nullable<Varchar>,
+        description -> Nullable<Text>,
     }
 }
 
@@ -17,6 +36,77 @@ table! {
         hash -> Binary,
         file_extension -> Varchar,
         deleted -> Bool,
+        cover_hash -> Nullable<Varchar>,
+        cover_mime -> Nullable<Varchar>,
+        file_mtime -> Nullable<Timestamp>,
+        file_size -> Nullable<BigInt>,
+        data_hash -> Nullable<Binary>,
+        sort_title -> Varchar,
+        author_id -> Nullable<Text>,
+        deleted_at -> Nullable<Timestamp>,
+        description -> Nullable<Text>,
+        updated_at -> Nullable<Timestamp>,
+        series_id -> Nullable<Text>,
+        series_position -> Nullable<Double>,
+        language -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Text,
+        created_at -> Timestamp,
+        action -> Varchar,
+        user_id -> Nullable<Text>,
+        ip -> Nullable<Varchar>,
+        target -> Nullable<Varchar>,
+        details -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    author_aliases (normalized_name) {
+        normalized_name -> Varchar,
+        author_id -> Text,
+    }
+}
+
+table! {
+    authors (id) {
+        id -> Text,
+        name -> Varchar,
+    }
+}
+
+table! {
+    book_matches (audiobook_id) {
+        audiobook_id -> Text,
+        provider -> Varchar,
+        external_id -> Varchar,
+        narrator -> Nullable<Varchar>,
+        series -> Nullable<Varchar>,
+        series_position -> Nullable<Double>,
+        matched_at -> Timestamp,
+    }
+}
+
+table! {
+    book_tombstones (id) {
+        id -> Integer,
+        audiobook_id -> Text,
+        user_id -> Nullable<Text>,
+        deleted_at -> Timestamp,
+    }
+}
+
+table! {
+    bookmarks (id) {
+        id -> Text,
+        user_id -> Text,
+        audiobook_id -> Text,
+        position_secs -> Float8,
+        note -> Nullable<Text>,
+        created_at -> Timestamp,
     }
 }
 
@@ -27,6 +117,7 @@ table! {
         audiobook_id -> Text,
         start_time -> Float8,
         number -> Int8,
+        has_image -> Bool,
     }
 }
 
@@ -36,6 +127,7 @@ table! {
         location -> Text,
         is_audiobook_regex -> Text,
         last_scan -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -46,12 +138,62 @@ table! {
     }
 }
 
+table! {
+    listening_events (id) {
+        id -> Integer,
+        user_id -> Text,
+        audiobook_id -> Text,
+        day -> Date,
+        seconds -> Double,
+        speed -> Double,
+    }
+}
+
+table! {
+    password_resets (token_hash) {
+        token_hash -> Binary,
+        user_id -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
 table! {
     playstates (audiobook_id, user_id) {
         audiobook_id -> Text,
         user_id -> Text,
         position -> Float8,
         timestamp -> Timestamp,
+        api_token_id -> Nullable<Text>,
+        speed -> Float8,
+    }
+}
+
+table! {
+    scan_errors (id) {
+        id -> Text,
+        scan_id -> Text,
+        path -> Varchar,
+        message -> Text,
+    }
+}
+
+table! {
+    scans (id) {
+        id -> Text,
+        library_id -> Text,
+        full -> Bool,
+        started_at -> Timestamp,
+        finished_at -> Nullable<Timestamp>,
+        error -> Nullable<Text>,
+    }
+}
+
+table! {
+    series (id) {
+        id -> Text,
+        name -> Varchar,
+        normalized_name -> Varchar,
     }
 }
 
@@ -62,23 +204,54 @@ table! {
         updated_at -> Timestamp,
         email -> Varchar,
         password_hash -> Varchar,
+        is_admin -> Bool,
+        feed_token -> Nullable<Varchar>,
+        preferred_language -> Nullable<Varchar>,
+        allow_download -> Bool,
     }
 }
 
 joinable!(api_tokens -> users (user_id));
+joinable!(audiobook_metadata -> audiobooks (audiobook_id));
+joinable!(audiobook_translations -> audiobooks (audiobook_id));
 joinable!(audiobooks -> libraries (library_id));
+joinable!(audiobooks -> authors (author_id));
+joinable!(audiobooks -> series (series_id));
+joinable!(author_aliases -> authors (author_id));
+joinable!(book_matches -> audiobooks (audiobook_id));
+joinable!(bookmarks -> audiobooks (audiobook_id));
+joinable!(bookmarks -> users (user_id));
 joinable!(chapters -> audiobooks (audiobook_id));
 joinable!(library_permissions -> libraries (library_id));
 joinable!(library_permissions -> users (user_id));
+joinable!(listening_events -> audiobooks (audiobook_id));
+joinable!(listening_events -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(playstates -> api_tokens (api_token_id));
 joinable!(playstates -> audiobooks (audiobook_id));
 joinable!(playstates -> users (user_id));
+joinable!(scan_errors -> scans (scan_id));
+joinable!(scans -> libraries (library_id));
 
 allow_tables_to_appear_in_same_query!(
     api_tokens,
+    audiobook_metadata,
+    audiobook_translations,
     audiobooks,
+    audit_log,
+    author_aliases,
+    authors,
+    book_matches,
+    book_tombstones,
+    bookmarks,
     chapters,
     libraries,
     library_permissions,
+    listening_events,
+    password_resets,
     playstates,
+    scan_errors,
+    scans,
+    series,
     users,
 );
@@ -1,4 +1,4 @@
-use crate::helpers::db::init_test_db_pool;
+use crate::helpers::db::{init_test_db_pool, Pool};
 use crate::helpers;
 use diesel::prelude::*;
 use crate::models::user::User;
@@ -7,7 +7,11 @@ use rocket::Response;
 use rocket::http::{Status, Method, Header, ContentType};
 use serde_json::{self, Value};
 use crate::worker::scanner::{Scanner, LockingBehavior};
+use crate::worker::scheduler::{self, ScanClaim};
+use crate::worker::janitor;
 use crate::models::library::Library;
+use crate::models::audiobook::Audiobook;
+use crate::models::audit_log;
 use regex::Regex;
 use crate::config;
 
@@ -37,6 +41,64 @@ fn get<'a>(client: &'a Client, url: &'a str, auth: Option<&str>) -> LocalRespons
     }
 }
 
+fn delete<'a>(client: &'a Client, url: &'a str, auth: Option<&str>) -> LocalResponse<'a> {
+    if let Some(token) = auth {
+        client.delete(url)
+            .header(Header::new("Authorization", token.to_owned()))
+            .dispatch()
+    } else {
+        client.delete(url)
+            .dispatch()
+    }
+}
+
+fn login(client: &Client, email: &str, password: &str) -> String {
+    let data = json!({"email": email, "password": password});
+    let mut res = post(client, "/api/auth/login", &data, None);
+    let data: Value = serde_json::from_str(&res.body_string().expect("no body string")).expect("JSON failed");
+    data.get("secret").expect("no auth token").as_str().expect("not valid utf8").to_owned()
+}
+
+/// Create an admin, admin@test.com, and log them in.
+fn admin_token(client: &Client, pool: &Pool) -> String {
+    let mut admin = User::create(&"admin@test.com", &"admin", &*pool.get().unwrap()).unwrap();
+    admin.set_admin(true, &*pool.get().unwrap()).unwrap();
+    login(client, "admin@test.com", "admin")
+}
+
+/// Scan a library holding only test-data/all.m4b, returns it with its book.
+fn scanned_book(pool: &Pool, config: &config::Config) -> (Library, Audiobook) {
+    let library = Library::create("test-data".to_owned(), "^all\\.m4b$".to_owned(), &*pool.get().unwrap()).unwrap();
+    scheduler::run_scan(pool, config, library.clone(), false).unwrap();
+    let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*pool.get().unwrap()).unwrap();
+    (library, book)
+}
+
+/// Answers requests on `listener` with the body of the first route whose pattern is part of the
+/// request line, and 404 if there is none. Stands in for metadata providers.
+fn serve_stub(listener: std::net::TcpListener, routes: Vec<(String, Vec<u8>)>) {
+    use std::io::{BufRead, BufReader, Write};
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let (status, body) = match routes.iter().find(|(pattern, _)| request_line.contains(pattern.as_str())) {
+                Some((_, body)) => ("200 OK", body.clone()),
+                None => ("404 Not Found", Vec::new()),
+            };
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len())
+                .unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+}
+
 speculate! {
     before {
         let pool = init_test_db_pool();
@@ -75,6 +137,37 @@ speculate! {
             assert_eq!(res2.status(), Status::Unauthorized);
         }
 
+        it "slows down password guessing" {
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.auth.login_attempts = 2;
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            let wrong = json!({"email": "test@test.com", "password": "lola"});
+            assert_eq!(post(&client, "/api/auth/login", &wrong, None).status(), Status::Unauthorized);
+            assert_eq!(post(&client, "/api/auth/login", &wrong, None).status(), Status::Unauthorized);
+            let right = json!({"email": "test@test.com", "password": "lol"});
+            let res = post(&client, "/api/auth/login", &right, None);
+            assert_eq!(res.status(), Status::TooManyRequests);
+            assert_eq!(res.headers().get_one("Retry-After"), Some("1"));
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            assert_eq!(post(&client, "/api/auth/login", &right, None).status(), Status::Ok);
+            assert_eq!(post(&client, "/api/auth/login", &wrong, None).status(), Status::Unauthorized);
+        }
+
+        it "upgrades old password hashes" {
+            use argon2rs::verifier;
+            use crate::schema::users::dsl;
+            let legacy = base64::encode(&verifier::Encoded::default2i(b"lol", b"saltsalt10", &[], &[]).to_u8());
+            diesel::update(dsl::users.filter(dsl::id.eq(&user.id)))
+                .set(dsl::password_hash.eq(&legacy))
+                .execute(&*pool.get().unwrap())
+                .unwrap();
+            login(&client, "test@test.com", "lol");
+            let upgraded = dsl::users.find(&user.id).first::<User>(&*pool.get().unwrap()).unwrap();
+            assert!(upgraded.password_hash.starts_with("$argon2id$"));
+            assert!(upgraded.verify_password("lol"));
+            assert_eq!(upgraded.updated_at, user.updated_at);
+        }
+
         it "should not work with a wrong auth token" {
             let res = get(&client, "/api/auth/whoami", Some("secret"));
             assert_eq!(res.status(), Status::BadRequest);
@@ -109,6 +202,30 @@ speculate! {
             assert_eq!(whoami_resp_second.status(), Status::Ok);
         }
 
+        it "should refresh tokens" {
+            let mut res = post(&client, "/api/auth/refresh", &Value::Null, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let new_token = data["secret"].as_str().unwrap().to_owned();
+            assert!(!data["expires_at"].is_null());
+
+            let old_resp = get(&client, "/api/auth/whoami", Some(auth_token));
+            assert_eq!(old_resp.status(), Status::Unauthorized);
+            let new_resp = get(&client, "/api/auth/whoami", Some(&new_token));
+            assert_eq!(new_resp.status(), Status::Ok);
+        }
+
+        it "should reject expired tokens" {
+            use crate::schema::api_tokens::dsl;
+            let yesterday = chrono::Utc::now().naive_utc() - chrono::Duration::days(1);
+            diesel::update(dsl::api_tokens)
+                .set(dsl::expires_at.eq(yesterday))
+                .execute(&*pool.get().unwrap())
+                .unwrap();
+            let res = get(&client, "/api/auth/whoami", Some(auth_token));
+            assert_eq!(res.status(), Status::Unauthorized);
+        }
+
         it "should logout everyone" {
             let second_auth_token = {
                 let data = json!({"email": "test@test.com", "password": "lol"});
@@ -142,17 +259,398 @@ speculate! {
         }
     }
 
+    describe "password reset" {
+        it "sets a new password with a token from an admin" {
+            let admin_token = admin_token(&client, &pool);
+
+            let request = json!({"email": "test@test.com"});
+            // Without [smtp] only admins can hand out tokens
+            assert_eq!(post(&client, "/api/auth/request_reset", &request, None).status(), Status::NotFound);
+            assert_eq!(post(&client, "/api/auth/request_reset", &request, Some(auth_token)).status(), Status::NotFound);
+            let unknown = json!({"email": "nobody@test.com"});
+            assert_eq!(post(&client, "/api/auth/request_reset", &unknown, Some(&admin_token)).status(), Status::NotFound);
+            let mut res = post(&client, "/api/auth/request_reset", &request, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let token = data["token"].as_str().unwrap().to_owned();
+
+            let wrong = json!({"token": "0000", "password": "new"});
+            assert_eq!(post(&client, "/api/auth/reset", &wrong, None).status(), Status::UnprocessableEntity);
+            let reset = json!({"token": token, "password": "new"});
+            assert_eq!(post(&client, "/api/auth/reset", &reset, None).status(), Status::Ok);
+            assert_eq!(get(&client, "/api/auth/whoami", Some(auth_token)).status(), Status::Unauthorized);
+            let old = json!({"email": "test@test.com", "password": "lol"});
+            assert_eq!(post(&client, "/api/auth/login", &old, None).status(), Status::Unauthorized);
+            login(&client, "test@test.com", "new");
+            // Tokens work once
+            let again = json!({"token": token, "password": "newer"});
+            assert_eq!(post(&client, "/api/auth/reset", &again, None).status(), Status::UnprocessableEntity);
+        }
+
+        it "does not accept expired tokens" {
+            use crate::helpers::clock::FixedClock;
+            use crate::models::password_reset;
+            let conn = pool.get().unwrap();
+            let clock = FixedClock::new(chrono::Utc::now().naive_utc() - chrono::Duration::hours(2));
+            let token = password_reset::create(&user, chrono::Duration::hours(1), &clock, &*conn).unwrap();
+            let reset = json!({"token": token, "password": "new"});
+            assert_eq!(post(&client, "/api/auth/reset", &reset, None).status(), Status::UnprocessableEntity);
+            assert_eq!(get(&client, "/api/auth/whoami", Some(auth_token)).status(), Status::Ok);
+        }
+
+        it "limits mailed resets per address and per client" {
+            use crate::config::SmtpConfig;
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.smtp = Some(SmtpConfig { host: "127.0.0.1".to_owned(), port: 1, from: "vorleser@test.com".to_owned() });
+            config.auth.reset_requests_per_hour = 2;
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            let request_from = |ip: &str, address: &str| {
+                client.post("/api/auth/request_reset")
+                    .remote(format!("{}:4000", ip).parse().unwrap())
+                    .header(ContentType::JSON)
+                    .body(json!({"email": address}).to_string())
+                    .dispatch()
+                    .status()
+            };
+            assert_eq!(request_from("10.0.0.1", "test@test.com"), Status::Accepted);
+            assert_eq!(request_from("10.0.0.1", "test@test.com"), Status::Accepted);
+            assert_eq!(request_from("10.0.0.2", "TEST@test.com"), Status::TooManyRequests);
+            assert_eq!(request_from("10.0.0.1", "other@test.com"), Status::TooManyRequests);
+            assert_eq!(request_from("10.0.0.3", "other@test.com"), Status::Accepted);
+        }
+    }
+
+    describe "public status" {
+        it "is off by default" {
+            let res = get(&client, "/api/status", None);
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        it "shows totals and is rate limited" {
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.status.public = true;
+            config.status.requests_per_minute = 2;
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            let mut res = get(&client, "/api/status", None);
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["books"], json!(0).into_inner());
+            assert!(data["version"].is_string());
+            assert_eq!(get(&client, "/api/status", None).status(), Status::Ok);
+            assert_eq!(get(&client, "/api/status", None).status(), Status::TooManyRequests);
+        }
+    }
+
+    describe "quotas" {
+        it "limits requests per user" {
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.auth.requests_per_hour = Some(2);
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            let token = login(&client, "test@test.com", "lol");
+            let res = get(&client, "/api/auth/whoami", Some(&token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.headers().get_one("RateLimit-Limit"), Some("2"));
+            assert_eq!(res.headers().get_one("RateLimit-Remaining"), Some("1"));
+            assert_eq!(get(&client, "/api/libraries", Some(&token)).status(), Status::Ok);
+            let res = get(&client, "/api/auth/whoami", Some(&token));
+            assert_eq!(res.status(), Status::TooManyRequests);
+            assert_eq!(res.headers().get_one("RateLimit-Remaining"), Some("0"));
+            assert!(res.headers().get_one("Retry-After").is_some());
+
+            // Logging in again doesn't start over
+            let other_token = login(&client, "test@test.com", "lol");
+            assert_eq!(get(&client, "/api/auth/whoami", Some(&other_token)).status(), Status::TooManyRequests);
+            User::create(&"other@test.com", &"lol", &*pool.get().unwrap()).unwrap();
+            let other_user_token = login(&client, "other@test.com", "lol");
+            assert_eq!(get(&client, "/api/auth/whoami", Some(&other_user_token)).status(), Status::Ok);
+        }
+
+        it "sends no quota headers by default" {
+            let res = get(&client, "/api/auth/whoami", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert!(res.headers().get_one("RateLimit-Limit").is_none());
+        }
+    }
+
+    describe "admin" {
+        before {
+            let admin_token = admin_token(&client, &pool);
+        }
+
+        it "is forbidden for regular users" {
+            let res = get(&client, "/api/admin/users", Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+
+        it "keeps an audit log" {
+            post(&client, "/api/auth/login", &json!({"email": "test@test.com", "password": "wrong"}), None);
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let url = format!("/api/libraries/{}/permissions/{}", library.id.hyphenated(), user.id.hyphenated());
+            delete(&client, &url, Some(&admin_token));
+
+            let url = format!("/api/admin/audit_log?user_id={}", user.id.hyphenated());
+            let mut res = get(&client, &url, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let actions = data["items"].as_array().unwrap().iter()
+                .map(|e| e["action"].as_str().unwrap())
+                .collect::<Vec<&str>>();
+            assert_eq!(actions, vec!["permission_revoked", "failed_login", "login"]);
+            let admin_id = crate::schema::users::table.select(crate::schema::users::dsl::id)
+                .filter(crate::schema::users::dsl::email.eq("admin@test.com"))
+                .get_result::<helpers::uuid::Uuid>(&*pool.get().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["user_id"], json!(admin_id).into_inner());
+            assert_eq!(data["items"][1]["target"], json!("test@test.com").into_inner());
+
+            let mut res = get(&client, "/api/admin/audit_log?action=failed_login", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], json!(1).into_inner());
+
+            let res = get(&client, "/api/admin/audit_log?action=dancing", Some(&admin_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let res = get(&client, "/api/admin/audit_log", Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+
+        it "audits feed tokens and deleted libraries" {
+            post(&client, "/api/auth/feed_token", &Value::Null, Some(auth_token));
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let url = format!("/api/libraries/{}", library.id.hyphenated());
+            delete(&client, &url, Some(&admin_token));
+
+            let mut res = get(&client, "/api/admin/audit_log?action=feed_token_regenerated", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], json!(1).into_inner());
+            assert_eq!(data["items"][0]["user_id"], json!(user.id).into_inner());
+            let mut res = get(&client, "/api/admin/audit_log?action=library_deleted", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["target"], json!(library.id).into_inner());
+            assert_eq!(data["items"][0]["details"], "purged");
+        }
+
+        it "caps and prunes the audit log" {
+            let long = format!("{}@test.com", "a".repeat(1000));
+            post(&client, "/api/auth/login", &json!({"email": long, "password": "wrong"}), None);
+            let mut res = get(&client, "/api/admin/audit_log?action=failed_login", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["target"].as_str().unwrap().len(), audit_log::MAX_TARGET_LENGTH);
+
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            assert_eq!(janitor::prune_audit_log(&pool, &config).unwrap(), 0);
+            config.retention.audit_log = 0;
+            assert!(janitor::prune_audit_log(&pool, &config).unwrap() > 0);
+            let mut res = get(&client, "/api/admin/audit_log?action=failed_login", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], json!(0).into_inner());
+        }
+
+        it "only believes trusted proxies about client addresses" {
+            let proxy: std::net::SocketAddr = "10.0.0.1:4000".parse().unwrap();
+            let wrong = json!({"email": "test@test.com", "password": "wrong"}).to_string();
+            client.post("/api/auth/login")
+                .remote(proxy)
+                .header(Header::new("X-Real-IP", "192.0.2.7"))
+                .header(ContentType::JSON)
+                .body(wrong.clone())
+                .dispatch();
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.web.trusted_proxies = vec![proxy.ip()];
+            let trusting = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            trusting.post("/api/auth/login")
+                .remote(proxy)
+                .header(Header::new("X-Real-IP", "192.0.2.7"))
+                .header(ContentType::JSON)
+                .body(wrong)
+                .dispatch();
+
+            let mut res = get(&client, "/api/admin/audit_log?action=failed_login", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["ip"], "192.0.2.7");
+            assert_eq!(data["items"][1]["ip"], "10.0.0.1");
+        }
+
+        it "lists users" {
+            let mut res = get(&client, "/api/admin/users", Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"].as_array().unwrap().len(), 2);
+            assert_eq!(data["page"]["total"], 2);
+        }
+
+        it "pages through users" {
+            let mut res = get(&client, "/api/admin/users?limit=1", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["email"], "admin@test.com");
+            assert_eq!(data["page"]["next_offset"], 1);
+            assert!(data["page"]["prev_offset"].is_null());
+
+            let mut res = get(&client, "/api/admin/users?limit=1&offset=1", Some(&admin_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["email"], "test@test.com");
+            assert!(data["page"]["next_offset"].is_null());
+            assert_eq!(data["page"]["prev_offset"], 0);
+        }
+
+        it "deletes users" {
+            let url = format!("/api/admin/users/{}", user.id.hyphenated());
+            let res = delete(&client, &url, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let whoami_resp = get(&client, "/api/auth/whoami", Some(auth_token));
+            assert_eq!(whoami_resp.status(), Status::Unauthorized);
+        }
+
+        it "reports auth cache hits" {
+            get(&client, "/api/auth/whoami", Some(auth_token));
+            get(&client, "/api/auth/whoami", Some(auth_token));
+            let mut res = get(&client, "/api/admin/auth_cache", Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["hits"], 1);
+            assert_eq!(data["misses"], 2);
+            assert_eq!(data["entries"], 2);
+        }
+
+        it "resets passwords" {
+            let url = format!("/api/admin/users/{}/password", user.id.hyphenated());
+            let res = post(&client, &url, &json!({"password": "new"}), Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let res = post(&client, "/api/auth/login", &json!({"email": "test@test.com", "password": "new"}), None);
+            assert_eq!(res.status(), Status::Ok);
+        }
+
+        it "updates libraries" {
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let url = format!("/api/libraries/{}", library.id.hyphenated());
+            let res = client.patch(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"is_audiobook_regex": "^.*\\.mp3$"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            let res = client.patch(url)
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"location": "does/not/exist", "is_audiobook_regex": "("}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+
+        it "deletes libraries" {
+            use crate::schema::libraries::dsl::libraries;
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let url = format!("/api/libraries/{}", library.id.hyphenated());
+            let res = delete(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+            let res = delete(&client, &url, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(libraries.count().get_result::<i64>(&*pool.get().unwrap()).unwrap(), 0);
+        }
+
+        it "purges deleted books on request" {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, _) = scanned_book(&pool, &config);
+            let url = format!("/api/libraries/{}?purge=false", library.id.hyphenated());
+            let res = delete(&client, &url, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+
+            let res = post(&client, "/api/admin/purge_deleted", &Value::Null, Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+            let mut res = post(&client, "/api/admin/purge_deleted", &Value::Null, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["purged"], json!(1).into_inner());
+        }
+
+        it "keeps deleted libraries out of scans" {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, _) = scanned_book(&pool, &config);
+            let url = format!("/api/libraries/{}?purge=false", library.id.hyphenated());
+
+            let claim = ScanClaim::new(&library).unwrap();
+            assert_eq!(delete(&client, &url, Some(&admin_token)).status(), Status::Conflict);
+            drop(claim);
+            assert_eq!(delete(&client, &url, Some(&admin_token)).status(), Status::Ok);
+
+            assert!(scheduler::run_scan(&pool, &config, library.clone(), true).is_err());
+            let mut res = get(&client, "/api/audiobooks", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"].as_array().unwrap().len(), 0);
+            let books = crate::models::audiobook::Audiobook::belonging_to(&library)
+                .load::<crate::models::audiobook::Audiobook>(&*pool.get().unwrap())
+                .unwrap();
+            assert_eq!(books.len(), 1);
+            assert!(books[0].deleted);
+        }
+
+        it "restores books whose files came back" {
+            let dir = std::env::temp_dir().join("vorleser-tests").join("restore");
+            std::fs::remove_dir_all(&dir).ok();
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::copy("test-data/1.mp3", dir.join("book.mp3")).unwrap();
+            let library = Library::create(dir.to_string_lossy().into_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let book = user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().remove(0);
+            let url = format!("/api/audiobooks/{}/restore", book.id.hyphenated());
+            let res = post(&client, &url, &Value::Null, Some(&admin_token));
+            assert_eq!(res.status(), Status::Conflict);
+
+            let away = dir.with_file_name("restore-away.mp3");
+            std::fs::rename(dir.join("book.mp3"), &away).unwrap();
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            assert!(user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().is_empty());
+            let res = post(&client, &url, &Value::Null, Some(&admin_token));
+            assert_eq!(res.status(), Status::Conflict);
+
+            std::fs::rename(&away, dir.join("book.mp3")).unwrap();
+            let res = post(&client, &url, &Value::Null, Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+            let mut res = post(&client, &url, &Value::Null, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["id"], json!(book.id));
+            assert_eq!(user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().len(), 1);
+        }
+
+        it "grants and revokes access to libraries" {
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let url = format!("/api/libraries/{}/permissions/{}", library.id.hyphenated(), user.id.hyphenated());
+            let res = delete(&client, &url, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, "/api/libraries", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 0);
+            let res = delete(&client, &url, Some(&admin_token));
+            assert_eq!(res.status(), Status::NotFound);
+
+            let res = client.put(url)
+                .header(Header::new("Authorization", admin_token.clone()))
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, "/api/libraries", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 1);
+        }
+
+        it "lists every invalid field" {
+            let mut res = post(&client, "/api/admin/users", &json!({"email": "nope", "password": ""}), Some(&admin_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["errors"]["email"].is_array());
+            assert!(data["errors"]["password"].is_array());
+        }
+    }
+
     describe "read_books_from_api" {
         before {
             let path = "data";
             let regex = "^[^/]+$";
             let mut library = Library::create(path.to_owned(), regex.to_owned(), &*pool.get().unwrap()).unwrap();
-            let mut scanner = Scanner {
-                regex: Regex::new(regex).unwrap(),
-                library: library,
-                pool: pool.clone(),
-                config: config::load_config_from_path(&"test-data/test-config.toml").unwrap()
-            };
+            let mut scanner = Scanner::new(
+                pool.clone(),
+                library,
+                config::load_config_from_path(&"test-data/test-config.toml").unwrap()
+            );
             scanner.incremental_scan(LockingBehavior::Dont);
         }
 
@@ -161,6 +659,1338 @@ speculate! {
             println!("Libraries: {:?}", res.body_string());
             assert_eq!(res.status(), Status::Ok);
         }
+
+        it "validates pagination parameters" {
+            let res = get(&client, "/api/audiobooks?limit=10&offset=5", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, "/api/audiobooks?limit=0&offset=-1", Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["errors"]["limit"].is_array());
+            assert!(data["errors"]["offset"].is_array());
+        }
+    }
+
+    describe "feeds" {
+        before {
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let mut res = get(&client, "/api/auth/feed_token", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let feed_token = data["feed_token"].as_str().unwrap().to_owned();
+        }
+
+        it "serves library feeds" {
+            let url = format!("/feeds/{}/libraries/{}", feed_token, library.id.hyphenated());
+            let mut res = get(&client, &url, None);
+            assert_eq!(res.status(), Status::Ok);
+            assert!(res.body_string().unwrap().contains("<rss"));
+        }
+
+        it "links to the configured address unless a trusted proxy forwarded the request" {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+            let url = format!("/feeds/{}/libraries/{}", feed_token, library.id.hyphenated());
+            let mut res = client.get(url)
+                .header(Header::new("X-Forwarded-Proto", "https"))
+                .header(Header::new("Host", "evil.example"))
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            let body = res.body_string().unwrap();
+            assert!(body.contains(&format!("http://localhost:8000/feeds/{}/data/{}", feed_token, book.id.hyphenated())));
+            assert!(!body.contains("evil.example"));
+        }
+
+        it "rejects unknown feed tokens" {
+            let url = format!("/feeds/nope/libraries/{}", library.id.hyphenated());
+            let res = get(&client, &url, None);
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        it "are only for users who may download" {
+            let mut blocked = user.clone();
+            blocked.set_allow_download(false, &*pool.get().unwrap()).unwrap();
+            let url = format!("/feeds/{}/libraries/{}", feed_token, library.id.hyphenated());
+            assert_eq!(get(&client, &url, None).status(), Status::Forbidden);
+            let url = format!("/feeds/{}/data/{}.mp3", feed_token, helpers::uuid::Uuid::new_v4().hyphenated());
+            assert_eq!(get(&client, &url, None).status(), Status::Forbidden);
+        }
+
+        it "invalidates old feed urls" {
+            post(&client, "/api/auth/feed_token", &Value::Null, Some(auth_token));
+            let url = format!("/feeds/{}/libraries/{}", feed_token, library.id.hyphenated());
+            let res = get(&client, &url, None);
+            assert_eq!(res.status(), Status::NotFound);
+        }
+    }
+
+    describe "scans" {
+        before {
+            let library = Library::create("data".to_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+        }
+
+        it "records scans" {
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let url = format!("/api/libraries/{}/scans", library.id.hyphenated());
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let scans = data.as_array().unwrap();
+            assert_eq!(scans.len(), 1);
+            assert!(!scans[0]["finished_at"].is_null());
+            assert!(scans[0]["error"].is_null());
+        }
+
+        it "reports files it could not scan" {
+            let dir = std::env::temp_dir().join("vorleser-tests").join("scan-report");
+            std::fs::remove_dir_all(&dir).ok();
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::copy("test-data/1.mp3", dir.join("good.mp3")).unwrap();
+            std::fs::write(dir.join("broken.mp3"), b"this is not an mp3 file").unwrap();
+            let library = Library::create(dir.to_string_lossy().into_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let url = format!("/api/libraries/{}/scan_report", library.id.hyphenated());
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            assert_eq!(user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().len(), 1);
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["error"].is_null());
+            let errors = data["errors"]["items"].as_array().unwrap();
+            assert_eq!(data["errors"]["page"]["total"], 1);
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0]["path"].as_str().unwrap().ends_with("broken.mp3"));
+            assert!(!errors[0]["message"].as_str().unwrap().is_empty());
+        }
+
+        it "reports injected faults instead of failing" {
+            use crate::worker::faults::{Faults, Inject};
+            let library = Library::create("test-data".to_owned(), "^[^/]+\\.mp3$".to_owned(), &*pool.get().unwrap()).unwrap();
+            {
+                let _faults = Inject::apply(Faults { io: 1.0, ffmpeg: 1.0, slow_reads: 0 });
+                scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            }
+            assert!(user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().is_empty());
+            let url = format!("/api/libraries/{}/scan_report", library.id.hyphenated());
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["errors"]["page"]["total"].as_i64().unwrap() > 0);
+
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            assert!(!user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().is_empty());
+        }
+
+        it "keeps books moved to another library" {
+            let dir = std::env::temp_dir().join("vorleser-tests").join("library-move");
+            std::fs::remove_dir_all(&dir).ok();
+            std::fs::create_dir_all(dir.join("a")).unwrap();
+            std::fs::create_dir_all(dir.join("b")).unwrap();
+            std::fs::copy("test-data/1.mp3", dir.join("a").join("book.mp3")).unwrap();
+            let first = Library::create(dir.join("a").to_string_lossy().into_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let second = Library::create(dir.join("b").to_string_lossy().into_owned(), "^[^/]+$".to_owned(), &*pool.get().unwrap()).unwrap();
+            scheduler::run_scan(&pool, &config, first.clone(), false).unwrap();
+            let book = user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().remove(0);
+            let url = format!("/api/audiobooks/{}/bookmarks", book.id.hyphenated());
+            let res = post(&client, &url, &json!({"position_secs": 12.5}), Some(auth_token));
+            assert_eq!(res.status(), Status::Created);
+
+            std::fs::rename(dir.join("a").join("book.mp3"), dir.join("b").join("moved.mp3")).unwrap();
+            scheduler::run_scan(&pool, &config, second.clone(), false).unwrap();
+            scheduler::run_scan(&pool, &config, first.clone(), false).unwrap();
+            let books = user.accessible_audiobooks(&*pool.get().unwrap()).unwrap();
+            assert_eq!(books.len(), 1);
+            assert_eq!(books[0].id, book.id);
+            assert_eq!(books[0].library_id, second.id);
+            assert_eq!(books[0].location, "moved.mp3");
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 1);
+
+            let mut res = get(&client, &format!("/data/{}", book.id.hyphenated()), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.body_bytes().unwrap(), std::fs::read(dir.join("b").join("moved.mp3")).unwrap());
+        }
+
+        it "refuses to start a second scan of the same library" {
+            let admin_token = admin_token(&client, &pool);
+            let claim = ScanClaim::new(&library).unwrap();
+            let url = format!("/api/libraries/{}/scan", library.id.hyphenated());
+            let res = post(&client, &url, &Value::Null, Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+            let res = post(&client, &url, &Value::Null, Some(&admin_token));
+            assert_eq!(res.status(), Status::Conflict);
+
+            let url = format!("/api/libraries/{}/scan_status", library.id.hyphenated());
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["scanning"], json!(true).into_inner());
+            assert!(data["throttle"]["max_hash_rate"].is_null());
+            assert_eq!(data["throttle"]["pause_between_books"], json!(0).into_inner());
+        }
+
+        it "marks libraries whose first scan is running" {
+            let initial_scan = || {
+                let mut res = get(&client, "/api/libraries", Some(auth_token));
+                let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+                data[0]["initial_scan"].as_bool().unwrap()
+            };
+            assert!(!initial_scan());
+            let claim = ScanClaim::new(&library).unwrap();
+            assert!(initial_scan());
+            drop(claim);
+
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let _claim = ScanClaim::new(&library).unwrap();
+            assert!(!initial_scan());
+        }
+
+        it "reports how far a scan got" {
+            let url = format!("/api/libraries/{}/scan_status", library.id.hyphenated());
+            let claim = ScanClaim::new(&library).unwrap();
+            scheduler::update_progress(&library.id, |p| {
+                p.discovered = 3;
+                p.processed = 1;
+                p.current = Some("b/book.mp3".to_owned());
+            });
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["progress"], json!({
+                "discovered": 3, "processed": 1, "errored": 0, "current": "b/book.mp3"
+            }).into_inner());
+
+            drop(claim);
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["scanning"], json!(false).into_inner());
+            assert!(data["progress"].is_null());
+        }
+    }
+
+    describe "rescans" {
+        before {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+            let admin_token = admin_token(&client, &pool);
+        }
+
+        it "rescans selected books" {
+            let unknown = helpers::uuid::Uuid::new_v4();
+            let data = json!({"ids": [book.id, unknown]});
+            let mut res = post(&client, "/api/audiobooks/rescan", &data, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data[0]["error"].is_null());
+            assert!(!data[1]["error"].is_null());
+        }
+
+        it "tells which step failed but not where the book is" {
+            use crate::worker::faults::{Faults, Inject};
+            let _faults = Inject::apply(Faults { io: 1.0, ffmpeg: 1.0, slow_reads: 0 });
+            let mut res = post(&client, "/api/audiobooks/rescan", &json!({"ids": [book.id]}), Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let error = data[0]["error"].as_str().unwrap();
+            assert!(error.starts_with("Could not "));
+            assert!(!error.contains("test-data"));
+        }
+
+        it "needs at least one id" {
+            let res = post(&client, "/api/audiobooks/rescan", &json!({"ids": []}), Some(&admin_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+
+        it "is forbidden for regular users" {
+            let res = post(&client, "/api/audiobooks/rescan", &json!({"ids": [book.id]}), Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+    }
+
+    describe "streaming" {
+        before {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+            let url = format!("/data/{}", book.id.hyphenated());
+            let original = std::fs::read(format!("data/{}.{}", book.id.hyphenated(), book.file_extension)).unwrap();
+        }
+
+        it "fails cleanly when reads or ffmpeg fail" {
+            use crate::worker::faults::{Faults, Inject};
+            let _faults = Inject::apply(Faults { io: 1.0, ffmpeg: 1.0, slow_reads: 0 });
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::InternalServerError);
+            let res = get(&client, &format!("/api/audiobooks/{}/chapters/1/file", book.id.hyphenated()), Some(auth_token));
+            assert_eq!(res.status(), Status::InternalServerError);
+        }
+
+        it "tells the checksum of downloads" {
+            use crate::worker::hashing;
+            use crate::api::ranged_file::digest_header;
+            let sha256 = hashing::checksum_bytes(&original);
+            let mut res = get(&client, &format!("/api/audiobooks/{}/checksum", book.id.hyphenated()), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["sha256"].as_str(), Some(&*hashing::to_hex(&sha256)));
+            assert_eq!(data["size"].as_u64(), Some(original.len() as u64));
+
+            // Partial responses carry the hash of the whole file
+            let res = client.get(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("Range", "bytes=100-199"))
+                .dispatch();
+            assert_eq!(res.status(), Status::PartialContent);
+            assert_eq!(res.headers().get_one("Digest"), Some(&*digest_header(&sha256)));
+
+            let other = format!("/api/audiobooks/{}/checksum", helpers::uuid::Uuid::new_v4().hyphenated());
+            assert_eq!(get(&client, &other, Some(auth_token)).status(), Status::NotFound);
+        }
+
+        it "serves covers with etags and in smaller sizes" {
+            use crate::schema::audiobooks::dsl;
+            use crate::worker::layout;
+            use crate::worker::mediafile::MediaFile;
+            let conn = pool.get().unwrap();
+            let cover_url = format!("/api/audiobooks/{}/cover", book.id.hyphenated());
+            diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                .set(dsl::cover_hash.eq(None::<String>))
+                .execute(&*conn).unwrap();
+            assert_eq!(get(&client, &cover_url, Some(auth_token)).status(), Status::NotFound);
+
+            let cover = MediaFile::read_file(std::path::Path::new("test-data/2.mp3")).unwrap().get_coverart().unwrap().unwrap();
+            let path = layout::cover_path(&config.data_directory, &book.id);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &cover.data).unwrap();
+            diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                .set((dsl::cover_hash.eq(cover.checksum()), dsl::cover_mime.eq("image/jpeg")))
+                .execute(&*conn).unwrap();
+
+            let mut res = get(&client, &cover_url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.content_type(), Some(ContentType::JPEG));
+            let etag = res.headers().get_one("ETag").unwrap().to_owned();
+            assert_eq!(etag, format!("\"{}\"", cover.checksum()));
+            assert_eq!(res.body_bytes().unwrap(), cover.data);
+            let res = client.get(cover_url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("If-None-Match", etag.clone()))
+                .dispatch();
+            assert_eq!(res.status(), Status::NotModified);
+
+            let mut res = get(&client, &format!("{}?size=small", cover_url), Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_ne!(res.headers().get_one("ETag").unwrap(), etag);
+            let content_type = res.content_type();
+            let small = res.body_bytes().unwrap();
+            if crate::worker::thumbnails::is_webp(&small) {
+                assert_eq!(content_type, Some(ContentType::WEBP));
+            } else {
+                assert_eq!(content_type, Some(ContentType::JPEG));
+                assert!(small.starts_with(&[0xff, 0xd8]));
+            }
+            let res = get(&client, &format!("{}?size=huge", cover_url), Some(auth_token));
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+
+        it "only splits books into mp3 or opus files" {
+            let chapter = format!("/api/audiobooks/{}/chapters/1/file?format=flac", book.id.hyphenated());
+            assert_eq!(get(&client, &chapter, Some(auth_token)).status(), Status::BadRequest);
+            let zip = format!("/api/audiobooks/{}/chapters.zip?format=opus", book.id.hyphenated());
+            let mut res = get(&client, &zip, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            // Stored zip entries carry their names in the clear
+            let archive = res.body_bytes().unwrap();
+            assert!(archive.windows(5).any(|name| name == b".opus"));
+        }
+
+        it "lets admins take away downloads but not streaming" {
+            let admin_token = admin_token(&client, &pool);
+            let res = client.put(format!("/api/admin/users/{}/allow_download", user.id.hyphenated()))
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"allow_download": false}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+
+            let mut res = get(&client, "/api/auth/whoami", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["allow_download"], false);
+            assert_eq!(get(&client, &url, Some(auth_token)).status(), Status::Ok);
+            let chapter = format!("/api/audiobooks/{}/chapters/1/file", book.id.hyphenated());
+            assert_eq!(get(&client, &chapter, Some(auth_token)).status(), Status::Forbidden);
+            let zip = format!("/api/audiobooks/{}/chapters.zip", book.id.hyphenated());
+            assert_eq!(get(&client, &zip, Some(auth_token)).status(), Status::Forbidden);
+        }
+
+        it "reassembles a book from overlapping ranges" {
+            let size = original.len();
+            let ranges = vec![
+                format!("bytes=0-{}", size / 3),
+                format!("bytes={}-{}", size / 4, size / 2),
+                format!("bytes={}-{}", size / 2 - 100, size / 2 + 100),
+                format!("bytes={}-", size / 2),
+            ];
+            let mut stream: Vec<u8> = Vec::new();
+            for range in ranges {
+                let mut res = client.get(url.clone())
+                    .header(Header::new("Authorization", auth_token.to_owned()))
+                    .header(Header::new("Range", range.clone()))
+                    .dispatch();
+                assert_eq!(res.status(), Status::PartialContent);
+                let content_range = res.headers().get_one("Content-Range").unwrap().to_owned();
+                let from = content_range["bytes ".len()..].split('-').next().unwrap().parse::<usize>().unwrap();
+                let body = res.body_bytes().unwrap();
+                // Overlapping parts have to agree with what was received before
+                let overlap = stream.len().saturating_sub(from).min(body.len());
+                assert_eq!(&stream[from..from + overlap], &body[..overlap], "{}", range);
+                stream.extend_from_slice(&body[overlap..]);
+            }
+            assert_eq!(stream.len(), size);
+            assert!(stream == original);
+        }
+
+        it "ignores backwards ranges" {
+            let mut res = client.get(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("Range", "bytes=500-100"))
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.body_bytes().unwrap().len(), original.len());
+        }
+
+        it "refuses ranges beyond the end" {
+            let res = client.get(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("Range", format!("bytes={}-", original.len())))
+                .dispatch();
+            assert_eq!(res.status(), Status::RangeNotSatisfiable);
+            assert_eq!(res.headers().get_one("Content-Range").unwrap(), format!("bytes */{}", original.len()));
+        }
+
+        it "resumes only unchanged files" {
+            let res = get(&client, &url, Some(auth_token));
+            let etag = res.headers().get_one("ETag").unwrap().to_owned();
+            let resume = |if_range: &str| client.get(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("Range", "bytes=100-"))
+                .header(Header::new("If-Range", if_range.to_owned()))
+                .dispatch();
+
+            let mut res = resume(&etag);
+            assert_eq!(res.status(), Status::PartialContent);
+            assert_eq!(res.body_bytes().unwrap().len(), original.len() - 100);
+            let mut res = resume("\"something-else\"");
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.body_bytes().unwrap().len(), original.len());
+            let res = resume("Wed, 21 Oct 2015 07:28:00 GMT");
+            assert_eq!(res.status(), Status::Ok);
+        }
+    }
+
+    describe "pagination" {
+        before {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+        }
+
+        it "wraps books in a page" {
+            let mut res = get(&client, "/api/audiobooks?limit=10", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["id"], json!(book.id));
+            assert_eq!(data["page"], json!({
+                "total": 1, "limit": 10, "offset": 0, "next_offset": null, "prev_offset": null
+            }));
+        }
+
+        it "sorts books" {
+            let library = Library::create("test-data".to_owned(), "^[^/]+\\.mp3$".to_owned(), &*pool.get().unwrap()).unwrap();
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let mut res = get(&client, "/api/audiobooks?sort=length", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let lengths = data["items"].as_array().unwrap().iter()
+                .map(|b| b["length"].as_f64().unwrap())
+                .collect::<Vec<f64>>();
+            assert!(lengths.len() > 1);
+            assert!(lengths.windows(2).all(|w| w[0] <= w[1]));
+
+            let mut res = get(&client, "/api/audiobooks?sort=artist&limit=2", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"].as_array().unwrap().len(), 2);
+
+            let mut res = get(&client, "/api/audiobooks?sort=popularity", Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["errors"]["sort"].is_array());
+        }
+
+        it "groups books by series" {
+            use crate::models::series::Series;
+            let library = Library::create("test-data".to_owned(), "^[^/]+\\.mp3$".to_owned(), &*pool.get().unwrap()).unwrap();
+            scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let conn = pool.get().unwrap();
+            let mut books = user.accessible_audiobooks(&*conn).unwrap();
+            assert!(books.len() > 2);
+            let expanse = Series::for_name("The Expanse", &*conn).unwrap();
+            books[0].set_series(&expanse, Some(2.0), &*conn).unwrap();
+            books[1].set_series(&Series::for_name("Expanse, The", &*conn).unwrap(), Some(1.0), &*conn).unwrap();
+
+            let mut res = get(&client, "/api/audiobooks?group_by=series", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], json!(books.len() - 1));
+            let groups = data["items"].as_array().unwrap();
+            let grouped = groups.iter().find(|g| g["series"]["id"] == json!(expanse.id)).unwrap();
+            assert_eq!(grouped["series"]["name"], "The Expanse");
+            assert_eq!(grouped["books"][0]["id"], json!(books[1].id));
+            assert_eq!(grouped["books"][1]["id"], json!(books[0].id));
+            assert!(groups.iter().filter(|g| g["series"].is_null()).all(|g| g["books"].as_array().unwrap().len() == 1));
+
+            let res = get(&client, "/api/audiobooks?group_by=author", Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+
+        it "filters books by language" {
+            let conn = pool.get().unwrap();
+            let mut book = book.clone();
+            book.set_language(Some("de-at".to_owned()), &*conn).unwrap();
+            let mut res = get(&client, "/api/audiobooks?language=de", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["language"], "de-at");
+            assert_eq!(data["page"]["total"], 1);
+
+            let mut res = get(&client, "/api/audiobooks?language=en", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], 0);
+
+            let res = get(&client, "/api/audiobooks?language=not%20a%20language", Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+
+        it "searches titles for percent signs and underscores literally" {
+            let mut res = get(&client, "/api/audiobooks?q=lle", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], 1);
+            for q in &["%25", "_", "%5C"] {
+                let mut res = get(&client, &format!("/api/audiobooks?q={}", q), Some(auth_token));
+                assert_eq!(res.status(), Status::Ok);
+                let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+                assert_eq!(data["page"]["total"], 0);
+            }
+        }
+
+        it "pages through chapters" {
+            let url = format!("/api/audiobooks/{}/chapters", book.id.hyphenated());
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["page"]["total"], 4);
+            assert_eq!(data["items"].as_array().unwrap().len(), 4);
+
+            let mut res = get(&client, &format!("{}?limit=2&offset=1", url), Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"].as_array().unwrap().len(), 2);
+            assert_eq!(data["page"]["next_offset"], 3);
+            assert_eq!(data["page"]["prev_offset"], 0);
+
+            let mut res = get(&client, &format!("/api/audiobooks/{}", book.id.hyphenated()), Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["chapter_count"], 4);
+        }
+
+        it "serves chapter images or the cover instead" {
+            use crate::schema::{audiobooks, chapters};
+            use crate::worker::layout;
+            let conn = pool.get().unwrap();
+            let chapter = chapters::table.filter(chapters::dsl::audiobook_id.eq(&book.id))
+                .order(chapters::dsl::number.asc())
+                .first::<crate::models::chapter::Chapter>(&*conn).unwrap();
+            let url = format!("/api/chapters/{}/image", chapter.id.hyphenated());
+            assert_eq!(get(&client, &url, Some(auth_token)).status(), Status::NotFound);
+
+            let cover = b"\xff\xd8\xff a cover";
+            let path = layout::cover_path(&config.data_directory, &book.id);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &cover[..]).unwrap();
+            diesel::update(audiobooks::table.find(&book.id))
+                .set((audiobooks::dsl::cover_hash.eq("cafe"), audiobooks::dsl::cover_mime.eq("image/jpeg")))
+                .execute(&*conn).unwrap();
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.body_bytes().unwrap(), cover.to_vec());
+
+            let slide = b"\x89PNG\r\n\x1a\n a slide";
+            let path = layout::chapter_image_path(&config.data_directory, &book.id, &chapter.id);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &slide[..]).unwrap();
+            diesel::update(chapters::table.find(&chapter.id))
+                .set(chapters::dsl::has_image.eq(true))
+                .execute(&*conn).unwrap();
+            let mut res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.content_type(), Some(ContentType::PNG));
+            assert_eq!(res.body_bytes().unwrap(), slide.to_vec());
+            let url = format!("/api/audiobooks/{}/chapters/{}/image", book.id.hyphenated(), chapter.id.hyphenated());
+            assert_eq!(get(&client, &url, Some(auth_token)).status(), Status::Ok);
+        }
+
+        it "serves covers shared with books of libraries the user can't access" {
+            use crate::schema::{audiobooks, libraries};
+            use crate::models::audiobook::Audiobook;
+            use crate::models::library_permission::LibraryPermission;
+            use crate::worker::layout;
+            let conn = pool.get().unwrap();
+            let hidden = Library::create("elsewhere".to_owned(), "^$".to_owned(), &*conn).unwrap();
+            LibraryPermission::revoke(&user, &hidden, &*conn).unwrap();
+            let copy = |library_id, location: &str| Audiobook {
+                id: helpers::uuid::Uuid::new_v4(),
+                location: location.to_owned(),
+                library_id,
+                hash: location.as_bytes().to_vec(),
+                cover_hash: Some("cafe".to_owned()),
+                cover_mime: Some("image/jpeg".to_owned()),
+                ..book.clone()
+            };
+            // The inaccessible book comes first in the table
+            let other = copy(hidden.id, "other.m4b");
+            let shared = copy(book.library_id, "shared.m4b");
+            diesel::insert_into(audiobooks::table).values(&vec![other.clone(), shared.clone()]).execute(&*conn).unwrap();
+            let path = layout::cover_path(&config.data_directory, &shared.id);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, b"\xff\xd8\xff a cover").unwrap();
+
+            assert_eq!(get(&client, "/api/covers/cafe", Some(auth_token)).status(), Status::Ok);
+            let library = libraries::table.find(&book.library_id).first::<Library>(&*conn).unwrap();
+            LibraryPermission::revoke(&user, &library, &*conn).unwrap();
+            assert_eq!(get(&client, "/api/covers/cafe", Some(auth_token)).status(), Status::NotFound);
+        }
+    }
+
+    describe "search" {
+        before {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+        }
+
+        it "finds books by chapter titles" {
+            let mut res = get(&client, "/api/search?q=otplu", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 1);
+            assert_eq!(data[0]["id"], json!(book.id));
+
+            let mut res = get(&client, "/api/search?q=%22lekii%20zzz", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 0);
+        }
+
+        it "finds books by translated descriptions regardless of accents" {
+            use crate::models::translation;
+            translation::set(
+                &book, "de", None, Some("Ein Drache überfällt <das> Schloss."), &*pool.get().unwrap()
+            ).unwrap();
+            let mut res = get(&client, "/api/search?q=drache%20uberfallt", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 1);
+            assert_eq!(data[0]["id"], json!(book.id));
+            let snippet = data[0]["snippet"].as_str().unwrap();
+            assert!(snippet.contains("<mark>Drache</mark>"));
+            assert!(snippet.contains("&lt;das&gt;"));
+        }
+
+        it "only finds accessible books" {
+            use crate::models::library_permission::LibraryPermission;
+            let conn = pool.get().unwrap();
+            for library in user.accessible_libraries(&*conn).unwrap() {
+                LibraryPermission::revoke(&user, &library, &*conn).unwrap();
+            }
+            let mut res = get(&client, "/api/search?q=otpluva", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 0);
+        }
     }
 
+    describe "metadata" {
+        before {
+            let admin_token = admin_token(&client, &pool);
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+            let url = format!("/api/audiobooks/{}/metadata", book.id.hyphenated());
+        }
+
+        it "matches books at a metadata provider" {
+            use crate::config::LookupConfig;
+            use crate::models::book_match::BookMatch;
+            let match_url = format!("/api/audiobooks/{}/match", book.id.hyphenated());
+            let res = post(&client, &match_url, &json!({}), Some(&admin_token));
+            assert_eq!(res.status(), Status::NotFound);
+
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.metadata.lookup = Some(LookupConfig {
+                url: "http://127.0.0.1:1".to_owned(),
+                on_scan: false,
+                timeout: 1,
+            });
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            let res = post(&client, &match_url, &json!({"title": "Dune"}), Some(&admin_token));
+            assert_eq!(res.status(), Status::ServiceUnavailable);
+            let res = post(&client, &match_url, &json!({}), Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+
+            BookMatch {
+                audiobook_id: book.id,
+                provider: "audible".to_owned(),
+                external_id: "B002V0QK4C".to_owned(),
+                narrator: Some("Scott Brick".to_owned()),
+                series: Some("Dune".to_owned()),
+                series_position: Some(1.0),
+                matched_at: chrono::Utc::now().naive_utc(),
+            }.save(&*pool.get().unwrap()).unwrap();
+            let mut res = get(&client, &format!("/api/audiobooks/{}", book.id.hyphenated()), Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["match"]["narrator"], "Scott Brick");
+            assert_eq!(data["match"]["series_position"], 1.0);
+        }
+
+        it "applies matches chosen from the provider's results" {
+            use crate::config::LookupConfig;
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let provider = format!("http://{}", listener.local_addr().unwrap());
+            let products = json!({"products": [{
+                "asin": "B002V0QK4C",
+                "title": "Dune",
+                "authors": [{"name": "Frank Herbert"}],
+                "narrators": [{"name": "Scott Brick"}],
+                "publisher_summary": "<p>Set on the desert planet Arrakis.</p>",
+                "product_images": {"500": format!("{}/dune.jpg", provider)}
+            }]}).to_string();
+            serve_stub(listener, vec![
+                ("/1.0/catalog/products".to_owned(), products.into_bytes()),
+                ("/dune.jpg".to_owned(), b"\xff\xd8\xff a cover".to_vec()),
+            ]);
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.metadata.lookup = Some(LookupConfig { url: provider, on_scan: false, timeout: 5 });
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config.clone()).unwrap()).unwrap();
+            let match_url = format!("/api/audiobooks/{}/match", book.id.hyphenated());
+
+            let mut res = post(&client, &match_url, &json!({"title": "Dune"}), Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["candidates"][0]["id"], "B002V0QK4C");
+            let res = post(&client, &match_url, &json!({"isbn": "not an isbn"}), Some(&admin_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+
+            let mut res = post(&client, &match_url, &json!({"candidate": "B002V0QK4C"}), Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["description"], "Set on the desert planet Arrakis.");
+            assert_eq!(data["match"]["narrator"], "Scott Brick");
+            assert!(data["cover_url"].is_string());
+            let cover = std::fs::read(crate::worker::layout::cover_path(&config.data_directory, &book.id)).unwrap();
+            assert_eq!(cover, b"\xff\xd8\xff a cover".to_vec());
+        }
+
+        it "matches books while scanning by isbn or title and author" {
+            use crate::config::LookupConfig;
+            use crate::models::book_match::BookMatch;
+            use crate::worker::lookup;
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let provider = format!("http://{}", listener.local_addr().unwrap());
+            let by_isbn = json!({"products": [{"asin": "B00ISBN", "title": "Another edition's title"}]});
+            let by_title = json!({"products": [
+                {"asin": "B00OTHER", "title": "Something else entirely"},
+                {"asin": "B00TITLE", "title": book.title.clone(), "authors": [{"name": book.artist.clone()}]},
+            ]});
+            serve_stub(listener, vec![
+                ("keywords=9780441172719".to_owned(), by_isbn.to_string().into_bytes()),
+                ("keywords=".to_owned(), json!({"products": []}).to_string().into_bytes()),
+                ("/1.0/catalog/products".to_owned(), by_title.to_string().into_bytes()),
+            ]);
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.metadata.lookup = Some(LookupConfig { url: provider, on_scan: true, timeout: 5 });
+            let conn = pool.get().unwrap();
+            let isbn = lookup::normalize_isbn("978-0-441-17271-9");
+
+            assert!(lookup::on_scan(&book, isbn.as_ref().map(String::as_str), &config, &*conn).unwrap());
+            assert_eq!(BookMatch::of(&book, &*conn).unwrap().unwrap().external_id, "B00ISBN");
+            // Matched books are left alone
+            assert!(!lookup::on_scan(&book, None, &config, &*conn).unwrap());
+
+            diesel::delete(crate::schema::book_matches::table).execute(&*conn).unwrap();
+            // The provider doesn't know this ISBN, so the title decides
+            assert!(lookup::on_scan(&book, Some("9780000000002"), &config, &*conn).unwrap());
+            assert_eq!(BookMatch::of(&book, &*conn).unwrap().unwrap().external_id, "B00TITLE");
+        }
+
+        it "puts matched books into their series" {
+            use crate::config::LookupConfig;
+            use crate::models::series::Series;
+            use crate::worker::lookup::{self, Candidate};
+            let lookup_config = LookupConfig {
+                url: "http://127.0.0.1:1".to_owned(),
+                on_scan: false,
+                timeout: 1,
+            };
+            let candidate = Candidate {
+                id: "B002V0QK4C".to_owned(),
+                title: book.title.clone(),
+                authors: vec!["Frank Herbert".to_owned()],
+                narrators: vec!["Scott Brick".to_owned()],
+                series: Some("Dune".to_owned()),
+                series_position: Some(1.0),
+                description: None,
+                cover_url: None,
+            };
+            let conn = pool.get().unwrap();
+            let updated = lookup::apply(&book, &candidate, true, &lookup_config, &config, &*conn).unwrap();
+            let series = Series::find(&updated.series_id.unwrap(), &*conn).unwrap().unwrap();
+            assert_eq!(series.name, "Dune");
+            assert_eq!(updated.series_position, Some(1.0));
+
+            let again = lookup::apply(&updated, &candidate, true, &lookup_config, &config, &*conn).unwrap();
+            assert_eq!(again.series_id, Some(series.id));
+        }
+
+        it "shares library metadata through bundles" {
+            use crate::config::BundleConfig;
+            let export_url = format!("/api/libraries/{}/export", library.id.hyphenated());
+            let import_url = format!("/api/libraries/{}/import", library.id.hyphenated());
+            let res = get(&client, &export_url, Some(&admin_token));
+            assert_eq!(res.status(), Status::NotFound);
+
+            let mut config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            config.bundles = Some(BundleConfig { secret: "a secret of some length".to_owned() });
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config.clone()).unwrap()).unwrap();
+            let res = get(&client, &export_url, Some(auth_token));
+            assert_eq!(res.status(), Status::Forbidden);
+
+            client.patch(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"translator": "Jane Doe"}).to_string())
+                .dispatch();
+            let mut res = get(&client, &export_url, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let bundle: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(bundle["books"].as_str().unwrap().contains("Jane Doe"));
+
+            client.patch(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"translator": null}).to_string())
+                .dispatch();
+            let mut res = post(&client, &import_url, &bundle, Some(&admin_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data, json!({"books": 1, "matched": 1}).into_inner());
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["translator"], "Jane Doe");
+
+            let mut tampered = bundle.clone();
+            tampered["books"] = json!(bundle["books"].as_str().unwrap().replace("Jane Doe", "John Doe")).into_inner();
+            let res = post(&client, &import_url, &tampered, Some(&admin_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+
+            config.bundles = Some(BundleConfig { secret: "another secret of some length".to_owned() });
+            let client = Client::new(helpers::rocket::factory(pool.clone(), config).unwrap()).unwrap();
+            let res = post(&client, &import_url, &bundle, Some(&admin_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+
+        it "sets and clears configured fields" {
+            let res = client.patch(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"translator": "Jane Doe"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+
+            let mut res = get(&client, &format!("/api/audiobooks/{}", book.id.hyphenated()), Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["metadata"], json!({"translator": "Jane Doe", "publisher": null}).into_inner());
+
+            client.patch(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"translator": null}).to_string())
+                .dispatch();
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["translator"].is_null());
+        }
+
+        it "rejects unknown fields" {
+            let mut res = client.patch(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"narrator": "Someone"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["errors"]["narrator"].is_array());
+        }
+
+        it "can only be edited by admins" {
+            let res = client.patch(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(ContentType::JSON)
+                .body(json!({"translator": "Jane Doe"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Forbidden);
+        }
+    }
+
+    describe "sync" {
+        before {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+        }
+
+        it "saves playstates" {
+            let states = json!([{"audiobook_id": book.id, "position": 12.5, "timestamp": "2020-05-01T12:00:00Z"}]);
+            let res = post(&client, "/api/update_playstates", &states, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, "/api/all_the_things", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["playstates"][0]["position"], 12.5);
+        }
+
+        it "sends what changed since the last sync" {
+            use crate::models::sync;
+            use crate::schema::audiobooks::dsl;
+            let mut res = get(&client, "/api/sync", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["books"][0]["id"], json!(book.id));
+            assert_eq!(data["chapters"].as_array().unwrap().len(), 4);
+            assert_eq!(data["deleted"], json!([]));
+
+            // Changes shortly before the last sync are sent again
+            let url = format!("/api/sync?since={}", data["now"].as_str().unwrap());
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["books"][0]["id"], json!(book.id));
+
+            let long_ago = chrono::Utc::now().naive_utc() - chrono::Duration::seconds(sync::OVERLAP_SECS + 1);
+            diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                .set(dsl::updated_at.eq(long_ago))
+                .execute(&*pool.get().unwrap())
+                .unwrap();
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["books"], json!([]));
+            assert_eq!(data["chapters"], json!([]));
+
+            diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                .set(dsl::deleted.eq(true))
+                .execute(&*pool.get().unwrap())
+                .unwrap();
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["books"], json!([]));
+            assert_eq!(data["deleted"], json!([book.id]));
+
+            let res = get(&client, "/api/sync?since=yesterday", Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+
+        it "saves none of the playstates if one is invalid" {
+            let states = json!([
+                {"audiobook_id": book.id, "position": 12.5, "timestamp": "2020-05-01T12:00:00Z"},
+                {"audiobook_id": book.id, "position": 1.0, "speed": 0.0, "timestamp": "2020-05-01T12:00:00Z"},
+            ]);
+            let res = post(&client, "/api/update_playstates", &states, Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let mut res = get(&client, "/api/all_the_things", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["playstates"], json!([]));
+        }
+
+        it "skips playstates of books that went away" {
+            let gone = "00000000-0000-0000-0000-000000000000";
+            let states = json!([
+                {"audiobook_id": book.id, "position": 12.5, "timestamp": "2020-05-01T12:00:00Z"},
+                {"audiobook_id": gone, "position": 1.0, "timestamp": "2020-05-01T12:00:00Z"},
+            ]);
+            let mut res = post(&client, "/api/update_playstates", &states, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["skipped"], json!([gone]));
+            let mut res = get(&client, "/api/all_the_things", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["playstates"].as_array().unwrap().len(), 1);
+            assert_eq!(data["playstates"][0]["audiobook_id"], json!(book.id));
+        }
+
+        it "counts listening time but not seeks" {
+            let at = |minutes_ago| (chrono::Utc::now() - chrono::Duration::minutes(minutes_ago)).to_rfc3339();
+            for (position, minutes_ago) in &[(0.0, 5), (120.0, 3), (2000.0, 2)] {
+                let states = json!([{"audiobook_id": book.id, "position": position, "timestamp": at(*minutes_ago)}]);
+                let res = post(&client, "/api/update_playstates", &states, Some(auth_token));
+                assert_eq!(res.status(), Status::Ok);
+            }
+            let mut res = get(&client, "/api/stats", Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["total_seconds"], 120.0);
+            assert_eq!(data["week_seconds"], 120.0);
+            assert_eq!(data["week"].as_array().unwrap().len(), 7);
+            assert_eq!(data["books"], json!([{"audiobook_id": book.id, "seconds": 120.0, "real_seconds": 120.0}]));
+            assert_eq!(data["streak_days"], 1);
+        }
+
+        it "tells listening time apart from content time" {
+            let at = |minutes_ago| (chrono::Utc::now() - chrono::Duration::minutes(minutes_ago)).to_rfc3339();
+            let states = json!([{"audiobook_id": book.id, "position": 0.0, "timestamp": at(5)}]);
+            post(&client, "/api/update_playstates", &states, Some(auth_token));
+            let states = json!([{"audiobook_id": book.id, "position": 120.0, "timestamp": at(4), "speed": 2.0}]);
+            let res = post(&client, "/api/update_playstates", &states, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, "/api/stats", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["total_seconds"], 120.0);
+            assert_eq!(data["total_real_seconds"], 60.0);
+            let mut res = get(&client, "/api/all_the_things", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["playstates"][0]["speed"], 2.0);
+
+            let states = json!([{"audiobook_id": book.id, "position": 130.0, "timestamp": at(3), "speed": 0.0}]);
+            let res = post(&client, "/api/update_playstates", &states, Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+    }
+
+    describe "events" {
+        it "publishes what happened" {
+            use crate::helpers::events::{self, Event};
+            let events = events::subscribe();
+            let library = Library::create("test-data".to_owned(), "^all\\.m4b$".to_owned(), &*pool.get().unwrap()).unwrap();
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let scan = scheduler::run_scan(&pool, &config, library.clone(), false).unwrap();
+            let book = user.accessible_audiobooks(&*pool.get().unwrap()).unwrap().remove(0);
+
+            let states = json!([{"audiobook_id": book.id, "position": 12.5, "timestamp": "2020-05-01T12:00:00Z"}]);
+            post(&client, "/api/update_playstates", &states, Some(auth_token));
+            let url = format!("/data/{}", book.id.hyphenated());
+            let res = client.get(url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(Header::new("Range", "bytes=1000-"))
+                .dispatch();
+            assert_eq!(res.status(), Status::PartialContent);
+            let res = get(&client, &url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+
+            // Other tests publish too
+            let ours = events.try_iter().filter(|e| match e {
+                Event::BookAdded { library_id, .. } | Event::ScanFinished { library_id, .. } => *library_id == library.id,
+                Event::PlaystateUpdated { user_id, .. } | Event::StreamStarted { user_id, .. } => *user_id == user.id,
+            }).collect::<Vec<Event>>();
+            assert_eq!(ours, vec![
+                Event::BookAdded { library_id: library.id, audiobook_id: book.id },
+                Event::ScanFinished { library_id: library.id, scan_id: scan.id, error: None },
+                Event::PlaystateUpdated { user_id: user.id, audiobook_id: book.id, position: 12.5 },
+                Event::StreamStarted { user_id: user.id, audiobook_id: book.id },
+            ]);
+        }
+    }
+
+    describe "translations" {
+        before {
+            let admin_token = admin_token(&client, &pool);
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+            let url = format!("/api/audiobooks/{}/translations/de", book.id.hyphenated());
+            let book_url = format!("/api/audiobooks/{}", book.id.hyphenated());
+        }
+
+        it "shows books in the preferred language" {
+            let res = client.put(url.clone())
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"title": "Alles"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+
+            let mut res = get(&client, &book_url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["title"], json!(book.title.clone()).into_inner());
+            assert_eq!(data["translations"][0]["language"], "de");
+
+            let res = client.put("/api/auth/language")
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(ContentType::JSON)
+                .body(json!({"language": "de-AT"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            let mut res = get(&client, &book_url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["title"], "Alles");
+            let mut res = get(&client, "/api/audiobooks", Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["items"][0]["title"], "Alles");
+
+            delete(&client, &url, Some(&admin_token));
+            let mut res = get(&client, &book_url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data["title"], json!(book.title.clone()).into_inner());
+        }
+
+        it "rejects invalid languages" {
+            let mut res = client.put("/api/auth/language")
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(ContentType::JSON)
+                .body(json!({"language": "not a language"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert!(data["errors"]["language"].is_array());
+
+            let res = client.put(format!("/api/audiobooks/{}/translations/x_y", book.id.hyphenated()))
+                .header(Header::new("Authorization", admin_token.clone()))
+                .header(ContentType::JSON)
+                .body(json!({"title": "Alles"}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+    }
+
+    describe "bookmarks" {
+        before {
+            let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+            let (library, book) = scanned_book(&pool, &config);
+            let url = format!("/api/audiobooks/{}/bookmarks", book.id.hyphenated());
+        }
+
+        it "creates, lists, updates and deletes bookmarks" {
+            let mut res = post(&client, &url, &json!({"position_secs": 12.5, "note": "Nice"}), Some(auth_token));
+            assert_eq!(res.status(), Status::Created);
+            let created: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            let bookmark_url = format!("{}/{}", url, created["id"].as_str().unwrap());
+
+            let res = client.put(bookmark_url.clone())
+                .header(Header::new("Authorization", auth_token.to_owned()))
+                .header(ContentType::JSON)
+                .body(json!({"position_secs": 20.0}).to_string())
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+
+            let mut res = get(&client, &url, Some(auth_token));
+            let data: Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            assert_eq!(data.as_array().unwrap().len(), 1);
+            assert_eq!(data[0]["position_secs"], json!(20.0));
+            assert!(data[0]["note"].is_null());
+
+            let res = delete(&client, &bookmark_url, Some(auth_token));
+            assert_eq!(res.status(), Status::Ok);
+            let res = delete(&client, &bookmark_url, Some(auth_token));
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        it "rejects negative positions" {
+            let res = post(&client, &url, &json!({"position_secs": -1.0}), Some(auth_token));
+            assert_eq!(res.status(), Status::UnprocessableEntity);
+        }
+    }
+
+}
+
+#[test]
+fn resolves_byte_ranges() {
+    use rocket::http::hyper::header::ByteRangeSpec::*;
+    use crate::api::ranged_file::resolve_range;
+
+    assert_eq!(resolve_range(&FromTo(0, 99), 1000), Some((0, 99)));
+    assert_eq!(resolve_range(&FromTo(900, 2000), 1000), Some((900, 999)));
+    assert_eq!(resolve_range(&FromTo(1000, 2000), 1000), None);
+    assert_eq!(resolve_range(&FromTo(10, 5), 1000), None);
+    assert_eq!(resolve_range(&AllFrom(500), 1000), Some((500, 999)));
+    assert_eq!(resolve_range(&AllFrom(1000), 1000), None);
+    assert_eq!(resolve_range(&Last(100), 1000), Some((900, 999)));
+    assert_eq!(resolve_range(&Last(5000), 1000), Some((0, 999)));
+    assert_eq!(resolve_range(&Last(0), 1000), None);
+    assert_eq!(resolve_range(&AllFrom(0), 0), None);
+}
+
+#[test]
+fn parses_durations() {
+    use crate::config::parse_duration;
+
+    assert_eq!(parse_duration("600"), Ok(600));
+    assert_eq!(parse_duration("45s"), Ok(45));
+    assert_eq!(parse_duration("30m"), Ok(1800));
+    assert_eq!(parse_duration("2h"), Ok(7200));
+    assert_eq!(parse_duration("7d"), Ok(604800));
+    assert!(parse_duration("m").is_err());
+    assert!(parse_duration("5 weeks").is_err());
+}
+
+#[test]
+fn checks_config() {
+    use std::net::TcpListener;
+    use crate::config::{check, load_config_from_path};
+
+    let mut config = load_config_from_path(&"test-data/test-config.toml").unwrap();
+    config.data_directory = "test-data/missing".to_owned();
+    config.database = "test-data/vorleser.sqlite".to_owned();
+    config.logging.level = "loud".to_owned();
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    config.web.address = "127.0.0.1".to_owned();
+    config.web.port = taken.local_addr().unwrap().port();
+
+    let problems = check(&config);
+    assert_eq!(problems.len(), 3);
+    assert!(problems[0].starts_with("data_directory:"));
+    assert!(problems[1].starts_with("logging.level:"));
+    assert!(problems[2].starts_with("web:"));
+}
+
+#[test]
+fn turns_away_requests_when_shutting_down() {
+    use crate::helpers::shutdown::Shutdown;
+    let pool = init_test_db_pool();
+    let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+    let rocket = helpers::rocket::factory(pool, config).unwrap();
+    let shutdown = rocket.state::<Shutdown>().unwrap().clone();
+    let client = Client::new(rocket).unwrap();
+    let res = post(&client, "/api/auth/login", &json!({"email": "nobody@test.com", "password": "x"}), None);
+    assert_eq!(res.status(), Status::Unauthorized);
+    drop(res);
+    assert_eq!(shutdown.active_requests(), 0);
+
+    shutdown.begin();
+    let res = post(&client, "/api/auth/login", &json!({"email": "nobody@test.com", "password": "x"}), None);
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+    drop(res);
+    assert!(shutdown.drain(std::time::Duration::from_secs(1)));
+}
+
+/// A body that takes a while to send, like a download.
+struct SlowBody(u8);
+
+impl std::io::Read for SlowBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.0 == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        self.0 -= 1;
+        buf[0] = b'.';
+        Ok(1)
+    }
+}
+
+#[get("/slow")]
+fn slow_body() -> rocket::response::Stream<SlowBody> {
+    rocket::response::Stream::from(SlowBody(3))
+}
+
+#[test]
+fn waits_for_bodies_to_be_sent_when_shutting_down() {
+    use crate::helpers::shutdown::Shutdown;
+    let pool = init_test_db_pool();
+    let config = config::load_config_from_path(&"test-data/test-config.toml").unwrap();
+    let rocket = helpers::rocket::factory(pool, config).unwrap().mount("/test", routes![slow_body]);
+    let shutdown = rocket.state::<Shutdown>().unwrap().clone();
+    let client = Client::new(rocket).unwrap();
+    let mut res = get(&client, "/test/slow", None);
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(shutdown.active_requests(), 1);
+
+    shutdown.begin();
+    assert!(!shutdown.drain(std::time::Duration::from_millis(50)));
+    assert_eq!(res.body_string().unwrap(), "...");
+    drop(res);
+    assert_eq!(shutdown.active_requests(), 0);
+    assert!(shutdown.drain(std::time::Duration::from_secs(1)));
+}
+
+#[test]
+fn checks_tls_config() {
+    use crate::config::{check, load_config_from_path, TlsConfig};
+
+    let mut config = load_config_from_path(&"test-data/test-config.toml").unwrap();
+    config.tls = Some(TlsConfig {
+        cert_path: "test-data/missing.pem".to_owned(),
+        key_path: "test-data/missing.key".to_owned(),
+        redirect_port: Some(config.web.port),
+    });
+    let problems = check(&config).into_iter()
+        .filter(|p| p.starts_with("tls"))
+        .collect::<Vec<String>>();
+    let missing_feature = if cfg!(feature = "tls") { 0 } else { 1 };
+    assert_eq!(problems.len(), 3 + missing_feature);
+    assert!(problems.iter().any(|p| p.starts_with("tls.cert_path:")));
+    assert!(problems.iter().any(|p| p.starts_with("tls.redirect_port:")));
+}
+
+#[test]
+fn redirects_to_https() {
+    use crate::config::{load_config_from_path, TlsConfig};
+
+    let mut config = load_config_from_path(&"test-data/test-config.toml").unwrap();
+    config.tls = Some(TlsConfig {
+        cert_path: "cert.pem".to_owned(),
+        key_path: "key.pem".to_owned(),
+        redirect_port: Some(8080),
+    });
+    let client = Client::new(helpers::rocket::redirect_factory(config).unwrap()).unwrap();
+    let res = client.get("/api/libraries?page=2").header(Header::new("Host", "example.com:8080")).dispatch();
+    assert_eq!(res.status(), Status::PermanentRedirect);
+    assert_eq!(res.headers().get_one("Location"), Some("https://example.com:8000/api/libraries?page=2"));
+    let res = client.get("/").header(Header::new("Host", "[::1]")).dispatch();
+    assert_eq!(res.headers().get_one("Location"), Some("https://[::1]:8000/"));
+}
+
+#[test]
+fn reloads_only_reloadable_settings() {
+    use crate::config::{SharedConfig, load_config_from_path};
+
+    let config = load_config_from_path(&"test-data/test-config.toml").unwrap();
+    let shared = SharedConfig::new(config.clone());
+    let mut changed = config.clone();
+    changed.register_web = true;
+    changed.scan.interval = 60;
+    changed.logging.level = "debug".to_owned();
+    changed.data_directory = "elsewhere".to_owned();
+    changed.web.port = 1234;
+    shared.reload(&changed);
+
+    let reloaded = shared.get();
+    assert!(reloaded.register_web);
+    assert_eq!(reloaded.scan.interval, 60);
+    assert_eq!(reloaded.logging.level, "debug");
+    assert_eq!(reloaded.data_directory, config.data_directory);
+    assert_eq!(reloaded.web.port, config.web.port);
+}
+
+#[test]
+fn formats_content_disposition() {
+    use crate::api::ranged_file::content_disposition;
+    assert_eq!(
+        content_disposition("01 - Vorwort \"Über\".mp3"),
+        "attachment; filename=\"01 - Vorwort __ber_.mp3\"; filename*=UTF-8''01%20-%20Vorwort%20%22%C3%9Cber%22.mp3"
+    );
+}
+
+#[test]
+fn matches_if_range() {
+    use crate::api::ranged_file::if_range_matches;
+    assert!(if_range_matches(None, None));
+    assert!(if_range_matches(None, Some("abc")));
+    assert!(if_range_matches(Some("\"abc\""), Some("abc")));
+    assert!(!if_range_matches(Some("W/\"abc\""), Some("abc")));
+    assert!(!if_range_matches(Some("\"abd\""), Some("abc")));
+    assert!(!if_range_matches(Some("\"abc\""), None));
+    assert!(!if_range_matches(Some("Wed, 21 Oct 2015 07:28:00 GMT"), Some("abc")));
+}
+
+#[test]
+fn formats_digest_headers() {
+    use crate::api::ranged_file::digest_header;
+    use crate::worker::hashing::checksum_bytes;
+
+    assert_eq!(digest_header(&checksum_bytes(b"")), "sha-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
 }
@@ -0,0 +1,60 @@
+use validator::{Validate, ValidationError};
+
+use crate::helpers::uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct RescanSerializer {
+    #[validate(length(min = 1, max = 100, message = "Must contain between 1 and 100 ids."))]
+    pub ids: Vec<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct TranslationSerializer {
+    #[validate(length(max = 500, message = "Must be at most 500 characters."))]
+    pub title: Option<String>,
+    #[validate(length(max = 10000, message = "Must be at most 10000 characters."))]
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct MatchSerializer {
+    /// Search for this instead of the book's title.
+    #[validate(length(min = 1, max = 500, message = "Must contain between 1 and 500 characters."))]
+    pub title: Option<String>,
+    /// Search for this instead of the book's artist.
+    #[validate(length(min = 1, max = 500, message = "Must contain between 1 and 500 characters."))]
+    pub author: Option<String>,
+    /// Search for the book with this ISBN instead of by title and author.
+    #[validate(length(min = 10, max = 30, message = "Must contain between 10 and 30 characters."))]
+    pub isbn: Option<String>,
+    /// Id of the result to apply.
+    #[validate(length(min = 1, max = 100, message = "Must contain between 1 and 100 characters."))]
+    pub candidate: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct LanguageSerializer {
+    /// `null` shows books as they were tagged.
+    #[validate(custom = "language_tag")]
+    pub language: Option<String>,
+}
+
+/// Tags like `"en"`, `"de-AT"` or `"zh-Hant"`: letters and digits in parts of up to eight
+/// characters, starting with a two or three letter language.
+pub fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let primary = parts.next().unwrap_or("");
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|p| !p.is_empty() && p.len() <= 8 && p.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+pub fn language_tag(tag: &str) -> Result<(), ValidationError> {
+    if is_language_tag(tag) {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("language");
+        error.message = Some("Must be a language tag like \"en\" or \"pt-BR\".".into());
+        Err(error)
+    }
+}
@@ -0,0 +1,7 @@
+use crate::helpers::uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MergeAuthorSerializer {
+    /// The author that is kept.
+    pub into: Uuid,
+}
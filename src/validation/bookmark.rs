@@ -0,0 +1,10 @@
+use validator::Validate;
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct BookmarkSerializer {
+    #[validate(range(min = 0, max = 1000000, message = "Must be a position in the book."))]
+    pub position_secs: f64,
+    #[serde(default)]
+    #[validate(length(max = 10000, message = "Must be at most 10000 characters."))]
+    pub note: Option<String>,
+}
@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use regex::Regex;
+use validator::{Validate, ValidationError};
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct LibraryUpdateSerializer {
+    #[validate(custom = "existing_directory")]
+    pub location: Option<String>,
+    #[validate(custom = "valid_regex")]
+    pub is_audiobook_regex: Option<String>,
+}
+
+fn existing_directory(location: &str) -> Result<(), ValidationError> {
+    if Path::new(location).is_dir() {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("directory");
+        error.message = Some("Must be an existing directory.".into());
+        Err(error)
+    }
+}
+
+fn valid_regex(regex: &str) -> Result<(), ValidationError> {
+    match Regex::new(regex) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            let mut error = ValidationError::new("regex");
+            error.message = Some("Must be a valid regular expression.".into());
+            Err(error)
+        }
+    }
+}
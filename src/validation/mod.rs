@@ -1,2 +1,7 @@
 pub mod user;
 pub mod token;
+pub mod query;
+pub mod author;
+pub mod audiobook;
+pub mod library;
+pub mod bookmark;
@@ -0,0 +1,142 @@
+use chrono::{DateTime, NaiveDateTime};
+use validator::{Validate, ValidationError};
+
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::BookOrder;
+use crate::models::audit_log::{Action, AuditFilter};
+use crate::validation::audiobook::language_tag;
+
+/// Query parameters for listing audiobooks, all of them are optional.
+#[derive(FromForm, Debug, Validate)]
+pub struct AudiobookQuery {
+    /// Only books whose title contains this.
+    #[validate(length(min = 1, max = 200, message = "Must be between 1 and 200 characters."))]
+    pub q: Option<String>,
+    #[validate(range(min = 1, max = 1000, message = "Must be between 1 and 1000."))]
+    pub limit: Option<i64>,
+    #[validate(range(min = 0, max = 1000000, message = "Must not be negative."))]
+    pub offset: Option<i64>,
+    /// One of `title` (the default), `artist`, `recent` or `length`.
+    #[validate(custom = "book_order")]
+    pub sort: Option<String>,
+    /// `series` lists the books of a series together, see `models::series::group`.
+    #[validate(custom = "book_grouping")]
+    pub group_by: Option<String>,
+    /// Only books in this language, like `de`.
+    #[validate(custom = "language_tag")]
+    pub language: Option<String>,
+}
+
+impl AudiobookQuery {
+    pub fn order(&self) -> BookOrder {
+        self.sort.as_ref().and_then(|s| BookOrder::parse(s)).unwrap_or_default()
+    }
+}
+
+fn book_order(sort: &str) -> Result<(), ValidationError> {
+    match BookOrder::parse(sort) {
+        Some(_) => Ok(()),
+        None => {
+            let mut error = ValidationError::new("sort");
+            error.message = Some("Must be one of title, artist, recent or length.".into());
+            Err(error)
+        }
+    }
+}
+
+fn book_grouping(group_by: &str) -> Result<(), ValidationError> {
+    if group_by == "series" {
+        return Ok(());
+    }
+    let mut error = ValidationError::new("group_by");
+    error.message = Some("Must be series.".into());
+    Err(error)
+}
+
+/// Query parameters of lists returned as `helpers::pagination::Page`.
+#[derive(FromForm, Debug, Validate)]
+pub struct PageQuery {
+    #[validate(range(min = 1, max = 1000, message = "Must be between 1 and 1000."))]
+    pub limit: Option<i64>,
+    #[validate(range(min = 0, max = 1000000, message = "Must not be negative."))]
+    pub offset: Option<i64>,
+}
+
+/// Query parameters of a full text search.
+#[derive(FromForm, Debug, Validate)]
+pub struct SearchQuery {
+    #[validate(length(min = 1, max = 200, message = "Must be between 1 and 200 characters."))]
+    pub q: String,
+    #[validate(custom = "language_tag")]
+    pub language: Option<String>,
+    #[validate(range(min = 1, max = 100, message = "Must be between 1 and 100."))]
+    pub limit: Option<i64>,
+    #[validate(range(min = 0, max = 1000000, message = "Must not be negative."))]
+    pub offset: Option<i64>,
+}
+
+/// Parse a timestamp as given in a sync's `now`, or as RFC 3339.
+pub fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f").ok()
+        .or_else(|| DateTime::parse_from_rfc3339(timestamp).ok().map(|t| t.naive_utc()))
+}
+
+/// Query parameters of the audit log, all of them are optional.
+#[derive(FromForm, Debug, Validate)]
+pub struct AuditLogQuery {
+    /// Only entries where this user acted or was acted on.
+    #[validate(custom = "uuid")]
+    pub user_id: Option<String>,
+    /// Only entries of this action, like `failed_login`.
+    #[validate(custom = "audit_action")]
+    pub action: Option<String>,
+    /// Only entries from this time on.
+    #[validate(custom = "timestamp")]
+    pub since: Option<String>,
+    /// Only entries before this time.
+    #[validate(custom = "timestamp")]
+    pub until: Option<String>,
+    #[validate(range(min = 1, max = 1000, message = "Must be between 1 and 1000."))]
+    pub limit: Option<i64>,
+    #[validate(range(min = 0, max = 1000000, message = "Must not be negative."))]
+    pub offset: Option<i64>,
+}
+
+impl AuditLogQuery {
+    /// The filter to query with, only call this after validating.
+    pub fn filter(&self) -> AuditFilter {
+        AuditFilter {
+            user_id: self.user_id.as_ref().and_then(|id| Uuid::parse_str(id).ok()),
+            action: self.action.as_ref().and_then(|a| Action::parse(a)),
+            since: self.since.as_ref().and_then(|t| parse_timestamp(t)),
+            until: self.until.as_ref().and_then(|t| parse_timestamp(t)),
+        }
+    }
+}
+
+fn uuid(id: &str) -> Result<(), ValidationError> {
+    if Uuid::parse_str(id).is_ok() {
+        return Ok(());
+    }
+    let mut error = ValidationError::new("uuid");
+    error.message = Some("Must be an id.".into());
+    Err(error)
+}
+
+fn audit_action(action: &str) -> Result<(), ValidationError> {
+    if Action::parse(action).is_some() {
+        return Ok(());
+    }
+    let mut error = ValidationError::new("action");
+    error.message = Some("Must be an action of the audit log.".into());
+    Err(error)
+}
+
+fn timestamp(timestamp: &str) -> Result<(), ValidationError> {
+    if parse_timestamp(timestamp).is_some() {
+        return Ok(());
+    }
+    let mut error = ValidationError::new("timestamp");
+    error.message = Some("Must be a timestamp like 2020-05-01T12:00:00.".into());
+    Err(error)
+}
@@ -1,16 +1,19 @@
+use chrono::NaiveDateTime;
 use crate::helpers::uuid::Uuid;
 use crate::models::user::ApiToken;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TokenSerializer {
-    pub secret: Uuid
+    pub secret: Uuid,
+    pub expires_at: Option<NaiveDateTime>,
 }
 
 
 impl From<ApiToken> for TokenSerializer {
     fn from(model: ApiToken) -> Self {
         TokenSerializer {
-            secret: model.id
+            secret: model.id,
+            expires_at: model.expires_at,
         }
     }
 }
@@ -4,6 +4,46 @@ use validator::Validate;
 #[derive(Serialize, Deserialize, Debug, Validate)]
 pub struct UserSerializer {
     pub id: Option<Uuid>,
+    #[validate(email(message = "Must be a valid email address."))]
     pub email: String,
+    #[validate(length(min = 1, message = "Must not be empty."))]
+    pub password: String,
+    /// Name of the device logging in, shown to the user next to its playstates.
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct NewUserSerializer {
+    #[validate(email(message = "Must be a valid email address."))]
+    pub email: String,
+    #[validate(length(min = 1, message = "Must not be empty."))]
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct PasswordSerializer {
+    #[validate(length(min = 1, message = "Must not be empty."))]
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AllowDownloadSerializer {
+    pub allow_download: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct ResetRequestSerializer {
+    #[validate(email(message = "Must be a valid email address."))]
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
+pub struct PasswordResetSerializer {
+    #[validate(length(min = 1, message = "Must not be empty."))]
+    pub token: String,
+    #[validate(length(min = 1, message = "Must not be empty."))]
     pub password: String,
 }
@@ -0,0 +1,280 @@
+//! Sharing the metadata of a library with another server.
+//!
+//! A bundle carries what a library knows about its books beyond their files: titles and
+//! descriptions, chapter titles, covers, extra fields, translations, provider matches and series.
+//! Audio is not part of it. Importing applies a bundle to the books of another library whose
+//! files have the same hash, others are left alone. Bundles are signed with `bundles.secret`, so
+//! servers sharing a library need the same secret.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use base64;
+use chrono::Utc;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use ring::{digest, hmac};
+use serde_json;
+
+use crate::config::Config;
+use crate::helpers::sorting;
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::book_match::BookMatch;
+use crate::models::chapter::Chapter;
+use crate::models::library::Library;
+use crate::models::metadata::{self, MetadataEntry};
+use crate::models::search;
+use crate::models::series::Series;
+use crate::models::translation;
+use crate::schema::{audiobooks, chapters};
+use crate::worker::error::Result;
+use crate::worker::hashing;
+use crate::worker::layout;
+use crate::worker::mediafile::{Image, ImageType};
+use crate::worker::thumbnails;
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Fail)]
+pub enum BundleError {
+    #[fail(display = "The bundle was not signed with this server's secret.")]
+    InvalidSignature,
+    #[fail(display = "Bundles of version {} are not supported.", version)]
+    UnsupportedVersion {
+        version: u32,
+    },
+    #[fail(display = "The bundle could not be read: {}", description)]
+    Malformed {
+        description: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub version: u32,
+    /// The `BundledBook`s as JSON, kept as text so the signature covers exactly what was signed.
+    pub books: String,
+    /// Base64 of the HMAC-SHA256 of `books`.
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundledBook {
+    /// Hex encoded hash of the book's files, see `Audiobook::hash`.
+    pub hash: String,
+    pub title: String,
+    pub description: Option<String>,
+    /// Titles of the chapters by their number.
+    pub chapters: BTreeMap<i64, String>,
+    /// Base64 of the cover image.
+    pub cover: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub translations: Vec<BundledTranslation>,
+    pub book_match: Option<BundledMatch>,
+    pub series: Option<String>,
+    pub series_position: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundledTranslation {
+    pub language: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundledMatch {
+    pub provider: String,
+    pub external_id: String,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub series_position: Option<f64>,
+}
+
+/// What importing a bundle did.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Imported {
+    /// Books in the bundle.
+    pub books: usize,
+    /// Books of the library that were updated from the bundle.
+    pub matched: usize,
+}
+
+/// The cover of an imported book. Covers are written once the import is committed, so an import
+/// that is rolled back leaves no covers behind.
+pub struct PendingCover {
+    pub audiobook_id: Uuid,
+    image: Image,
+}
+
+impl PendingCover {
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let dest = layout::cover_path(&config.data_directory, &self.audiobook_id);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.image.save(&dest)?;
+        thumbnails::remove_stale(&config.data_directory, &self.audiobook_id, Some(&self.image.checksum()))
+    }
+}
+
+fn signing_key(secret: &str) -> hmac::SigningKey {
+    hmac::SigningKey::new(&digest::SHA256, secret.as_bytes())
+}
+
+/// Sign `books` with `secret`.
+pub fn seal(books: &[BundledBook], secret: &str) -> Result<Bundle> {
+    let books = serde_json::to_string(books)?;
+    let signature = base64::encode(hmac::sign(&signing_key(secret), books.as_bytes()).as_ref());
+    Ok(Bundle { version: VERSION, books, signature })
+}
+
+/// The books of a bundle, if it was signed with `secret`.
+pub fn open(bundle: &Bundle, secret: &str) -> Result<Vec<BundledBook>> {
+    if bundle.version != VERSION {
+        return Err(BundleError::UnsupportedVersion { version: bundle.version }.into());
+    }
+    let signature = base64::decode(&bundle.signature).map_err(|_| BundleError::InvalidSignature)?;
+    hmac::verify_with_own_key(&signing_key(secret), bundle.books.as_bytes(), &signature)
+        .map_err(|_| BundleError::InvalidSignature)?;
+    serde_json::from_str(&bundle.books)
+        .map_err(|e| BundleError::Malformed { description: e.to_string() }.into())
+}
+
+fn bundle_book(book: &Audiobook, config: &Config, conn: &SqliteConnection) -> Result<BundledBook> {
+    let chapters = Chapter::belonging_to(book)
+        .load::<Chapter>(conn)?
+        .into_iter()
+        .filter_map(|c| c.title.map(|title| (c.number, title)))
+        .collect();
+    let cover = match book.cover_hash {
+        Some(_) => Some(base64::encode(&fs::read(layout::cover_path(&config.data_directory, &book.id))?)),
+        None => None,
+    };
+    let metadata = MetadataEntry::belonging_to(book)
+        .load::<MetadataEntry>(conn)?
+        .into_iter()
+        .map(|e| (e.key, e.value))
+        .collect();
+    let translations = translation::of(book, conn)?
+        .into_iter()
+        .map(|t| BundledTranslation { language: t.language, title: t.title, description: t.description })
+        .collect();
+    let book_match = BookMatch::of(book, conn)?.map(|m| BundledMatch {
+        provider: m.provider,
+        external_id: m.external_id,
+        narrator: m.narrator,
+        series: m.series,
+        series_position: m.series_position,
+    });
+    let series = match book.series_id {
+        Some(ref id) => Series::find(id, conn)?.map(|s| s.name),
+        None => None,
+    };
+    Ok(BundledBook {
+        hash: hashing::to_hex(&book.hash),
+        title: book.title.clone(),
+        description: book.description.clone(),
+        chapters,
+        cover,
+        metadata,
+        translations,
+        book_match,
+        series,
+        series_position: book.series_position,
+    })
+}
+
+/// A bundle of the books in `library` that are not deleted.
+pub fn export(library: &Library, secret: &str, config: &Config, conn: &SqliteConnection) -> Result<Bundle> {
+    let books = Audiobook::belonging_to(library)
+        .filter(audiobooks::dsl::deleted.eq(false))
+        .order(audiobooks::dsl::location.asc())
+        .load::<Audiobook>(conn)?;
+    let bundled = books.iter()
+        .map(|b| bundle_book(b, config, conn))
+        .collect::<Result<Vec<BundledBook>>>()?;
+    seal(&bundled, secret)
+}
+
+fn apply(book: &Audiobook, bundled: &BundledBook, conn: &SqliteConnection) -> Result<Option<PendingCover>> {
+    let mut updated = book.clone();
+    updated.title = bundled.title.clone();
+    updated.sort_title = sorting::sort_title(&bundled.title);
+    updated.description = bundled.description.clone();
+    let mut cover = None;
+    if let Some(ref encoded) = bundled.cover {
+        let data = base64::decode(encoded).map_err(|e| BundleError::Malformed { description: e.to_string() })?;
+        if let Some(image_type) = ImageType::guess(&data) {
+            let image = Image { data, image_type };
+            updated.cover_hash = Some(image.checksum());
+            updated.cover_mime = Some(image.image_type.mime_type().to_owned());
+            cover = Some(PendingCover { audiobook_id: book.id, image });
+        }
+    }
+    diesel::update(audiobooks::table.filter(audiobooks::dsl::id.eq(&book.id)))
+        .set((
+            audiobooks::dsl::title.eq(&updated.title),
+            audiobooks::dsl::sort_title.eq(&updated.sort_title),
+            audiobooks::dsl::description.eq(&updated.description),
+            audiobooks::dsl::cover_hash.eq(&updated.cover_hash),
+            audiobooks::dsl::cover_mime.eq(&updated.cover_mime),
+        ))
+        .execute(conn)?;
+    for (number, title) in &bundled.chapters {
+        diesel::update(Chapter::belonging_to(book).filter(chapters::dsl::number.eq(number)))
+            .set(chapters::dsl::title.eq(title))
+            .execute(conn)?;
+    }
+    let fields = bundled.metadata.iter()
+        .map(|(k, v)| (k.clone(), Some(v.clone())))
+        .collect::<BTreeMap<String, Option<String>>>();
+    metadata::update(book, &fields, conn)?;
+    for t in &bundled.translations {
+        translation::set(
+            book, &t.language, t.title.as_ref().map(String::as_str), t.description.as_ref().map(String::as_str), conn
+        )?;
+    }
+    if let Some(ref m) = bundled.book_match {
+        BookMatch {
+            audiobook_id: book.id,
+            provider: m.provider.clone(),
+            external_id: m.external_id.clone(),
+            narrator: m.narrator.clone(),
+            series: m.series.clone(),
+            series_position: m.series_position,
+            matched_at: Utc::now().naive_utc(),
+        }.save(conn)?;
+    }
+    if let Some(ref name) = bundled.series {
+        let s = Series::for_name(name, conn)?;
+        updated.set_series(&s, bundled.series_position, conn)?;
+    }
+    search::index_book(&updated, conn)?;
+    Ok(cover)
+}
+
+/// Apply the books of a bundle to the books of `library` with the same hash. What the bundle
+/// has replaces what the books had, fields and translations the bundle lacks are kept.
+///
+/// Callers run this in a transaction and save the returned covers once it is committed.
+pub fn import(library: &Library, books: &[BundledBook], conn: &SqliteConnection)
+    -> Result<(Imported, Vec<PendingCover>)> {
+    let mut by_hash = Audiobook::belonging_to(library)
+        .filter(audiobooks::dsl::deleted.eq(false))
+        .load::<Audiobook>(conn)?
+        .into_iter()
+        .map(|b| (hashing::to_hex(&b.hash), b))
+        .collect::<HashMap<String, Audiobook>>();
+    let mut matched = 0;
+    let mut covers = Vec::new();
+    for bundled in books {
+        if let Some(book) = by_hash.remove(&bundled.hash.to_lowercase()) {
+            covers.extend(apply(&book, bundled, conn)?);
+            matched += 1;
+        }
+    }
+    Ok((Imported { books: books.len(), matched }, covers))
+}
@@ -7,13 +7,15 @@ use diesel;
 use crate::helpers;
 use std::io;
 use std::error::Error as StdError;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
-use failure::Error;
+use failure::{Backtrace, Error, Fail};
 use std::str;
 use std;
 
 pub type Result<T> = StdResult<T, Error>;
 
+/// Everything that can go wrong in the worker, the API turns these into responses, see `APIError`.
 #[derive(Debug, Fail)]
 pub enum WorkerError {
     #[fail(display = "Invalid Utf-8")]
@@ -37,6 +39,14 @@ pub enum WorkerError {
     NotAnAudioFile,
     #[fail(display = "This path is outside the library")]
     OutsideLibrary,
+    #[fail(display = "The server is shutting down")]
+    Stopped,
+    #[fail(display = "A scan of this library is already running")]
+    AlreadyRunning,
+    #[fail(display = "The library was deleted")]
+    LibraryDeleted,
+    #[fail(display = "{}", _0)]
+    File(#[cause] FileError),
 }
 
 pub fn new_media_error(code: i32) -> WorkerError {
@@ -52,3 +62,42 @@ pub fn new_media_error(code: i32) -> WorkerError {
         }
     }
 }
+
+/// An error along with what was done to which file when it happened. Errors from FFmpeg or the
+/// file system alone, like "Error -22: Invalid argument", don't tell which book is broken.
+/// These messages are logged, API responses only tell the step so they don't give away paths on
+/// the server, see `APIError`.
+#[derive(Debug)]
+pub struct FileError {
+    /// What was done, e.g. "open", read as "Could not open <path>".
+    pub step: &'static str,
+    pub path: PathBuf,
+    pub cause: Error,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Could not {} {}: {}", self.step, self.path.display(), self.cause)
+    }
+}
+
+impl Fail for FileError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.cause.as_fail())
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        Some(self.cause.backtrace())
+    }
+}
+
+pub trait ResultExt<T> {
+    /// Turn the error into a `FileError` for doing `step` to the file at `path`.
+    fn in_file(self, step: &'static str, path: &Path) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for StdResult<T, E> {
+    fn in_file(self, step: &'static str, path: &Path) -> Result<T> {
+        self.map_err(|e| WorkerError::File(FileError { step, path: path.to_owned(), cause: e.into() }).into())
+    }
+}
@@ -0,0 +1,117 @@
+//! Fault injection for exercising the error paths of scans and streaming.
+//!
+//! Debug builds read `VORLESER_FAULTS` when a thread first touches a fault point, e.g.
+//! `VORLESER_FAULTS="io=0.05,ffmpeg=0.1,slow=200"` fails 5% of file reads, 10% of ffmpeg calls and
+//! delays every read by 200 milliseconds. Tests use `Inject` instead. Release builds never fail
+//! here.
+
+use std::cell::Cell;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::worker::error::{Result, WorkerError};
+
+/// Error code of injected ffmpeg failures, so they can be told apart in logs.
+pub const INJECTED_MEDIA_ERROR: i32 = -0x4641_554c;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Faults {
+    /// Probability of a file read failing.
+    pub io: f64,
+    /// Probability of an ffmpeg call failing.
+    pub ffmpeg: f64,
+    /// Milliseconds each file read is delayed by.
+    pub slow_reads: u64,
+}
+
+impl Faults {
+    /// Parse a spec like `"io=0.05,ffmpeg=0.1,slow=200"`, unknown or broken parts are ignored.
+    pub fn parse(spec: &str) -> Faults {
+        let mut faults = Faults::default();
+        for part in spec.split(',') {
+            let mut kv = part.splitn(2, '=').map(str::trim);
+            match (kv.next(), kv.next()) {
+                (Some("io"), Some(p)) => faults.io = p.parse().unwrap_or(0.0),
+                (Some("ffmpeg"), Some(p)) => faults.ffmpeg = p.parse().unwrap_or(0.0),
+                (Some("slow"), Some(ms)) => faults.slow_reads = ms.parse().unwrap_or(0),
+                _ => warn!("Ignoring fault spec {:?}", part),
+            }
+        }
+        faults
+    }
+
+    #[cfg(debug_assertions)]
+    fn from_env() -> Faults {
+        std::env::var("VORLESER_FAULTS").map(|s| Faults::parse(&s)).unwrap_or_default()
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn from_env() -> Faults {
+        Faults::default()
+    }
+}
+
+thread_local! {
+    static FAULTS: Cell<Faults> = Cell::new(Faults::from_env());
+}
+
+/// Injects faults on the current thread for as long as it lives.
+pub struct Inject(Faults);
+
+impl Inject {
+    pub fn apply(faults: Faults) -> Inject {
+        Inject(FAULTS.with(|f| f.replace(faults)))
+    }
+}
+
+impl Drop for Inject {
+    fn drop(&mut self) {
+        FAULTS.with(|f| f.set(self.0));
+    }
+}
+
+fn current() -> Faults {
+    if cfg!(debug_assertions) {
+        FAULTS.with(Cell::get)
+    } else {
+        Faults::default()
+    }
+}
+
+fn happens(probability: f64) -> bool {
+    if probability <= 0.0 {
+        return false;
+    }
+    let mut bytes = [0u8; 4];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return false;
+    }
+    let roll = u32::from(bytes[0]) << 24 | u32::from(bytes[1]) << 16 | u32::from(bytes[2]) << 8 | u32::from(bytes[3]);
+    f64::from(roll) / f64::from(u32::max_value()) < probability
+}
+
+/// Call before reading from a file.
+pub fn read() -> io::Result<()> {
+    let faults = current();
+    if faults.slow_reads > 0 {
+        thread::sleep(Duration::from_millis(faults.slow_reads));
+    }
+    if happens(faults.io) {
+        return Err(io::Error::new(io::ErrorKind::Other, "Injected read error"));
+    }
+    Ok(())
+}
+
+/// Call before handing a file to ffmpeg.
+pub fn ffmpeg() -> Result<()> {
+    if happens(current().ffmpeg) {
+        return Err(WorkerError::MediaError {
+            description: "Injected ffmpeg error".to_owned(),
+            code: INJECTED_MEDIA_ERROR,
+        }.into());
+    }
+    Ok(())
+}
@@ -1,4 +1,7 @@
+use std::cell::Cell;
 use std::fs::File;
+use std::thread;
+use std::time::{Duration, Instant};
 use ring::digest;
 use std::io::Read;
 use walkdir::WalkDir;
@@ -6,24 +9,73 @@ use std::path::{Path, PathBuf};
 use humanesort::HumaneOrder;
 
 use super::error::*;
+use super::faults;
+
+/// Audiobooks tend to be large, reading them in big chunks keeps the number of syscalls low.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+thread_local! {
+    /// Bytes per second hashing may read on this thread, see `RateLimit`.
+    static RATE_LIMIT: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Limits how fast hashing reads on the current thread for as long as it lives, so scans on slow
+/// disks leave some bandwidth for streaming.
+pub struct RateLimit(Option<u64>);
+
+impl RateLimit {
+    pub fn apply(bytes_per_sec: Option<u64>) -> RateLimit {
+        RateLimit(RATE_LIMIT.with(|limit| limit.replace(bytes_per_sec)))
+    }
+}
+
+impl Drop for RateLimit {
+    fn drop(&mut self) {
+        RATE_LIMIT.with(|limit| limit.set(self.0));
+    }
+}
 
 /// Checksum of a whole directory.
 pub fn checksum_file(path: &dyn AsRef<Path>) -> Result<Vec<u8>> {
     let mut ctx = digest::Context::new(&digest::SHA256);
-    update_hash_from_file(&mut ctx, path)?;
+    update_hash_from_file(&mut ctx, path).in_file("hash", path.as_ref())?;
     let mut res = Vec::new();
     res.extend_from_slice(ctx.finish().as_ref());
     Ok(res)
 }
 
+/// Checksum of an in-memory buffer.
+pub fn checksum_bytes(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::new();
+    res.extend_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    res
+}
+
+/// Lowercase hex representation of a checksum.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Update hash object using file content
 fn update_hash_from_file(ctx: &mut digest::Context, path: &dyn AsRef<Path>) -> Result<()> {
     let mut file = File::open(path.as_ref())?;
-    let mut buf: [u8; 1024] = [0; 1024];
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let limit = RATE_LIMIT.with(Cell::get).filter(|l| *l > 0);
+    let started = Instant::now();
+    let mut total: u64 = 0;
     loop {
+        faults::read()?;
         let count = file.read(&mut buf[..])?;
-        ctx.update(&buf[0..count]);
         if count == 0 { break }
+        ctx.update(&buf[0..count]);
+        total += count as u64;
+        if let Some(bytes_per_sec) = limit {
+            let due = Duration::from_millis(total * 1000 / bytes_per_sec);
+            let elapsed = started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
     }
     Ok(())
 }
@@ -51,3 +103,17 @@ pub fn checksum_dir(path: &dyn AsRef<Path>) -> Result<Vec<u8>> {
     res.extend_from_slice(ctx.finish().as_ref());
     Ok(res)
 }
+
+/// Checksum of a list of files in the given order, their names are part of it.
+pub fn checksum_files(paths: &[PathBuf]) -> Result<Vec<u8>> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    for path in paths {
+        update_hash_from_file(&mut ctx, path)?;
+        if let Some(name) = path.file_name() {
+            ctx.update(name.to_string_lossy().as_bytes());
+        }
+    }
+    let mut res = Vec::new();
+    res.extend_from_slice(ctx.finish().as_ref());
+    Ok(res)
+}
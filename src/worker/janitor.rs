@@ -0,0 +1,165 @@
+//! Keeps track of unfinished output files and removes the ones left behind by crashes.
+//!
+//! Output is written to a temporary file next to its destination and renamed once complete,
+//! so readers never see half written files. If the process dies in between, the temporary file
+//! stays around until `clean` finds it.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use log::error as error_log;
+
+use chrono::prelude::*;
+
+use crate::config::Config;
+use crate::helpers::db::Pool;
+use crate::models::audiobook::Audiobook;
+use crate::models::audit_log;
+use crate::worker::error::Result;
+use crate::worker::layout;
+
+/// Unfinished files are named like their destination with this inserted before the extension.
+/// The extension itself is kept as ffmpeg uses it to pick the output format.
+const PARTIAL_MARKER: &str = ".part";
+
+/// Files older than this are assumed to be left over from a crash.
+pub const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+lazy_static! {
+    static ref IN_PROGRESS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// A temporary file that is moved to its destination with `persist`.
+/// Dropping it without persisting removes the file.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    destination: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    pub fn new(destination: &dyn AsRef<Path>) -> TempFile {
+        let destination = destination.as_ref().to_owned();
+        let path = partial_path(&destination);
+        IN_PROGRESS.lock().unwrap().insert(path.clone());
+        TempFile {
+            path,
+            destination,
+            persisted: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Atomically move the file to its destination.
+    pub fn persist(mut self) -> Result<()> {
+        fs::rename(&self.path, &self.destination)?;
+        self.persisted = true;
+        Ok(())
+    }
+
+    /// Like `persist`, for files whose destination is only known once they are written.
+    pub fn persist_to(mut self, destination: &dyn AsRef<Path>) -> Result<()> {
+        self.destination = destination.as_ref().to_owned();
+        self.persist()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted && self.path.exists() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error_log!("Could not remove unfinished file {}: {}", self.path.display(), e);
+            }
+        }
+        IN_PROGRESS.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// `data/book.mp3` becomes `data/book.part.mp3`.
+pub fn partial_path(destination: &Path) -> PathBuf {
+    let mut file_name = OsString::new();
+    if let Some(stem) = destination.file_stem() {
+        file_name.push(stem);
+    }
+    file_name.push(PARTIAL_MARKER);
+    if let Some(extension) = destination.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    destination.with_file_name(file_name)
+}
+
+pub fn is_partial(path: &Path) -> bool {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().ends_with(PARTIAL_MARKER))
+        .unwrap_or(false)
+}
+
+/// Remove unfinished files in `directory` that are older than `max_age` and not being written to
+/// by this process. Returns the number of removed files.
+pub fn clean(directory: &dyn AsRef<Path>, max_age: Duration) -> Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(directory.as_ref())? {
+        let path = entry?.path();
+        if !is_partial(&path) || IN_PROGRESS.lock().unwrap().contains(&path) {
+            continue;
+        }
+        let modified = fs::metadata(&path)?.modified()?;
+        let age = SystemTime::now().duration_since(modified).unwrap_or_else(|_| Duration::from_secs(0));
+        if age >= max_age {
+            info!("Removing leftover unfinished file {}", path.display());
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove everything the data directory holds for the given books: the data file, the cover and
+/// its thumbnails, chapter images and split chapters. Files that are already gone are fine.
+pub fn remove_book_files(data_directory: &str, books: &[Audiobook]) {
+    let chapters = Path::new(data_directory).join("chapters");
+    for book in books {
+        let id = book.id.hyphenated().to_string();
+        let mut paths = vec![
+            Path::new(data_directory).join(format!("{}.{}", id, book.file_extension)),
+            layout::cover_path(data_directory, &book.id),
+        ];
+        if let Ok(entries) = fs::read_dir(&chapters) {
+            paths.extend(entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.file_name().map(|n| n.to_string_lossy().starts_with(&id)).unwrap_or(false)));
+        }
+        for path in paths {
+            if let Err(e) = fs::remove_file(&path) {
+                if path.exists() {
+                    warn!("Could not remove {}: {}", path.display(), e);
+                }
+            }
+        }
+        let directories = [
+            layout::thumbnail_directory(data_directory, &book.id),
+            layout::chapter_image_directory(data_directory, &book.id),
+        ];
+        for directory in directories.iter() {
+            if let Err(e) = fs::remove_dir_all(directory) {
+                if directory.exists() {
+                    warn!("Could not remove {}: {}", directory.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Remove audit log entries older than `retention.audit_log`, returns how many there were.
+pub fn prune_audit_log(pool: &Pool, config: &Config) -> Result<usize> {
+    let cutoff = Utc::now().naive_utc() - chrono::Duration::seconds(config.retention.audit_log as i64);
+    Ok(audit_log::prune(cutoff, &*pool.get()?)?)
+}
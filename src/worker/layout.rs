@@ -0,0 +1,142 @@
+//! The layout of the data directory and upgrades of it.
+//!
+//! The version of the layout is kept in a `layout_version` file inside the data directory.
+//! Directories without one predate versioning and are at version 1. Each upgrade moves files
+//! written by older versions to where the current code looks for them and records the version it
+//! reached, so an interrupted upgrade picks up where it stopped.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::helpers::uuid::Uuid;
+use crate::worker::error::{Result, WorkerError};
+use crate::worker::thumbnails;
+
+pub const CURRENT_VERSION: u32 = 3;
+
+const VERSION_FILE: &str = "layout_version";
+
+/// Where the cover of a book is kept. Covers are spread over subdirectories named after the
+/// start of the book id, so no single directory grows too large.
+pub fn cover_path(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let id = book_id.hyphenated().to_string();
+    Path::new(data_directory).join("img").join(&id[..2]).join(id)
+}
+
+/// Where the images shown at the starts of a book's chapters are kept, next to the cover of the
+/// book.
+pub fn chapter_image_directory(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let mut path = cover_path(data_directory, book_id);
+    path.set_extension("chapters");
+    path
+}
+
+/// Where the image shown at the start of a chapter is kept.
+pub fn chapter_image_path(data_directory: &str, book_id: &Uuid, chapter_id: &Uuid) -> PathBuf {
+    chapter_image_directory(data_directory, book_id).join(chapter_id.hyphenated().to_string())
+}
+
+/// Where the thumbnails of a book's cover are kept, see `worker::thumbnails`.
+pub fn thumbnail_directory(data_directory: &str, book_id: &Uuid) -> PathBuf {
+    let mut path = cover_path(data_directory, book_id);
+    path.set_extension("thumbnails");
+    path
+}
+
+pub fn version(data_directory: &str) -> Result<u32> {
+    match fs::read_to_string(Path::new(data_directory).join(VERSION_FILE)) {
+        Ok(content) => content.trim().parse().map_err(|_| WorkerError::Other {
+            description: format!("Invalid {} in {}: {:?}", VERSION_FILE, data_directory, content)
+        }.into()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(1),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn set_version(data_directory: &str, version: u32) -> Result<()> {
+    fs::write(Path::new(data_directory).join(VERSION_FILE), format!("{}\n", version))?;
+    Ok(())
+}
+
+/// Bring the data directory up to `CURRENT_VERSION`, returns the version it was at.
+/// Directories written by a newer version are refused rather than guessed at.
+pub fn upgrade(data_directory: &str) -> Result<u32> {
+    fs::create_dir_all(data_directory)?;
+    let found = version(data_directory)?;
+    if found > CURRENT_VERSION {
+        return Err(WorkerError::Other {
+            description: format!(
+                "The data directory {} has layout version {}, this version of vorleser only knows up to {}.",
+                data_directory, found, CURRENT_VERSION
+            )
+        }.into());
+    }
+    for from in found..CURRENT_VERSION {
+        info!("Upgrading the data directory from layout version {} to {}", from, from + 1);
+        match from {
+            1 => move_covers_into_subdirectories(data_directory)?,
+            2 => remove_shared_thumbnails(data_directory)?,
+            _ => unreachable!(),
+        }
+        set_version(data_directory, from + 1)?;
+    }
+    Ok(found)
+}
+
+/// Version 1 kept all covers directly in `img`.
+fn move_covers_into_subdirectories(data_directory: &str) -> Result<()> {
+    let img = Path::new(data_directory).join("img");
+    let entries = match fs::read_dir(&img) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let book_id = match path.file_name().and_then(|n| n.to_str()).and_then(|n| Uuid::parse_str(n).ok()) {
+            Some(id) => id,
+            None => {
+                warn!("Leaving {} in place, it is not named after a book.", path.display());
+                continue;
+            }
+        };
+        let target = cover_path(data_directory, &book_id);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&path, &target)?;
+    }
+    Ok(())
+}
+
+/// Version 2 kept thumbnails next to the covers, named after the cover alone. Nothing tells which
+/// book they belong to, so they are removed and made again when they are asked for.
+fn remove_shared_thumbnails(data_directory: &str) -> Result<()> {
+    let img = Path::new(data_directory).join("img");
+    let directories = match fs::read_dir(&img) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for directory in directories {
+        let directory = directory?.path();
+        if !directory.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let is_thumbnail = path.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| thumbnails::SIZES.iter().any(|(size, _)| n.ends_with(&format!("-{}", size))))
+                .unwrap_or(false);
+            if path.is_file() && is_thumbnail {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,270 @@
+//! Looking up books at a metadata provider.
+//!
+//! Books are searched in the Audible catalog by their ISBN if their tags have one, or else by title
+//! and author. A match fills in the narrator
+//! and series (kept as a `BookMatch`) along with the description and cover of the book. Matches
+//! made while scanning only fill in what the tags left out, matches chosen by an admin replace it.
+
+use std::fs;
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use serde_json::Value;
+
+use crate::config::{Config, LookupConfig};
+use crate::models::audiobook::Audiobook;
+use crate::models::book_match::BookMatch;
+use crate::models::search;
+use crate::models::series::{self, Series};
+use crate::schema::audiobooks;
+use crate::worker::error::{Result, WorkerError};
+use crate::worker::layout;
+use crate::worker::mediafile::{Image, ImageType};
+use crate::worker::thumbnails;
+
+pub const PROVIDER: &str = "audible";
+
+const RESPONSE_GROUPS: &str = "contributors,media,product_desc,product_extended_attrs,series";
+
+/// A book as the provider knows it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candidate {
+    /// The provider's id of the book, the ASIN for Audible.
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub narrators: Vec<String>,
+    pub series: Option<String>,
+    pub series_position: Option<f64>,
+    pub description: Option<String>,
+    pub cover_url: Option<String>,
+}
+
+fn client(config: &LookupConfig) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout))
+        .build()?)
+}
+
+fn query_products(config: &LookupConfig, mut query: Vec<(&str, &str)>) -> Result<Vec<Candidate>> {
+    let url = format!("{}/1.0/catalog/products", config.url.trim_end_matches('/'));
+    query.extend(vec![
+        ("num_results", "10"),
+        ("products_sort_by", "Relevance"),
+        ("response_groups", RESPONSE_GROUPS),
+    ]);
+    let body: Value = client(config)?.get(&url).query(&query).send()?.error_for_status()?.json()?;
+    Ok(parse_products(&body))
+}
+
+/// Books at the provider with this title and author, best matches first.
+pub fn search(config: &LookupConfig, title: &str, author: Option<&str>) -> Result<Vec<Candidate>> {
+    let mut query = vec![("title", title)];
+    if let Some(author) = author {
+        query.push(("author", author));
+    }
+    query_products(config, query)
+}
+
+/// Books at the provider with this ISBN, see `normalize_isbn`.
+pub fn search_isbn(config: &LookupConfig, isbn: &str) -> Result<Vec<Candidate>> {
+    query_products(config, vec![("keywords", isbn)])
+}
+
+/// The digits of an ISBN-10 or ISBN-13 without hyphens or spaces, `None` if `text` is neither.
+pub fn normalize_isbn(text: &str) -> Option<String> {
+    let isbn = text.trim()
+        .trim_start_matches("ISBN")
+        .trim_start_matches(':')
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect::<String>();
+    let valid = match isbn.len() {
+        10 => isbn[..9].chars().all(|c| c.is_ascii_digit()) && isbn[9..].chars().all(|c| c.is_ascii_digit() || c == 'X'),
+        13 => isbn.chars().all(|c| c.is_ascii_digit()),
+        _ => false,
+    };
+    if valid { Some(isbn) } else { None }
+}
+
+/// The candidates in a response of the catalog API, products without an id or title are skipped.
+pub fn parse_products(body: &Value) -> Vec<Candidate> {
+    let names = |product: &Value, key: &str| -> Vec<String> {
+        product[key].as_array()
+            .map(|people| people.iter().filter_map(|p| p["name"].as_str().map(str::to_owned)).collect())
+            .unwrap_or_else(Vec::new)
+    };
+    let products = match body["products"].as_array() {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+    products.iter().filter_map(|product| {
+        let id = product["asin"].as_str()?.to_owned();
+        let title = product["title"].as_str()?.to_owned();
+        let series = &product["series"][0];
+        Some(Candidate {
+            id,
+            title,
+            authors: names(product, "authors"),
+            narrators: names(product, "narrators"),
+            series: series["title"].as_str().map(str::to_owned),
+            series_position: series["sequence"].as_str().and_then(series::parse_position),
+            description: product["publisher_summary"].as_str()
+                .or_else(|| product["merchandising_summary"].as_str())
+                .map(strip_html)
+                .filter(|d| !d.is_empty()),
+            cover_url: product["product_images"]["500"].as_str().map(str::to_owned),
+        })
+    }).collect()
+}
+
+/// Text of the HTML descriptions the provider sends, paragraphs become lines.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut tag: Option<String> = None;
+    for c in html.chars() {
+        match tag.take() {
+            None if c == '<' => tag = Some(String::new()),
+            None => text.push(c),
+            Some(name) => if c == '>' {
+                let name = name.trim_start_matches('/').trim_end_matches('/').trim().to_lowercase();
+                if name == "p" || name.starts_with("p ") || name == "br" {
+                    text.push('\n');
+                }
+            } else {
+                tag = Some(name + &c.to_string());
+            },
+        }
+    }
+    let text = text.replace("&nbsp;", " ")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+    text.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn normalized(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_alphanumeric() { c.to_lowercase().next().unwrap_or(c) } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Whether `candidate` is sure enough to be the book with this title and artist to apply it
+/// without asking: the titles are the same, and if there is an artist one of the authors is part
+/// of it.
+pub fn is_confident(title: &str, artist: Option<&str>, candidate: &Candidate) -> bool {
+    let same_title = normalized(title) == normalized(&candidate.title);
+    let same_author = match artist {
+        Some(artist) => {
+            let artist = normalized(artist);
+            candidate.authors.iter().any(|a| !a.is_empty() && artist.contains(&normalized(a)))
+        },
+        None => true,
+    };
+    same_title && same_author
+}
+
+fn download_cover(config: &LookupConfig, url: &str) -> Result<Image> {
+    let mut data = Vec::new();
+    client(config)?.get(url).send()?.error_for_status()?.copy_to(&mut data)?;
+    match ImageType::guess(&data) {
+        Some(image_type) => Ok(Image { data, image_type }),
+        None => Err(WorkerError::Other { description: format!("The cover at {} is no PNG or JPEG.", url) }.into()),
+    }
+}
+
+/// Apply `candidate` to `book`. Unless `replace` is set, the description and cover are only
+/// filled in if the book has none. Returns the updated book.
+pub fn apply(book: &Audiobook, candidate: &Candidate, replace: bool, lookup: &LookupConfig, config: &Config,
+             conn: &SqliteConnection) -> Result<Audiobook> {
+    let mut updated = book.clone();
+    if let Some(ref description) = candidate.description {
+        if replace || book.description.is_none() {
+            updated.description = Some(description.clone());
+        }
+    }
+    if let Some(ref url) = candidate.cover_url {
+        if replace || book.cover_hash.is_none() {
+            let cover = download_cover(lookup, url)?;
+            let dest = layout::cover_path(&config.data_directory, &book.id);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            cover.save(&dest)?;
+            thumbnails::remove_stale(&config.data_directory, &book.id, Some(&cover.checksum()))?;
+            updated.cover_hash = Some(cover.checksum());
+            updated.cover_mime = Some(cover.image_type.mime_type().to_owned());
+        }
+    }
+    let found = BookMatch {
+        audiobook_id: book.id,
+        provider: PROVIDER.to_owned(),
+        external_id: candidate.id.clone(),
+        narrator: Some(candidate.narrators.join(", ")).filter(|n| !n.is_empty()),
+        series: candidate.series.clone(),
+        series_position: candidate.series_position,
+        matched_at: Utc::now().naive_utc(),
+    };
+    conn.exclusive_transaction(|| -> Result<()> {
+        if let Some(ref name) = candidate.series {
+            if replace || updated.series_id.is_none() {
+                let s = Series::for_name(name, conn)?;
+                updated.set_series(&s, candidate.series_position, conn)?;
+            }
+        }
+        diesel::update(audiobooks::table.filter(audiobooks::dsl::id.eq(&book.id)))
+            .set((
+                audiobooks::dsl::description.eq(&updated.description),
+                audiobooks::dsl::cover_hash.eq(&updated.cover_hash),
+                audiobooks::dsl::cover_mime.eq(&updated.cover_mime),
+            ))
+            .execute(conn)?;
+        found.save(conn)?;
+        search::index_book(&updated, conn)?;
+        Ok(())
+    })?;
+    Ok(updated)
+}
+
+/// Match a book that was just scanned, if looking up books on scan is enabled and it wasn't
+/// matched before. Returns whether a match was applied.
+///
+/// An ISBN from the tags names the book, the first result for it is applied. Books without one,
+/// or whose ISBN the provider doesn't know, are searched by title and author.
+pub fn on_scan(book: &Audiobook, isbn: Option<&str>, config: &Config, conn: &SqliteConnection) -> Result<bool> {
+    let lookup = match config.metadata.lookup {
+        Some(ref l) if l.on_scan => l,
+        _ => return Ok(false),
+    };
+    if BookMatch::of(book, conn)?.is_some() {
+        return Ok(false);
+    }
+    if let Some(isbn) = isbn {
+        if let Some(candidate) = search_isbn(lookup, isbn)?.first() {
+            apply(book, candidate, false, lookup, config, conn)?;
+            return Ok(true);
+        }
+    }
+    let artist = book.artist.as_ref().map(String::as_str);
+    let candidates = search(lookup, &book.title, artist)?;
+    match candidates.iter().find(|c| is_confident(&book.title, artist, c)) {
+        Some(candidate) => {
+            apply(book, candidate, false, lookup, config, conn)?;
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
@@ -18,6 +18,26 @@ use crate::ffmpeg::{
     av_read_frame,
     AV_TIME_BASE_Q,
     AVERROR_EOF,
+    AV_DISPOSITION_ATTACHED_PIC,
+    AV_NOPTS_VALUE,
+    AVPixelFormat,
+    AVRational,
+    AVERROR,
+    AVSEEK_FLAG_BACKWARD,
+    FF_COMPLIANCE_UNOFFICIAL,
+    av_frame_unref,
+    av_frame_move_ref,
+    av_init_packet,
+    av_packet_unref,
+    av_seek_frame,
+    avcodec_find_decoder,
+    avcodec_find_encoder,
+    avcodec_flush_buffers,
+    avcodec_parameters_to_context,
+    avcodec_receive_frame,
+    avcodec_receive_packet,
+    avcodec_send_frame,
+    avcodec_send_packet,
 };
 
 use std::mem;
@@ -29,15 +49,17 @@ use super::util::*;
 use std::collections::HashMap;
 use std::fmt::{Formatter, Debug};
 use std::str::Split;
-use crate::worker::error::{Result, WorkerError};
+use crate::worker::error::{Result, ResultExt, WorkerError};
 use crate::worker::util::string_from_ptr;
+use crate::worker::hashing;
+use crate::worker::faults;
 use std::fmt;
 use std::error;
 use std::result;
 use std::fs::File;
 use std::io::Write;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum ImageType {
     PNG,
     JPG
@@ -63,12 +85,37 @@ pub struct Chapter {
     pub start: f64,
 }
 
+impl ImageType {
+    /// Recognize an image by its magic number.
+    pub fn guess(data: &[u8]) -> Option<ImageType> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageType::PNG)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(ImageType::JPG)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match *self {
+            ImageType::PNG => "image/png",
+            ImageType::JPG => "image/jpeg",
+        }
+    }
+}
+
 impl Image {
     pub fn save(&self, path: &dyn AsRef<Path>) -> Result<()> {
         let mut file = File::create(path)?;
         file.write_all(&self.data[..])?;
         Ok(())
     }
+
+    /// Hex encoded SHA-256 of the image data.
+    pub fn checksum(&self) -> String {
+        hashing::to_hex(&hashing::checksum_bytes(&self.data))
+    }
 }
 
 impl Chapter {
@@ -92,6 +139,86 @@ impl Chapter {
     }
 }
 
+/// How much later than a chapter a slide may start and still count as shown at its start.
+const SLIDE_TOLERANCE: f64 = 0.5;
+
+/// The indices of `starts` along with them, earliest first.
+fn by_start(starts: &[f64]) -> Vec<(usize, f64)> {
+    let mut starts = starts.iter().cloned().enumerate().collect::<Vec<(usize, f64)>>();
+    starts.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    starts
+}
+
+/// Picks the slide shown at each chapter start while a slide track is read in order, so only the
+/// slide that is currently shown has to be kept.
+pub struct SlidePicker<T> {
+    /// Chapter indices and starts, by start.
+    starts: Vec<(usize, f64)>,
+    next: usize,
+    current: Option<T>,
+}
+
+impl<T: Clone> SlidePicker<T> {
+    pub fn new(starts: &[f64]) -> Self {
+        SlidePicker { starts: by_start(starts), next: 0, current: None }
+    }
+
+    /// A slide shown from `time` on, returns the chapters whose slide is now known.
+    pub fn push(&mut self, time: f64, slide: T) -> Vec<(usize, T)> {
+        let mut picked = Vec::new();
+        while self.next < self.starts.len() && time > self.starts[self.next].1 + SLIDE_TOLERANCE {
+            if let Some(ref current) = self.current {
+                picked.push((self.starts[self.next].0, current.clone()));
+            }
+            self.next += 1;
+        }
+        self.current = Some(slide);
+        picked
+    }
+
+    /// The remaining chapters, which show the last slide.
+    pub fn finish(self) -> Vec<(usize, T)> {
+        match self.current {
+            Some(current) => self.starts[self.next..].iter().map(|(i, _)| (*i, current.clone())).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Pixel formats the JPEG encoder takes as they are, slides in other formats are skipped.
+const JPEG_PIXEL_FORMATS: &[AVPixelFormat] = &[
+    AVPixelFormat::AV_PIX_FMT_YUVJ420P,
+    AVPixelFormat::AV_PIX_FMT_YUVJ422P,
+    AVPixelFormat::AV_PIX_FMT_YUVJ444P,
+    AVPixelFormat::AV_PIX_FMT_YUV420P,
+    AVPixelFormat::AV_PIX_FMT_YUV422P,
+    AVPixelFormat::AV_PIX_FMT_YUV444P,
+];
+
+/// Encode a decoded slide as JPEG, `None` if it has a pixel format the encoder doesn't take.
+unsafe fn encode_jpeg(frame: &Frame) -> Result<Option<Image>> {
+    let pix_fmt = match JPEG_PIXEL_FORMATS.iter().find(|f| **f as i32 == (*frame.0).format) {
+        Some(f) => *f,
+        None => return Ok(None)
+    };
+    let encoder = CodecContext::open(avcodec_find_encoder(AVCodecID::AV_CODEC_ID_MJPEG), |ctx| {
+        (*ctx).width = (*frame.0).width;
+        (*ctx).height = (*frame.0).height;
+        (*ctx).pix_fmt = pix_fmt;
+        (*ctx).time_base = AVRational { num: 1, den: 1 };
+        // Needed for the formats that aren't full range
+        (*ctx).strict_std_compliance = FF_COMPLIANCE_UNOFFICIAL;
+        0
+    })?;
+    check_av_result(avcodec_send_frame(encoder.0, frame.0))?;
+    let mut pkt: AVPacket = mem::zeroed();
+    av_init_packet(&mut pkt);
+    check_av_result(avcodec_receive_packet(encoder.0, &mut pkt))?;
+    let data = slice::from_raw_parts(pkt.data, pkt.size as usize).to_owned();
+    av_packet_unref(&mut pkt);
+    Ok(Some(Image { data, image_type: ImageType::JPG }))
+}
+
 pub struct Format<'a> {
     pub name: Option<String>,
     pub mime_type: Option<String>,
@@ -126,9 +253,12 @@ impl MediaFile {
             Some(s) => s,
             None => return Err(WorkerError::InvalidUtf8.into())
         };
+        faults::ffmpeg()?;
+        let c_file_name = CString::new(file_name_str).map_err(|_| WorkerError::Other {
+            description: format!("File name contains a null byte: {:?}", file_name)
+        })?;
         unsafe {
             ensure_av_register_all();
-            let c_file_name = CString::new(file_name_str).expect("Null byte in filename.");
             let mut new = Self {
                 path: file_name.to_owned(),
                 ctx: avformat_alloc_context(),
@@ -140,8 +270,9 @@ impl MediaFile {
                 c_file_name.as_ptr(),
                 ptr::null_mut(),
                 ptr::null_mut()
-            ))?;
-            check_av_result(avformat_find_stream_info(new.ctx, ptr::null_mut()))?;
+            )).in_file("open", file_name)?;
+            check_av_result(avformat_find_stream_info(new.ctx, ptr::null_mut()))
+                .in_file("read the streams of", file_name)?;
             Ok(new)
         }
     }
@@ -234,6 +365,117 @@ impl MediaFile {
         }
     }
 
+    /// Calls `f` with the index of each of `starts` and the slide shown there, for files that have
+    /// a video track besides their cover, like enhanced podcasts and some m4b audiobooks. Slides
+    /// are handed out one at a time as they are found, starts without a slide are left out.
+    pub fn for_each_slide_at(&self, starts: &[f64], mut f: impl FnMut(usize, Image) -> Result<()>) -> Result<()> {
+        unsafe {
+            let track = self.get_streams().iter().find(|s| {
+                (*s.codecpar).codec_type == AVMediaType::AVMEDIA_TYPE_VIDEO
+                    && s.disposition & AV_DISPOSITION_ATTACHED_PIC == 0
+            });
+            let track = match track {
+                Some(t) => t,
+                None => return Ok(())
+            };
+            let image_type = match (*track.codecpar).codec_id {
+                AVCodecID::AV_CODEC_ID_PNG => ImageType::PNG,
+                AVCodecID::AV_CODEC_ID_MJPEG => ImageType::JPG,
+                _ => return self.decode_slides_at(track, starts, f)
+            };
+            // Every packet is a whole image
+            let mut picker = SlidePicker::new(starts);
+            while let Some(mut pkt) = self.read_packet()? {
+                if pkt.stream_index == track.index && pkt.pts != AV_NOPTS_VALUE {
+                    let data = slice::from_raw_parts(pkt.data, pkt.size as usize).to_owned();
+                    for (i, data) in picker.push(apply_timebase(pkt.pts, track.time_base), data) {
+                        f(i, Image { data, image_type })?;
+                    }
+                }
+                av_free_packet(&mut pkt);
+            }
+            for (i, data) in picker.finish() {
+                f(i, Image { data, image_type })?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Slides of codecs like H.264, where a slide is a frame that has to be decoded. For each start
+    /// this seeks to the key frame before it and decodes up to the frame shown there, which is
+    /// encoded as JPEG.
+    unsafe fn decode_slides_at(&self, track: &AVStream, starts: &[f64], mut f: impl FnMut(usize, Image) -> Result<()>) -> Result<()> {
+        let decoder = CodecContext::open(avcodec_find_decoder((*track.codecpar).codec_id), |ctx| {
+            avcodec_parameters_to_context(ctx, track.codecpar)
+        }).in_file("decode the slides of", &self.path)?;
+        let frame = Frame::new();
+        let shown = Frame::new();
+        for (i, start) in by_start(starts) {
+            let until = start + SLIDE_TOLERANCE;
+            self.seek(track, until)?;
+            avcodec_flush_buffers(decoder.0);
+            av_frame_unref(shown.0);
+            let mut has_shown = false;
+            let mut past = false;
+            let mut draining = false;
+            while !past {
+                match self.read_packet()? {
+                    Some(mut pkt) => {
+                        if pkt.stream_index == track.index {
+                            let sent = avcodec_send_packet(decoder.0, &pkt);
+                            av_free_packet(&mut pkt);
+                            check_av_result(sent).in_file("decode the slides of", &self.path)?;
+                        } else {
+                            av_free_packet(&mut pkt);
+                            continue;
+                        }
+                    },
+                    None if !draining => {
+                        draining = true;
+                        check_av_result(avcodec_send_packet(decoder.0, ptr::null()))
+                            .in_file("decode the slides of", &self.path)?;
+                    },
+                    None => break,
+                }
+                loop {
+                    let received = avcodec_receive_frame(decoder.0, frame.0);
+                    if received == AVERROR(libc::EAGAIN) || received == AVERROR_EOF {
+                        break;
+                    }
+                    check_av_result(received).in_file("decode the slides of", &self.path)?;
+                    let pts = (*frame.0).best_effort_timestamp;
+                    if pts != AV_NOPTS_VALUE && apply_timebase(pts, track.time_base) > until {
+                        av_frame_unref(frame.0);
+                        past = true;
+                        break;
+                    }
+                    av_frame_unref(shown.0);
+                    av_frame_move_ref(shown.0, frame.0);
+                    has_shown = true;
+                }
+                if draining {
+                    break;
+                }
+            }
+            if has_shown {
+                if let Some(image) = encode_jpeg(&shown).in_file("encode the slides of", &self.path)? {
+                    f(i, image)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Seek to the key frame of `stream` at or before `seconds`.
+    pub fn seek(&self, stream: &AVStream, seconds: f64) -> Result<()> {
+        let timestamp = (seconds * f64::from(stream.time_base.den) / f64::from(stream.time_base.num)) as i64;
+        unsafe {
+            check_av_result(av_seek_frame(self.ctx, stream.index, timestamp, AVSEEK_FLAG_BACKWARD))
+                .in_file("seek in", &self.path)?;
+        }
+        Ok(())
+    }
+
     pub fn get_chapters(&self) -> Vec<Chapter> {
         Chapter::from_av_chapters(self.av_chapter_slice())
     }
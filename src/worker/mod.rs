@@ -2,8 +2,18 @@ pub mod muxer;
 pub mod mediafile;
 pub mod error;
 pub mod scanner;
+pub mod scheduler;
 pub mod util;
 pub mod hashing;
+pub mod janitor;
+pub mod layout;
+pub mod splitter;
+pub mod playlist;
+pub mod watcher;
+pub mod faults;
+pub mod lookup;
+pub mod bundle;
+pub mod thumbnails;
 #[cfg(test)]
 pub mod tests;
 #[cfg(test)]
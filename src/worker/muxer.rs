@@ -10,6 +10,8 @@ use super::mediafile::MediaFile;
 use super::util::*;
 use crate::helpers::mllt;
 use crate::worker::error::*;
+use crate::worker::janitor::TempFile;
+use crate::worker::faults;
 use std::error::Error;
 
 use log::error as error_log;
@@ -34,11 +36,14 @@ impl NewMediaFile {
 
     pub fn new(file_name: &Path, codec: &mut AVCodecParameters, time_base: AVRational) -> Result<Self> {
         ensure_av_register_all();
+        faults::ffmpeg()?;
         let c_file_name = CString::new(
                 match file_name.to_str() {
                     Some(s) => s,
                     None => return Err(WorkerError::InvalidUtf8.into())
-                }).unwrap();
+                }).map_err(|_| WorkerError::Other {
+                    description: format!("File name contains a null byte: {:?}", file_name)
+                })?;
         let ipod_short_name = CString::new("ipod").unwrap();
         unsafe {
             let extension = file_name.extension().and_then(OsStr::to_str);
@@ -77,13 +82,31 @@ impl NewMediaFile {
             let mut io_ctx = ptr::null_mut();
             check_av_result(avio_open2(&mut io_ctx, c_file_name.as_ptr(), AVIO_FLAG_WRITE, ptr::null(), ptr::null_mut()))?;
             (*ctx).pb = io_ctx;
-            let stream = ptr_to_opt_mut(avformat_new_stream(ctx, ptr::null())).unwrap();
+            let stream = match ptr_to_opt_mut(avformat_new_stream(ctx, ptr::null())) {
+                Some(s) => s,
+                None => return Err(WorkerError::Other {
+                    description: format!("Could not add a stream to {:?}", file_name)
+                }.into())
+            };
             (*stream).time_base = time_base;
             avcodec_parameters_copy((*stream).codecpar, codec);
             Ok(Self{ ctx, is_mp3, path: file_name.to_owned() })
         }
     }
 
+    /// Set a tag of the output file, has to happen before the header is written.
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> Result<()> {
+        let to_c_string = |s: &str| CString::new(s).map_err(|_| WorkerError::Other {
+            description: format!("Tag contains a null byte: {:?}", s)
+        });
+        let c_key = to_c_string(key)?;
+        let c_value = to_c_string(value)?;
+        unsafe {
+            check_av_result(av_dict_set(&mut (*self.ctx).metadata, c_key.as_ptr(), c_value.as_ptr(), 0))?;
+        }
+        Ok(())
+    }
+
     pub fn write_header(&mut self) -> Result<()> {
         unsafe {
             check_av_result(avformat_write_header(self.ctx, ptr::null_mut()))?;
@@ -110,7 +133,10 @@ impl NewMediaFile {
     }
 }
 
+/// Merge the audio streams of `in_files` into a new file at `path`.
+/// The output is written to a temporary file first and only moved to `path` once it is complete.
 pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile]) -> Result<NewMediaFile> {
+    let temp_file = TempFile::new(path);
     let steps = || -> Result<NewMediaFile> {
         // TODO: check in_files length
         // TODO: check that formats are actually compatible
@@ -121,7 +147,7 @@ pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile]) -> Result<New
         }
         let mut out = {
             let stream = in_files.first().unwrap().get_best_stream(AVMEDIA_TYPE_AUDIO)?;
-            NewMediaFile::from_stream(path.as_ref(), stream)?
+            NewMediaFile::from_stream(temp_file.path(), stream)?
         };
         debug!("writing header");
         out.write_header()?;
@@ -168,15 +194,286 @@ pub fn merge_files(path: &dyn AsRef<Path>, in_files: &[MediaFile]) -> Result<New
         Ok(out)
     };
 
-    match steps() {
-        Ok(out) => Ok(out),
-        Err(error) => {
-            let remove_result = fs::remove_file(path);
-            if let Err(remove_error) = remove_result {
-                error_log!("additional error while trying to remove unfinished muxed file at {}: {}", path.as_ref().display(), remove_error);
+    // The temporary file removes itself when dropped without being persisted
+    let mut out = steps()?;
+    temp_file.persist()?;
+    out.path = path.as_ref().to_owned();
+    Ok(out)
+}
+
+/// Copy the audio of `in_file` between `start` and `end` (in seconds, `None` for the end of the
+/// file) to a new file at `path`, tagged with `tags`.
+/// Packets are copied as they are, so the cuts happen at packet boundaries.
+pub fn extract_range(path: &dyn AsRef<Path>, in_file: &MediaFile, start: f64, end: Option<f64>,
+                     tags: &[(&str, String)]) -> Result<NewMediaFile> {
+    let temp_file = TempFile::new(path);
+    let steps = || -> Result<NewMediaFile> {
+        let best = in_file.get_best_stream(AVMEDIA_TYPE_AUDIO)?;
+        let time_base = best.time_base;
+        let to_timestamp = |seconds: f64| (seconds * f64::from(time_base.den) / f64::from(time_base.num)) as i64;
+        let start_ts = to_timestamp(start);
+        let end_ts = end.map(to_timestamp);
+
+        let mut out = NewMediaFile::from_stream(temp_file.path(), best)?;
+        for (key, value) in tags {
+            out.set_metadata(key, value)?;
+        }
+        out.write_header()?;
+        loop {
+            match in_file.read_packet()? {
+                Some(mut pkt) => {
+                    let ours = pkt.stream_index == best.index;
+                    let past_end = ours && end_ts.map_or(false, |end| pkt.pts >= end);
+                    if ours && !past_end && pkt.pts >= start_ts {
+                        pkt.pts -= start_ts;
+                        pkt.dts -= start_ts;
+                        out.write_frame(&mut pkt)?;
+                    }
+                    unsafe {
+                        av_free_packet(&mut pkt);
+                    }
+                    if past_end {
+                        break;
+                    }
+                },
+                None => break
             }
+        }
+        out.write_trailer()?;
+        Ok(out)
+    };
+
+    let mut out = steps()?;
+    temp_file.persist()?;
+    out.path = path.as_ref().to_owned();
+    Ok(out)
+}
+
+/// Converts decoded audio to what an encoder takes and keeps it until there is a whole frame.
+struct Resampler {
+    swr: *mut SwrContext,
+    fifo: *mut AVAudioFifo,
+    channel_layout: u64,
+    sample_rate: i32,
+    sample_fmt: AVSampleFormat,
+}
+
+impl Resampler {
+    unsafe fn new(decoder: &CodecContext, in_layout: u64, encoder: &CodecContext) -> Result<Self> {
+        let enc = encoder.0;
+        let mut swr = swr_alloc_set_opts(
+            ptr::null_mut(),
+            (*enc).channel_layout as i64, (*enc).sample_fmt, (*enc).sample_rate,
+            in_layout as i64, (*decoder.0).sample_fmt, (*decoder.0).sample_rate,
+            0, ptr::null_mut()
+        );
+        if let Err(e) = check_av_result(swr_init(swr)) {
+            swr_free(&mut swr);
+            return Err(e);
+        }
+        Ok(Resampler {
+            swr,
+            fifo: av_audio_fifo_alloc((*enc).sample_fmt, (*enc).channels, 1),
+            channel_layout: (*enc).channel_layout,
+            sample_rate: (*enc).sample_rate,
+            sample_fmt: (*enc).sample_fmt,
+        })
+    }
+
+    fn frame(&self) -> Frame {
+        let frame = Frame::new();
+        unsafe {
+            (*frame.0).channel_layout = self.channel_layout;
+            (*frame.0).sample_rate = self.sample_rate;
+            (*frame.0).format = self.sample_fmt as i32;
+        }
+        frame
+    }
+
+    /// Convert `input`, a null frame takes out what the resampler still holds.
+    unsafe fn push(&mut self, input: *const AVFrame) -> Result<()> {
+        let converted = self.frame();
+        check_av_result(swr_convert_frame(self.swr, converted.0, input))?;
+        let samples = (*converted.0).nb_samples;
+        if samples > 0 {
+            check_av_result(av_audio_fifo_write(self.fifo, (*converted.0).extended_data as *mut *mut _, samples))?;
+        }
+        Ok(())
+    }
+
+    fn available(&self) -> i32 {
+        unsafe { av_audio_fifo_size(self.fifo) }
+    }
+
+    /// The next `samples` samples, or less if there aren't as many.
+    unsafe fn pull(&mut self, samples: i32) -> Result<Frame> {
+        let frame = self.frame();
+        (*frame.0).nb_samples = samples.min(self.available());
+        check_av_result(av_frame_get_buffer(frame.0, 0))?;
+        check_av_result(av_audio_fifo_read(self.fifo, (*frame.0).extended_data as *mut *mut _, (*frame.0).nb_samples))?;
+        Ok(frame)
+    }
+}
 
-            Err(error)
-        },
+impl Drop for Resampler {
+    fn drop(&mut self) {
+        unsafe {
+            swr_free(&mut self.swr);
+            av_audio_fifo_free(self.fifo);
+        }
+    }
+}
+
+/// Send `frame` to `encoder`, a null frame ends the stream, and write what comes out to `out`.
+unsafe fn encode(encoder: &CodecContext, frame: *const AVFrame, out: &mut NewMediaFile) -> Result<()> {
+    check_av_result(avcodec_send_frame(encoder.0, frame))?;
+    let stream_time_base = (**(*out.ctx).streams).time_base;
+    loop {
+        let mut pkt: AVPacket = mem::zeroed();
+        av_init_packet(&mut pkt);
+        let received = avcodec_receive_packet(encoder.0, &mut pkt);
+        if received == AVERROR(libc::EAGAIN) || received == AVERROR_EOF {
+            return Ok(());
+        }
+        check_av_result(received)?;
+        av_packet_rescale_ts(&mut pkt, (*encoder.0).time_base, stream_time_base);
+        let result = out.write_frame(&mut pkt);
+        av_packet_unref(&mut pkt);
+        result?;
+    }
+}
+
+/// Like `extract_range`, but the audio is decoded and encoded again with the FFmpeg encoder named
+/// `encoder` at `bit_rate` bits per second. The sample rate is kept if the encoder takes it, more
+/// than two channels are mixed down to stereo. Cuts happen at the decoded frames' boundaries.
+pub fn transcode_range(path: &dyn AsRef<Path>, in_file: &MediaFile, start: f64, end: Option<f64>,
+                       tags: &[(&str, String)], encoder_name: &str, bit_rate: i64) -> Result<NewMediaFile> {
+    let temp_file = TempFile::new(path);
+    let steps = || -> Result<NewMediaFile> { unsafe {
+        let best = in_file.get_best_stream(AVMEDIA_TYPE_AUDIO)?;
+        let time_base = best.time_base;
+        let decoder = CodecContext::open(avcodec_find_decoder((*best.codecpar).codec_id), |ctx| {
+            avcodec_parameters_to_context(ctx, best.codecpar)
+        })?;
+        let in_layout = match (*decoder.0).channel_layout {
+            0 => av_get_default_channel_layout((*decoder.0).channels) as u64,
+            layout => layout,
+        };
+
+        let c_encoder_name = CString::new(encoder_name).unwrap();
+        let codec = avcodec_find_encoder_by_name(c_encoder_name.as_ptr());
+        if codec.is_null() {
+            return Err(WorkerError::Other {
+                description: format!("FFmpeg was built without the {} encoder", encoder_name)
+            }.into());
+        }
+        let encoder = CodecContext::open(codec, |ctx| {
+            let channels = (*decoder.0).channels.min(2);
+            (*ctx).channels = channels;
+            (*ctx).channel_layout = av_get_default_channel_layout(channels) as u64;
+            (*ctx).sample_rate = supported_sample_rate(codec, (*decoder.0).sample_rate);
+            (*ctx).sample_fmt = *(*codec).sample_fmts;
+            (*ctx).bit_rate = bit_rate;
+            (*ctx).time_base = AVRational { num: 1, den: (*ctx).sample_rate };
+            0
+        })?;
+        let mut resampler = Resampler::new(&decoder, in_layout, &encoder)?;
+        // Encoders that take any number of samples get them as they come
+        let frame_size = match (*encoder.0).frame_size {
+            0 => 1024,
+            size => size,
+        };
+
+        let mut params = avcodec_parameters_alloc();
+        let copied = check_av_result(avcodec_parameters_from_context(params, encoder.0))
+            .and_then(|_| NewMediaFile::new(temp_file.path(), &mut *params, (*encoder.0).time_base));
+        avcodec_parameters_free(&mut params);
+        let mut out = copied?;
+        for (key, value) in tags {
+            out.set_metadata(key, value)?;
+        }
+        out.write_header()?;
+
+        in_file.seek(best, start)?;
+        let decoded = Frame::new();
+        let mut written: i64 = 0;
+        let mut draining = false;
+        let mut past_end = false;
+        while !past_end {
+            match in_file.read_packet()? {
+                Some(mut pkt) => {
+                    if pkt.stream_index != best.index {
+                        av_free_packet(&mut pkt);
+                        continue;
+                    }
+                    let sent = avcodec_send_packet(decoder.0, &pkt);
+                    av_free_packet(&mut pkt);
+                    check_av_result(sent)?;
+                },
+                None => {
+                    draining = true;
+                    check_av_result(avcodec_send_packet(decoder.0, ptr::null()))?;
+                }
+            }
+            loop {
+                let received = avcodec_receive_frame(decoder.0, decoded.0);
+                if received == AVERROR(libc::EAGAIN) || received == AVERROR_EOF {
+                    break;
+                }
+                check_av_result(received)?;
+                let frame_start = apply_timebase((*decoded.0).best_effort_timestamp, time_base);
+                let frame_end = frame_start + f64::from((*decoded.0).nb_samples) / f64::from((*decoded.0).sample_rate);
+                if end.map_or(false, |end| frame_start >= end) {
+                    past_end = true;
+                } else if frame_end > start {
+                    (*decoded.0).channel_layout = in_layout;
+                    resampler.push(decoded.0)?;
+                }
+                av_frame_unref(decoded.0);
+                if past_end {
+                    break;
+                }
+            }
+            while resampler.available() >= frame_size {
+                let frame = resampler.pull(frame_size)?;
+                (*frame.0).pts = written;
+                written += i64::from((*frame.0).nb_samples);
+                encode(&encoder, frame.0, &mut out)?;
+            }
+            if draining {
+                break;
+            }
+        }
+        resampler.push(ptr::null())?;
+        while resampler.available() > 0 {
+            let frame = resampler.pull(frame_size)?;
+            (*frame.0).pts = written;
+            written += i64::from((*frame.0).nb_samples);
+            encode(&encoder, frame.0, &mut out)?;
+        }
+        encode(&encoder, ptr::null(), &mut out)?;
+        out.write_trailer()?;
+        Ok(out)
+    }};
+
+    let mut out = steps()?;
+    temp_file.persist()?;
+    out.path = path.as_ref().to_owned();
+    Ok(out)
+}
+
+/// `sample_rate` if `codec` takes it, otherwise the rate it prefers.
+unsafe fn supported_sample_rate(codec: *const AVCodec, sample_rate: i32) -> i32 {
+    let mut rates = (*codec).supported_samplerates;
+    if rates.is_null() {
+        return sample_rate;
+    }
+    let preferred = *rates;
+    while *rates != 0 {
+        if *rates == sample_rate {
+            return sample_rate;
+        }
+        rates = rates.offset(1);
     }
+    preferred
 }
@@ -0,0 +1,100 @@
+//! Books curated as M3U playlists.
+//!
+//! A playlist found in a library is a book made of the files it lists, in the order they are
+//! listed. A book directory containing a single playlist is ordered by it instead of by file
+//! names. Either way the book is named after the playlist.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::worker::error::{Result, WorkerError};
+
+#[derive(Debug, PartialEq)]
+pub struct Playlist {
+    pub path: PathBuf,
+    /// Taken from a `#PLAYLIST:` line, falls back to the file name.
+    pub title: String,
+    /// Files in playlist order, relative entries are resolved against the playlist's directory.
+    /// Paths are canonical.
+    pub entries: Vec<PathBuf>,
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    match path.extension().map(|e| e.to_string_lossy().to_lowercase()) {
+        Some(ref e) => e == "m3u" || e == "m3u8",
+        None => false,
+    }
+}
+
+/// Parse the playlist at `path` in the library at `root`. Entries that don't exist or are outside
+/// of the library are skipped, a playlist can't make the scanner read arbitrary files.
+pub fn parse(path: &Path, root: &Path) -> Result<Playlist> {
+    let root = root.canonicalize()?;
+    let data = fs::read(path)?;
+    // Plain .m3u files are often Latin-1, don't give up on them
+    let content = String::from_utf8_lossy(&data);
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut title = None;
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('#') {
+            if line.starts_with("#PLAYLIST:") {
+                title = Some(line["#PLAYLIST:".len()..].trim().to_owned());
+            }
+            continue;
+        }
+        let entry = base.join(line.replace('\\', "/"));
+        match entry.canonicalize() {
+            Ok(ref real) if !real.starts_with(&root) => {
+                warn!("Skipping playlist entry {} outside of the library in {}", entry.display(), path.display());
+            },
+            Ok(ref real) if real.is_file() => entries.push(real.to_owned()),
+            _ => warn!("Skipping missing playlist entry {} in {}", entry.display(), path.display()),
+        }
+    }
+    if entries.is_empty() {
+        return Err(WorkerError::Other {
+            description: format!("The playlist {} has no playable entries", path.display())
+        }.into());
+    }
+
+    let title = match title {
+        Some(t) => t,
+        None => path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .ok_or(WorkerError::InvalidUtf8)?,
+    };
+    Ok(Playlist {
+        path: path.to_owned(),
+        title,
+        entries,
+    })
+}
+
+/// The playlist that defines the book at `path` in the library at `root`: the path itself if it is
+/// a playlist or the only playlist directly in the book's directory.
+pub fn for_book(path: &Path, root: &Path) -> Result<Option<Playlist>> {
+    if is_playlist(path) && path.is_file() {
+        return parse(path, root).map(Some);
+    }
+    if !path.is_dir() {
+        return Ok(None);
+    }
+    let mut playlists = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        if is_playlist(&entry_path) && entry_path.is_file() {
+            playlists.push(entry_path);
+        }
+    }
+    if playlists.len() == 1 {
+        parse(&playlists[0], root).map(Some)
+    } else {
+        Ok(None)
+    }
+}
@@ -5,9 +5,12 @@ use std::ffi::{OsString, OsStr};
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::env;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use std::os::unix::prelude::*;
 use std::os::unix::fs;
-use std::fs::{create_dir, rename};
+use std::fs::rename;
 use log::error as error_log;
 
 use walkdir::WalkDir;
@@ -19,7 +22,9 @@ use ring::digest;
 use humanesort::HumaneOrder;
 use chrono::prelude::*;
 use chrono::NaiveDateTime;
-use crate::helpers::uuid::Uuid;
+use crate::helpers::uuid::{Uuid, IdGen, RandomIds};
+use crate::helpers::clock::{Clock, SystemClock};
+use crate::helpers::sorting;
 use diesel::sqlite::SqliteConnection;
 use fs2::FileExt;
 
@@ -27,23 +32,44 @@ use crate::config::Config;
 use crate::helpers::db::Pool;
 use crate::models::library::*;
 use crate::models::audiobook::{Audiobook, Update};
-use crate::models::chapter::Chapter;
+use crate::models::chapter::{self, Chapter};
+use crate::models::author::Author;
+use crate::models::search;
+use crate::models::series::{self, Series};
+use crate::helpers::language;
+use crate::helpers::events::{self, Event};
+use crate::models::book_match::BookMatch;
+use crate::worker::layout;
+use crate::worker::thumbnails;
 use crate::schema::audiobooks;
 use crate::schema::chapters;
 use crate::schema::libraries;
 use crate::worker::mediafile::MediaFile;
 use crate::worker::muxer;
-use crate::worker::error::{Result, WorkerError};
+use crate::worker::janitor::{self, TempFile};
+use crate::worker::lookup;
+use crate::worker::playlist;
+use crate::worker::scheduler;
+use crate::worker::error::{Result, ResultExt, WorkerError};
 use diesel::BelongingToDsl;
 use crate::worker::util;
-use crate::worker::mediafile::Image;
+use crate::worker::mediafile::{Image, ImageType};
 use super::hashing;
 
 pub struct Scanner {
     pub regex: Regex,
     pub library: Library,
     pub pool: Pool,
-    pub config: Config
+    pub config: Config,
+    pub clock: Arc<dyn Clock + Send + Sync>,
+    pub ids: Arc<dyn IdGen + Send + Sync>,
+    /// Held for as long as the scanner lives, dropping it releases the lock.
+    lock_file: Option<File>,
+    /// Read metadata again even if the hash of a book did not change.
+    reprobe: bool,
+    /// Paths that could not be scanned during the last scan and why, one broken file doesn't
+    /// stop a scan.
+    pub failures: Vec<(PathBuf, String)>,
 }
 
 struct MultifileMetadata {
@@ -80,16 +106,21 @@ impl Scanner {
             regex: Regex::new(library.is_audiobook_regex.as_str()).expect("Invalid Regex!"),
             library,
             pool: conn_pool,
-            config
+            config,
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(RandomIds),
+            lock_file: None,
+            reprobe: false,
+            failures: Vec::new(),
         }
     }
 
     fn aquire_lock_file(&mut self, locking_behavior: LockingBehavior) -> Result<()> {
-        if locking_behavior == LockingBehavior::Dont { return Ok(()) }
+        if locking_behavior == LockingBehavior::Dont || self.lock_file.is_some() { return Ok(()) }
         let mut lock_file_path = PathBuf::from(self.config.data_directory.clone());
         lock_file_path.push("scan.lock");
         let lock_file = File::create(&lock_file_path)?;
-        match lock_file.try_lock_exclusive() {
+        let locked = match lock_file.try_lock_exclusive() {
             Err(_) => {
                 println!(
                     "It looks like another scan is currently running.\
@@ -102,7 +133,11 @@ impl Scanner {
                 }
             }
             Ok(_) => { Ok(()) }
+        };
+        if locked.is_ok() {
+            self.lock_file = Some(lock_file);
         }
+        locked
     }
 
     /// Perform an incremental scan, this takes file change dates into account.
@@ -122,6 +157,72 @@ impl Scanner {
         self.scan_library(Scan::Full)
     }
 
+    /// Probe the given books of this library again without walking the whole library, e.g. after
+    /// their tags were fixed. Returns the outcome for each book in order.
+    pub fn rescan_books(&mut self, books: &[Audiobook], block_on_lock: LockingBehavior) -> Result<Vec<Result<()>>> {
+        self.aquire_lock_file(block_on_lock)?;
+        let conn = &*self.pool.get()?;
+        let _rate_limit = hashing::RateLimit::apply(self.config.scan.max_hash_bytes_per_sec());
+        self.reprobe = true;
+        let results = books.iter().map(|book| {
+            let path = Path::new(&self.library.location).join(&book.location);
+            if !path.exists() {
+                return Err(WorkerError::Other { description: format!("{} does not exist", path.display()) }.into());
+            }
+            self.handle_book_at_path(conn, Scan::Full, &path, Path::new(&book.location)).map(|_| ())
+        }).collect();
+        self.reprobe = false;
+        Ok(results)
+    }
+
+    /// Bring the books containing the given paths up to date, for reacting to file system events
+    /// without walking the whole library. Books that went away are marked as deleted.
+    pub fn scan_paths(&mut self, paths: &[PathBuf], block_on_lock: LockingBehavior) -> Result<()> {
+        self.aquire_lock_file(block_on_lock)?;
+        let conn = &*self.pool.get()?;
+        let _rate_limit = hashing::RateLimit::apply(self.config.scan.max_hash_bytes_per_sec());
+        let mut roots = paths.iter()
+            .filter_map(|p| self.book_root(p))
+            .collect::<Vec<PathBuf>>();
+        roots.sort();
+        roots.dedup();
+        let mut removed = false;
+        for root in roots {
+            if scheduler::stopping() {
+                return Err(WorkerError::Stopped.into());
+            }
+            if !root.exists() {
+                removed = true;
+                continue;
+            }
+            let relative_path = root.strip_prefix(&self.library.location).unwrap_or(&root).to_owned();
+            if let Err(e) = self.handle_book_at_path(conn, Scan::Incremental, &root, &relative_path) {
+                error_log!("Error while processing {}: {}", root.display(), e);
+            }
+        }
+        if removed {
+            self.delete_not_in_fs(conn)?;
+        }
+        Ok(())
+    }
+
+    /// The book a path inside the library belongs to, that is the outermost directory or file
+    /// that is an audiobook according to the library's regex, as when walking the library.
+    fn book_root(&self, path: &Path) -> Option<PathBuf> {
+        // File system events have absolute paths, library locations may be relative
+        let library = Path::new(&self.library.location).canonicalize().ok()?;
+        let path = canonicalize_existing(path)?;
+        let relative_path = path.strip_prefix(&library).ok()?;
+        let mut candidate = PathBuf::new();
+        for component in relative_path.components() {
+            candidate.push(component);
+            if is_audiobook(&candidate, &self.regex) {
+                return Some(Path::new(&self.library.location).join(candidate));
+            }
+        }
+        None
+    }
+
     /// Gets path for cache directory entry of the book.
     /// This may or may not actually be a file
     fn data_path_of(&self, book: &Audiobook) -> PathBuf {
@@ -135,13 +236,18 @@ impl Scanner {
     // if hashes have not changed: check symlinked/remuxed files still there? if not re-link/mux
     fn scan_library(&mut self, scan_type: Scan) -> Result<()> {
         info!("Scanning library: {}", self.library.location);
-        let last_scan = self.library.last_scan;
-        self.library.last_scan = Some(Utc::now().naive_utc());
-        let conn = &*self.pool.get().unwrap();
+        self.library.last_scan = Some(self.clock.now());
+        if let Err(e) = janitor::clean(&self.config.data_directory, janitor::STALE_AFTER) {
+            warn!("Could not clean up unfinished files: {}", e);
+        }
+        let conn = &*self.pool.get()?;
+        let _rate_limit = hashing::RateLimit::apply(self.config.scan.max_hash_bytes_per_sec());
         self.recover_deleted(conn)?;
-        let mut walker = WalkDir::new(&self.library.location).follow_links(true).into_iter();
+        let walker = WalkDir::new(&self.library.location).follow_links(true).into_iter();
 
-        self.walk_books(scan_type, walker, last_scan, conn);
+        let discovered = self.count_books();
+        scheduler::update_progress(&self.library.id, |p| p.discovered = discovered);
+        self.failures = self.walk_books(scan_type, walker, conn)?;
 
         self.delete_not_in_fs(conn)?;
         
@@ -153,23 +259,73 @@ impl Scanner {
             }
     }
 
-    fn walk_books(&self, scan_type: Scan, mut walker: walkdir::IntoIter,
-                  last_scan: Option<chrono::NaiveDateTime>, conn: &SqliteConnection) -> Result<()> {
+    /// How many books `walk_books` will find, so scans can tell how far they are.
+    fn count_books(&self) -> u64 {
+        let mut walker = WalkDir::new(&self.library.location).follow_links(true).into_iter();
+        let mut count = 0;
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let relative_path = entry.path().strip_prefix(&self.library.location).unwrap();
+            if relative_path.components().count() > 0 && is_audiobook(relative_path, &self.regex) {
+                count += 1;
+                if entry.path().is_dir() {
+                    walker.skip_current_dir();
+                }
+            }
+        }
+        count
+    }
+
+    /// Process all books found by `walker`, returns the paths that failed along with the reason.
+    fn walk_books(&self, scan_type: Scan, mut walker: walkdir::IntoIter, conn: &SqliteConnection)
+        -> Result<Vec<(PathBuf, String)>> {
+        let mut failures = Vec::new();
         loop {
+            // Stopping here skips marking the books that weren't walked yet as deleted
+            if scheduler::stopping() {
+                return Err(WorkerError::Stopped.into());
+            }
             let entry = match walker.next() {
                 None => break,
-                Some(Err(e)) => return Err(e.into()),
+                Some(Err(e)) => {
+                    // Without the library itself all books would be marked as deleted
+                    if e.depth() == 0 {
+                        return Err(e.into());
+                    }
+                    let path = e.path().map(Path::to_owned).unwrap_or_else(|| PathBuf::from(&self.library.location));
+                    error_log!("Error while walking {}: {}", path.display(), e);
+                    failures.push((path, e.to_string()));
+                    scheduler::update_progress(&self.library.id, |p| p.errored += 1);
+                    continue;
+                },
                 Some(Ok(i)) => i,
             };
             let path = entry.path();
             let relative_path = entry.path().strip_prefix(&self.library.location).unwrap();
             if relative_path.components().count() == 0 { continue };
             if is_audiobook(relative_path, &self.regex) {
-                let r = self.handle_book_at_path(conn, scan_type.clone(), path, relative_path, last_scan);
+                scheduler::update_progress(&self.library.id, |p| {
+                    p.current = Some(relative_path.to_string_lossy().into_owned())
+                });
+                let r = self.handle_book_at_path(conn, scan_type.clone(), path, relative_path);
+                scheduler::update_progress(&self.library.id, |p| {
+                    p.processed += 1;
+                    p.errored += r.is_err() as u64;
+                });
 
                 match r {
-                    Ok(_) => {},
-                    Err(e) => error_log!("Error while processing {}: {}", path.display(), e),
+                    // Give other readers of the disk a chance
+                    Ok(true) if self.config.scan.pause_between_books > 0 => {
+                        thread::sleep(Duration::from_secs(self.config.scan.pause_between_books));
+                    },
+                    Ok(_) => (),
+                    Err(e) => {
+                        error_log!("Error while processing {}: {}", path.display(), e);
+                        failures.push((path.to_owned(), e.to_string()));
+                    },
                 }
 
                 // Since we are in an audiobook we don't continue searching deeper in the dir tree from here
@@ -179,24 +335,39 @@ impl Scanner {
             };
             ()
         }
-        Ok(())
+        scheduler::update_progress(&self.library.id, |p| p.current = None);
+        Ok(failures)
     }
 
-    fn handle_book_at_path(&self, conn: &SqliteConnection, scan_type: Scan, path: &Path, relative_path: &Path,
-                           last_scan: Option<chrono::NaiveDateTime>) -> Result<()> {
+    /// Bring the book at `path` up to date, returns whether its files had to be read.
+    fn handle_book_at_path(&self, conn: &SqliteConnection, scan_type: Scan, path: &Path, relative_path: &Path)
+        -> Result<bool> {
         use crate::schema::audiobooks::dsl::location;
 
-        match scan_type {
+        let processed = match scan_type {
             Scan::Incremental => {
                 let preexisting_book = Audiobook::belonging_to(&self.library)
                     .filter(location.eq(&relative_path.to_string_lossy()))
                     .first::<Audiobook>(conn).optional()?;
-                if should_scan(path, last_scan)? || preexisting_book.is_none() {
+                let unchanged = match preexisting_book {
+                    Some(ref book) => {
+                        let (mtime, size) = book_stats(&self.library, &path)?;
+                        book.file_mtime == Some(mtime) && book.file_size == Some(size)
+                    },
+                    None => false,
+                };
+                if unchanged {
+                    debug!("{:?} has not changed since the last scan, not hashing it.", path);
+                } else {
                     self.process_audiobook(&path, conn)?;
                 }
+                !unchanged
             },
-            Scan::Full => self.process_audiobook(&path, conn)?
-        }
+            Scan::Full => {
+                self.process_audiobook(&path, conn)?;
+                true
+            }
+        };
 
         let mut book_result = Audiobook::belonging_to(&self.library)
             .filter(location.eq(&relative_path.to_string_lossy()))
@@ -206,9 +377,61 @@ impl Scanner {
         // Ensure cached file exists here no need to check if its current, that is ensured
         // above
         if let Ok(mut book) = book_result {
-            if path.is_dir() && !self.data_path_of(&book).exists() {
+            if book.cover_hash.is_none() {
+                if let Err(e) = self.backfill_cover_hash(&book, conn) {
+                    warn!("Could not hash cover of {}: {}", book.title, e);
+                }
+            }
+            if book.sort_title != sorting::sort_title(&book.title) {
+                if let Err(e) = book.update_sort_title(conn) {
+                    warn!("Could not update sort title of {}: {}", book.title, e);
+                }
+            }
+            if book.author_id.is_none() {
+                if let Some(artist) = book.artist.clone() {
+                    let linked = Author::for_name(&artist, conn)
+                        .and_then(|author| book.set_author(&author, conn));
+                    if let Err(e) = linked {
+                        warn!("Could not link author of {}: {}", book.title, e);
+                    }
+                }
+            }
+            if book.language.is_none() {
+                // Books scanned before languages were detected, only the title and description are left
+                let guessed = language::guess(
+                    &format!("{} {}", book.title, book.description.as_ref().map(String::as_str).unwrap_or(""))
+                );
+                if guessed.is_some() {
+                    if let Err(e) = book.set_language(guessed, conn) {
+                        warn!("Could not set language of {}: {}", book.title, e);
+                    }
+                }
+            }
+            if book.series_id.is_none() {
+                if let Err(e) = self.link_series(&mut book, conn) {
+                    warn!("Could not link series of {}: {}", book.title, e);
+                }
+            }
+            if processed {
+                if let Err(e) = search::index_book(&book, conn) {
+                    warn!("Could not index {} for searching: {}", book.title, e);
+                }
+            }
+            if book.data_hash.is_none() && self.data_path_of(&book).exists() {
+                let hashed = hashing::checksum_file(&self.data_path_of(&book))
+                    .and_then(|data_hash| Ok(book.set_data_hash(data_hash, conn)?));
+                if let Err(e) = hashed {
+                    warn!("Could not hash data file of {}: {}", book.title, e);
+                }
+            }
+            let multifile = path.is_dir() || playlist::is_playlist(path);
+            if multifile && !self.data_path_of(&book).exists() {
                 debug!("No remuxed version of {}, remuxing!", book.title);
-                match self.multifile_remux(&mut book) {
+                let remuxed = self.multifile_remux(&mut book).and_then(|_| {
+                    let data_hash = hashing::checksum_file(&self.data_path_of(&book))?;
+                    Ok(book.set_data_hash(data_hash, conn)?)
+                });
+                match remuxed {
                     Ok(_) => info!("Successfully remuxed {}", book.title),
                     Err(e) => info!("Error {:?} while remuxing {}", e, book.title),
                 }
@@ -220,11 +443,11 @@ impl Scanner {
                 }
             }
         }
-        Ok(())
+        Ok(processed)
     }
 
     fn process_audiobook(&self, path: &dyn AsRef<Path>, conn: &SqliteConnection) -> Result<()> {
-        if path.as_ref().is_dir() {
+        if path.as_ref().is_dir() || playlist::is_playlist(path.as_ref()) {
             self.create_multifile_audiobook(conn, path)
         } else {
             self.create_audiobook(conn, path)
@@ -238,24 +461,8 @@ impl Scanner {
         use crate::schema::audiobooks::dsl as dsl;
         let mut recovered = 0;
         for book in Audiobook::belonging_to(&self.library).filter(dsl::deleted.eq(true)).get_results::<Audiobook>(&*conn)? {
-            let path = Path::new(&self.library.location).join(Path::new(&book.location));
-            if !path.exists() { continue }
-
-            info!("Recovering previously deleted book: {:?}", path);
-            let hash = if path.is_dir() {
-                hashing::checksum_dir(&path)?
-            } else {
-                hashing::checksum_file(&path)?
-            };
-
-            if hash == book.hash {
-                use crate::schema::audiobooks::dsl::*;
-                diesel::update(
-                        Audiobook::belonging_to(&self.library)
-                        .filter(dsl::id.eq(book.id))
-                    )
-                    .set(dsl::deleted.eq(false))
-                    .execute(&*conn)?;
+            if recover_book(&self.library, &book, conn)? {
+                info!("Recovered previously deleted book: {:?}", book.location);
                 recovered += 1;
             }
         }
@@ -278,7 +485,7 @@ impl Scanner {
                         Audiobook::belonging_to(&self.library)
                         .filter(id.eq(book.id))
                     )
-                    .set(deleted.eq(true))
+                    .set((deleted.eq(true), deleted_at.eq(self.clock.now())))
                     .execute(&*conn)?;
                 debug!("deleted: {}", del);
                 match del {
@@ -292,31 +499,83 @@ impl Scanner {
     }
 
 
-    /// Save cover art to directory
+    /// Books scanned before cover hashes were stored still have their cover in the data directory,
+    /// hash it so the book gets a cover url and remember its type.
+    fn backfill_cover_hash(&self, book: &Audiobook, conn: &SqliteConnection) -> Result<()> {
+        let cover_path = layout::cover_path(&self.config.data_directory, &book.id);
+        if !cover_path.exists() {
+            return Ok(());
+        }
+        let data = std::fs::read(&cover_path)?;
+        let cover_hash = hashing::to_hex(&hashing::checksum_bytes(&data));
+        let cover_mime = ImageType::guess(&data).map(|t| t.mime_type());
+        diesel::update(audiobooks::dsl::audiobooks.filter(audiobooks::dsl::id.eq(&book.id)))
+            .set((audiobooks::dsl::cover_hash.eq(cover_hash), audiobooks::dsl::cover_mime.eq(cover_mime)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Save cover art to directory along with its thumbnails
     fn save_coverart(&self, book: &Audiobook, image: &Image) -> Result<()> {
-        let mut dest = PathBuf::from(format!("{}/img", self.config.data_directory));
-        if let Err(e) = create_dir(dest.clone()) {
-            match e.kind() {
-                io::ErrorKind::AlreadyExists => (),
-                _ => Err(e)?
-            };
-        };
-        dest.push(&book.id.hyphenated().to_string());
+        let dest = layout::cover_path(&self.config.data_directory, &book.id);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
         image.save(&dest)?;
+        // They can still be made when they are asked for
+        if let Err(e) = thumbnails::generate(&self.config.data_directory, &book.id, &image.checksum(), &image.data) {
+            warn!("Could not make thumbnails of the cover of {}: {}", book.title, e);
+        }
         Ok(())
     }
 
+    /// The slides at the starts of `chapters`, none if the file can't be read again. Slides are
+    /// written to unfinished files in the data directory as they are found, they are moved to their
+    /// chapters once those have ids.
+    fn read_chapter_images(&self, path: &Path, hash: &str, chapters: &[crate::worker::mediafile::Chapter]) -> Vec<Option<TempFile>> {
+        let starts = chapters.iter().map(|c| c.start).collect::<Vec<f64>>();
+        let mut slides = chapters.iter().map(|_| None).collect::<Vec<Option<TempFile>>>();
+        let read = MediaFile::read_file(path).and_then(|f| f.for_each_slide_at(&starts, |i, image| {
+            let slide = TempFile::new(&Path::new(&self.config.data_directory).join(format!("{}-{}.slide", hash, i)));
+            image.save(&slide.path())?;
+            slides[i] = Some(slide);
+            Ok(())
+        }));
+        match read {
+            Ok(()) => slides,
+            Err(e) => {
+                warn!("Could not read the chapter images of {:?}: {}", path, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn save_chapter_image(&self, book: &Audiobook, chapter: &Chapter, slide: TempFile) -> Result<()> {
+        let dest = layout::chapter_image_path(&self.config.data_directory, &book.id, &chapter.id);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        slide.persist_to(&dest)
+    }
+
     pub(super) fn create_audiobook(&self, conn: &diesel::sqlite::SqliteConnection, path: &dyn AsRef<Path>) -> Result<()> {
         info!("Scanning single file audiobook at: {:?}", path.as_ref());
         let relative_path = self.relative_path_str(path)?;
+        let (file_mtime, file_size) = file_stats(path)?;
         let hash = hashing::checksum_file(path)?;
 
-        let done = match Audiobook::update_path(&hash, &relative_path, conn)? {
-            Update::Nothing | Update::Path => true,
+        let done = match Audiobook::update_path(&hash, &relative_path, &self.library, conn)? {
+            Update::Path(book) | Update::Library(book) => {
+                // The data file still links to where the book was
+                self.link_audiobook(&book)?;
+                true
+            },
+            Update::Nothing => true,
             Update::NotFound => false
         };
-        if done {
+        if done && !self.reprobe {
             debug!("This audiobook already exists in the database, moving on.");
+            Audiobook::update_file_stats(&hash, file_mtime, file_size, conn)?;
             return Ok(());
         };
 
@@ -329,41 +588,78 @@ impl Scanner {
         });
 
         let metadata = file.get_mediainfo();
-        let cover_file = MediaFile::read_file(path.as_ref())?;
+        let chapters = file.get_chapters();
+        let maybe_image = file.get_coverart()?;
+        let chapter_images = if self.config.scan.chapter_images {
+            self.read_chapter_images(path.as_ref(), &hash, &chapters)
+        } else {
+            Vec::new()
+        };
+        let tagged_series = match series::from_tags(&metadata.metadata) {
+            Some((name, position)) => Some((Series::for_name(&name, conn)?, position)),
+            None => None,
+        };
+        let isbn = isbn_tag(&metadata.metadata);
+
         let default_book = Audiobook {
-            id: Uuid::new_v4(),
-            title: metadata.title,
+            id: self.ids.new_id(),
             artist: metadata.metadata.get("artist").cloned(),
             length: metadata.length,
             location: relative_path.to_owned(),
             library_id: self.library.id,
-            hash,
             file_extension: file_extension.unwrap_or_else(|| "".to_owned()),
             deleted: false,
+            cover_hash: maybe_image.as_ref().map(Image::checksum),
+            cover_mime: maybe_image.as_ref().map(|i| i.image_type.mime_type().to_owned()),
+            file_mtime: Some(file_mtime),
+            file_size: Some(file_size),
+            // The data file is a link to the original
+            data_hash: Some(hash.clone()),
+            sort_title: sorting::sort_title(&metadata.title),
+            author_id: None,
+            deleted_at: None,
+            description: description_tag(&metadata.metadata),
+            updated_at: None,
+            series_id: tagged_series.as_ref().map(|(s, _)| s.id),
+            series_position: tagged_series.and_then(|(_, position)| position),
+            language: language::detect(
+                &metadata.metadata, &metadata.title, description_tag(&metadata.metadata).as_ref().map(String::as_str)
+            ),
+            title: metadata.title,
+            hash,
         };
 
-        let chapters = file.get_chapters();
-        let maybe_image = file.get_coverart()?;
-
         let inserted = conn.exclusive_transaction(|| -> Result<(Audiobook, usize)> {
             debug!("Start transaction inserting single audiobook.");
             let book = Audiobook::ensure_exists_in(
                 &relative_path, &self.library, &default_book, conn
             )?;
+            let old_chapters = Chapter::belonging_to(&book).load::<Chapter>(conn)?;
             book.delete_all_chapters(conn);
             if let Some(image) = maybe_image {
                 self.save_coverart(&book, &image);
             };
             self.link_audiobook(&book)?;
-            let new_chapters: Vec<Chapter> = chapters.iter().enumerate().map(|(i, chapter)| {
+            let mut new_chapters: Vec<Chapter> = chapters.iter().enumerate().map(|(i, chapter)| {
                 Chapter {
-                    id: Uuid::new_v4(),
+                    id: self.ids.new_id(),
                     audiobook_id: book.id,
                     start_time: chapter.start,
                     title: chapter.title.clone(),
-                    number: i as i64
+                    number: i as i64,
+                    has_image: false,
                 }
             }).collect();
+            chapter::keep_ids(&old_chapters, &mut new_chapters);
+            for (chapter, slide) in new_chapters.iter_mut().zip(chapter_images.into_iter()) {
+                if let Some(slide) = slide {
+                    // A chapter without its image is still a chapter
+                    match self.save_chapter_image(&book, chapter, slide) {
+                        Ok(()) => chapter.has_image = true,
+                        Err(e) => warn!("Could not save the image of chapter {} of {}: {}", chapter.number, book.title, e),
+                    }
+                }
+            }
             debug!("End transaction inserting single audiobook.");
             Ok((book, diesel::replace_into(chapters::table)
                 .values(&new_chapters).execute(&*conn)?))
@@ -371,21 +667,53 @@ impl Scanner {
         match inserted {
             Ok((b, num_chapters)) => {
                 info!("Successfully saved book: {} with {} chapters.", b.title, num_chapters);
+                if b.id == default_book.id {
+                    events::publish(Event::BookAdded { library_id: b.library_id, audiobook_id: b.id });
+                }
+                self.look_up(&b, isbn.as_ref().map(String::as_str), conn);
                 Ok(())
             },
             Err(e) => Err(e)
         }
     }
 
+    /// Put a book without a series tag into the series its metadata provider knows, or else the
+    /// one its folder suggests, see `series::from_location`.
+    fn link_series(&self, book: &mut Audiobook, conn: &SqliteConnection) -> Result<()> {
+        let matched = BookMatch::of(book, conn)?
+            .and_then(|m| m.series.map(|name| (name, m.series_position)));
+        let found = matched.or_else(|| {
+            series::from_location(&book.location).map(|(name, position)| (name, Some(position)))
+        });
+        if let Some((name, position)) = found {
+            let s = Series::for_name(&name, conn)?;
+            book.set_series(&s, position, conn)?;
+        }
+        Ok(())
+    }
+
+    /// Match a book at the metadata provider, a failed lookup doesn't fail the scan.
+    fn look_up(&self, book: &Audiobook, isbn: Option<&str>, conn: &SqliteConnection) {
+        match lookup::on_scan(book, isbn, &self.config, conn) {
+            Ok(true) => info!("Matched {} at {}.", book.title, lookup::PROVIDER),
+            Ok(false) => (),
+            Err(e) => warn!("Looking up {} failed: {}", book.title, e),
+        }
+    }
+
     /// Audiobooks that are not remuxed are linked into our data directory so we have one canonical
-    /// source of data.
+    /// source of data. A link to where the book was before it moved is replaced.
     fn link_audiobook(&self, book: &Audiobook) -> Result<()> {
-        let mut dest = PathBuf::from(self.config.data_directory.to_owned());
-        dest.push(&book.id.hyphenated().to_string());
-        dest.set_extension(&book.file_extension);
-        let mut src = PathBuf::from(&self.library.location);
-        src.push(&book.location);
-        fs::symlink(src, dest);
+        let dest = self.data_path_of(book);
+        // Links are resolved relative to the data directory, so they need the absolute path
+        let src = Path::new(&self.library.location).join(&book.location).canonicalize()?;
+        let is_link = std::fs::symlink_metadata(&dest)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_link {
+            std::fs::remove_file(&dest)?;
+        }
+        fs::symlink(src, &dest)?;
         Ok(())
     }
 
@@ -395,18 +723,30 @@ impl Scanner {
         muxer::merge_files(
             &target_path,
             &collection.media_files
-            )?;
+            ).in_file("merge the files of a book into", &target_path)?;
         Ok(())
     }
 
 
     fn multifile_extract_chapters(&self, book: &mut Audiobook) -> Result<MultifileMetadata> {
         let book_path = Path::new(&self.library.location).join(book.location.clone());
-        let walker = WalkDir::new(book_path)
-            .follow_links(true)
-            .sort_by(
-                |s, o| s.path().to_string_lossy().humane_cmp(&o.path().to_string_lossy())
-            );
+        // A playlist decides on the order of the files, otherwise they are sorted by name
+        let playlist = playlist::for_book(&book_path, Path::new(&self.library.location))?;
+        let files = match playlist {
+            Some(ref p) => p.entries.clone(),
+            None => {
+                let walker = WalkDir::new(&book_path)
+                    .follow_links(true)
+                    .sort_by(
+                        |s, o| s.path().to_string_lossy().humane_cmp(&o.path().to_string_lossy())
+                    );
+                let mut files = Vec::new();
+                for entry in walker {
+                    files.push(entry?.path().to_owned());
+                }
+                files
+            }
+        };
 
         let mut all_chapters: Vec<Chapter> = Vec::new();
         let mut mediafiles = Vec::new();
@@ -414,48 +754,67 @@ impl Scanner {
         let mut chapter_index = 0;
         let mut cover: Option<Image> = None;
 
-        for entry in walker {
-            match entry {
-                Ok(file) => {
-                    if file.path().is_dir() { continue };
-                    match file.path().extension() {
-                        Some(ext) => if (ext.to_string_lossy()) != book.file_extension { continue },
-                        None => { continue }
-                    };
-                    let media = match MediaFile::read_file(file.path()) {
-                        Ok(f) => {
-                            let info = f.get_mediainfo();
-                            if chapter_index == 0 {
-                                use self::audiobooks::dsl::*;
-                                if let Some(new_title) = info.metadata.get("album") {
-                                    book.title = new_title.to_owned();
-                                }
-                                if let Some(new_artist) = info.metadata.get("artist") {
-                                    book.artist = Some(new_artist.to_owned());
-                                }
-                                let m = MediaFile::read_file(file.path()).unwrap();
-                                cover = m.get_coverart()?;
-                            };
-                            if Some(&info.title) != all_chapters.last().and_then(|c| c.title.as_ref() ) {
-                                let new_chapter = Chapter {
-                                    id: Uuid::new_v4(),
-                                    title: Some(info.title),
-                                    start_time,
-                                    audiobook_id: book.id,
-                                    number: chapter_index
-                                };
-                                chapter_index += 1;
-                                all_chapters.push(new_chapter);
-                            }
-                            start_time += info.length;
-                            f
+        for file in files {
+            if file.is_dir() { continue };
+            match file.extension() {
+                Some(ext) => if (ext.to_string_lossy()) != book.file_extension { continue },
+                None => { continue }
+            };
+            let media = match MediaFile::read_file(&file) {
+                Ok(f) => {
+                    let info = f.get_mediainfo();
+                    if chapter_index == 0 {
+                        use self::audiobooks::dsl::*;
+                        // Curated playlists are named by their curator
+                        if let (None, Some(new_title)) = (&playlist, info.metadata.get("album")) {
+                            book.title = new_title.to_owned();
+                            book.sort_title = sorting::sort_title(new_title);
+                        }
+                        if let Some(new_artist) = info.metadata.get("artist") {
+                            book.artist = Some(new_artist.to_owned());
                         }
-                        Err(e) => return Err(e)
+                        book.description = description_tag(&info.metadata);
+                        book.language = language::detect(
+                            &info.metadata, &book.title, book.description.as_ref().map(String::as_str)
+                        );
+                        let m = MediaFile::read_file(&file)?;
+                        cover = m.get_coverart()?;
                     };
-                    mediafiles.push(media)
-                },
-                Err(e) => return Err(e.into())
+                    let embedded_chapters = f.get_chapters();
+                    if !embedded_chapters.is_empty() {
+                        // Chapters of each file start at zero, shift them to where the
+                        // file starts in the merged book
+                        for chapter in embedded_chapters {
+                            all_chapters.push(Chapter {
+                                id: self.ids.new_id(),
+                                title: chapter.title,
+                                start_time: start_time + chapter.start,
+                                audiobook_id: book.id,
+                                number: chapter_index,
+                                has_image: false,
+                            });
+                            chapter_index += 1;
+                        }
+                    } else if Some(&info.title) != all_chapters.last().and_then(|c| c.title.as_ref() ) {
+                        // Without embedded chapters each file is a chapter, consecutive
+                        // files with the same title are parts of one chapter
+                        let new_chapter = Chapter {
+                            id: self.ids.new_id(),
+                            title: Some(info.title),
+                            start_time,
+                            audiobook_id: book.id,
+                            number: chapter_index,
+                            has_image: false,
+                        };
+                        chapter_index += 1;
+                        all_chapters.push(new_chapter);
+                    }
+                    start_time += info.length;
+                    f
+                }
+                Err(e) => return Err(e)
             };
+            mediafiles.push(media)
         };
 
         Ok(MultifileMetadata {
@@ -470,9 +829,11 @@ impl Scanner {
         // This might lead to inconsistent data as we hash before iterating over the files,
         // not better way to go about this seems possible to me
         // TODO: think about this
-        let hash = hashing::checksum_dir(path)?;
+        let (file_mtime, file_size) = book_stats(&self.library, path)?;
+        let hash = book_hash(&self.library, path)?;
         let relative_path = self.relative_path_str(path)?.to_owned();
         info!("Scanning multi-file audiobook at {:?}", path.as_ref());
+        let playlist = playlist::for_book(path.as_ref(), Path::new(&self.library.location))?;
 
         // if a book with the same hash exists in the database all we want to do is adjust the
         // path to retain all other information related to the book
@@ -480,38 +841,57 @@ impl Scanner {
         // What happens if we have two exact same audiobooks in the library path?:
         // It should just keep switching the paths around whenever a file creation time is
         // updated which is not to bad.
-        let done = match Audiobook::update_path(&hash, &relative_path, conn)? {
-            Update::Nothing | Update::Path => true,
+        let done = match Audiobook::update_path(&hash, &relative_path, &self.library, conn)? {
+            Update::Nothing | Update::Path(_) | Update::Library(_) => true,
             Update::NotFound => false
         };
         debug!("Checking if {} is up to date, result is: {}", relative_path, done);
-        if done {
+        if done && !self.reprobe {
             debug!("This audiobook already exists in the database, moving on.");
+            Audiobook::update_file_stats(&hash, file_mtime, file_size, conn)?;
             return Ok(());
         };
 
-        let filetype = match probable_audio_filetype(&path)? {
+        let probable_filetype = match playlist {
+            Some(ref p) => probable_filetype_of(&p.entries),
+            None => probable_audio_filetype(&path)?,
+        };
+        let filetype = match probable_filetype {
             Some(e) => e,
             None => return Err(WorkerError::NoValidFileExtensions.into())
         };
 
         debug!("decided on file type {:?}", filetype);
 
-        let title = match path.as_ref().file_name().map(|el| el.to_string_lossy()) {
-            Some(s) => s.into_owned(),
-            None => return Err(WorkerError::InvalidUtf8.into())
+        let title = match (playlist, path.as_ref().file_name().map(|el| el.to_string_lossy())) {
+            (Some(p), _) => p.title,
+            (None, Some(s)) => s.into_owned(),
+            (None, None) => return Err(WorkerError::InvalidUtf8.into())
         };
 
         let mut default_book = Audiobook {
-            id: Uuid::new_v4(),
+            id: self.ids.new_id(),
             length: 0.0,
             library_id: self.library.id,
             location: relative_path.clone(),
+            sort_title: sorting::sort_title(&title),
+            author_id: None,
+            deleted_at: None,
+            description: None,
+            updated_at: None,
+            series_id: None,
+            series_position: None,
+            language: None,
             title,
             artist: None,
             hash,
-            file_extension: filetype.to_owned().into_string().unwrap(),
-            deleted: false
+            file_extension: filetype.to_owned().into_string().map_err(|_| WorkerError::InvalidUtf8)?,
+            deleted: false,
+            cover_hash: None,
+            cover_mime: None,
+            file_mtime: Some(file_mtime),
+            file_size: Some(file_size),
+            data_hash: None,
         };
 
         let temp_target_path = self.build_target_path(
@@ -519,11 +899,14 @@ impl Scanner {
         );
 
         let collection = self.multifile_extract_chapters(&mut default_book)?;
+        default_book.cover_hash = collection.cover.as_ref().map(Image::checksum);
+        default_book.cover_mime = collection.cover.as_ref().map(|i| i.image_type.mime_type().to_owned());
         debug!("muxing files into {:?}", temp_target_path);
         muxer::merge_files(
             &temp_target_path,
             &collection.media_files
-        )?;
+        ).in_file("merge the files of a book into", Path::new(&temp_target_path))?;
+        default_book.data_hash = Some(hashing::checksum_file(&temp_target_path)?);
 
 
         let inserted = conn.exclusive_transaction(||  -> Result<Audiobook> {
@@ -537,8 +920,13 @@ impl Scanner {
             }
 
             book.length = collection.length;
+            let old_chapters = Chapter::belonging_to(&book).load::<Chapter>(conn)?;
             book.delete_all_chapters(conn);
-            for new_chapter in collection.chapters {
+            let mut new_chapters = collection.chapters;
+            chapter::keep_ids(&old_chapters, &mut new_chapters);
+            for mut new_chapter in new_chapters {
+                // They were made for `default_book`, whose id is only used for new books
+                new_chapter.audiobook_id = book.id;
                 diesel::insert_into(chapters::table).values(&new_chapter).execute(conn)?;
             }
 
@@ -557,6 +945,11 @@ impl Scanner {
         match inserted {
             Ok(book) => {
                 info!("Successfully saved book: {}", book.title);
+                if book.id == default_book.id {
+                    events::publish(Event::BookAdded { library_id: book.library_id, audiobook_id: book.id });
+                }
+                // The tags of the files of a book aren't merged, so there is no ISBN to go by
+                self.look_up(&book, None, conn);
                 Ok(())
             },
             Err(e) => {
@@ -585,34 +978,105 @@ impl Scanner {
     }
 }
 
+/// The ISBN from the tags, normalized, see `lookup::normalize_isbn`.
+fn isbn_tag(tags: &HashMap<String, String>) -> Option<String> {
+    tags.get("isbn").and_then(|isbn| lookup::normalize_isbn(isbn))
+}
+
+/// Books carry their blurb in one of these tags, depending on what tagged them.
+fn description_tag(tags: &HashMap<String, String>) -> Option<String> {
+    ["description", "comment"].iter()
+        .filter_map(|t| tags.get(*t))
+        .map(|d| d.trim())
+        .find(|d| !d.is_empty())
+        .map(|d| d.to_owned())
+}
+
+/// `path` made absolute with symlinks resolved as far as it exists, the rest of the path is kept
+/// for files that were removed.
+fn canonicalize_existing(path: &Path) -> Option<PathBuf> {
+    let mut existing = path;
+    let mut removed = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return Some(removed.iter().rev().fold(canonical, |path, name| path.join(name)));
+        }
+        removed.push(existing.file_name()?);
+        existing = existing.parent()?;
+    }
+}
+
 fn is_audiobook(path: &Path, regex: &Regex) -> bool {
-    regex.is_match(path.to_str().unwrap())
+    regex.is_match(&path.to_string_lossy())
 }
 
-///
-/// Returns the largest changed time stamp on any file in a given directory
-///
-fn most_recent_change(path: &dyn AsRef<Path>) -> Result<Option<NaiveDateTime>> {
-    // this is a suboptimal solution it doesn't really matter here but creating a vector is not
-    // great.
-    let times: Result<Vec<NaiveDateTime>> = WalkDir::new(path.as_ref())
-        .follow_links(true)
-        .into_iter()
-        .map(|el| -> Result<NaiveDateTime> {
-            match el {
-                Ok(f) => {
-                    match f.metadata().map(|el| NaiveDateTime::from_timestamp(el.mtime(), el.mtime_nsec() as u32)) {
-                        Ok(modified) => Ok(modified),
-                        Err(e) => Err(e.into())
-                    }
-                },
-                Err(e) => Err(e.into())
-            }
-        })
-    .collect();
-    Ok(times?.iter().max().cloned())
+/// Most recent modification time and total size of all files at a path.
+/// If neither changed since the last scan the book does not need to be hashed again.
+fn file_stats(path: &dyn AsRef<Path>) -> Result<(NaiveDateTime, i64)> {
+    let mut latest = NaiveDateTime::from_timestamp(0, 0);
+    let mut size = 0;
+    for entry in WalkDir::new(path.as_ref()).follow_links(true) {
+        let metadata = entry?.metadata()?;
+        let modified = NaiveDateTime::from_timestamp(metadata.mtime(), metadata.mtime_nsec() as u32);
+        if modified > latest {
+            latest = modified;
+        }
+        if metadata.is_file() {
+            size += metadata.len() as i64;
+        }
+    }
+    Ok((latest, size))
+}
+
+/// Like `file_stats` but a playlist also covers the files it lists.
+fn book_stats(library: &Library, path: &dyn AsRef<Path>) -> Result<(NaiveDateTime, i64)> {
+    let (mut latest, mut size) = file_stats(path)?;
+    if path.as_ref().is_file() && playlist::is_playlist(path.as_ref()) {
+        for entry in playlist::parse(path.as_ref(), Path::new(&library.location))?.entries {
+            let (modified, entry_size) = file_stats(&entry)?;
+            latest = latest.max(modified);
+            size += entry_size;
+        }
+    }
+    Ok((latest, size))
 }
 
+/// Checksum identifying a book, covering all of its files.
+fn book_hash(library: &Library, path: &dyn AsRef<Path>) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    if path.is_dir() {
+        hashing::checksum_dir(&path)
+    } else if playlist::is_playlist(path) {
+        let mut files = vec![path.to_owned()];
+        files.extend(playlist::parse(path, Path::new(&library.location))?.entries);
+        hashing::checksum_files(&files)
+    } else {
+        hashing::checksum_file(&path)
+    }
+}
+
+/// Unmark `book` as deleted if its files are back at its location, unchanged.
+/// Returns whether it was recovered.
+pub fn recover_book(library: &Library, book: &Audiobook, conn: &SqliteConnection) -> Result<bool> {
+    use crate::schema::audiobooks::dsl;
+    let path = Path::new(&library.location).join(Path::new(&book.location));
+    if !path.exists() || book_hash(library, &path)? != book.hash {
+        return Ok(false);
+    }
+    diesel::update(dsl::audiobooks.filter(dsl::id.eq(book.id)))
+        .set((dsl::deleted.eq(false), dsl::deleted_at.eq(None::<NaiveDateTime>)))
+        .execute(conn)?;
+    Ok(true)
+}
+
+/// The most common extension among `files`.
+fn probable_filetype_of(files: &[PathBuf]) -> Option<OsString> {
+    let mut counts: HashMap<OsString, usize> = HashMap::new();
+    for extension in files.iter().filter_map(|f| f.extension()) {
+        *counts.entry(extension.to_owned()).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|&(_, count)| count).map(|(extension, _)| extension)
+}
 
 /// Find the most common extension in a directory that might be an audio file.
 pub(super) fn probable_audio_filetype(path: &dyn AsRef<Path>) -> Result<Option<OsString>> {
@@ -634,24 +1098,3 @@ pub(super) fn probable_audio_filetype(path: &dyn AsRef<Path>) -> Result<Option<O
     filetypes.sort_by(|&(_, count_left), &(_, count_right)| count_left.cmp(&count_right));
     Ok(filetypes.pop().map(|el| el.0))
 }
-
-/// Determines whether a scan of a path is necessary based on file change data
-fn should_scan(path: &Path, last_scan: Option<NaiveDateTime>) -> Result<bool> {
-    match most_recent_change(&path)? {
-        Some(recent_change_time) => if let Some(last_scan_time) = last_scan {
-            debug!("Should scan based on time stamps is: {:?} >= {:?} meaning: {}",
-                  recent_change_time,
-                  last_scan_time,
-                  recent_change_time >= last_scan_time);
-            Ok(recent_change_time >= last_scan_time)
-        } else {
-            debug!("First scan of this book ever, scanning!");
-            // if there was no scan before we should scan now
-            Ok(true)
-        },
-        None => {
-            debug!("No change data for files available, will hash everything.");
-            Ok(true)
-        }
-    }
-}
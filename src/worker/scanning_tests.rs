@@ -30,7 +30,7 @@ fn set_date(file: &str, date: &NaiveDate) {
 }
 
 fn data_cover_file(book: &Audiobook) -> PathBuf {
-    PathBuf::from(format!("data/img/{}", &book.id.hyphenated()))
+    super::layout::cover_path("data", &book.id)
 }
 
 fn data_file(book: &Audiobook) -> PathBuf {
@@ -102,6 +102,7 @@ speculate! {
             location: "".to_owned(),
             is_audiobook_regex: "^[^/]+$".to_owned(),
             last_scan: None,
+            deleted_at: None,
         };
         diesel::insert_into(libraries::table)
             .values(&library)
@@ -142,6 +143,26 @@ speculate! {
             assert_eq!(0, count_books(&scanner, &pool));
         }
 
+        it "scans_changed_paths" {
+            let mut base = String::from("integration-tests/scans_changed_paths/01");
+            scanner.library.location = base.clone();
+            let changed = vec![
+                PathBuf::from(format!("{}/book/book.mp3", base)),
+                PathBuf::from(format!("{}/book", base)),
+                PathBuf::from("somewhere/else.mp3"),
+            ];
+            scanner.scan_paths(&changed, LockingBehavior::Dont).unwrap();
+            assert_eq!(1, count_books(&scanner, &pool));
+            assert_eq!(all_books(&scanner, &pool)[0].location, "book");
+
+            base = String::from("integration-tests/scans_changed_paths/02");
+            scanner.library.location = base.clone();
+            // Watchers report absolute paths, also of files that are gone
+            let changed = vec![std::env::current_dir().unwrap().join(format!("{}/book/book.mp3", base))];
+            scanner.scan_paths(&changed, LockingBehavior::Dont).unwrap();
+            assert_eq!(0, count_books(&scanner, &pool));
+        }
+
         it "ignores other files" {
             // Time step 01:
             let base = String::from("integration-tests/ignore_other_files/01");
@@ -366,11 +387,43 @@ speculate! {
 
             assert_eq!(1, count_books(&scanner, &pool));
             let book2 = all_books(&scanner, &pool).first().unwrap().clone();
-            // assert!(94.0 < book2.length, book2.length < 96.0);
+            // The timestamps are the same but the added file changes the total size
+            assert!(94.0 < book2.length && book2.length < 96.0, book2.length);
+
+            // The chapter that was there keeps its id
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            let chapters = |book: &Audiobook| Chapter::belonging_to(book)
+                .order(number.asc())
+                .load::<Chapter>(&*pool.get().unwrap())
+                .unwrap();
+            let (chapters1, chapters2) = (chapters(&book1), chapters(&book2));
+            assert!(!chapters2.is_empty());
+            assert_eq!(chapters2[0].id, chapters1[0].id);
+        }
 
-            // XFAIL - doesn't work yet, test for failed outcome
-            assert!(47.0 < book1.length, book1.length < 48.0);
+        it "skips_unchanged" {
+            use crate::schema::audiobooks::dsl;
+            let base = data_path!("01");
+            set_date(&base, &NaiveDate::from_ymd(1990, 1, 1));
+            scanner.library.location = base.clone();
+            scanner.incremental_scan(LockingBehavior::Dont).unwrap();
+            let book = all_books(&scanner, &pool).first().unwrap().clone();
+            assert!(book.file_mtime.is_some());
+            assert!(book.file_size.is_some());
+
+            // An incremental scan must not look at the file again, so it won't notice the bogus hash
+            diesel::update(dsl::audiobooks.filter(dsl::id.eq(&book.id)))
+                .set(dsl::hash.eq(vec![0u8]))
+                .execute(&*pool.get().unwrap())
+                .unwrap();
+            scanner.incremental_scan(LockingBehavior::Dont).unwrap();
+            assert_eq!(all_books(&scanner, &pool).first().unwrap().hash, vec![0u8]);
 
+            scanner.full_scan(LockingBehavior::Dont).unwrap();
+            let rescanned = all_books(&scanner, &pool).first().unwrap().clone();
+            assert_eq!(rescanned.id, book.id);
+            assert_eq!(rescanned.hash, book.hash);
         }
     }
 }
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::error as error_log;
+
+use chrono::prelude::*;
+use diesel::prelude::*;
+use failure::Error;
+
+use crate::config::{Config, SharedConfig};
+use crate::helpers::db::Pool;
+use crate::helpers::events::{self, Event};
+use crate::helpers::uuid::Uuid;
+use crate::models::audiobook::Audiobook;
+use crate::models::library::Library;
+use crate::models::scan::Scan;
+use crate::schema::libraries;
+use crate::worker::error::{Result, WorkerError};
+use crate::worker::janitor;
+use crate::worker::scanner::{Scanner, LockingBehavior};
+use crate::worker::watcher;
+
+/// Delay before the first scheduled scan, gives the web server some time to start up.
+const INITIAL_DELAY: Duration = Duration::from_secs(10);
+/// How often the scheduler looks for libraries it does not have a thread for yet.
+const LIBRARY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref RUNNING_SCANS: Mutex<HashSet<Uuid>> = Mutex::new(HashSet::new());
+    static ref PROGRESS: Mutex<HashMap<Uuid, ScanProgress>> = Mutex::new(HashMap::new());
+}
+
+/// How far a running scan of a library got.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ScanProgress {
+    /// Books the scan found in the library, known before it starts on them.
+    pub discovered: u64,
+    /// Books that are done, including those that failed.
+    pub processed: u64,
+    pub errored: u64,
+    /// Path of the book the scan is at, relative to the library.
+    pub current: Option<String>,
+}
+
+/// Set when the server shuts down, scans stop before their next book then.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Marks a library as being scanned by this process until it is dropped.
+pub struct ScanClaim(Uuid);
+
+impl ScanClaim {
+    pub fn new(library: &Library) -> Result<ScanClaim> {
+        if stopping() {
+            return Err(WorkerError::Stopped.into());
+        }
+        let mut running = RUNNING_SCANS.lock().unwrap();
+        if running.insert(library.id) {
+            Ok(ScanClaim(library.id))
+        } else {
+            Err(WorkerError::AlreadyRunning.into())
+        }
+    }
+}
+
+impl Drop for ScanClaim {
+    fn drop(&mut self) {
+        PROGRESS.lock().unwrap().remove(&self.0);
+        RUNNING_SCANS.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub fn is_scanning(library: &Library) -> bool {
+    RUNNING_SCANS.lock().unwrap().contains(&library.id)
+}
+
+/// The progress of the running scan of a library, `None` if there is none or it is still starting.
+pub fn progress(library: &Library) -> Option<ScanProgress> {
+    PROGRESS.lock().unwrap().get(&library.id).cloned()
+}
+
+/// Change the progress of the scan of a library, the scanner calls this as it goes. The progress
+/// is forgotten with the claim on the library.
+pub fn update_progress<F: FnOnce(&mut ScanProgress)>(library_id: &Uuid, update: F) {
+    update(PROGRESS.lock().unwrap().entry(*library_id).or_insert_with(ScanProgress::default));
+}
+
+pub fn any_scanning() -> bool {
+    !RUNNING_SCANS.lock().unwrap().is_empty()
+}
+
+/// Stop running scans before their next book and don't start new ones, see `helpers::shutdown`.
+pub fn stop_scans() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Claim a library for scanning unless it was deleted, deleting a library claims it too so this
+/// can't race a deletion.
+fn claim_existing(pool: &Pool, library: &Library) -> Result<ScanClaim> {
+    let claim = ScanClaim::new(library)?;
+    match load_library(pool, &library.id)? {
+        Some(_) => Ok(claim),
+        None => Err(WorkerError::LibraryDeleted.into()),
+    }
+}
+
+/// Scan a library and record the outcome in the scans table.
+pub fn run_scan(pool: &Pool, config: &Config, library: Library, full: bool) -> Result<Scan> {
+    let claim = claim_existing(pool, &library)?;
+    run_claimed_scan(claim, pool, config, library, full)
+}
+
+/// Finishes the record of a scan that didn't get to finish it itself because it panicked, so the
+/// scan doesn't look like it is still running.
+struct UnfinishedScan<'a> {
+    record: Option<Scan>,
+    pool: &'a Pool,
+}
+
+impl<'a> Drop for UnfinishedScan<'a> {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            let result = Err(format_err!("The scan stopped unexpectedly"));
+            let finished = self.pool.get().map_err(Error::from)
+                .and_then(|conn| record.finish(&result, &*conn).map_err(Error::from));
+            if let Err(e) = finished {
+                error_log!("Could not finish the record of scan {}: {}", record.id.hyphenated(), e);
+            }
+        }
+    }
+}
+
+fn run_claimed_scan(claim: ScanClaim, pool: &Pool, config: &Config, library: Library, full: bool) -> Result<Scan> {
+    // The scanner takes its own connection from the pool, so don't hold on to this one.
+    let mut unfinished = UnfinishedScan { record: Some(Scan::start(&library, full, &*pool.get()?)?), pool };
+    let mut scanner = Scanner::new(pool.clone(), library, config.clone());
+    let result = if full {
+        scanner.full_scan(LockingBehavior::Block)
+    } else {
+        scanner.incremental_scan(LockingBehavior::Block)
+    };
+    let conn = pool.get()?;
+    let mut record = unfinished.record.take().unwrap();
+    // Clients take a finished scan to be complete, so its errors have to be there by then
+    conn.exclusive_transaction(|| -> Result<()> {
+        record.record_errors(&scanner.failures, &*conn)?;
+        record.finish(&result, &*conn)?;
+        Ok(())
+    })?;
+    events::publish(Event::ScanFinished {
+        library_id: record.library_id,
+        scan_id: record.id,
+        error: record.error.clone(),
+    });
+    drop(conn);
+    drop(scanner);
+    drop(claim);
+    result.map(|_| record)
+}
+
+/// Probe some books of a library again, see `Scanner::rescan_books`.
+pub fn rescan_books(pool: &Pool, config: &Config, library: Library, books: &[Audiobook]) -> Result<Vec<Result<()>>> {
+    let _claim = claim_existing(pool, &library)?;
+    let mut scanner = Scanner::new(pool.clone(), library, config.clone());
+    scanner.rescan_books(books, LockingBehavior::Block)
+}
+
+/// Start a scan in the background, fails right away if the library is already being scanned.
+pub fn trigger_scan(pool: Pool, config: Config, library: Library, full: bool) -> Result<()> {
+    let claim = claim_existing(&pool, &library)?;
+    thread::spawn(move || {
+        let library_id = library.id;
+        if let Err(e) = run_claimed_scan(claim, &pool, &config, library, full) {
+            error_log!("Scan of library {} failed: {}", library_id.hyphenated(), e);
+        }
+    });
+    Ok(())
+}
+
+/// Purge books that have been marked as deleted for longer than `retention.deleted_books`.
+pub fn purge_expired_books(pool: &Pool, config: &Config) -> Result<usize> {
+    let retention = match config.retention.deleted_books {
+        Some(r) => r,
+        None => return Ok(0),
+    };
+    let cutoff = Utc::now().naive_utc() - chrono::Duration::seconds(retention as i64);
+    let purged = Audiobook::purge_deleted(Some(cutoff), &*pool.get()?)?;
+    janitor::remove_book_files(&config.data_directory, &purged);
+    Ok(purged.len())
+}
+
+/// Periodically scans all libraries, each library gets its own thread. With `scan.watch` every
+/// library also gets a thread watching it for changes.
+pub struct ScanScheduler {
+    pool: Pool,
+    config: SharedConfig,
+    threads: HashMap<Uuid, JoinHandle<()>>,
+}
+
+impl ScanScheduler {
+    /// Start the scheduler in a background thread. Reloading `config` changes the scan interval.
+    pub fn start(pool: Pool, config: SharedConfig) -> JoinHandle<()> {
+        let mut scheduler = ScanScheduler {
+            pool,
+            config,
+            threads: HashMap::new(),
+        };
+        thread::spawn(move || {
+            thread::sleep(INITIAL_DELAY);
+            loop {
+                if let Err(e) = scheduler.spawn_new_libraries() {
+                    error_log!("Could not load libraries for scheduling: {}", e);
+                }
+                match purge_expired_books(&scheduler.pool, &scheduler.config.get()) {
+                    Ok(0) => (),
+                    Ok(purged) => info!("Purged {} books deleted longer than the retention window.", purged),
+                    Err(e) => error_log!("Could not purge deleted books: {}", e),
+                }
+                if let Err(e) = janitor::prune_audit_log(&scheduler.pool, &scheduler.config.get()) {
+                    error_log!("Could not prune the audit log: {}", e);
+                }
+                thread::sleep(LIBRARY_POLL_INTERVAL);
+            }
+        })
+    }
+
+    fn spawn_new_libraries(&mut self) -> Result<()> {
+        let all_libraries = libraries::table
+            .filter(libraries::deleted_at.is_null())
+            .load::<Library>(&*self.pool.get()?)?;
+        for library in all_libraries {
+            if self.threads.contains_key(&library.id) {
+                continue;
+            }
+            let library_id = library.id;
+            let pool = self.pool.clone();
+            let config = self.config.clone();
+            if config.get().scan.watch {
+                let pool = pool.clone();
+                let config = config.get();
+                thread::spawn(move || watcher::watch_library(pool, config, library_id));
+            }
+            let handle = thread::spawn(move || schedule_library(pool, config, library_id));
+            self.threads.insert(library_id, handle);
+        }
+        Ok(())
+    }
+}
+
+/// The library with this id, `None` if it was deleted.
+pub(super) fn load_library(pool: &Pool, library_id: &Uuid) -> Result<Option<Library>> {
+    let conn = pool.get()?;
+    Ok(libraries::table
+        .filter(libraries::id.eq(library_id))
+        .filter(libraries::deleted_at.is_null())
+        .first::<Library>(&*conn)
+        .optional()?)
+}
+
+/// Scan a single library every `scan.interval` seconds until it is deleted.
+/// The interval is looked up again for every wait so reloading the config changes it.
+fn schedule_library(pool: Pool, shared: SharedConfig, library_id: Uuid) {
+    let interval = || Duration::from_secs(shared.get().scan.interval);
+    loop {
+        let library = match load_library(&pool, &library_id) {
+            Ok(Some(library)) => library,
+            Ok(None) => {
+                info!("Library {} is gone, no longer scanning it.", library_id.hyphenated());
+                return;
+            }
+            Err(e) => {
+                error_log!("Could not load library {}: {}", library_id.hyphenated(), e);
+                thread::sleep(interval());
+                continue;
+            }
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_scan(&pool, &shared.get(), library, false)
+        }));
+        match result {
+            Ok(Ok(_)) => info!("Scan of library {} succeeded.", library_id.hyphenated()),
+            Ok(Err(e)) => error_log!("Scan of library {} failed: {}", library_id.hyphenated(), e),
+            Err(_) => error_log!("Scan of library {} panicked.", library_id.hyphenated()),
+        }
+        thread::sleep(interval());
+    }
+}
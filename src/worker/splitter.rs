@@ -0,0 +1,225 @@
+//! Splitting books into one file per chapter, for players that navigate by file.
+//!
+//! Chapters are encoded as MP3 or Opus, MP3 books are cut without encoding them again. Split files
+//! are cached in the `chapters` directory below the data directory. Their names contain the hash
+//! of the book's data file and of its chapters and tags, so they are recreated when the book or
+//! its chapters change.
+//! Each of them has a stamp next to it naming the FFmpeg version that wrote it and the hash of
+//! its first bytes. Files written by another version are recreated too, so clients don't get
+//! chapters that were cut differently mixed within one book.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::ffmpeg;
+use crate::helpers::zip;
+use crate::models::audiobook::Audiobook;
+use crate::models::chapter::Chapter;
+use crate::worker::error::{Result, ResultExt, WorkerError};
+use crate::worker::hashing;
+use crate::worker::janitor::{self, TempFile};
+use crate::worker::mediafile::MediaFile;
+use crate::worker::muxer;
+
+/// How much of a cached file the hash in its stamp covers.
+const STAMPED_BYTES: u64 = 64 * 1024;
+
+fn cache_directory(config: &Config) -> Result<PathBuf> {
+    let directory = Path::new(&config.data_directory).join("chapters");
+    fs::create_dir_all(&directory)?;
+    Ok(directory)
+}
+
+/// What chapter files are encoded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Mp3,
+    Opus,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "mp3" => Some(Format::Mp3),
+            "opus" => Some(Format::Opus),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Mp3 => "mp3",
+            Format::Opus => "opus",
+        }
+    }
+
+    fn encoder(self) -> &'static str {
+        match self {
+            Format::Mp3 => "libmp3lame",
+            Format::Opus => "libopus",
+        }
+    }
+
+    /// Bits per second, enough for speech and most music.
+    fn bit_rate(self) -> i64 {
+        match self {
+            Format::Mp3 => 128_000,
+            Format::Opus => 64_000,
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Mp3
+    }
+}
+
+/// Start of the names of a book's cached files.
+fn cache_prefix(book: &Audiobook, chapters: &[Chapter]) -> String {
+    let mut tagged = format!("{}\n{}\n", book.title, book.artist.as_ref().map_or("", String::as_str));
+    for chapter in chapters {
+        tagged += &format!("{}\n{}\n", chapter.start_time, chapter.title.as_ref().map_or("", String::as_str));
+    }
+    let tags = hashing::to_hex(&hashing::checksum_bytes(tagged.as_bytes()));
+    match book.data_hash {
+        Some(ref hash) => format!("{}-{}-{}", book.id.hyphenated(), &hashing::to_hex(hash)[..16], &tags[..16]),
+        None => format!("{}-{}", book.id.hyphenated(), &tags[..16]),
+    }
+}
+
+/// Remove cached files of the book that don't start with `prefix`, they were made before the book
+/// or its chapters changed.
+fn remove_outdated(directory: &Path, book: &Audiobook, prefix: &str) -> Result<()> {
+    let id = book.id.hyphenated().to_string();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = match path.file_name() {
+            Some(n) => n.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if name.starts_with(&id) && !name.starts_with(prefix) && !janitor::is_partial(&path) {
+            info!("Removing outdated chapter file {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn ffmpeg_version() -> String {
+    unsafe {
+        format!("lavf {} lavc {}", ffmpeg::avformat_version(), ffmpeg::avcodec_version())
+    }
+}
+
+fn stamp_path(path: &Path) -> PathBuf {
+    let mut stamp = path.as_os_str().to_owned();
+    stamp.push(".stamp");
+    PathBuf::from(stamp)
+}
+
+fn stamp(path: &Path) -> Result<String> {
+    let mut start = Vec::new();
+    File::open(path)?.take(STAMPED_BYTES).read_to_end(&mut start)?;
+    Ok(format!("{}\n{}\n", ffmpeg_version(), hashing::to_hex(&hashing::checksum_bytes(&start))))
+}
+
+fn write_stamp(path: &Path) -> Result<()> {
+    fs::write(stamp_path(path), stamp(path)?)?;
+    Ok(())
+}
+
+/// Whether the cached file at `path` can be used, it is removed if it can't.
+fn is_cached(path: &Path) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let valid = match fs::read_to_string(stamp_path(path)) {
+        Ok(recorded) => recorded == stamp(path)?,
+        Err(_) => false,
+    };
+    if !valid {
+        info!("Recreating {}, it was written by another version or changed.", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(valid)
+}
+
+fn chapter_title(chapter: &Chapter, track: usize) -> String {
+    match chapter.title {
+        Some(ref t) if !t.trim().is_empty() => t.trim().to_owned(),
+        _ => format!("Chapter {}", track),
+    }
+}
+
+/// The name a chapter file is offered for download as, e.g. `03 - The Road.mp3`.
+pub fn download_name(chapter: &Chapter, track: usize, total: usize, extension: &str) -> String {
+    let width = total.to_string().len().max(2);
+    let title = chapter_title(chapter, track).replace(|c: char| "/\\:*?\"<>|".contains(c) || c.is_control(), "_");
+    format!("{:0width$} - {}.{}", track, title, extension, width = width)
+}
+
+/// Path of the file holding the `track`th chapter (starting at 1) of a book, created on first use.
+/// `chapters` have to be ordered by their start.
+pub fn chapter_file(config: &Config, book: &Audiobook, chapters: &[Chapter], track: usize, format: Format)
+    -> Result<PathBuf> {
+    if track == 0 || track > chapters.len() {
+        return Err(WorkerError::Other { description: format!("No chapter {}", track) }.into());
+    }
+    let directory = cache_directory(config)?;
+    let prefix = cache_prefix(book, chapters);
+    let path = directory.join(format!("{}-{}.{}", prefix, track, format.extension()));
+    if is_cached(&path)? {
+        return Ok(path);
+    }
+    remove_outdated(&directory, book, &prefix)?;
+
+    let mut source = PathBuf::from(&config.data_directory);
+    source.push(book.id.hyphenated().to_string());
+    source.set_extension(&book.file_extension);
+    let media = MediaFile::read_file(&source)?;
+
+    let chapter = &chapters[track - 1];
+    let end = chapters.get(track).map(|next| next.start_time);
+    let mut tags = vec![
+        ("title", chapter_title(chapter, track)),
+        ("album", book.title.clone()),
+        ("track", format!("{}/{}", track, chapters.len())),
+    ];
+    if let Some(ref artist) = book.artist {
+        tags.push(("artist", artist.clone()));
+    }
+    let cut = if book.file_extension == format.extension() {
+        muxer::extract_range(&path, &media, chapter.start_time, end, &tags)
+    } else {
+        muxer::transcode_range(&path, &media, chapter.start_time, end, &tags, format.encoder(), format.bit_rate())
+    };
+    cut.in_file("cut a chapter into", &path)?;
+    write_stamp(&path)?;
+    Ok(path)
+}
+
+/// Path of a zip archive containing every chapter of a book as its own file, created on first use.
+pub fn chapters_zip(config: &Config, book: &Audiobook, chapters: &[Chapter], format: Format) -> Result<PathBuf> {
+    let path = cache_directory(config)?.join(format!("{}-{}.zip", cache_prefix(book, chapters), format.extension()));
+    if is_cached(&path)? {
+        return Ok(path);
+    }
+
+    let mut entries = Vec::with_capacity(chapters.len());
+    for (i, chapter) in chapters.iter().enumerate() {
+        let file = chapter_file(config, book, chapters, i + 1, format)?;
+        entries.push((download_name(chapter, i + 1, chapters.len(), format.extension()), file));
+    }
+    let temp_file = TempFile::new(&path);
+    {
+        let mut out = BufWriter::new(File::create(temp_file.path())?);
+        zip::write_stored(&mut out, &entries)?;
+        out.flush()?;
+    }
+    temp_file.persist()?;
+    write_stamp(&path)?;
+    Ok(path)
+}
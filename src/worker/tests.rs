@@ -3,6 +3,9 @@ use super::mediafile::MediaFile;
 use std::env;
 use std::fs::create_dir_all;
 use super::muxer;
+use super::janitor::{self, TempFile};
+use std::fs;
+use std::time::Duration;
 use std::io::Cursor;
 use super::mediafile::ImageType;
 use image::jpeg::JPEGDecoder;
@@ -34,6 +37,7 @@ speculate! {
                 location: "test-data".to_owned(),
                 is_audiobook_regex: "^[^/]+$".to_owned(),
                 last_scan: None,
+                deleted_at: None,
             };
             diesel::insert_into(libraries::table)
                 .values(&library)
@@ -58,6 +62,99 @@ speculate! {
             assert_eq!(1, Audiobook::belonging_to(&library).count().first::<i64>(&*conn).unwrap());
         }
 
+        it "stitches embedded chapters of multi file audiobooks" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            test_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/m4bmulti")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number).load::<Chapter>(&*conn).unwrap();
+            // Both files are copies of all.m4b with its four chapters
+            let file_length = MediaFile::read_file(Path::new("test-data/m4bmulti/1.m4a")).unwrap().get_mediainfo().length;
+            assert_eq!(chapters.len(), 8);
+            assert_eq!(chapters[2].title.as_ref().unwrap(), "3 - Otpluva lekii cheln...");
+            assert_eq!(chapters[6].title, chapters[2].title);
+            assert_eq!(chapters[2].start_time.floor() as usize, 91);
+            assert!((chapters[4].start_time - file_length).abs() < 1.0);
+            assert!((chapters[6].start_time - chapters[2].start_time - file_length).abs() < 1.0);
+        }
+
+        it "makes a chapter of each file without embedded chapters" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::number;
+            test_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/all")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(number).load::<Chapter>(&*conn).unwrap();
+            assert_eq!(chapters.len(), 4);
+            assert_eq!(chapters[0].start_time, 0.0);
+            for pair in chapters.windows(2) {
+                assert!(pair[0].start_time < pair[1].start_time);
+            }
+            assert!(chapters.last().unwrap().start_time < book.length);
+        }
+
+        it "splits books into one file per chapter" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::start_time;
+            use crate::worker::splitter::{self, Format};
+            test_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/all")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(start_time).load::<Chapter>(&*conn).unwrap();
+            let second = splitter::chapter_file(&test_scanner.config, &book, &chapters, 2, Format::Mp3).unwrap();
+            let length = MediaFile::read_file(&second).unwrap().get_mediainfo().length;
+            assert!(length > 0.0 && length < book.length);
+            let archive = fs::read(splitter::chapters_zip(&test_scanner.config, &book, &chapters, Format::Mp3).unwrap()).unwrap();
+            assert_eq!(&archive[..4], b"PK\x03\x04");
+            assert!(splitter::chapter_file(&test_scanner.config, &book, &chapters, 5, Format::Mp3).is_err());
+        }
+
+        it "encodes chapters and recreates them when the chapters change" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::start_time;
+            use crate::worker::splitter::{self, Format};
+            test_scanner.create_audiobook(&*conn, &Path::new("test-data/all.m4b")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let mut chapters = Chapter::belonging_to(&book).order(start_time).load::<Chapter>(&*conn).unwrap();
+            for format in &[Format::Mp3, Format::Opus] {
+                let second = splitter::chapter_file(&test_scanner.config, &book, &chapters, 2, *format).unwrap();
+                assert_eq!(second.extension().unwrap(), format.extension());
+                let file = MediaFile::read_file(&second).unwrap();
+                assert_eq!(file.get_mediainfo().metadata.get("title"), chapters[1].title.as_ref());
+                let length = file.get_mediainfo().length;
+                assert!((length - (chapters[2].start_time - chapters[1].start_time)).abs() < 0.5);
+            }
+
+            let first = splitter::chapter_file(&test_scanner.config, &book, &chapters, 1, Format::Opus).unwrap();
+            chapters[0].title = Some("Renamed".to_owned());
+            let renamed = splitter::chapter_file(&test_scanner.config, &book, &chapters, 1, Format::Opus).unwrap();
+            assert_ne!(renamed, first);
+            assert!(!first.exists());
+            let title = MediaFile::read_file(&renamed).unwrap().get_mediainfo().metadata.get("title").cloned();
+            assert_eq!(title, Some("Renamed".to_owned()));
+        }
+
+        it "recreates split files written by another version" {
+            use crate::models::audiobook::Audiobook;
+            use crate::models::chapter::Chapter;
+            use crate::schema::chapters::dsl::start_time;
+            use crate::worker::splitter::{self, Format};
+            test_scanner.create_multifile_audiobook(&*conn, &Path::new("test-data/all")).unwrap();
+            let book = Audiobook::belonging_to(&library).first::<Audiobook>(&*conn).unwrap();
+            let chapters = Chapter::belonging_to(&book).order(start_time).load::<Chapter>(&*conn).unwrap();
+            let first = splitter::chapter_file(&test_scanner.config, &book, &chapters, 1, Format::Mp3).unwrap();
+            let stamp_path = format!("{}.stamp", first.display());
+            let stamp = fs::read_to_string(&stamp_path).unwrap();
+            assert!(stamp.starts_with("lavf "));
+
+            fs::write(&stamp_path, "lavf 0 lavc 0\n").unwrap();
+            let again = splitter::chapter_file(&test_scanner.config, &book, &chapters, 1, Format::Mp3).unwrap();
+            assert_eq!(again, first);
+            assert_eq!(fs::read_to_string(&stamp_path).unwrap().lines().next(), stamp.lines().next());
+        }
+
     }
 
     before {
@@ -132,6 +229,8 @@ speculate! {
             let mut tmp_dir = get_tempdir();
             tmp_dir.push(Path::new("muxed.mp3"));
             muxer::merge_files(&tmp_dir, &files).unwrap();
+            assert!(tmp_dir.exists());
+            assert!(!janitor::partial_path(&tmp_dir).exists());
         }
     }
 }
@@ -178,6 +277,15 @@ fn get_thumbnail_png() {
     assert_eq!((300, 300), png_dims);
 }
 
+#[test]
+fn guess_image_type() {
+    let jpeg_image = MediaFile::read_file(Path::new("test-data/1.mp3")).unwrap().get_coverart().unwrap().unwrap();
+    assert_eq!(ImageType::guess(&jpeg_image.data), Some(ImageType::JPG));
+    let png_image = MediaFile::read_file(Path::new("test-data/2.mp3")).unwrap().get_coverart().unwrap().unwrap();
+    assert_eq!(ImageType::guess(&png_image.data), Some(ImageType::PNG));
+    assert_eq!(ImageType::guess(b"ID3"), None);
+}
+
 #[test]
 fn get_thumbnail_none() {
     let f = MediaFile::read_file(Path::new("test-data/all.m4b")).unwrap();
@@ -204,3 +312,261 @@ fn assert_slice_starts_with(bytes: &[u8], start: &[u8]) {
         assert_eq!(i.next().unwrap(), b);
     }
 }
+
+#[test]
+fn janitor_removes_leftovers() {
+    let mut dir = get_tempdir();
+    dir.push("janitor");
+    fs::remove_dir_all(&dir).ok();
+    create_dir_all(&dir).unwrap();
+
+    // A merge that crashed midway never got to remove its temporary file
+    fs::write(janitor::partial_path(&dir.join("crashed.mp3")), b"half a book").unwrap();
+
+    let in_progress = TempFile::new(&dir.join("in_progress.mp3"));
+    fs::write(in_progress.path(), b"still muxing").unwrap();
+    fs::write(dir.join("finished.mp3"), b"a whole book").unwrap();
+
+    assert_eq!(janitor::clean(&dir, janitor::STALE_AFTER).unwrap(), 0);
+    assert_eq!(janitor::clean(&dir, Duration::from_secs(0)).unwrap(), 1);
+    assert!(!dir.join("crashed.part.mp3").exists());
+    assert!(in_progress.path().exists());
+    assert!(dir.join("finished.mp3").exists());
+
+    drop(in_progress);
+    assert!(!dir.join("in_progress.part.mp3").exists());
+}
+
+#[test]
+fn parses_playlists() {
+    use super::playlist;
+    let mut root = get_tempdir();
+    root.push("playlist");
+    fs::remove_dir_all(&root).ok();
+    let dir = root.join("book");
+    create_dir_all(dir.join("disc 2")).unwrap();
+    let test_data = env::current_dir().unwrap().join("test-data");
+    fs::copy(test_data.join("1.mp3"), dir.join("b.mp3")).unwrap();
+    fs::copy(test_data.join("3.mp3"), dir.join("disc 2").join("c.mp3")).unwrap();
+    fs::copy(test_data.join("2.mp3"), root.join("outside.mp3")).unwrap();
+    let manifest = format!(
+        "#EXTM3U\n#PLAYLIST:Curated\n#EXTINF:10,Second\n{}\n\nb.mp3\nmissing.mp3\n",
+        dir.join("disc 2").join("c.mp3").display()
+    );
+    fs::write(dir.join("book.m3u8"), manifest).unwrap();
+
+    let parsed = playlist::for_book(&dir, &root).unwrap().unwrap();
+    assert_eq!(parsed.title, "Curated");
+    let real = |p: PathBuf| p.canonicalize().unwrap();
+    assert_eq!(parsed.entries, vec![real(dir.join("disc 2").join("c.mp3")), real(dir.join("b.mp3"))]);
+    assert_eq!(playlist::parse(&dir.join("book.m3u8"), &root).unwrap(), parsed);
+    assert!(playlist::for_book(&test_data.join("1.mp3"), &test_data).unwrap().is_none());
+
+    // Entries may not leave the library, neither by absolute paths nor by going up
+    let escaping = format!("{}\n../outside.mp3\n", test_data.join("3.mp3").display());
+    fs::write(dir.join("book.m3u8"), escaping).unwrap();
+    assert_eq!(playlist::parse(&dir.join("book.m3u8"), &root).unwrap().entries, vec![real(root.join("outside.mp3"))]);
+    assert!(playlist::parse(&dir.join("book.m3u8"), &dir).is_err());
+}
+
+#[test]
+fn hashing_honors_rate_limit() {
+    use std::time::Instant;
+    use super::hashing;
+    let path = Path::new("test-data/1.mp3");
+    let size = fs::metadata(path).unwrap().len();
+    let unlimited = hashing::checksum_file(&path).unwrap();
+    let started = Instant::now();
+    let limited = {
+        let _limit = hashing::RateLimit::apply(Some(size * 4));
+        hashing::checksum_file(&path).unwrap()
+    };
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(limited, unlimited);
+}
+
+#[test]
+fn parses_fault_specs() {
+    use super::faults::Faults;
+    assert_eq!(Faults::parse("io=0.05, ffmpeg=0.1,slow=200"), Faults { io: 0.05, ffmpeg: 0.1, slow_reads: 200 });
+    assert_eq!(Faults::parse("io=lots,disk=1"), Faults::default());
+}
+
+#[test]
+fn injected_faults_are_errors() {
+    use super::faults::{self, Faults, Inject};
+    use super::hashing;
+    let path = Path::new("test-data/1.mp3");
+    {
+        let _faults = Inject::apply(Faults { io: 1.0, ffmpeg: 1.0, slow_reads: 0 });
+        assert!(hashing::checksum_file(&path).is_err());
+        assert!(MediaFile::read_file(path).is_err());
+    }
+    assert!(faults::read().is_ok());
+    assert!(hashing::checksum_file(&path).is_ok());
+    assert!(MediaFile::read_file(path).is_ok());
+}
+
+#[test]
+fn upgrades_data_directory_layout() {
+    use super::layout;
+    let dir = get_tempdir().join("layout");
+    fs::remove_dir_all(&dir).ok();
+    create_dir_all(dir.join("img")).unwrap();
+    let data_directory = dir.to_str().unwrap();
+    let book_id = Uuid::new_v4();
+    fs::write(dir.join("img").join(book_id.hyphenated().to_string()), b"cover").unwrap();
+    fs::write(dir.join("img").join("notes.txt"), b"mine").unwrap();
+    create_dir_all(dir.join("img").join("ab")).unwrap();
+    fs::write(dir.join("img").join("ab").join("cafe-small"), b"thumbnail").unwrap();
+
+    assert_eq!(layout::upgrade(data_directory).unwrap(), 1);
+    assert_eq!(fs::read(layout::cover_path(data_directory, &book_id)).unwrap(), b"cover");
+    assert!(dir.join("img").join("notes.txt").exists());
+    assert!(!dir.join("img").join("ab").join("cafe-small").exists());
+    assert_eq!(layout::version(data_directory).unwrap(), layout::CURRENT_VERSION);
+    assert_eq!(layout::upgrade(data_directory).unwrap(), layout::CURRENT_VERSION);
+
+    fs::write(dir.join("layout_version"), format!("{}\n", layout::CURRENT_VERSION + 1)).unwrap();
+    assert!(layout::upgrade(data_directory).is_err());
+}
+
+#[test]
+fn parses_provider_results() {
+    use crate::worker::lookup::{self, Candidate};
+    let body = json!({"products": [
+        {
+            "asin": "B002V0QK4C",
+            "title": "Dune",
+            "authors": [{"name": "Frank Herbert"}],
+            "narrators": [{"name": "Scott Brick"}, {"name": "Orlagh Cassidy"}],
+            "series": [{"title": "Dune", "sequence": "Book 1"}],
+            "publisher_summary": "<p>Set on the desert planet <b>Arrakis</b>&nbsp;&amp; more.</p><p>Second</p>",
+            "product_images": {"500": "https://example.com/dune.jpg"}
+        },
+        {"title": "Missing an id"}
+    ]}).into_inner();
+    let candidates = lookup::parse_products(&body);
+    assert_eq!(candidates, vec![Candidate {
+        id: "B002V0QK4C".to_owned(),
+        title: "Dune".to_owned(),
+        authors: vec!["Frank Herbert".to_owned()],
+        narrators: vec!["Scott Brick".to_owned(), "Orlagh Cassidy".to_owned()],
+        series: Some("Dune".to_owned()),
+        series_position: Some(1.0),
+        description: Some("Set on the desert planet Arrakis & more.\nSecond".to_owned()),
+        cover_url: Some("https://example.com/dune.jpg".to_owned()),
+    }]);
+
+    assert!(lookup::is_confident("DUNE", Some("Frank Herbert, Scott Brick"), &candidates[0]));
+    assert!(lookup::is_confident("Dune", None, &candidates[0]));
+    assert!(!lookup::is_confident("Dune", Some("Brian Herbert"), &candidates[0]));
+    assert!(!lookup::is_confident("Dune Messiah", Some("Frank Herbert"), &candidates[0]));
+
+    assert_eq!(lookup::normalize_isbn("ISBN 978-0-441-17271-9"), Some("9780441172719".to_owned()));
+    assert_eq!(lookup::normalize_isbn("0-441-17271-x"), Some("044117271X".to_owned()));
+    assert_eq!(lookup::normalize_isbn("978-0-441"), None);
+    assert_eq!(lookup::normalize_isbn("Dune"), None);
+}
+
+#[test]
+fn finds_series_in_tags_and_folders() {
+    use crate::models::series;
+    use std::collections::BTreeMap;
+    let mut tags = BTreeMap::new();
+    tags.insert("title".to_owned(), "Leviathan Wakes".to_owned());
+    assert_eq!(series::from_tags(&tags), None);
+    tags.insert("SERIES".to_owned(), "The Expanse".to_owned());
+    assert_eq!(series::from_tags(&tags), Some(("The Expanse".to_owned(), None)));
+    tags.insert("series-part".to_owned(), "1/9".to_owned());
+    assert_eq!(series::from_tags(&tags), Some(("The Expanse".to_owned(), Some(1.0))));
+
+    assert_eq!(series::from_location("Expanse/03 Abaddon's Gate.m4b"), Some(("Expanse".to_owned(), 3.0)));
+    assert_eq!(series::from_location("Expanse/Book 2.5 - The Churn"), Some(("Expanse".to_owned(), 2.5)));
+    assert_eq!(series::from_location("Orwell/1984.m4b"), None);
+    assert_eq!(series::from_location("Orwell/Animal Farm.m4b"), None);
+    assert_eq!(series::from_location("03 Abaddon's Gate.m4b"), None);
+}
+
+#[test]
+fn renders_cover_thumbnails() {
+    use super::thumbnails;
+    use image::GenericImage;
+    let cover = MediaFile::read_file(Path::new("test-data/2.mp3")).unwrap().get_coverart().unwrap().unwrap();
+
+    let small = thumbnails::render(&cover.data, 128).unwrap();
+    // JPEG only if FFmpeg has no libwebp
+    assert!(thumbnails::is_webp(&small) || ImageType::guess(&small) == Some(ImageType::JPG));
+    assert_eq!(image::load_from_memory(&small).unwrap().dimensions(), (128, 128));
+    // The 300x300 cover isn't blown up to 512 pixels
+    let medium = thumbnails::render(&cover.data, 512).unwrap();
+    assert_eq!(image::load_from_memory(&medium).unwrap().dimensions(), (300, 300));
+
+    assert_eq!(thumbnails::pixels("large"), Some(1024));
+    assert_eq!(thumbnails::pixels("huge"), None);
+}
+
+#[test]
+fn removes_thumbnails_of_old_covers_and_files_of_removed_books() {
+    use super::{layout, thumbnails};
+    use crate::models::audiobook::test_book;
+    let dir = get_tempdir().join("thumbnails");
+    fs::remove_dir_all(&dir).ok();
+    let data_directory = dir.to_str().unwrap();
+    let book = test_book(Uuid::new_v4(), "book.mp3", "Book");
+    let cover = MediaFile::read_file(Path::new("test-data/2.mp3")).unwrap().get_coverart().unwrap().unwrap();
+
+    thumbnails::generate(data_directory, &book.id, "old", &cover.data).unwrap();
+    thumbnails::generate(data_directory, &book.id, "new", &cover.data).unwrap();
+    assert!(!thumbnails::path(data_directory, &book.id, "old", "small").exists());
+    assert!(thumbnails::path(data_directory, &book.id, "new", "small").exists());
+
+    let chapter_image = layout::chapter_image_path(data_directory, &book.id, &Uuid::new_v4());
+    fs::create_dir_all(chapter_image.parent().unwrap()).unwrap();
+    fs::write(&chapter_image, b"slide").unwrap();
+    janitor::remove_book_files(data_directory, &[book.clone()]);
+    assert!(!layout::thumbnail_directory(data_directory, &book.id).exists());
+    assert!(!layout::chapter_image_directory(data_directory, &book.id).exists());
+}
+
+#[test]
+fn picks_slides_at_chapter_starts() {
+    use super::mediafile::SlidePicker;
+    use super::layout;
+    let pick = |times: &[f64], starts: &[f64]| {
+        let mut picker = SlidePicker::new(starts);
+        let mut picked = Vec::new();
+        for (slide, time) in times.iter().enumerate() {
+            picked.extend(picker.push(*time, slide));
+        }
+        picked.extend(picker.finish());
+        picked.sort();
+        picked
+    };
+    let times = [0.0, 30.0, 95.8, 200.0];
+    // Muxers put slides a little after the chapter they belong to
+    assert_eq!(pick(&times, &[0.0, 90.0, 95.5, 500.0]), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+    assert_eq!(pick(&times, &[500.0, 0.0]), vec![(0, 3), (1, 0)]);
+    assert_eq!(pick(&[10.0], &[0.0]), vec![]);
+    assert_eq!(pick(&[], &[0.0]), vec![]);
+
+    let book_id = Uuid::parse_str("0b9a3f0c-7c3e-4c7a-9a57-8d1f5c1e2a11").unwrap();
+    let chapter_id = Uuid::parse_str("5e1d7a8b-2f4c-4b1e-8a3d-6c9f0e2b4d77").unwrap();
+    assert_eq!(
+        layout::chapter_image_path("/data", &book_id, &chapter_id),
+        Path::new("/data/img/0b/0b9a3f0c-7c3e-4c7a-9a57-8d1f5c1e2a11.chapters/5e1d7a8b-2f4c-4b1e-8a3d-6c9f0e2b4d77")
+    );
+}
+
+#[test]
+fn errors_name_the_file() {
+    use super::error::WorkerError;
+    let error = MediaFile::read_file(Path::new("test-data/missing.mp3")).unwrap_err();
+    let file_error = match error.downcast_ref::<WorkerError>() {
+        Some(WorkerError::File(e)) => e,
+        e => panic!("{:?} is no file error", e),
+    };
+    assert_eq!(file_error.step, "open");
+    assert_eq!(file_error.path, Path::new("test-data/missing.mp3"));
+    assert!(error.to_string().starts_with("Could not open test-data/missing.mp3: Error -2"));
+}
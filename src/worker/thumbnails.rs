@@ -0,0 +1,171 @@
+//! Scaled down versions of covers for clients that don't need the full image.
+//!
+//! Embedded covers are often a megabyte or more, phones showing a list of books only need a small
+//! one. Thumbnails are made when a scan saves a cover, covers saved otherwise get theirs the first
+//! time they are asked for. Each book has a directory of them next to its cover, see
+//! `layout::thumbnail_directory`. They are named after the hash of the cover, thumbnails of covers
+//! the book had before are removed when it gets a new one. They are WebP images encoded by FFmpeg's
+//! libwebp, or JPEGs where FFmpeg was built without it, see `is_webp`.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+
+use image::{self, DynamicImage, GenericImage, ImageOutputFormat};
+
+use crate::ffmpeg::{
+    AVPacket,
+    AVPixelFormat,
+    AVRational,
+    av_frame_get_buffer,
+    av_init_packet,
+    av_packet_unref,
+    avcodec_find_encoder_by_name,
+    avcodec_receive_packet,
+    avcodec_send_frame,
+};
+use crate::helpers::uuid::Uuid;
+use crate::worker::error::Result;
+use crate::worker::janitor::{self, TempFile};
+use crate::worker::layout;
+use crate::worker::util::{CodecContext, Frame, check_av_result, ensure_av_register_all};
+
+/// Names of the sizes with the pixels along the longer side.
+pub const SIZES: &[(&str, u32)] = &[("small", 128), ("medium", 512), ("large", 1024)];
+
+pub fn pixels(size: &str) -> Option<u32> {
+    SIZES.iter().find(|(name, _)| *name == size).map(|(_, pixels)| *pixels)
+}
+
+pub fn path(data_directory: &str, book_id: &Uuid, cover_hash: &str, size: &str) -> PathBuf {
+    layout::thumbnail_directory(data_directory, book_id).join(format!("{}-{}", cover_hash, size))
+}
+
+/// Remove the thumbnails of covers the book had before `cover_hash`, or all of them without one.
+pub fn remove_stale(data_directory: &str, book_id: &Uuid, cover_hash: Option<&str>) -> Result<()> {
+    let entries = match fs::read_dir(layout::thumbnail_directory(data_directory, book_id)) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let current = match (cover_hash, path.file_name().and_then(|n| n.to_str())) {
+            (Some(hash), Some(name)) => name.starts_with(&format!("{}-", hash)),
+            _ => false,
+        };
+        if !current && !janitor::is_partial(&path) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a thumbnail is WebP rather than JPEG.
+pub fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP"
+}
+
+/// What libwebp calls RGB32, four bytes per pixel in native endianness.
+#[cfg(target_endian = "little")]
+const RGB32: AVPixelFormat = AVPixelFormat::AV_PIX_FMT_BGRA;
+#[cfg(target_endian = "big")]
+const RGB32: AVPixelFormat = AVPixelFormat::AV_PIX_FMT_ARGB;
+
+#[cfg(target_endian = "little")]
+fn rgb32(rgba: &[u8]) -> [u8; 4] {
+    [rgba[2], rgba[1], rgba[0], rgba[3]]
+}
+
+#[cfg(target_endian = "big")]
+fn rgb32(rgba: &[u8]) -> [u8; 4] {
+    [rgba[3], rgba[0], rgba[1], rgba[2]]
+}
+
+/// Encode an image as WebP, `None` if FFmpeg was built without libwebp.
+fn encode_webp(image: &DynamicImage) -> Result<Option<Vec<u8>>> {
+    ensure_av_register_all();
+    let name = CString::new("libwebp").unwrap();
+    unsafe {
+        let codec = avcodec_find_encoder_by_name(name.as_ptr());
+        if codec.is_null() {
+            return Ok(None);
+        }
+        let rgba = image.to_rgba();
+        let (width, height) = rgba.dimensions();
+        let encoder = CodecContext::open(codec, |ctx| {
+            (*ctx).width = width as i32;
+            (*ctx).height = height as i32;
+            (*ctx).pix_fmt = RGB32;
+            (*ctx).time_base = AVRational { num: 1, den: 1 };
+            0
+        })?;
+        let frame = Frame::new();
+        (*frame.0).width = width as i32;
+        (*frame.0).height = height as i32;
+        (*frame.0).format = RGB32 as i32;
+        check_av_result(av_frame_get_buffer(frame.0, 0))?;
+        let linesize = (*frame.0).linesize[0] as usize;
+        for (y, row) in rgba.chunks(width as usize * 4).enumerate() {
+            let line = slice::from_raw_parts_mut((*frame.0).data[0].add(y * linesize), row.len());
+            for (out, pixel) in line.chunks_mut(4).zip(row.chunks(4)) {
+                out.copy_from_slice(&rgb32(pixel));
+            }
+        }
+        check_av_result(avcodec_send_frame(encoder.0, frame.0))?;
+        check_av_result(avcodec_send_frame(encoder.0, ptr::null()))?;
+        let mut pkt: AVPacket = mem::zeroed();
+        av_init_packet(&mut pkt);
+        check_av_result(avcodec_receive_packet(encoder.0, &mut pkt))?;
+        let data = slice::from_raw_parts(pkt.data, pkt.size as usize).to_owned();
+        av_packet_unref(&mut pkt);
+        Ok(Some(data))
+    }
+}
+
+/// Scale a cover down so it fits into a `pixels` by `pixels` square, smaller covers keep their size.
+pub fn render(cover: &[u8], pixels: u32) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(cover)?;
+    let scaled = if image.width() <= pixels && image.height() <= pixels {
+        image
+    } else {
+        image.thumbnail(pixels, pixels)
+    };
+    if let Some(webp) = encode_webp(&scaled)? {
+        return Ok(webp);
+    }
+    let mut out = Vec::new();
+    scaled.write_to(&mut out, ImageOutputFormat::JPEG(85))?;
+    Ok(out)
+}
+
+fn write(data_directory: &str, book_id: &Uuid, cover_hash: &str, size: &str, data: &[u8]) -> Result<()> {
+    fs::create_dir_all(layout::thumbnail_directory(data_directory, book_id))?;
+    let temp = TempFile::new(&path(data_directory, book_id, cover_hash, size));
+    fs::write(temp.path(), data)?;
+    temp.persist()
+}
+
+/// Make all thumbnails of a cover that was just saved, the ones of the book's previous covers go.
+pub fn generate(data_directory: &str, book_id: &Uuid, cover_hash: &str, cover: &[u8]) -> Result<()> {
+    remove_stale(data_directory, book_id, Some(cover_hash))?;
+    for (size, pixels) in SIZES {
+        write(data_directory, book_id, cover_hash, size, &render(cover, *pixels)?)?;
+    }
+    Ok(())
+}
+
+/// The thumbnail of a cover in `size`, made now if it doesn't exist yet.
+pub fn get(data_directory: &str, book_id: &Uuid, cover_hash: &str, size: &str, pixels: u32, cover: &[u8])
+    -> Result<Vec<u8>> {
+    if let Ok(data) = fs::read(path(data_directory, book_id, cover_hash, size)) {
+        return Ok(data);
+    }
+    let data = render(cover, pixels)?;
+    write(data_directory, book_id, cover_hash, size, &data)?;
+    Ok(data)
+}
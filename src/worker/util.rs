@@ -1,9 +1,20 @@
 use std::ffi::{CStr, CString};
 use std::slice;
+use std::ptr;
 use std::collections::HashMap;
 use std::sync::Mutex;
 use std::os::raw::c_char;
 use crate::ffmpeg::{AVDictionaryEntry, AVRational, av_register_all, av_log_set_level, AV_LOG_QUIET};
+use crate::ffmpeg::{
+    AVCodec,
+    AVCodecContext,
+    AVFrame,
+    av_frame_alloc,
+    av_frame_free,
+    avcodec_alloc_context3,
+    avcodec_free_context,
+    avcodec_open2,
+};
 use crate::worker::error::*;
 use std::fs::File;
 use std::io::Read;
@@ -95,6 +106,45 @@ pub(super) fn ptr_to_opt<T>(ptr: *const T) -> Option<*const T> {
     }
 }
 
+/// An opened decoder or encoder.
+pub(super) struct CodecContext(pub(super) *mut AVCodecContext);
+
+impl CodecContext {
+    pub(super) unsafe fn open(codec: *mut AVCodec, setup: impl FnOnce(*mut AVCodecContext) -> i32) -> Result<Self> {
+        if codec.is_null() {
+            return Err(WorkerError::Other { description: "FFmpeg has no such codec".to_owned() }.into())
+        }
+        let ctx = CodecContext(avcodec_alloc_context3(codec));
+        check_av_result(setup(ctx.0))?;
+        check_av_result(avcodec_open2(ctx.0, codec, ptr::null_mut()))?;
+        Ok(ctx)
+    }
+}
+
+impl Drop for CodecContext {
+    fn drop(&mut self) {
+        unsafe {
+            avcodec_free_context(&mut self.0);
+        }
+    }
+}
+
+pub(super) struct Frame(pub(super) *mut AVFrame);
+
+impl Frame {
+    pub(super) fn new() -> Self {
+        unsafe { Frame(av_frame_alloc()) }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        unsafe {
+            av_frame_free(&mut self.0);
+        }
+    }
+}
+
 pub fn shut_up_ffmpeg() {
     unsafe {
         av_log_set_level(AV_LOG_QUIET);
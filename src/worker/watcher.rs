@@ -0,0 +1,151 @@
+//! Watching libraries for changes so new books show up without waiting for the next scan.
+//!
+//! Events are debounced by notify and then collected until the library has been quiet for a
+//! moment, copying an audiobook creates lots of events. The books containing the changed paths
+//! are then updated by the scanner, as in an incremental scan.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use log::error as error_log;
+
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+
+use crate::config::Config;
+use crate::helpers::db::Pool;
+use crate::helpers::uuid::Uuid;
+use crate::models::library::Library;
+use crate::worker::error::Result;
+use crate::worker::scanner::{Scanner, LockingBehavior};
+use crate::worker::scheduler::{self, ScanClaim, load_library};
+
+/// How long notify waits for further events on the same path.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// How long to wait for more changes before updating books.
+const QUIET_PERIOD: Duration = Duration::from_secs(5);
+/// How often to check whether the library was deleted or moved while nothing happens.
+const LIBRARY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What to do after a batch of events.
+#[derive(Debug, PartialEq)]
+enum Changes {
+    Paths(HashSet<PathBuf>),
+    /// Events were lost, only a scan of the whole library is sure to pick everything up.
+    Everything,
+}
+
+impl Changes {
+    fn add(&mut self, event: DebouncedEvent) {
+        let paths = match event {
+            DebouncedEvent::Create(p) | DebouncedEvent::Write(p) | DebouncedEvent::Remove(p) => vec![p],
+            DebouncedEvent::Rename(from, to) => vec![from, to],
+            DebouncedEvent::Rescan => {
+                *self = Changes::Everything;
+                return;
+            },
+            DebouncedEvent::Error(e, path) => {
+                warn!("Error watching {:?}: {}", path, e);
+                *self = Changes::Everything;
+                return;
+            },
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Chmod(_) => return,
+        };
+        if let Changes::Paths(ref mut changed) = *self {
+            changed.extend(paths);
+        }
+    }
+}
+
+/// Wait for a batch of events, `None` once the library should be looked at again.
+fn next_changes(events: &Receiver<DebouncedEvent>) -> Option<Changes> {
+    let mut changes = match events.recv_timeout(LIBRARY_CHECK_INTERVAL) {
+        Ok(event) => {
+            let mut changes = Changes::Paths(HashSet::new());
+            changes.add(event);
+            changes
+        },
+        Err(_) => return None,
+    };
+    loop {
+        match events.recv_timeout(QUIET_PERIOD) {
+            Ok(event) => changes.add(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => return Some(changes),
+        }
+    }
+}
+
+/// A scan claim for the library, waiting for running scans to finish.
+fn wait_for_claim(library: &Library) -> ScanClaim {
+    loop {
+        match ScanClaim::new(library) {
+            Ok(claim) => return claim,
+            Err(_) => thread::sleep(QUIET_PERIOD),
+        }
+    }
+}
+
+fn apply(pool: &Pool, config: &Config, library: Library, changes: Changes) -> Result<()> {
+    match changes {
+        Changes::Everything => scheduler::run_scan(pool, config, library, false).map(|_| ()),
+        Changes::Paths(paths) => {
+            let _claim = wait_for_claim(&library);
+            let paths = paths.into_iter().collect::<Vec<PathBuf>>();
+            Scanner::new(pool.clone(), library, config.clone()).scan_paths(&paths, LockingBehavior::Block)
+        },
+    }
+}
+
+/// Watch a library until it is deleted, following it when its location changes.
+pub fn watch_library(pool: Pool, config: Config, library_id: Uuid) {
+    'watching: loop {
+        let library = match load_library(&pool, &library_id) {
+            Ok(Some(library)) => library,
+            Ok(None) => return,
+            Err(e) => {
+                error_log!("Could not load library {}: {}", library_id.hyphenated(), e);
+                thread::sleep(LIBRARY_CHECK_INTERVAL);
+                continue;
+            }
+        };
+        let (sender, events) = channel();
+        let mut watcher = match notify::watcher(sender, DEBOUNCE) {
+            Ok(w) => w,
+            Err(e) => {
+                error_log!("Could not start watching files: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = watcher.watch(&library.location, RecursiveMode::Recursive) {
+            error_log!("Could not watch {}: {}", library.location, e);
+            thread::sleep(LIBRARY_CHECK_INTERVAL);
+            continue;
+        }
+        info!("Watching {} for changes.", library.location);
+
+        loop {
+            let changes = next_changes(&events);
+            let current = match load_library(&pool, &library_id) {
+                Ok(Some(current)) => current,
+                Ok(None) => {
+                    info!("Library {} is gone, no longer watching it.", library_id.hyphenated());
+                    return;
+                },
+                Err(e) => {
+                    error_log!("Could not load library {}: {}", library_id.hyphenated(), e);
+                    continue;
+                }
+            };
+            if current.location != library.location {
+                continue 'watching;
+            }
+            if let Some(changes) = changes {
+                debug!("Changes in {}: {:?}", current.location, changes);
+                if let Err(e) = apply(&pool, &config, current, changes) {
+                    error_log!("Updating library {} after changes failed: {}", library_id.hyphenated(), e);
+                }
+            }
+        }
+    }
+}